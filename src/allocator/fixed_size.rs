@@ -1,5 +1,6 @@
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::mem;
+use core::sync::atomic::Ordering;
 
 struct Node {
     next: Option<&'static mut Node>,
@@ -40,6 +41,7 @@ impl FixedSize {
 unsafe impl GlobalAlloc for super::Locked<FixedSize> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
+        super::USED.fetch_add(layout.size(), Ordering::Relaxed);
 
         let list_index = FixedSize::list_index(&layout);
         if let Some(list_index) = list_index {
@@ -62,6 +64,7 @@ unsafe impl GlobalAlloc for super::Locked<FixedSize> {
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
+        super::USED.fetch_sub(layout.size(), Ordering::Relaxed);
 
         let list_index = FixedSize::list_index(&layout);
         if let Some(list_index) = list_index {
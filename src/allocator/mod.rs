@@ -1,4 +1,5 @@
 use alloc::alloc::Layout;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use x86_64::{
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
@@ -52,6 +53,9 @@ static ALLOCATOR: Locked<fixed_size::FixedSize> = Locked::new(fixed_size::FixedS
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
+/// Bytes currently allocated on the heap (as requested, without the padding)
+static USED: AtomicUsize = AtomicUsize::new(0);
+
 /// Initialises a new heap.
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
@@ -84,6 +88,10 @@ pub fn is_ready() -> bool {
     ALLOCATOR.lock().is_ready()
 }
 
+pub fn used() -> usize {
+    USED.load(Ordering::Relaxed)
+}
+
 #[alloc_error_handler]
 fn alloc_error_handler(layout: Layout) -> ! {
     panic!("Allocation error: {:?}", layout)
@@ -0,0 +1,334 @@
+//! Local APIC and I/O APIC support
+//!
+//! When the MADT says we have APICs, they replace the legacy 8259 PICs,
+//! which are then completely masked. Until [`init`] is called, interrupts
+//! still go through the PICs.
+//!
+//! https://wiki.osdev.org/APIC
+//! https://wiki.osdev.org/IOAPIC
+
+use crate::interrupt::InterruptIndex;
+use crate::memory::{AcpiHandler, MEM_OFFSET};
+use crate::println;
+use crate::prt::LegacyInterrupt;
+use acpi::platform::interrupt::{InterruptModel, Polarity, TriggerMode};
+use acpi::AcpiTables;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::model_specific::Msr;
+
+/// Vector used for spurious interrupts. It has to end with 0xF on old CPUs.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Virtual address of the local APIC registers, or 0 if the APIC is not used
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+const IA32_APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC registers
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TPR: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xb0;
+const LAPIC_SVR: u64 = 0xf0;
+const LAPIC_ICR_LOW: u64 = 0x300;
+const LAPIC_ICR_HIGH: u64 = 0x310;
+const LAPIC_LVT_TIMER: u64 = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: u64 = 0x380;
+const LAPIC_TIMER_DIVIDE: u64 = 0x3e0;
+
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+// I/O APIC registers
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+
+struct IoApic {
+    /// Virtual address of the registers
+    base: u64,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    unsafe fn read(&self, reg: u32) -> u32 {
+        core::ptr::write_volatile(self.base as *mut u32, reg);
+        core::ptr::read_volatile((self.base + 0x10) as *const u32)
+    }
+
+    unsafe fn write(&self, reg: u32, value: u32) {
+        core::ptr::write_volatile(self.base as *mut u32, reg);
+        core::ptr::write_volatile((self.base + 0x10) as *mut u32, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+
+    fn redirection(&self, gsi: u32) -> u64 {
+        let reg = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        unsafe { self.read(reg) as u64 | ((self.read(reg + 1) as u64) << 32) }
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let reg = IOAPIC_REDIRECTION_TABLE + (gsi - self.gsi_base) * 2;
+        unsafe {
+            // mask the entry while we are changing it
+            self.write(reg, REDIRECTION_MASKED as u32);
+            self.write(reg + 1, (entry >> 32) as u32);
+            self.write(reg, entry as u32);
+        }
+    }
+}
+
+/// How an ISA IRQ is connected to the I/O APIC
+#[derive(Clone, Copy, Debug)]
+struct IsaRoute {
+    gsi: u32,
+    active_low: bool,
+    level: bool,
+}
+
+lazy_static::lazy_static! {
+    static ref IO_APICS: spin::Mutex<Vec<IoApic>> = spin::Mutex::new(Vec::new());
+    static ref ISA_ROUTES: spin::Mutex<[IsaRoute; 16]> = spin::Mutex::new({
+        let mut routes = [IsaRoute { gsi: 0, active_low: false, level: false }; 16];
+        for (irq, route) in routes.iter_mut().enumerate() {
+            route.gsi = irq as u32;
+        }
+        routes
+    });
+}
+
+/// Is the APIC used instead of the legacy PICs?
+pub fn is_enabled() -> bool {
+    LAPIC_BASE.load(Ordering::Relaxed) != 0
+}
+
+/// Sets up the local APIC of the current CPU and the I/O APICs described
+/// in the MADT.
+///
+/// Returns `false` if there is no APIC, in which case the PICs are still used.
+pub fn init(tables: &AcpiTables<AcpiHandler>) -> bool {
+    let apic = match tables.platform_info() {
+        Ok(info) => match info.interrupt_model {
+            InterruptModel::Apic(apic) => apic,
+            _ => return false,
+        },
+        Err(e) => {
+            println!("Couldn't read the MADT: {:?}", e);
+            return false;
+        }
+    };
+
+    // The PICs may still raise spurious interrupts, but they were remapped
+    // by `crate::init`, so it is fine.
+    // Interrupts are disabled so that the keyboard handler doesn't try to
+    // lock the PICs at the same time.
+    without_interrupts(|| unsafe {
+        crate::interrupt::PICS.lock().write_masks(0xff, 0xff);
+    });
+
+    {
+        let mut io_apics = IO_APICS.lock();
+        for io_apic in apic.io_apics.iter() {
+            let mut io_apic = IoApic {
+                base: MEM_OFFSET + io_apic.address as u64,
+                gsi_base: io_apic.global_system_interrupt_base,
+                entries: 0,
+            };
+            io_apic.entries = ((unsafe { io_apic.read(IOAPIC_VERSION) } >> 16) & 0xff) + 1;
+            for gsi in io_apic.gsi_base..(io_apic.gsi_base + io_apic.entries) {
+                io_apic.set_redirection(gsi, REDIRECTION_MASKED);
+            }
+            io_apics.push(io_apic);
+        }
+    }
+
+    {
+        let mut routes = ISA_ROUTES.lock();
+        for over in apic.interrupt_source_overrides.iter() {
+            if let Some(route) = routes.get_mut(over.isa_source as usize) {
+                *route = IsaRoute {
+                    gsi: over.global_system_interrupt,
+                    // ISA interrupts are active high and edge triggered by default
+                    active_low: matches!(over.polarity, Polarity::ActiveLow),
+                    level: matches!(over.trigger_mode, TriggerMode::Level),
+                };
+            }
+        }
+    }
+
+    init_local_apic(apic.local_apic_address);
+
+    // The timer stays masked until `crate::ready` is called
+    route_isa(0, InterruptIndex::Timer.as_u8(), true);
+    route_isa(1, InterruptIndex::Keyboard.as_u8(), false);
+    // The RTC is unmasked when its interrupts are enabled (see `crate::cmos`)
+    route_isa(8, InterruptIndex::Rtc.as_u8(), true);
+    // Unmasked once the mouse is initialized (see `crate::input::mouse`)
+    route_isa(12, InterruptIndex::Mouse.as_u8(), true);
+    true
+}
+
+fn init_local_apic(phys_addr: u64) {
+    LAPIC_BASE.store(MEM_OFFSET + phys_addr, Ordering::SeqCst);
+    enable_local_apic();
+}
+
+/// Enables the local APIC of the current CPU.
+///
+/// On application processors, it must be called after [`init`] was called by
+/// the bootstrap processor (all the local APICs are at the same address).
+pub fn enable_local_apic() {
+    unsafe {
+        let mut msr = Msr::new(IA32_APIC_BASE_MSR);
+        let value = msr.read();
+        msr.write(value | APIC_BASE_ENABLE);
+    }
+
+    unsafe {
+        write_lapic(LAPIC_TPR, 0);
+        write_lapic(LAPIC_SVR, 0x100 | SPURIOUS_VECTOR as u32);
+    }
+}
+
+unsafe fn write_lapic(reg: u64, value: u32) {
+    let base = LAPIC_BASE.load(Ordering::Relaxed);
+    core::ptr::write_volatile((base + reg) as *mut u32, value);
+}
+
+unsafe fn read_lapic(reg: u64) -> u32 {
+    let base = LAPIC_BASE.load(Ordering::Relaxed);
+    core::ptr::read_volatile((base + reg) as *const u32)
+}
+
+/// ID of the local APIC of the current CPU
+pub fn local_apic_id() -> u32 {
+    unsafe { read_lapic(LAPIC_ID) >> 24 }
+}
+
+/// Starts the local APIC timer in periodic mode.
+///
+/// The timer frequency is the bus frequency divided by 16, so
+/// `initial_count` should be calibrated against another clock.
+pub fn start_timer(vector: u8, initial_count: u32) {
+    unsafe {
+        write_lapic(LAPIC_TIMER_DIVIDE, 0b0011);
+        write_lapic(LAPIC_LVT_TIMER, vector as u32 | LVT_TIMER_PERIODIC);
+        write_lapic(LAPIC_TIMER_INITIAL_COUNT, initial_count);
+    }
+}
+
+/// Sends an INIT inter-processor interrupt, to reset another CPU.
+pub fn send_init(apic_id: u32) {
+    send_ipi(apic_id, ICR_INIT | ICR_ASSERT);
+}
+
+/// Sends a STARTUP inter-processor interrupt: the CPU will start executing
+/// real mode code at `page * 0x1000`.
+pub fn send_startup(apic_id: u32, page: u8) {
+    send_ipi(apic_id, ICR_STARTUP | ICR_ASSERT | page as u32);
+}
+
+/// Sends an interrupt to `vector` on all the other CPUs.
+pub fn send_to_others(vector: u8) {
+    send_ipi(0, ICR_ALL_EXCLUDING_SELF | ICR_ASSERT | vector as u32);
+}
+
+fn send_ipi(apic_id: u32, command: u32) {
+    unsafe {
+        write_lapic(LAPIC_ICR_HIGH, apic_id << 24);
+        write_lapic(LAPIC_ICR_LOW, command);
+        while read_lapic(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// Signals the end of an interrupt to the local APIC.
+///
+/// It is a single register write, so it can be called from any
+/// interrupt handler without locking anything.
+pub fn end_of_interrupt() {
+    unsafe {
+        write_lapic(LAPIC_EOI, 0);
+    }
+}
+
+/// Sends a global system interrupt to `vector`, on the CPU that is running
+/// this function.
+pub fn route(gsi: u32, vector: u8, active_low: bool, level: bool, masked: bool) {
+    let mut entry = vector as u64 | ((local_apic_id() as u64) << 56);
+    if active_low {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if level {
+        entry |= REDIRECTION_LEVEL;
+    }
+    if masked {
+        entry |= REDIRECTION_MASKED;
+    }
+
+    // interrupt handlers mask lines too (see `set_masked`)
+    let found = without_interrupts(|| {
+        let io_apics = IO_APICS.lock();
+        let io_apic = io_apics.iter().find(|io_apic| io_apic.handles(gsi));
+        io_apic.map(|io_apic| io_apic.set_redirection(gsi, entry))
+    });
+    if found.is_none() {
+        println!("WARNING: no I/O APIC handles GSI {}", gsi);
+    }
+}
+
+/// Routes a legacy ISA IRQ (taking the MADT overrides into account).
+pub fn route_isa(irq: u8, vector: u8, masked: bool) {
+    let route = ISA_ROUTES.lock()[irq as usize];
+    self::route(route.gsi, vector, route.active_low, route.level, masked);
+}
+
+/// Routes the legacy interrupt of a PCI device (see [`crate::prt`]).
+///
+/// Only drivers that handle the interrupt should route it: the line is
+/// level triggered, and stays asserted until the device is told to stop.
+pub fn route_pci(interrupt: LegacyInterrupt, vector: u8, masked: bool) {
+    route(
+        interrupt.gsi,
+        vector,
+        interrupt.active_low,
+        interrupt.level,
+        masked,
+    );
+}
+
+pub fn set_isa_masked(irq: u8, masked: bool) {
+    let gsi = ISA_ROUTES.lock()[irq as usize].gsi;
+    set_masked(gsi, masked);
+}
+
+/// Masks or unmasks a global system interrupt. It can be called from
+/// interrupt handlers.
+pub fn set_masked(gsi: u32, masked: bool) {
+    without_interrupts(|| {
+        let io_apics = IO_APICS.lock();
+        if let Some(io_apic) = io_apics.iter().find(|io_apic| io_apic.handles(gsi)) {
+            let entry = io_apic.redirection(gsi);
+            let entry = if masked {
+                entry | REDIRECTION_MASKED
+            } else {
+                entry & !REDIRECTION_MASKED
+            };
+            io_apic.set_redirection(gsi, entry);
+        }
+    });
+}
@@ -1,7 +1,60 @@
-/// CMOS is mostly used to get datetime information
-///
-/// https://wiki.osdev.org/CMOS
+//! CMOS is mostly used to get datetime information
+//!
+//! The real time clock (RTC) updates its registers once per second. Reading
+//! them during an update gives inconsistent values, so they are read until two
+//! readings in a row are the same.
+//!
+//! The RTC can also raise interrupts (IRQ 8) periodically ([`periodic`]) or at
+//! a given time of the day ([`alarm`]).
+//!
+//! https://wiki.osdev.org/CMOS
+//! https://wiki.osdev.org/RTC
+
+use crate::interrupt::PICS;
+use crate::memory::AcpiHandler;
+use acpi::AcpiTables;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use core::task::{Context, Poll};
+use futures_util::{stream::Stream, task::AtomicWaker};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const SECONDS: u8 = 0x00;
+const ALARM_SECONDS: u8 = 0x01;
+const MINUTES: u8 = 0x02;
+const ALARM_MINUTES: u8 = 0x03;
+const HOURS: u8 = 0x04;
+const ALARM_HOURS: u8 = 0x05;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+
+/// Status A: an update is in progress
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+/// Status B: stops updates while the time is being set
+const SET: u8 = 0x80;
+/// Status B and C: periodic interrupts
+const PERIODIC: u8 = 0x40;
+/// Status B and C: alarm interrupts
+const ALARM: u8 = 0x20;
+/// Status B: values are binary instead of BCD
+const BINARY: u8 = 0x04;
+/// Status B: hours go from 0 to 23 instead of 1 to 12
+const HOURS_24: u8 = 0x02;
+/// Hours in 12 hours mode: set for PM
+const PM: u8 = 0x80;
+
+const RTC_IRQ: u8 = 8;
+
+/// Register containing the century, given by the FADT. 0 if there is none.
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct DateTime {
     pub year: u16,
     pub month: u8,
@@ -11,74 +64,346 @@ pub struct DateTime {
     pub seconds: u8,
 }
 
+/// Finds the century register in the FADT.
+pub fn init(tables: &AcpiTables<AcpiHandler>) {
+    let fadt = unsafe { tables.get_sdt::<acpi::fadt::Fadt>(acpi::sdt::Signature::FADT) };
+    if let Ok(Some(fadt)) = fadt {
+        CENTURY_REGISTER.store(fadt.century, Ordering::SeqCst);
+    }
+}
+
 pub fn get_datetime() -> DateTime {
-    let format = format_info();
-    let century = convert(format.1, read_cmos_register(0x32)) as u16;
-    let year = convert(format.1, read_cmos_register(0x09)) as u16;
-    let year = (century * 100) + year;
-    let month = convert(format.1, read_cmos_register(0x08));
-    let day = convert(format.1, read_cmos_register(0x07));
-    let hours = convert(
-        format.1,
-        if !format.0 {
-            convert_to_24h(read_cmos_register(0x04))
+    let mut last = read_raw();
+    loop {
+        let raw = read_raw();
+        if raw == last {
+            break;
+        }
+        last = raw;
+    }
+    Format::read().decode_datetime(&last)
+}
+
+/// Sets the date and time of the RTC.
+pub fn set_datetime(dt: &DateTime) {
+    interrupts::without_interrupts(|| {
+        let format = Format::read();
+        let status_b = read_cmos_register(STATUS_B);
+        write_cmos_register(STATUS_B, status_b | SET);
+
+        write_cmos_register(SECONDS, format.encode(dt.seconds));
+        write_cmos_register(MINUTES, format.encode(dt.minutes));
+        write_cmos_register(HOURS, format.encode_hours(dt.hours));
+        write_cmos_register(DAY, format.encode(dt.day));
+        write_cmos_register(MONTH, format.encode(dt.month));
+        write_cmos_register(YEAR, format.encode((dt.year % 100) as u8));
+        let century = CENTURY_REGISTER.load(Ordering::SeqCst);
+        if century != 0 {
+            write_cmos_register(century, format.encode((dt.year / 100) as u8));
+        }
+
+        write_cmos_register(STATUS_B, status_b & !SET);
+    });
+}
+
+/// Registers of the RTC, as they are stored
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Raw {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_raw() -> Raw {
+    while read_cmos_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+
+    let century = CENTURY_REGISTER.load(Ordering::SeqCst);
+    Raw {
+        seconds: read_cmos_register(SECONDS),
+        minutes: read_cmos_register(MINUTES),
+        hours: read_cmos_register(HOURS),
+        day: read_cmos_register(DAY),
+        month: read_cmos_register(MONTH),
+        year: read_cmos_register(YEAR),
+        century: if century != 0 {
+            read_cmos_register(century)
         } else {
-            read_cmos_register(0x04)
+            0
         },
-    );
-    let minutes = convert(format.1, read_cmos_register(0x02));
-    let seconds = convert(format.1, read_cmos_register(0x00));
-    DateTime {
-        year,
-        month,
-        day,
-        hours,
-        minutes,
-        seconds,
     }
 }
 
-fn convert_to_24h(value: u8) -> u8 {
-    let is_pm = (value & 0x80) != 0;
-    let mut value = value & 0x78;
+/// How the RTC stores values
+#[derive(Clone, Copy)]
+struct Format {
+    binary: bool,
+    hours_24: bool,
+}
+
+impl Format {
+    fn read() -> Format {
+        let status_b = read_cmos_register(STATUS_B);
+        Format {
+            binary: status_b & BINARY != 0,
+            hours_24: status_b & HOURS_24 != 0,
+        }
+    }
 
-    if is_pm {
-        value = value + 12;
+    fn decode(self, value: u8) -> u8 {
+        if self.binary {
+            value
+        } else {
+            (value >> 4) * 10 + (value & 0xf)
+        }
     }
 
-    if value == 24 {
-        value = 0;
+    fn encode(self, value: u8) -> u8 {
+        if self.binary {
+            value
+        } else {
+            ((value / 10) << 4) | (value % 10)
+        }
     }
 
-    value
-}
+    /// In 12 hours mode, the PM bit is set on top of the (maybe BCD) value,
+    /// and midnight and noon are 12.
+    fn decode_hours(self, value: u8) -> u8 {
+        if self.hours_24 {
+            return self.decode(value);
+        }
 
-fn convert(is_binary: bool, value: u8) -> u8 {
-    if !is_binary {
-        ((value & 0xf0) >> 1) + ((value & 0xf0) >> 3) + (value & 0xf)
-    } else {
-        value
+        let hours = self.decode(value & !PM) % 12;
+        if value & PM != 0 {
+            hours + 12
+        } else {
+            hours
+        }
     }
-}
 
-fn format_info() -> (bool, bool) {
-    let status_reg_b = read_cmos_register(0x0b);
-    let is_24h_format = ((status_reg_b >> 1) & 0x1) == 0x1;
-    let is_binary_format = ((status_reg_b >> 2) & 0x1) == 0x1;
-    (is_24h_format, is_binary_format)
+    fn encode_hours(self, hours: u8) -> u8 {
+        if self.hours_24 {
+            return self.encode(hours);
+        }
+
+        let pm = if hours >= 12 { PM } else { 0 };
+        match hours % 12 {
+            0 => self.encode(12) | pm,
+            hours => self.encode(hours) | pm,
+        }
+    }
+
+    fn decode_datetime(self, raw: &Raw) -> DateTime {
+        let year = self.decode(raw.year) as u16;
+        // without a century register, assume we are in the 21st century
+        let century = if CENTURY_REGISTER.load(Ordering::SeqCst) != 0 {
+            self.decode(raw.century) as u16
+        } else {
+            20
+        };
+        DateTime {
+            year: century * 100 + year,
+            month: self.decode(raw.month),
+            day: self.decode(raw.day),
+            hours: self.decode_hours(raw.hours),
+            minutes: self.decode(raw.minutes),
+            seconds: self.decode(raw.seconds),
+        }
+    }
 }
 
 fn read_cmos_register(reg: u8) -> u8 {
-    x86_64::instructions::interrupts::disable();
-
-    let mut port70 = x86_64::instructions::port::Port::new(0x70);
-    let mut port71 = x86_64::instructions::port::Port::new(0x71);
+    let mut port70 = Port::new(0x70);
+    let mut port71 = Port::new(0x71);
 
-    let result = unsafe {
+    interrupts::without_interrupts(|| unsafe {
         port70.write(reg);
         port71.read()
-    };
+    })
+}
+
+fn write_cmos_register(reg: u8, value: u8) {
+    let mut port70 = Port::new(0x70);
+    let mut port71 = Port::new(0x71);
+
+    interrupts::without_interrupts(|| unsafe {
+        port70.write(reg);
+        port71.write(value);
+    })
+}
+
+/// Sets or clears bits of the status register B
+fn update_status_b(bits: u8, set: bool) {
+    interrupts::without_interrupts(|| {
+        let status_b = read_cmos_register(STATUS_B);
+        let status_b = if set {
+            status_b | bits
+        } else {
+            status_b & !bits
+        };
+        write_cmos_register(STATUS_B, status_b);
+    });
+}
 
-    x86_64::instructions::interrupts::enable();
-    result
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+static PERIODIC_WAKER: AtomicWaker = AtomicWaker::new();
+static ALARM_RANG: AtomicBool = AtomicBool::new(false);
+static ALARM_WAKER: AtomicWaker = AtomicWaker::new();
+/// Whether a [`Periodic`] stream exists
+static PERIODIC_TAKEN: AtomicBool = AtomicBool::new(false);
+/// Whether an [`Alarm`] exists
+static ALARM_TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Called by the RTC interrupt handler
+///
+/// Reading the status register C acknowledges the interrupt: the RTC doesn't
+/// raise any other interrupt until it is read.
+pub(crate) fn on_interrupt() {
+    let flags = read_cmos_register(STATUS_C);
+    if flags & PERIODIC != 0 {
+        PERIODIC_TICKS.fetch_add(1, Ordering::SeqCst);
+        PERIODIC_WAKER.wake();
+    }
+    if flags & ALARM != 0 {
+        ALARM_RANG.store(true, Ordering::SeqCst);
+        ALARM_WAKER.wake();
+    }
+}
+
+/// Lets IRQ 8 through the interrupt controller.
+fn unmask_irq() {
+    if crate::apic::is_enabled() {
+        crate::apic::set_isa_masked(RTC_IRQ, false);
+        return;
+    }
+
+    interrupts::without_interrupts(|| unsafe {
+        let mut pics = PICS.lock();
+        let [mask1, mask2] = pics.read_masks();
+        // IRQ 2 is where the second PIC is connected
+        pics.write_masks(mask1 & !(1 << 2), mask2 & !(1 << (RTC_IRQ - 8)));
+    });
+    // an interrupt may have been missed while it was masked
+    read_cmos_register(STATUS_C);
+}
+
+/// A stream of periodic RTC interrupts
+///
+/// Each item is the number of interrupts since the previous one (more than 1
+/// if the task was too slow). Only one stream can exist at a time (see
+/// [`periodic`]).
+pub struct Periodic {
+    seen: u64,
+}
+
+/// Starts periodic interrupts at `32768 >> (rate - 1)` Hz, unless another
+/// stream already exists.
+///
+/// `rate` goes from 3 (8192 Hz) to 15 (2 Hz). The default is 6 (1024 Hz).
+pub fn periodic(rate: u8) -> Option<Periodic> {
+    if PERIODIC_TAKEN.swap(true, Ordering::SeqCst) {
+        return None;
+    }
+    let rate = rate.clamp(3, 15);
+    interrupts::without_interrupts(|| {
+        let status_a = read_cmos_register(STATUS_A);
+        write_cmos_register(STATUS_A, (status_a & 0xf0) | rate);
+    });
+    update_status_b(PERIODIC, true);
+    unmask_irq();
+    Some(Periodic {
+        seen: PERIODIC_TICKS.load(Ordering::SeqCst),
+    })
+}
+
+impl Stream for Periodic {
+    type Item = u64;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u64>> {
+        PERIODIC_WAKER.register(cx.waker());
+        let ticks = PERIODIC_TICKS.load(Ordering::SeqCst);
+        if ticks == self.seen {
+            return Poll::Pending;
+        }
+
+        let count = ticks - self.seen;
+        self.seen = ticks;
+        Poll::Ready(Some(count))
+    }
+}
+
+impl Drop for Periodic {
+    fn drop(&mut self) {
+        update_status_b(PERIODIC, false);
+        PERIODIC_TAKEN.store(false, Ordering::SeqCst);
+    }
+}
+
+/// A future that completes at a given time of the day
+///
+/// Only one alarm can be set at a time (see [`alarm`]).
+pub struct Alarm {
+    _private: (),
+}
+
+/// Sets the alarm of the RTC, unless another alarm is already set.
+pub fn alarm(hours: u8, minutes: u8, seconds: u8) -> Option<Alarm> {
+    if ALARM_TAKEN.swap(true, Ordering::SeqCst) {
+        return None;
+    }
+    interrupts::without_interrupts(|| {
+        let format = Format::read();
+        write_cmos_register(ALARM_SECONDS, format.encode(seconds));
+        write_cmos_register(ALARM_MINUTES, format.encode(minutes));
+        write_cmos_register(ALARM_HOURS, format.encode_hours(hours));
+    });
+    ALARM_RANG.store(false, Ordering::SeqCst);
+    update_status_b(ALARM, true);
+    unmask_irq();
+    Some(Alarm { _private: () })
+}
+
+impl Future for Alarm {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        ALARM_WAKER.register(cx.waker());
+        if ALARM_RANG.swap(false, Ordering::SeqCst) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Alarm {
+    fn drop(&mut self) {
+        update_status_b(ALARM, false);
+        ALARM_TAKEN.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn bcd_12h_hours() {
+        let format = Format {
+            binary: false,
+            hours_24: false,
+        };
+        // 12 AM is midnight, 12 PM is noon
+        assert_eq!(format.decode_hours(0x12), 0);
+        assert_eq!(format.decode_hours(0x12 | PM), 12);
+        assert_eq!(format.decode_hours(0x11 | PM), 23);
+        for hours in 0..24 {
+            assert_eq!(format.decode_hours(format.encode_hours(hours)), hours);
+        }
+    }
 }
@@ -0,0 +1,411 @@
+use adb::{Db, DbValue, TypeDef, TypeId, TypeInfo};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+/// Formats a database value along with its type information.
+///
+/// By default values are printed on multiple lines, with field names,
+/// variant names and the names of the composite types. Use
+/// [`DbValueDisplay::compact`] to print everything on a single line, and
+/// [`DbValueDisplay::max_depth`] / [`DbValueDisplay::max_width`] to
+/// truncate large values.
+///
+/// The `Debug` implementation prints the same thing, but annotates every
+/// node (including primitives) with its type name. `{:?}` is always
+/// compact, `{:#?}` follows the layout settings.
+///
+/// If a value doesn't match its type (or refers to a type that the database
+/// doesn't know), an error node is printed instead of the value.
+pub struct DbValueDisplay<'a> {
+    db: &'a Db<Vec<u8>>,
+    value: Arc<DbValue>,
+    ty: Arc<TypeInfo>,
+    compact: bool,
+    max_depth: Option<usize>,
+    max_width: Option<usize>,
+}
+
+impl<'a> DbValueDisplay<'a> {
+    pub fn new(db: &'a Db<Vec<u8>>, value: Arc<DbValue>, ty: Arc<TypeInfo>) -> Self {
+        DbValueDisplay {
+            db,
+            value,
+            ty,
+            compact: false,
+            max_depth: None,
+            max_width: None,
+        }
+    }
+
+    /// Prints the whole value on a single line.
+    pub fn compact(mut self) -> Self {
+        self.compact = true;
+        self
+    }
+
+    /// Composite values nested deeper than `depth` are replaced by an ellipsis.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Only the first `width` elements of arrays and products (or characters
+    /// of strings) are printed.
+    pub fn max_width(mut self, width: usize) -> Self {
+        self.max_width = Some(width);
+        self
+    }
+}
+
+impl<'a> fmt::Display for DbValueDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Printer {
+            db: self.db,
+            compact: self.compact,
+            max_depth: self.max_depth,
+            max_width: self.max_width,
+            annotate: false,
+        }
+        .value(f, &self.value, &self.ty, 0)
+    }
+}
+
+impl<'a> fmt::Debug for DbValueDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Printer {
+            db: self.db,
+            compact: self.compact || !f.alternate(),
+            max_depth: self.max_depth,
+            max_width: self.max_width,
+            annotate: true,
+        }
+        .value(f, &self.value, &self.ty, 0)
+    }
+}
+
+struct Printer<'a> {
+    db: &'a Db<Vec<u8>>,
+    compact: bool,
+    max_depth: Option<usize>,
+    max_width: Option<usize>,
+    /// Print the type name of primitive values too
+    annotate: bool,
+}
+
+impl<'a> Printer<'a> {
+    fn value(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        value: &DbValue,
+        ty: &TypeInfo,
+        depth: usize,
+    ) -> fmt::Result {
+        match *value {
+            DbValue::Unit => self.primitive(f, ty, "unit", format_args!("()")),
+            DbValue::U8(x) => self.primitive(f, ty, "u8", format_args!("{}", x)),
+            DbValue::U64(x) => self.primitive(f, ty, "u64", format_args!("{}", x)),
+            DbValue::F64(x) => self.primitive(f, ty, "f64", format_args!("{}", x)),
+            DbValue::Array(ref items) => {
+                let item_ty = match ty.definition {
+                    TypeDef::Array(id) => id,
+                    _ => return self.mismatch(f, ty, "array"),
+                };
+                let item_ty = match self.db.get_type_info(item_ty) {
+                    Some(item_ty) => item_ty,
+                    None => return unknown_type(f, item_ty),
+                };
+                if self.annotate {
+                    write!(f, "{} ", ty.name)?;
+                }
+                // Arrays of bytes are usually strings: if they are valid UTF-8,
+                // we print them as such.
+                if item_ty.id == adb::type_ids::U8 {
+                    if let Some(s) = super::as_string(value) {
+                        return self.string(f, &s);
+                    }
+                }
+                if items.is_empty() {
+                    return f.write_str("[]");
+                }
+                if self.too_deep(depth) {
+                    return f.write_str("[...]");
+                }
+
+                f.write_char('[')?;
+                self.children(f, items.len(), depth, |f, i| {
+                    self.value(f, &items[i], &item_ty, depth + 1)
+                })?;
+                f.write_char(']')
+            }
+            DbValue::Sum {
+                ref variant,
+                ref data,
+            } => {
+                let variants = match ty.definition {
+                    TypeDef::Sum { ref variants } => variants,
+                    _ => return self.mismatch(f, ty, "sum"),
+                };
+                let (name, var_ty) = match variants.get(*variant as usize) {
+                    Some(v) => v,
+                    None => return write!(f, "<error: {} has no variant {}>", ty.name, variant),
+                };
+
+                write!(f, "{}::{}", ty.name, name)?;
+                if let DbValue::Unit = **data {
+                    if !self.annotate {
+                        return Ok(());
+                    }
+                }
+                let var_ty = match self.db.get_type_info(*var_ty) {
+                    Some(var_ty) => var_ty,
+                    None => return unknown_type(f, *var_ty),
+                };
+                if self.too_deep(depth) {
+                    return f.write_str("(...)");
+                }
+                f.write_char('(')?;
+                self.value(f, data, &var_ty, depth + 1)?;
+                f.write_char(')')
+            }
+            DbValue::Product { ref fields } => {
+                let fields_ty = match ty.definition {
+                    TypeDef::Product { ref fields } => fields,
+                    _ => return self.mismatch(f, ty, "product"),
+                };
+                if fields.len() != fields_ty.len() {
+                    return write!(
+                        f,
+                        "<error: {} has {} fields, found {}>",
+                        ty.name,
+                        fields_ty.len(),
+                        fields.len()
+                    );
+                }
+
+                write!(f, "{} ", ty.name)?;
+                if fields.is_empty() {
+                    return f.write_str("{}");
+                }
+                if self.too_deep(depth) {
+                    return f.write_str("{ ... }");
+                }
+
+                f.write_char('{')?;
+                if self.compact {
+                    f.write_char(' ')?;
+                }
+                self.children(f, fields.len(), depth, |f, i| {
+                    let (ref name, field_ty) = fields_ty[i];
+                    write!(f, "{}: ", name)?;
+                    match self.db.get_type_info(field_ty) {
+                        Some(field_ty) => self.value(f, &fields[i], &field_ty, depth + 1),
+                        None => unknown_type(f, field_ty),
+                    }
+                })?;
+                if self.compact {
+                    f.write_char(' ')?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+
+    /// Prints the `len` children of a composite value, separated by commas,
+    /// and indented if needed.
+    fn children(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        len: usize,
+        depth: usize,
+        mut child: impl FnMut(&mut fmt::Formatter<'_>, usize) -> fmt::Result,
+    ) -> fmt::Result {
+        let shown = self.max_width.map_or(len, |w| w.min(len));
+        for i in 0..shown {
+            if self.compact {
+                if i != 0 {
+                    f.write_str(", ")?;
+                }
+            } else {
+                f.write_char('\n')?;
+                indent(f, depth + 1)?;
+            }
+            child(f, i)?;
+            if !self.compact {
+                f.write_char(',')?;
+            }
+        }
+
+        if shown < len {
+            if self.compact {
+                f.write_str(", ")?;
+            } else {
+                f.write_char('\n')?;
+                indent(f, depth + 1)?;
+            }
+            write!(f, "... ({} more)", len - shown)?;
+        }
+
+        if !self.compact {
+            f.write_char('\n')?;
+            indent(f, depth)?;
+        }
+        Ok(())
+    }
+
+    /// Prints a string, cut after `max_width` characters.
+    fn string(&self, f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+        let cut = self
+            .max_width
+            .and_then(|width| s.char_indices().nth(width))
+            .map(|(end, _)| end);
+        match cut {
+            Some(end) => write!(f, "{:?}... ({} more)", &s[..end], s[end..].chars().count()),
+            None => write!(f, "{:?}", s),
+        }
+    }
+
+    /// Prints a primitive value, whose kind is `found`.
+    fn primitive(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        ty: &TypeInfo,
+        found: &str,
+        value: fmt::Arguments,
+    ) -> fmt::Result {
+        match ty.definition {
+            TypeDef::Array(_) | TypeDef::Sum { .. } | TypeDef::Product { .. } => {
+                return self.mismatch(f, ty, found)
+            }
+            _ => {}
+        }
+        match primitive_kind(ty.id) {
+            Some(expected) if expected != found => {
+                return write!(
+                    f,
+                    "<error: found {} value, but {} holds {} values>",
+                    found, ty.name, expected
+                )
+            }
+            _ => {}
+        }
+
+        if self.annotate {
+            write!(f, "{}({})", ty.name, value)
+        } else {
+            f.write_fmt(value)
+        }
+    }
+
+    fn mismatch(&self, f: &mut fmt::Formatter<'_>, ty: &TypeInfo, found: &str) -> fmt::Result {
+        write!(
+            f,
+            "<error: found {} value, but {} is {}>",
+            found,
+            ty.name,
+            kind(&ty.definition)
+        )
+    }
+
+    fn too_deep(&self, depth: usize) -> bool {
+        self.max_depth.map_or(false, |max| depth >= max)
+    }
+}
+
+fn unknown_type(f: &mut fmt::Formatter<'_>, id: TypeId) -> fmt::Result {
+    write!(f, "<error: unknown type {:#x}>", id.0)
+}
+
+/// The kind of values of the primitive types that the kernel knows
+fn primitive_kind(id: TypeId) -> Option<&'static str> {
+    if id == adb::type_ids::U8 {
+        Some("u8")
+    } else if id == adb::type_ids::TYPE_ID {
+        // type ids are also used for plain numbers
+        Some("u64")
+    } else {
+        None
+    }
+}
+
+fn kind(def: &TypeDef) -> &'static str {
+    match def {
+        TypeDef::Array(_) => "an array type",
+        TypeDef::Sum { .. } => "a sum type",
+        TypeDef::Product { .. } => "a product type",
+        _ => "a primitive type",
+    }
+}
+
+fn indent(f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+    for _ in 0..depth {
+        f.write_str("    ")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::types;
+    use alloc::string::ToString;
+
+    fn test_db() -> Db<Vec<u8>> {
+        super::super::open(Vec::from(*include_bytes!("../../test.adb"))).unwrap()
+    }
+
+    #[test_case]
+    fn primitives_match_their_type() {
+        let db = test_db();
+        let byte = db.get_type_info(adb::type_ids::U8).unwrap();
+        let shown = |value: DbValue| {
+            alloc::format!(
+                "{}",
+                DbValueDisplay::new(&db, Arc::new(value), Arc::clone(&byte))
+            )
+        };
+        assert!(shown(DbValue::U8(3)) == "3");
+        assert!(shown(DbValue::U64(3)).starts_with("<error: found u64 value"));
+        assert!(shown(DbValue::Unit).starts_with("<error: found unit value"));
+    }
+
+    #[test_case]
+    fn strings_are_cut() {
+        let db = test_db();
+        let value = super::super::string_value("hello");
+        let shown = DbValueDisplay::new(&db, Arc::clone(&value), types::string());
+        assert!(alloc::format!("{}", shown.max_width(2)) == "\"he\"... (3 more)");
+        let shown = DbValueDisplay::new(&db, value, types::string());
+        assert!(alloc::format!("{}", shown.max_width(5)) == "\"hello\"");
+    }
+
+    #[test_case]
+    fn variants_are_nested() {
+        let db = test_db();
+        let choice = Arc::new(TypeInfo {
+            name: "Test.Choice".to_string(),
+            id: TypeId(0x300),
+            definition: TypeDef::Sum {
+                variants: alloc::vec![("Identity".to_string(), types::IDENTITY)],
+            },
+        });
+        let value = Arc::new(DbValue::Sum {
+            variant: 0,
+            data: Arc::new(DbValue::Product {
+                fields: alloc::vec![
+                    Arc::new(DbValue::U64(1)),
+                    super::super::string_value("root"),
+                ],
+            }),
+        });
+        let shown = DbValueDisplay::new(&db, Arc::clone(&value), Arc::clone(&choice)).compact();
+        assert!(
+            alloc::format!("{}", shown)
+                == "Test.Choice::Identity(Os.Identity { id: 1, name: \"root\" })"
+        );
+        let shown = DbValueDisplay::new(&db, value, choice)
+            .compact()
+            .max_depth(1);
+        assert!(alloc::format!("{}", shown) == "Test.Choice::Identity(Os.Identity { ... })");
+    }
+}
@@ -0,0 +1,74 @@
+//! Types whose objects are events sent by drivers (key presses, for instance)
+//!
+//! Events are not stored: a stream of an event type receives the events that
+//! happen after it was opened, and waits when there is no new event.
+
+use super::register_type;
+use crate::security::{self, Security};
+use adb::{Db, DbObject, DbValue, TypeId, TypeInfo};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::task::{Context, Poll};
+use futures_util::future::poll_fn;
+use spin::RwLock;
+
+/// Events of a given type, as seen by one reader
+pub trait EventSource: Send {
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Arc<DbValue>>;
+}
+
+/// Starts listening for events of a type
+pub type Subscribe = fn() -> Box<dyn EventSource>;
+
+lazy_static::lazy_static! {
+    static ref EVENT_TYPES: RwLock<BTreeMap<u64, (Arc<TypeInfo>, Subscribe)>> =
+        RwLock::new(BTreeMap::new());
+}
+
+/// Registers a type of events, with its security policy (processes can't
+/// write events, so it should at least deny that).
+pub fn register(
+    db: &mut Db<Vec<u8>>,
+    ty: Arc<TypeInfo>,
+    subscribe: Subscribe,
+    policy: impl Security + 'static,
+) {
+    register_type(db, Arc::clone(&ty));
+    security::register(ty.id, policy);
+    EVENT_TYPES.write().insert(ty.id.0, (ty, subscribe));
+}
+
+pub fn is_event(ty: TypeId) -> bool {
+    EVENT_TYPES.read().contains_key(&ty.0)
+}
+
+pub struct Subscription {
+    type_info: Arc<TypeInfo>,
+    source: Box<dyn EventSource>,
+}
+
+/// Starts listening for events of a type.
+pub fn subscribe(ty: TypeId) -> Option<Subscription> {
+    let (type_info, subscribe) = EVENT_TYPES.read().get(&ty.0).cloned()?;
+    Some(Subscription {
+        type_info,
+        source: subscribe(),
+    })
+}
+
+impl Subscription {
+    pub fn ty(&self) -> TypeId {
+        self.type_info.id
+    }
+
+    /// Waits for the next event.
+    pub async fn next(&mut self) -> DbObject {
+        let value = poll_fn(|cx| self.source.poll_next(cx)).await;
+        DbObject {
+            type_info: Arc::clone(&self.type_info),
+            value,
+        }
+    }
+}
@@ -0,0 +1,40 @@
+//! Types whose objects are not stored, but computed each time they are read
+//!
+//! For instance, reading an `Os.Time.DateTime` gives the current time. Streams
+//! of these types never end, and they can't be written to.
+
+use super::register_type;
+use crate::security::{self, ReadOnly};
+use adb::{Db, DbObject, DbValue, TypeId, TypeInfo};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::RwLock;
+
+/// Computes the current value of a generated type
+pub type Generator = fn() -> Arc<DbValue>;
+
+lazy_static::lazy_static! {
+    static ref GENERATORS: RwLock<BTreeMap<u64, (Arc<TypeInfo>, Generator)>> =
+        RwLock::new(BTreeMap::new());
+}
+
+/// Registers a type whose values are given by `generator`.
+pub fn register(db: &mut Db<Vec<u8>>, ty: Arc<TypeInfo>, generator: Generator) {
+    register_type(db, Arc::clone(&ty));
+    security::register(ty.id, ReadOnly);
+    GENERATORS.write().insert(ty.id.0, (ty, generator));
+}
+
+pub fn is_generated(ty: TypeId) -> bool {
+    GENERATORS.read().contains_key(&ty.0)
+}
+
+/// Computes a new object of a generated type.
+pub fn read(ty: TypeId) -> Option<DbObject> {
+    let (type_info, generator) = GENERATORS.read().get(&ty.0).cloned()?;
+    Some(DbObject {
+        type_info,
+        value: generator(),
+    })
+}
@@ -1,7 +1,20 @@
-use crate::{print, println};
-use adb::{Db, DbValue, TypeInfo};
+use crate::println;
+use crate::storage::{self, BlockDevice, BlockError};
+use adb::{Db, DbValue, TypeId, TypeInfo};
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+mod display;
+pub mod events;
+pub mod generated;
+pub mod query;
+pub mod remote;
+pub mod types;
+
+pub use display::DbValueDisplay;
 
 pub static DB: spin::Mutex<Option<Db<Vec<u8>>>> = spin::Mutex::new(None);
 
@@ -10,88 +23,189 @@ fn db_logger(args: core::fmt::Arguments) {
 }
 
 pub fn init() {
+    let datab = open(Vec::from(*include_bytes!("../../test.adb"))).unwrap();
+    *DB.lock() = Some(datab);
+}
+
+/// Reads a database image, and registers the types of the kernel in it.
+fn open(image: Vec<u8>) -> Option<Db<Vec<u8>>> {
+    let mut datab = Db::read_from(image).ok()?;
+    datab.set_logger(db_logger);
+    register_type(&mut datab, types::string());
+    register_type(&mut datab, types::identity());
+    register_type(&mut datab, types::session());
+    register_type(&mut datab, types::executable());
+    register_type(&mut datab, types::pci_device());
+    register_type(&mut datab, types::pci_binding());
+    register_type(&mut datab, types::pci_interrupt());
+    crate::security::register(types::PCI_INTERRUPT, crate::security::ReadOnly);
+    generated::register(&mut datab, types::datetime(), crate::time::datetime_value);
+    generated::register(&mut datab, types::uptime(), crate::time::uptime_value);
+    // the other processes get their input from the compositor, that gives
+    // the keys to the focused window only
+    events::register(
+        &mut datab,
+        types::key_event(),
+        crate::input::keyboard::subscribe_db,
+        crate::security::PrivilegedReadOnly,
+    );
+    events::register(
+        &mut datab,
+        types::pointer_event(),
+        crate::input::mouse::subscribe_db,
+        crate::security::PrivilegedReadOnly,
+    );
+    Some(datab)
+}
+
+/// The magic number at the start of a database (see `docs/disk-format.md`)
+const MAGIC: u16 = 0x0adb;
+/// The magic number, the version, the number of blocks and their size
+const HEADER_SIZE: usize = 24;
+/// The checksum of the type table
+const CHECKSUM_SIZE: usize = 64;
+
+/// Objects that the kernel records as it runs, that are moved to a database
+/// loaded from a disk
+const RUNTIME_TYPES: &[TypeId] = &[types::PCI_DEVICE, types::PCI_BINDING];
+
+static MOUNTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the database was loaded from a disk
+pub fn is_mounted() -> bool {
+    MOUNTED.load(Ordering::SeqCst)
+}
+
+/// The size of a database image, from its header, or `None` if it isn't one.
+fn image_size(header: &[u8]) -> Option<usize> {
+    let number = |offset: usize| {
+        let bytes = header.get(offset..offset + 8)?;
+        let mut value = [0; 8];
+        value.copy_from_slice(bytes);
+        usize::try_from(u64::from_be_bytes(value)).ok()
+    };
+    if header.get(0..2)? != MAGIC.to_be_bytes() {
+        return None;
+    }
+    let blocks = number(8)?;
+    let block_size = number(16)?;
+    // the two copies of the type table start with their length
+    let type_tables = 2 * 8 * blocks.checked_add(1)?;
+    blocks
+        .checked_mul(block_size)?
+        .checked_add(HEADER_SIZE + CHECKSUM_SIZE)?
+        .checked_add(type_tables)
+}
+
+/// The largest database image loaded from a disk, which is read in the heap
+const MAX_IMAGE_SIZE: usize = crate::allocator::HEAP_SIZE / 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MountError {
+    Disk(BlockError),
+    /// The image is larger than [`MAX_IMAGE_SIZE`]
+    TooLarge(usize),
+    /// The recorded objects (or the default identity) could not be written
+    /// to the new database
+    Database,
+}
+
+impl From<BlockError> for MountError {
+    fn from(err: BlockError) -> MountError {
+        MountError::Disk(err)
+    }
+}
+
+/// Loads the database that a disk holds, in place of the current one.
+/// Returns `false` if the disk doesn't start with a database.
+///
+/// The disk is only read: the database is kept in memory, and changes to it
+/// are lost at the next boot.
+pub async fn mount(device: &dyn BlockDevice) -> Result<bool, MountError> {
+    let header = storage::read_start(device, HEADER_SIZE).await?;
+    let size = match image_size(&header) {
+        Some(size) if size as u64 <= storage::size(device) => size,
+        _ => return Ok(false),
+    };
+    if size > MAX_IMAGE_SIZE {
+        return Err(MountError::TooLarge(size));
+    }
+    let image = storage::read_start(device, size).await?;
+    let mut datab = match open(image) {
+        Some(datab) => datab,
+        None => return Ok(false),
+    };
+
     let mut db = DB.lock();
-    *db = Some({
-        let mut datab = Db::read_from(Vec::from(*include_bytes!("../../test.adb"))).unwrap();
-        datab.set_logger(db_logger);
-        datab
-    });
+    if let Some(old) = db.as_mut() {
+        for &ty in RUNTIME_TYPES {
+            let objects: Vec<_> = old.iter_type(ty).collect();
+            for object in objects {
+                datab
+                    .write_object(object)
+                    .map_err(|_| MountError::Database)?;
+            }
+        }
+    }
+    crate::identity::init(&mut datab).map_err(|_| MountError::Database)?;
+    *db = Some(datab);
+    MOUNTED.store(true, Ordering::SeqCst);
+    Ok(true)
+}
+
+/// Makes a type known to the database, if it isn't already.
+pub fn register_type(db: &mut Db<Vec<u8>>, ty: Arc<TypeInfo>) {
+    if db.get_type_info(ty.id).is_none() {
+        db.create_type(ty);
+    }
+}
+
+/// Builds the value of an `Os.String`.
+pub fn string_value(s: &str) -> Arc<DbValue> {
+    Arc::new(DbValue::Array(
+        s.bytes().map(|b| Arc::new(DbValue::U8(b))).collect(),
+    ))
+}
+
+/// Reads the value of an `Os.String` (or any other UTF-8 array of bytes).
+pub fn as_string(value: &DbValue) -> Option<String> {
+    match *value {
+        DbValue::Array(ref items) => {
+            let bytes = items
+                .iter()
+                .map(|item| match **item {
+                    DbValue::U8(b) => Some(b),
+                    _ => None,
+                })
+                .collect::<Option<Vec<u8>>>()?;
+            String::from_utf8(bytes).ok()
+        }
+        _ => None,
+    }
 }
 
 pub fn display_contents(db: &mut Db<Vec<u8>>) {
     for ty in db.all_type_ids() {
         let items: Vec<_> = db.iter_type(ty).collect();
         for item in items {
-            show_db_object(&db, item.value, item.type_info, 0);
+            println!("{}", DbValueDisplay::new(db, item.value, item.type_info));
         }
     }
 }
 
-fn show_db_object(db: &Db<Vec<u8>>, value: Arc<DbValue>, ty: Arc<TypeInfo>, padding: usize) {
-    for _ in 0..padding {
-        print!("    ");
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    match *value {
-        DbValue::Unit => println!("()"),
-        DbValue::U8(x) => println!("{}", x),
-        DbValue::U64(x) => println!("{}", x),
-        DbValue::F64(x) => println!("{}", x),
-        DbValue::Array(ref arr) => {
-            println!("[");
-            let mut str = alloc::vec::Vec::with_capacity(arr.len());
-            for item in arr {
-                let arr_ty = match ty.definition {
-                    adb::TypeDef::Array(id) => db.get_type_info(id).unwrap(),
-                    _ => unreachable!(),
-                };
-                if arr_ty.id == adb::type_ids::U8 {
-                    str.push(match item.as_ref() {
-                        adb::DbValue::U8(b) => *b,
-                        _ => unreachable!(),
-                    })
-                } else {
-                    show_db_object(db, Arc::clone(item), arr_ty, padding + 1)
-                }
-            }
-            if !str.is_empty() {
-                println!(
-                    "\"{}\"",
-                    alloc::string::String::from_utf8(str).unwrap_or_default()
-                );
-            }
-            for _ in 0..padding {
-                print!("    ");
-            }
-            println!("]")
-        }
-        DbValue::Sum {
-            ref variant,
-            ref data,
-        } => {
-            print!("{} : ", variant);
-            let var_ty = match ty.definition {
-                adb::TypeDef::Sum { ref variants } => {
-                    db.get_type_info(variants[*variant as usize].1).unwrap()
-                }
-                _ => unreachable!(),
-            };
-            show_db_object(db, Arc::clone(data), var_ty, padding + 1);
-        }
-        DbValue::Product { ref fields } => {
-            println!("{{");
-            let fields_ty: Vec<_> = match ty.definition {
-                adb::TypeDef::Product { ref fields } => fields.iter().map(|x| x.1).collect(),
-                _ => unreachable!(),
-            };
-            for (f, f_ty) in fields.iter().zip(fields_ty.iter()) {
-                let f_ty = db.get_type_info(*f_ty).unwrap();
-                show_db_object(db, Arc::clone(f), f_ty, padding + 1);
-            }
-            for _ in 0..padding {
-                print!("    ");
-            }
-            println!("}}");
-        }
+    #[test_case]
+    fn image_header() {
+        let mut header = [0; HEADER_SIZE];
+        header[0..2].copy_from_slice(&MAGIC.to_be_bytes());
+        header[8..16].copy_from_slice(&4u64.to_be_bytes());
+        header[16..24].copy_from_slice(&512u64.to_be_bytes());
+        assert!(image_size(&header) == Some(24 + 64 + 2 * 8 * 5 + 4 * 512));
+        assert!(image_size(&header[..16]).is_none());
+        header[1] = 0;
+        assert!(image_size(&header).is_none());
     }
 }
@@ -0,0 +1,92 @@
+//! Finding types and objects from what people type
+//!
+//! Types can be given by name (`Os.Identity`) or by id (`0xC2`). Conditions
+//! compare a field of an object with a value: numbers and strings are
+//! compared directly, other values with how they are displayed.
+
+use super::{as_string, DbValueDisplay};
+use adb::{Db, DbValue, TypeDef, TypeId, TypeInfo};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// Parses a decimal or hexadecimal (`0x…`) number.
+pub fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Splits a `field=value` condition.
+pub fn parse_condition(s: &str) -> Option<(&str, &str)> {
+    let (field, value) = s.split_once('=')?;
+    if field.is_empty() {
+        return None;
+    }
+    Some((field, value))
+}
+
+pub fn find_type(db: &Db<Vec<u8>>, name: &str) -> Option<Arc<TypeInfo>> {
+    if let Some(id) = parse_number(name) {
+        return db.get_type_info(TypeId(id));
+    }
+    db.all_type_ids()
+        .into_iter()
+        .filter_map(|id| db.get_type_info(id))
+        .find(|ty| ty.name.eq_ignore_ascii_case(name))
+}
+
+/// Whether objects of this type have a field with this name
+pub fn has_field(ty: &TypeInfo, field: &str) -> bool {
+    match ty.definition {
+        TypeDef::Product { ref fields } => fields.iter().any(|(name, _)| name == field),
+        _ => false,
+    }
+}
+
+/// Whether a field of an object has the given value
+pub fn field_matches(
+    db: &Db<Vec<u8>>,
+    ty: &TypeInfo,
+    value: &DbValue,
+    field: &str,
+    expected: &str,
+) -> bool {
+    let (fields, types) = match (value, &ty.definition) {
+        (DbValue::Product { fields }, TypeDef::Product { fields: types }) => (fields, types),
+        _ => return false,
+    };
+    let index = match types.iter().position(|(name, _)| name == field) {
+        Some(index) => index,
+        None => return false,
+    };
+    let (value, field_ty) = match (fields.get(index), db.get_type_info(types[index].1)) {
+        (Some(value), Some(field_ty)) => (value, field_ty),
+        _ => return false,
+    };
+    match **value {
+        DbValue::U8(x) => parse_number(expected) == Some(x as u64),
+        DbValue::U64(x) => parse_number(expected) == Some(x),
+        _ => match as_string(value) {
+            Some(s) => s == expected,
+            None => {
+                let display = DbValueDisplay::new(db, Arc::clone(value), field_ty).compact();
+                alloc::format!("{}", display) == expected
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn parse_arguments() {
+        assert!(parse_number("0xC2") == Some(0xc2));
+        assert!(parse_number("42") == Some(42));
+        assert!(parse_number("Os.String").is_none());
+        assert!(parse_condition("name=root") == Some(("name", "root")));
+        assert!(parse_condition("=root").is_none());
+    }
+}
@@ -0,0 +1,828 @@
+//! Environments: the database of this computer, and the ones of other
+//! computers, reached over TCP
+//!
+//! Objects are copied from an environment to another one type by type (see
+//! [`copy`]). Other computers speak the protocol described in
+//! `docs/sync-protocol.md`: numbers are big endian, and types are matched by
+//! name, since each database gives its own IDs to its types.
+//!
+//! What is pulled is checked before anything is written: each object must
+//! match its type, and the types that the local database doesn't have are
+//! only created when the objects are saved.
+
+use super::{query, register_type, DB};
+use crate::allocator::HEAP_SIZE;
+use crate::net::tcp::TcpStream;
+use crate::net::{Endpoint, NetError};
+use crate::security::{self, Access};
+use crate::time::{self, Duration};
+use adb::{type_ids, Db, DbObject, DbValue, TypeDef, TypeId, TypeInfo};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use futures_util::future::{FutureExt, LocalBoxFuture};
+
+/// The port that servers listen on, by default
+pub const PORT: u16 = 0x0adb;
+
+const MAGIC: u16 = 0x0adb;
+const VERSION: u16 = 1;
+
+const HELLO: u8 = 1;
+const PULL: u8 = 2;
+const PUSH: u8 = 3;
+const TYPE: u8 = 4;
+const OBJECT: u8 = 5;
+const END: u8 = 6;
+const ERROR: u8 = 7;
+
+const DEF_PRIMITIVE: u8 = 0;
+const DEF_ARRAY: u8 = 1;
+const DEF_PRODUCT: u8 = 2;
+const DEF_SUM: u8 = 3;
+
+const VALUE_UNIT: u8 = 0;
+const VALUE_U8: u8 = 1;
+const VALUE_U64: u8 = 2;
+const VALUE_F64: u8 = 3;
+const VALUE_ARRAY: u8 = 4;
+const VALUE_SUM: u8 = 5;
+const VALUE_PRODUCT: u8 = 6;
+
+/// The kind and the length of a message
+const MESSAGE_HEADER_SIZE: usize = 5;
+const MAX_MESSAGE_SIZE: usize = 16 * 1024;
+/// How much of the heap the objects of a pull can take
+const PULL_BUDGET: usize = HEAP_SIZE / 4;
+/// What a value takes on the heap (without its items)
+const VALUE_COST: usize = core::mem::size_of::<DbValue>() + 2 * core::mem::size_of::<usize>();
+/// What a type takes on the heap (without its name and its items)
+const TYPE_COST: usize = core::mem::size_of::<TypeInfo>() + 2 * core::mem::size_of::<usize>();
+/// Values nested deeper than that are refused
+const MAX_DEPTH: usize = 64;
+/// Types below this ID are the same everywhere (see `docs/disk-format.md`)
+const BUILTIN_END: u64 = 0x10;
+/// Where IDs of the types that come from another environment start
+const FIRST_FREE_ID: u64 = 0x100;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum SyncError {
+    NoDatabase,
+    Net(NetError),
+    /// The peer doesn't speak the same protocol, or sent an invalid message
+    Protocol,
+    UnknownType(String),
+    /// A type exists on both sides with the same name, but they differ
+    TypeMismatch(String),
+    Denied(TypeId),
+    /// The peer refused the request, with this message
+    Remote(String),
+    /// An object doesn't match its type (given by name)
+    InvalidObject(String),
+    /// A message, or all the types and objects together, would take too much
+    /// memory
+    TooLarge,
+    /// The local database could not write an object
+    Database,
+}
+
+impl From<NetError> for SyncError {
+    fn from(err: NetError) -> SyncError {
+        SyncError::Net(err)
+    }
+}
+
+/// Objects read from an environment
+pub struct Batch {
+    /// The types that the objects use and that the local database doesn't
+    /// have yet, the ones they use first
+    pub types: Vec<Arc<TypeInfo>>,
+    pub objects: Vec<DbObject>,
+}
+
+/// Somewhere objects can be read from and written to
+pub trait Environment {
+    /// Reads all the objects of a type, given by name. The objects that are
+    /// returned have types of the local database, or types of the batch.
+    fn open_stream<'a>(&'a self, ty: &'a str) -> LocalBoxFuture<'a, Result<Batch, SyncError>>;
+
+    /// Writes objects, and creates the types they need.
+    fn save_stream(&self, batch: Batch) -> LocalBoxFuture<'_, Result<(), SyncError>>;
+}
+
+/// The database of this computer
+pub struct Local;
+
+impl Environment for Local {
+    fn open_stream<'a>(&'a self, ty: &'a str) -> LocalBoxFuture<'a, Result<Batch, SyncError>> {
+        async move {
+            let mut db = DB.lock();
+            let db = db.as_mut().ok_or(SyncError::NoDatabase)?;
+            let ty = query::find_type(db, ty).ok_or_else(|| SyncError::UnknownType(ty.into()))?;
+            security::check(ty.id, Access::Read).map_err(|_| SyncError::Denied(ty.id))?;
+            Ok(Batch {
+                types: Vec::new(),
+                objects: db.iter_type(ty.id).collect(),
+            })
+        }
+        .boxed_local()
+    }
+
+    fn save_stream(&self, batch: Batch) -> LocalBoxFuture<'_, Result<(), SyncError>> {
+        async move {
+            let mut db = DB.lock();
+            let db = db.as_mut().ok_or(SyncError::NoDatabase)?;
+            // nothing is written if an object can't be
+            for object in &batch.objects {
+                let ty = object.type_info.id;
+                security::check(ty, Access::Write).map_err(|_| SyncError::Denied(ty))?;
+            }
+            // the database may have changed since the types were received
+            for ty in &batch.types {
+                match db.get_type_info(ty.id) {
+                    Some(existing)
+                        if existing.name == ty.name
+                            && same_definition(&existing.definition, &ty.definition) => {}
+                    Some(_) => return Err(SyncError::TypeMismatch(ty.name.clone())),
+                    None => {}
+                }
+            }
+            for ty in batch.types {
+                register_type(db, ty);
+            }
+            for object in batch.objects {
+                register_type(db, Arc::clone(&object.type_info));
+                db.write_object(object).map_err(|_| SyncError::Database)?;
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
+
+/// The database of another computer
+pub struct Remote {
+    pub server: Endpoint,
+}
+
+impl Remote {
+    pub fn new(server: Endpoint) -> Remote {
+        Remote { server }
+    }
+
+    /// Connects to the server, and checks that it speaks the same protocol.
+    async fn connect(&self) -> Result<TcpStream, SyncError> {
+        let mut stream = TcpStream::connect(self.server).await?;
+        let mut hello = Writer::default();
+        hello.u16(MAGIC);
+        hello.u16(VERSION);
+        send(&mut stream, HELLO, &hello.0).await?;
+        let (kind, reply) = receive(&mut stream).await?;
+        let mut reply = Reader::new(&reply);
+        if kind != HELLO || reply.u16() != Some(MAGIC) || reply.u16() != Some(VERSION) {
+            return Err(SyncError::Protocol);
+        }
+        Ok(stream)
+    }
+
+    async fn pull(&self, ty: &str) -> Result<Batch, SyncError> {
+        let mut stream = self.connect().await?;
+        let mut request = Writer::default();
+        request.string(ty);
+        send(&mut stream, PULL, &request.0).await?;
+
+        let mut types = TypeMap::default();
+        let mut objects = Vec::new();
+        let mut budget = Budget(PULL_BUDGET);
+        loop {
+            let (kind, message) = receive(&mut stream).await?;
+            let mut message = Reader::new(&message);
+            match kind {
+                TYPE => {
+                    let db = DB.lock();
+                    let db = db.as_ref().ok_or(SyncError::NoDatabase)?;
+                    types.receive(db, &mut message, &mut budget)?;
+                }
+                OBJECT => {
+                    let ty = message.u64().ok_or(SyncError::Protocol)?;
+                    let type_info = types.get(ty).ok_or(SyncError::Protocol)?;
+                    let value = read_value(&mut message, 0, &mut budget).ok_or(match budget.0 {
+                        0 => SyncError::TooLarge,
+                        _ => SyncError::Protocol,
+                    })?;
+                    let db = DB.lock();
+                    let db = db.as_ref().ok_or(SyncError::NoDatabase)?;
+                    if !matches_type(&value, &type_info, &|id| types.lookup(db, id)) {
+                        return Err(SyncError::InvalidObject(type_info.name.clone()));
+                    }
+                    objects.try_reserve(1).map_err(|_| SyncError::TooLarge)?;
+                    objects.push(DbObject { type_info, value });
+                }
+                END => break,
+                ERROR => return Err(remote_error(&mut message)),
+                _ => return Err(SyncError::Protocol),
+            }
+        }
+        stream.close();
+        Ok(Batch {
+            types: types.new,
+            objects,
+        })
+    }
+
+    async fn push(&self, batch: Batch) -> Result<(), SyncError> {
+        let objects = batch.objects;
+        let types = {
+            let db = DB.lock();
+            let db = db.as_ref().ok_or(SyncError::NoDatabase)?;
+            let lookup = |id: TypeId| {
+                let new = batch.types.iter().find(|ty| ty.id == id).cloned();
+                new.or_else(|| db.get_type_info(id))
+            };
+            let mut types = Vec::new();
+            for object in &objects {
+                dependencies(&lookup, &object.type_info, &mut types)?;
+            }
+            types
+        };
+
+        let mut stream = self.connect().await?;
+        send(&mut stream, PUSH, &[]).await?;
+        for ty in &types {
+            let mut message = Writer::default();
+            write_type(&mut message, ty);
+            send(&mut stream, TYPE, &message.0).await?;
+        }
+        for object in &objects {
+            let mut message = Writer::default();
+            message.u64(object.type_info.id.0);
+            write_value(&mut message, &object.value);
+            send(&mut stream, OBJECT, &message.0).await?;
+        }
+        send(&mut stream, END, &[]).await?;
+        let (kind, reply) = receive(&mut stream).await?;
+        stream.close();
+        match kind {
+            END => Ok(()),
+            ERROR => Err(remote_error(&mut Reader::new(&reply))),
+            _ => Err(SyncError::Protocol),
+        }
+    }
+}
+
+impl Environment for Remote {
+    fn open_stream<'a>(&'a self, ty: &'a str) -> LocalBoxFuture<'a, Result<Batch, SyncError>> {
+        self.pull(ty).boxed_local()
+    }
+
+    fn save_stream(&self, batch: Batch) -> LocalBoxFuture<'_, Result<(), SyncError>> {
+        self.push(batch).boxed_local()
+    }
+}
+
+/// Copies all the objects of a type, and returns how many there were.
+pub async fn copy(
+    from: &dyn Environment,
+    to: &dyn Environment,
+    ty: &str,
+) -> Result<usize, SyncError> {
+    let batch = from.open_stream(ty).await?;
+    let count = batch.objects.len();
+    to.save_stream(batch).await?;
+    Ok(count)
+}
+
+async fn send(stream: &mut TcpStream, kind: u8, payload: &[u8]) -> Result<(), SyncError> {
+    let mut message = Writer::default();
+    message.u8(kind);
+    message.u32(payload.len() as u32);
+    message.0.extend_from_slice(payload);
+    Ok(stream.write(&message.0).await?)
+}
+
+async fn receive(stream: &mut TcpStream) -> Result<(u8, Vec<u8>), SyncError> {
+    let message = async {
+        let mut header = [0; MESSAGE_HEADER_SIZE];
+        stream.read_exact(&mut header).await?;
+        let mut header = Reader::new(&header);
+        let (kind, len) = match (header.u8(), header.u32()) {
+            (Some(kind), Some(len)) if len as usize <= MAX_MESSAGE_SIZE => (kind, len),
+            _ => return Err(SyncError::Protocol),
+        };
+        let mut payload = Vec::new();
+        payload
+            .try_reserve_exact(len as usize)
+            .map_err(|_| SyncError::TooLarge)?;
+        payload.resize(len as usize, 0);
+        stream.read_exact(&mut payload).await?;
+        Ok((kind, payload))
+    };
+    time::timeout(REPLY_TIMEOUT, message)
+        .await
+        .map_err(|_| SyncError::Net(NetError::TimedOut))?
+}
+
+fn remote_error(message: &mut Reader) -> SyncError {
+    match message.string() {
+        Some(message) => SyncError::Remote(message.into()),
+        None => SyncError::Protocol,
+    }
+}
+
+/// Adds the types that a type uses (and itself) to `types`, the ones it uses
+/// first. The builtin types are skipped.
+fn dependencies(
+    lookup: &impl Fn(TypeId) -> Option<Arc<TypeInfo>>,
+    ty: &Arc<TypeInfo>,
+    types: &mut Vec<Arc<TypeInfo>>,
+) -> Result<(), SyncError> {
+    if ty.id.0 < BUILTIN_END || types.iter().any(|known| known.id == ty.id) {
+        return Ok(());
+    }
+    let used: Vec<TypeId> = match ty.definition {
+        TypeDef::Array(item) => alloc::vec![item],
+        TypeDef::Product { ref fields } => fields.iter().map(|(_, id)| *id).collect(),
+        TypeDef::Sum { ref variants } => variants.iter().map(|(_, id)| *id).collect(),
+        _ => Vec::new(),
+    };
+    for id in used {
+        if id.0 < BUILTIN_END {
+            continue;
+        }
+        let used =
+            lookup(id).ok_or_else(|| SyncError::UnknownType(alloc::format!("{:#x}", id.0)))?;
+        dependencies(lookup, &used, types)?;
+    }
+    types.push(Arc::clone(ty));
+    Ok(())
+}
+
+fn write_type(message: &mut Writer, ty: &TypeInfo) {
+    message.u64(ty.id.0);
+    message.string(&ty.name);
+    let named = |message: &mut Writer, kind, items: &[(String, TypeId)]| {
+        message.u8(kind);
+        message.u32(items.len() as u32);
+        for (name, id) in items {
+            message.string(name);
+            message.u64(id.0);
+        }
+    };
+    match ty.definition {
+        TypeDef::Array(item) => {
+            message.u8(DEF_ARRAY);
+            message.u64(item.0);
+        }
+        TypeDef::Product { ref fields } => named(message, DEF_PRODUCT, fields),
+        TypeDef::Sum { ref variants } => named(message, DEF_SUM, variants),
+        _ => message.u8(DEF_PRIMITIVE),
+    }
+}
+
+/// The local types of the types of a peer
+#[derive(Default)]
+struct TypeMap {
+    /// By their ID on the peer
+    types: BTreeMap<u64, Arc<TypeInfo>>,
+    /// The types that the local database doesn't have, the ones they use
+    /// first
+    new: Vec<Arc<TypeInfo>>,
+    /// The ID of the next new type, once the database was looked at
+    next_id: Option<u64>,
+}
+
+impl TypeMap {
+    fn get(&self, remote: u64) -> Option<Arc<TypeInfo>> {
+        self.types.get(&remote).cloned()
+    }
+
+    fn local_id(&self, db: &Db<Vec<u8>>, remote: u64) -> Option<TypeId> {
+        if remote < BUILTIN_END {
+            return db.get_type_info(TypeId(remote)).map(|ty| ty.id);
+        }
+        self.types.get(&remote).map(|ty| ty.id)
+    }
+
+    /// Finds a local type, in the database or in the new types.
+    fn lookup(&self, db: &Db<Vec<u8>>, id: TypeId) -> Option<Arc<TypeInfo>> {
+        let new = self.new.iter().find(|ty| ty.id == id).cloned();
+        new.or_else(|| db.get_type_info(id))
+    }
+
+    /// Reads the definition of a type of the peer, and finds the local type
+    /// with the same name, or makes a new one. The types it uses must have
+    /// been received before.
+    ///
+    /// Each type takes its size from the `budget` of the pull.
+    fn receive(
+        &mut self,
+        db: &Db<Vec<u8>>,
+        message: &mut Reader,
+        budget: &mut Budget,
+    ) -> Result<(), SyncError> {
+        let remote = message.u64().ok_or(SyncError::Protocol)?;
+        let name = message.string().ok_or(SyncError::Protocol)?;
+        budget
+            .take(TYPE_COST + name.len())
+            .ok_or(SyncError::TooLarge)?;
+        let mut named = |message: &mut Reader| -> Result<Vec<(String, TypeId)>, SyncError> {
+            let count = message.u32().ok_or(SyncError::Protocol)?;
+            let mut items = Vec::new();
+            for _ in 0..count {
+                let item = message.string().ok_or(SyncError::Protocol)?;
+                budget
+                    .take(core::mem::size_of::<(String, TypeId)>() + item.len())
+                    .ok_or(SyncError::TooLarge)?;
+                let id = message.u64().ok_or(SyncError::Protocol)?;
+                let id = self
+                    .local_id(db, id)
+                    .ok_or_else(|| SyncError::UnknownType(alloc::format!("{:#x}", id)))?;
+                items.push((String::from(item), id));
+            }
+            Ok(items)
+        };
+        let definition = match message.u8().ok_or(SyncError::Protocol)? {
+            DEF_ARRAY => {
+                let item = message.u64().ok_or(SyncError::Protocol)?;
+                let item = self
+                    .local_id(db, item)
+                    .ok_or_else(|| SyncError::UnknownType(alloc::format!("{:#x}", item)))?;
+                Some(TypeDef::Array(item))
+            }
+            DEF_PRODUCT => Some(TypeDef::Product {
+                fields: named(message)?,
+            }),
+            DEF_SUM => Some(TypeDef::Sum {
+                variants: named(message)?,
+            }),
+            DEF_PRIMITIVE => None,
+            _ => return Err(SyncError::Protocol),
+        };
+
+        let existing = db
+            .all_type_ids()
+            .into_iter()
+            .filter_map(|id| db.get_type_info(id))
+            .chain(self.new.iter().cloned())
+            .find(|ty| ty.name == name);
+        let local = match (existing, definition) {
+            (Some(existing), Some(definition))
+                if same_definition(&existing.definition, &definition) =>
+            {
+                existing
+            }
+            (Some(existing), None) if !is_composite(&existing.definition) => existing,
+            (Some(_), _) => return Err(SyncError::TypeMismatch(name.into())),
+            // primitive types can't be created
+            (None, None) => return Err(SyncError::UnknownType(name.into())),
+            (None, Some(definition)) => {
+                let ty = Arc::new(TypeInfo {
+                    name: name.into(),
+                    id: self.free_id(db),
+                    definition,
+                });
+                self.new.push(Arc::clone(&ty));
+                ty
+            }
+        };
+        self.types.insert(remote, local);
+        Ok(())
+    }
+
+    /// An ID that no type of the database has (nor a new type), for a type
+    /// from another environment
+    fn free_id(&mut self, db: &Db<Vec<u8>>) -> TypeId {
+        // the database is only looked at for the first new type, the next
+        // ones follow it
+        let id = self.next_id.unwrap_or_else(|| {
+            let last = db.all_type_ids().into_iter().map(|id| id.0).max();
+            last.map_or(FIRST_FREE_ID, |last| (last + 1).max(FIRST_FREE_ID))
+        });
+        self.next_id = Some(id + 1);
+        TypeId(id)
+    }
+}
+
+fn is_composite(definition: &TypeDef) -> bool {
+    matches!(
+        definition,
+        TypeDef::Array(_) | TypeDef::Product { .. } | TypeDef::Sum { .. }
+    )
+}
+
+fn same_definition(a: &TypeDef, b: &TypeDef) -> bool {
+    match (a, b) {
+        (TypeDef::Array(a), TypeDef::Array(b)) => a == b,
+        (TypeDef::Product { fields: a }, TypeDef::Product { fields: b })
+        | (TypeDef::Sum { variants: a }, TypeDef::Sum { variants: b }) => a == b,
+        _ => false,
+    }
+}
+
+/// Checks that a value has the shape that its type describes.
+fn matches_type(
+    value: &DbValue,
+    ty: &TypeInfo,
+    lookup: &impl Fn(TypeId) -> Option<Arc<TypeInfo>>,
+) -> bool {
+    let check = |value: &DbValue, id: TypeId| {
+        lookup(id).map_or(false, |ty| matches_type(value, &ty, lookup))
+    };
+    match (value, &ty.definition) {
+        (DbValue::Array(items), TypeDef::Array(item)) => {
+            items.iter().all(|value| check(value, *item))
+        }
+        (DbValue::Sum { variant, data }, TypeDef::Sum { variants }) => variants
+            .get(*variant as usize)
+            .map_or(false, |&(_, id)| check(data, id)),
+        (DbValue::Product { fields }, TypeDef::Product { fields: types }) => {
+            fields.len() == types.len()
+                && fields
+                    .iter()
+                    .zip(types)
+                    .all(|(value, &(_, id))| check(value, id))
+        }
+        (DbValue::Array(_), _) | (DbValue::Sum { .. }, _) | (DbValue::Product { .. }, _) => false,
+        // primitive values
+        (_, definition) => {
+            !is_composite(definition) && (ty.id == type_ids::U8) == matches!(value, DbValue::U8(_))
+        }
+    }
+}
+
+fn write_value(message: &mut Writer, value: &DbValue) {
+    match *value {
+        DbValue::Unit => message.u8(VALUE_UNIT),
+        DbValue::U8(x) => {
+            message.u8(VALUE_U8);
+            message.u8(x);
+        }
+        DbValue::U64(x) => {
+            message.u8(VALUE_U64);
+            message.u64(x);
+        }
+        DbValue::F64(x) => {
+            message.u8(VALUE_F64);
+            message.u64(x.to_bits());
+        }
+        DbValue::Array(ref items) => {
+            message.u8(VALUE_ARRAY);
+            message.u32(items.len() as u32);
+            for item in items {
+                write_value(message, item);
+            }
+        }
+        DbValue::Sum {
+            ref variant,
+            ref data,
+        } => {
+            message.u8(VALUE_SUM);
+            message.u64(*variant as u64);
+            write_value(message, data);
+        }
+        DbValue::Product { ref fields } => {
+            message.u8(VALUE_PRODUCT);
+            message.u32(fields.len() as u32);
+            for field in fields {
+                write_value(message, field);
+            }
+        }
+    }
+}
+
+/// What the values read so far can still take on the heap
+struct Budget(usize);
+
+impl Budget {
+    /// Takes `cost` bytes. The budget is exhausted (0) if there are not
+    /// enough.
+    fn take(&mut self, cost: usize) -> Option<()> {
+        match self.0.checked_sub(cost) {
+            Some(left) => {
+                self.0 = left;
+                Some(())
+            }
+            None => {
+                self.0 = 0;
+                None
+            }
+        }
+    }
+}
+
+fn read_value(message: &mut Reader, depth: usize, budget: &mut Budget) -> Option<Arc<DbValue>> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    budget.take(VALUE_COST)?;
+    let items = |message: &mut Reader, budget: &mut Budget| {
+        let count = message.u32()? as usize;
+        // each value takes at least a byte
+        if count > message.remaining() {
+            return None;
+        }
+        budget.take(count * core::mem::size_of::<Arc<DbValue>>())?;
+        let mut items = Vec::new();
+        items.try_reserve_exact(count).ok()?;
+        for _ in 0..count {
+            items.push(read_value(message, depth + 1, budget)?);
+        }
+        Some(items)
+    };
+    let value = match message.u8()? {
+        VALUE_UNIT => DbValue::Unit,
+        VALUE_U8 => DbValue::U8(message.u8()?),
+        VALUE_U64 => DbValue::U64(message.u64()?),
+        VALUE_F64 => DbValue::F64(f64::from_bits(message.u64()?)),
+        VALUE_ARRAY => DbValue::Array(items(message, budget)?),
+        VALUE_SUM => DbValue::Sum {
+            variant: message.u64()? as _,
+            data: read_value(message, depth + 1, budget)?,
+        },
+        VALUE_PRODUCT => DbValue::Product {
+            fields: items(message, budget)?,
+        },
+        _ => return None,
+    };
+    Some(Arc::new(value))
+}
+
+/// Builds a message, with numbers in big endian
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, x: u8) {
+        self.0.push(x);
+    }
+
+    fn u16(&mut self, x: u16) {
+        self.0.extend_from_slice(&x.to_be_bytes());
+    }
+
+    fn u32(&mut self, x: u32) {
+        self.0.extend_from_slice(&x.to_be_bytes());
+    }
+
+    fn u64(&mut self, x: u64) {
+        self.0.extend_from_slice(&x.to_be_bytes());
+    }
+
+    /// The length, and UTF-8 bytes
+    fn string(&mut self, s: &str) {
+        self.u32(s.len() as u32);
+        self.0.extend_from_slice(s.as_bytes());
+    }
+}
+
+/// Reads a message, with numbers in big endian
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data, offset: 0 }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len() - self.offset
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.offset..self.offset.checked_add(len)?)?;
+        self.offset += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let mut x = [0; 2];
+        x.copy_from_slice(self.bytes(2)?);
+        Some(u16::from_be_bytes(x))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let mut x = [0; 4];
+        x.copy_from_slice(self.bytes(4)?);
+        Some(u32::from_be_bytes(x))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut x = [0; 8];
+        x.copy_from_slice(self.bytes(8)?);
+        Some(u64::from_be_bytes(x))
+    }
+
+    fn string(&mut self) -> Option<&'a str> {
+        let len = self.u32()? as usize;
+        core::str::from_utf8(self.bytes(len)?).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::types;
+
+    #[test_case]
+    fn read_message() {
+        let message = [
+            0x0a, 0xdb, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 2, b'o', b's', 0, 0, 0, 9,
+        ];
+        let mut reader = Reader::new(&message);
+        assert!(reader.u16() == Some(MAGIC));
+        assert!(reader.u64() == Some(0x100));
+        assert!(reader.string() == Some("os"));
+        // the string is longer than the message
+        assert!(reader.string().is_none());
+        assert!(reader.remaining() == 0);
+    }
+
+    #[test_case]
+    fn value_round_trip() {
+        let value = DbValue::Product {
+            fields: alloc::vec![
+                crate::db::string_value("name"),
+                Arc::new(DbValue::Sum {
+                    variant: 1,
+                    data: Arc::new(DbValue::U64(42)),
+                }),
+                Arc::new(DbValue::F64(0.5)),
+                Arc::new(DbValue::Unit),
+            ],
+        };
+        let mut message = Writer::default();
+        write_value(&mut message, &value);
+        let read = read_value(&mut Reader::new(&message.0), 0, &mut Budget(PULL_BUDGET));
+        let mut again = Writer::default();
+        write_value(&mut again, &read.unwrap());
+        assert!(again.0 == message.0);
+
+        // not enough memory for the string
+        let mut budget = Budget(4 * VALUE_COST);
+        assert!(read_value(&mut Reader::new(&message.0), 0, &mut budget).is_none());
+        assert!(budget.0 == 0);
+    }
+
+    #[test_case]
+    fn receive_types() {
+        let db = super::super::open(Vec::from(*include_bytes!("../../test.adb"))).unwrap();
+        let point = TypeInfo {
+            name: "Test.Point".into(),
+            id: TypeId(0x300),
+            definition: TypeDef::Product {
+                fields: alloc::vec![("x".into(), type_ids::U8), ("y".into(), type_ids::U8)],
+            },
+        };
+        let mut message = Writer::default();
+        write_type(&mut message, &types::string());
+        write_type(&mut message, &point);
+        let mut reader = Reader::new(&message.0);
+        let mut map = TypeMap::default();
+        let mut budget = Budget(PULL_BUDGET);
+        map.receive(&db, &mut reader, &mut budget).unwrap();
+        map.receive(&db, &mut reader, &mut budget).unwrap();
+
+        // the string type is the local one, the point type is only created
+        // when the objects are saved
+        assert!(map.get(types::STRING.0).unwrap().id == types::STRING);
+        let new = map.get(0x300).unwrap();
+        assert!(new.id.0 >= FIRST_FREE_ID && new.name == "Test.Point");
+        assert!(map.new.len() == 1 && db.get_type_info(new.id).is_none());
+
+        let lookup = |id| map.lookup(&db, id);
+        let bytes = DbValue::Product {
+            fields: alloc::vec![Arc::new(DbValue::U8(1)), Arc::new(DbValue::U8(2))],
+        };
+        assert!(matches_type(&bytes, &new, &lookup));
+        let numbers = DbValue::Product {
+            fields: alloc::vec![Arc::new(DbValue::U64(1)), Arc::new(DbValue::U64(2))],
+        };
+        assert!(!matches_type(&numbers, &new, &lookup));
+
+        // a type with the name of a local type, but another definition
+        let string = TypeInfo {
+            name: "Os.String".into(),
+            id: types::STRING,
+            definition: TypeDef::Array(type_ids::TYPE_ID),
+        };
+        let mut message = Writer::default();
+        write_type(&mut message, &string);
+        let res = map.receive(&db, &mut Reader::new(&message.0), &mut budget);
+        assert!(matches!(res, Err(SyncError::TypeMismatch(_))));
+
+        // types count in the memory that a pull can take
+        let mut message = Writer::default();
+        write_type(&mut message, &point);
+        let res = map.receive(&db, &mut Reader::new(&message.0), &mut Budget(TYPE_COST));
+        assert!(matches!(res, Err(SyncError::TooLarge)));
+    }
+}
@@ -0,0 +1,202 @@
+//! Types defined by the kernel itself.
+//!
+//! Their IDs are all in the `0xC0..0x100` range.
+
+use adb::{type_ids, TypeDef, TypeId, TypeInfo};
+use alloc::string::ToString;
+use alloc::sync::Arc;
+
+pub const STRING: TypeId = TypeId(0xC0);
+/// Written when a PCI device is found, see `crate::driver`
+pub const PCI_DEVICE: TypeId = TypeId(0xC1);
+pub const IDENTITY: TypeId = TypeId(0xC2);
+pub const SESSION: TypeId = TypeId(0xC3);
+pub const DATETIME: TypeId = TypeId(0xC4);
+pub const UPTIME: TypeId = TypeId(0xC5);
+pub const KEY_EVENT: TypeId = TypeId(0xC6);
+pub const POINTER_EVENT: TypeId = TypeId(0xC7);
+pub const EXECUTABLE: TypeId = TypeId(0xC8);
+/// Written when a PCI device is found, see `crate::driver`
+pub const PCI_BINDING: TypeId = TypeId(0xC9);
+/// Read from the interrupt streams of `crate::driver::grant`
+pub const PCI_INTERRUPT: TypeId = TypeId(0xCA);
+
+pub fn string() -> Arc<TypeInfo> {
+    Arc::new(TypeInfo {
+        name: "Os.String".to_string(),
+        id: STRING,
+        definition: TypeDef::Array(type_ids::U8),
+    })
+}
+
+pub fn identity() -> Arc<TypeInfo> {
+    Arc::new(TypeInfo {
+        name: "Os.Identity".to_string(),
+        id: IDENTITY,
+        definition: TypeDef::Product {
+            fields: alloc::vec![
+                ("id".to_string(), type_ids::TYPE_ID),
+                ("name".to_string(), STRING),
+            ],
+        },
+    })
+}
+
+pub fn session() -> Arc<TypeInfo> {
+    Arc::new(TypeInfo {
+        name: "Os.Session".to_string(),
+        id: SESSION,
+        definition: TypeDef::Product {
+            fields: alloc::vec![
+                ("id".to_string(), type_ids::TYPE_ID),
+                ("identity".to_string(), type_ids::TYPE_ID),
+            ],
+        },
+    })
+}
+
+/// Generated: the current date and time
+pub fn datetime() -> Arc<TypeInfo> {
+    Arc::new(TypeInfo {
+        name: "Os.Time.DateTime".to_string(),
+        id: DATETIME,
+        definition: TypeDef::Product {
+            fields: alloc::vec![
+                ("year".to_string(), type_ids::TYPE_ID),
+                ("month".to_string(), type_ids::TYPE_ID),
+                ("day".to_string(), type_ids::TYPE_ID),
+                ("weekday".to_string(), type_ids::TYPE_ID),
+                ("hours".to_string(), type_ids::TYPE_ID),
+                ("minutes".to_string(), type_ids::TYPE_ID),
+                ("seconds".to_string(), type_ids::TYPE_ID),
+                ("unix_time".to_string(), type_ids::TYPE_ID),
+            ],
+        },
+    })
+}
+
+/// Generated: the time elapsed since boot
+pub fn uptime() -> Arc<TypeInfo> {
+    Arc::new(TypeInfo {
+        name: "Os.Time.Uptime".to_string(),
+        id: UPTIME,
+        definition: TypeDef::Product {
+            fields: alloc::vec![
+                ("seconds".to_string(), type_ids::TYPE_ID),
+                ("nanoseconds".to_string(), type_ids::TYPE_ID),
+            ],
+        },
+    })
+}
+
+/// Event: a key was pressed, released or repeated
+///
+/// `code` is a `pc_keyboard::KeyCode`, `state` is 0 for up, 1 for down and 2
+/// for repeat, and `modifiers` uses the bits of `crate::input::Modifiers`.
+/// `text` is the character typed with the current layout, if any.
+pub fn key_event() -> Arc<TypeInfo> {
+    Arc::new(TypeInfo {
+        name: "Os.Input.KeyEvent".to_string(),
+        id: KEY_EVENT,
+        definition: TypeDef::Product {
+            fields: alloc::vec![
+                ("code".to_string(), type_ids::TYPE_ID),
+                ("state".to_string(), type_ids::TYPE_ID),
+                ("modifiers".to_string(), type_ids::TYPE_ID),
+                ("text".to_string(), STRING),
+            ],
+        },
+    })
+}
+
+/// Event: the mouse moved, or its buttons changed
+///
+/// `dx`, `dy` and `wheel` are signed, stored in two's complement. `buttons`
+/// uses the bits of `crate::input::mouse::Buttons`.
+pub fn pointer_event() -> Arc<TypeInfo> {
+    Arc::new(TypeInfo {
+        name: "Os.Input.PointerEvent".to_string(),
+        id: POINTER_EVENT,
+        definition: TypeDef::Product {
+            fields: alloc::vec![
+                ("dx".to_string(), type_ids::TYPE_ID),
+                ("dy".to_string(), type_ids::TYPE_ID),
+                ("wheel".to_string(), type_ids::TYPE_ID),
+                ("buttons".to_string(), type_ids::TYPE_ID),
+            ],
+        },
+    })
+}
+
+/// A program that can be started by name
+///
+/// Only pipelines can be saved for now (see `crate::pipeline`): `pipeline` is
+/// the text of the pipeline. The input and output types are
+/// `crate::pipeline::NO_TYPE` when it reads or writes nothing.
+pub fn executable() -> Arc<TypeInfo> {
+    Arc::new(TypeInfo {
+        name: "Os.Executable".to_string(),
+        id: EXECUTABLE,
+        definition: TypeDef::Product {
+            fields: alloc::vec![
+                ("name".to_string(), STRING),
+                ("input_type".to_string(), type_ids::TYPE_ID),
+                ("output_type".to_string(), type_ids::TYPE_ID),
+                ("pipeline".to_string(), STRING),
+            ],
+        },
+    })
+}
+
+pub fn pci_device() -> Arc<TypeInfo> {
+    Arc::new(TypeInfo {
+        name: "Os.Pci.Device".to_string(),
+        id: PCI_DEVICE,
+        definition: TypeDef::Product {
+            fields: alloc::vec![
+                ("vendor".to_string(), type_ids::TYPE_ID),
+                ("device".to_string(), type_ids::TYPE_ID),
+                ("class".to_string(), type_ids::TYPE_ID),
+                ("subclass".to_string(), type_ids::TYPE_ID),
+            ],
+        },
+    })
+}
+
+/// The driver of a PCI device
+///
+/// `state` is `bound`, `failed` (the driver could not start) or
+/// `unsupported` (no driver matches the device, and `driver` is empty). When
+/// a binding changes, another object is written: the last one is the current
+/// one.
+pub fn pci_binding() -> Arc<TypeInfo> {
+    Arc::new(TypeInfo {
+        name: "Os.Pci.Binding".to_string(),
+        id: PCI_BINDING,
+        definition: TypeDef::Product {
+            fields: alloc::vec![
+                ("segment".to_string(), type_ids::TYPE_ID),
+                ("bus".to_string(), type_ids::TYPE_ID),
+                ("device".to_string(), type_ids::TYPE_ID),
+                ("function".to_string(), type_ids::TYPE_ID),
+                ("driver".to_string(), STRING),
+                ("state".to_string(), STRING),
+            ],
+        },
+    })
+}
+
+/// Interrupts of a device granted to a process: `count` interrupts were
+/// signaled on `vector` since the previous object of the stream.
+pub fn pci_interrupt() -> Arc<TypeInfo> {
+    Arc::new(TypeInfo {
+        name: "Os.Pci.Interrupt".to_string(),
+        id: PCI_INTERRUPT,
+        definition: TypeDef::Product {
+            fields: alloc::vec![
+                ("vector".to_string(), type_ids::TYPE_ID),
+                ("count".to_string(), type_ids::TYPE_ID),
+            ],
+        },
+    })
+}
@@ -0,0 +1,399 @@
+//! Direct access to a device, for drivers that run in processes
+//!
+//! The kernel gives a PCI device to a process with [`grant`], as a handle to
+//! a [`DeviceGrant`]. With it, the process can map the memory BARs of the
+//! device, use its I/O ports, receive its interrupts as a stream of
+//! `Os.Pci.Interrupt`, and allocate memory for DMA.
+//!
+//! Interrupts are signaled with MSI or MSI-X when the device has them. The
+//! legacy interrupt is used otherwise: its line is masked each time it
+//! fires, until the process waits for the next interrupts (it must have
+//! told the device to stop asserting it by then).
+//!
+//! Everything is taken back when the last handle to the grant is closed (for
+//! instance when the process is killed): the device is disabled first, so
+//! that it stops accessing memory that may be reused.
+
+use super::{set_binding, Binding, State};
+use crate::apic;
+use crate::db::types;
+use crate::interrupt::{self, Vector, DYNAMIC_COUNT, DYNAMIC_START};
+use crate::memory::{self, AddressSpace, DmaPage, DmaRegion, MEMORY};
+use crate::pci::{self, MsiError, PciDevice, PciResolver};
+use crate::percpu;
+use crate::process::{
+    self,
+    handle::{Handle, HandleError, Object, Rights},
+    PId,
+};
+use crate::security::{self, Denied};
+use adb::{DbObject, DbValue};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::Poll;
+use futures_util::future::poll_fn;
+use futures_util::task::AtomicWaker;
+use pci_types::{Bar, PciAddress};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+/// The name of the driver in the bindings of granted devices
+const DRIVER_NAME: &str = "process";
+
+/// How many pages of DMA memory a grant can allocate, so that a process
+/// can't take the whole pool
+const MAX_DMA_PAGES: usize = 64;
+
+#[derive(Debug)]
+pub enum GrantError {
+    Denied(Denied),
+    NoDevice,
+    /// A kernel driver or another process uses the device
+    InUse,
+    NoProcess,
+    /// The process has too many handles for the grant
+    TooManyHandles,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceError {
+    /// The BAR doesn't exist, or isn't of the right kind
+    InvalidBar,
+    /// Not enough DMA memory (or the grant has too much already), or no
+    /// room to map it
+    NoMemory,
+    NoVector,
+    Msi(MsiError),
+    /// The device has no MSI, and its legacy interrupt can't be routed
+    NoInterrupt,
+    /// Another granted device uses the same legacy interrupt line
+    SharedInterrupt,
+    /// The interrupts are already delivered to a stream
+    AlreadyOpen,
+    NoAccess,
+}
+
+/// Counts the interrupts of each dynamic vector
+struct Interrupts {
+    count: AtomicU64,
+    waker: AtomicWaker,
+}
+
+static INTERRUPTS: [Interrupts; DYNAMIC_COUNT] = {
+    const NONE: Interrupts = Interrupts {
+        count: AtomicU64::new(0),
+        waker: AtomicWaker::new(),
+    };
+    [NONE; DYNAMIC_COUNT]
+};
+
+/// The GSI of the legacy interrupt delivered to each dynamic vector, if
+/// it isn't a message signaled one
+static LEGACY: spin::Mutex<[Option<u32>; DYNAMIC_COUNT]> = spin::Mutex::new([None; DYNAMIC_COUNT]);
+
+fn index(vector: u8) -> usize {
+    (vector - DYNAMIC_START) as usize
+}
+
+fn interrupts(vector: u8) -> &'static Interrupts {
+    &INTERRUPTS[index(vector)]
+}
+
+fn on_interrupt(vector: u8) {
+    // the line stays asserted until the process handles the interrupt
+    if let Some(gsi) = LEGACY.lock()[index(vector)] {
+        apic::set_masked(gsi, true);
+    }
+    let interrupts = interrupts(vector);
+    interrupts.count.fetch_add(1, Ordering::SeqCst);
+    interrupts.waker.wake();
+}
+
+/// Unmasks the legacy interrupt of a vector, if it has one.
+fn unmask(vector: u8) {
+    without_interrupts(|| {
+        if let Some(gsi) = LEGACY.lock()[index(vector)] {
+            apic::set_masked(gsi, false);
+        }
+    });
+}
+
+/// The interrupts of a granted device
+///
+/// The vector stays allocated as long as the stream or the grant exists.
+pub struct InterruptStream {
+    vector: Arc<Vector>,
+    /// How many interrupts were already read
+    seen: u64,
+}
+
+impl InterruptStream {
+    /// Waits for the next interrupts, and returns an `Os.Pci.Interrupt`.
+    pub async fn next(&mut self) -> DbObject {
+        // the process handled the previous interrupts
+        unmask(self.vector.as_u8());
+        let interrupts = interrupts(self.vector.as_u8());
+        let count = poll_fn(|cx| {
+            interrupts.waker.register(cx.waker());
+            let total = interrupts.count.load(Ordering::SeqCst);
+            if total > self.seen {
+                let count = total - self.seen;
+                self.seen = total;
+                Poll::Ready(count)
+            } else {
+                Poll::Pending
+            }
+        })
+        .await;
+        DbObject {
+            type_info: types::pci_interrupt(),
+            value: Arc::new(DbValue::Product {
+                fields: alloc::vec![
+                    Arc::new(DbValue::U64(self.vector.as_u8() as u64)),
+                    Arc::new(DbValue::U64(count)),
+                ],
+            }),
+        }
+    }
+}
+
+/// A device that a process can access directly
+pub struct DeviceGrant {
+    address: PciAddress,
+    device: PciDevice,
+    /// The binding of the device before it was granted, to restore it
+    previous: Binding,
+    /// The address space of the process
+    space: Arc<spin::Mutex<AddressSpace>>,
+    /// BARs and DMA memory mapped in the process
+    mappings: Vec<(VirtAddr, usize)>,
+    /// Where each BAR is mapped, if it is
+    bars: [Option<VirtAddr>; 6],
+    /// I/O ports the process can use (the first one and how many)
+    ports: Vec<(u16, u16)>,
+    /// The I/O ports of all the grants of the process
+    process_ports: Arc<spin::Mutex<Vec<(u16, u16)>>>,
+    dma: Vec<DmaRegion>,
+    vector: Option<Arc<Vector>>,
+}
+
+/// Gives a device to a process, if the security policy allows it and if
+/// nothing else uses it. Returns the handle of the grant in the process.
+pub fn grant(pid: PId, address: PciAddress) -> Result<Handle, GrantError> {
+    security::check_device(pid, address).map_err(GrantError::Denied)?;
+    let previous = super::binding(address).ok_or(GrantError::NoDevice)?;
+    if previous.state == State::Bound {
+        return Err(GrantError::InUse);
+    }
+    let device = {
+        let access = pci::ACCESS.lock();
+        let access = access.as_ref().ok_or(GrantError::NoDevice)?;
+        PciResolver::get_info(access)
+            .devices
+            .remove(&address)
+            .ok_or(GrantError::NoDevice)?
+    };
+    let proc = match process::get(pid) {
+        Some(proc) if !proc.is_killed() => proc,
+        _ => return Err(GrantError::NoProcess),
+    };
+
+    set_binding(
+        address,
+        Binding {
+            driver: Some(DRIVER_NAME),
+            state: State::Bound,
+        },
+    );
+    let grant = DeviceGrant {
+        address,
+        device,
+        previous,
+        space: Arc::clone(proc.space()),
+        mappings: Vec::new(),
+        bars: [None; 6],
+        ports: Vec::new(),
+        process_ports: Arc::clone(proc.io_ports()),
+        dma: Vec::new(),
+        vector: None,
+    };
+    proc.insert_handle(Object::Device(grant), Rights::ALL)
+        .map_err(|err| match err {
+            HandleError::TooMany => GrantError::TooManyHandles,
+            _ => GrantError::NoProcess,
+        })
+}
+
+impl DeviceGrant {
+    pub fn address(&self) -> PciAddress {
+        self.address
+    }
+
+    /// Maps a memory BAR in the process (if it isn't yet), and returns
+    /// where it is.
+    pub fn map_bar(&mut self, index: usize) -> Result<u64, DeviceError> {
+        let (phys, size) = match self.device.bars.get(index).copied().flatten() {
+            Some(Bar::Memory32 { address, size, .. }) => (address as u64, size as u64),
+            Some(Bar::Memory64 { address, size, .. }) => (address, size),
+            _ => return Err(DeviceError::InvalidBar),
+        };
+        if let Some(addr) = self.bars[index] {
+            return Ok(addr.as_u64());
+        }
+        let addr = self.map(phys, size as usize)?;
+        self.bars[index] = Some(addr);
+        self.enable(pci::enable_bus_master)?;
+        Ok(addr.as_u64())
+    }
+
+    /// Lets the process use the ports of an I/O BAR, and returns the first
+    /// one.
+    pub fn allow_ports(&mut self, index: usize) -> Result<u64, DeviceError> {
+        let first = match self.device.bars.get(index).copied().flatten() {
+            Some(Bar::Io { port }) => port as u16,
+            _ => return Err(DeviceError::InvalidBar),
+        };
+        let count = {
+            let access = pci::ACCESS.lock();
+            let access = access.as_ref().ok_or(DeviceError::NoAccess)?;
+            pci::io_bar_size(access, self.address, index as u8)
+        };
+        if !self.ports.contains(&(first, count)) {
+            self.ports.push((first, count));
+            without_interrupts(|| self.process_ports.lock().push((first, count)));
+        }
+        self.enable(pci::enable_io)?;
+        // the process is running: it gets the ports now, and then each time
+        // it is scheduled
+        if let Some(bitmap) = percpu::current().and_then(|cpu| cpu.io_bitmap()) {
+            bitmap.allow(first, count);
+        }
+        Ok(first as u64)
+    }
+
+    /// Sends the interrupts of the device to a new stream.
+    pub fn open_interrupts(&mut self) -> Result<InterruptStream, DeviceError> {
+        if self.vector.is_some() {
+            return Err(DeviceError::AlreadyOpen);
+        }
+        let vector = interrupt::allocate_vector(on_interrupt).ok_or(DeviceError::NoVector)?;
+        interrupts(vector.as_u8()).count.store(0, Ordering::SeqCst);
+        {
+            let access = pci::ACCESS.lock();
+            let access = access.as_ref().ok_or(DeviceError::NoAccess)?;
+            let capabilities = &self.device.capabilities;
+            if capabilities.msi.is_some() || capabilities.msix.is_some() {
+                pci::enable_message_interrupts(access, self.address, &self.device, &vector)
+                    .map_err(DeviceError::Msi)?;
+                // MSIs are memory writes
+                pci::enable_bus_master(access, self.address);
+            } else {
+                let legacy = self
+                    .device
+                    .legacy_interrupt
+                    .ok_or(DeviceError::NoInterrupt)?;
+                // masking a shared line would hide the interrupts of the
+                // other device
+                let shared = without_interrupts(|| {
+                    let mut gsis = LEGACY.lock();
+                    let shared = gsis.iter().any(|&gsi| gsi == Some(legacy.gsi));
+                    if !shared {
+                        gsis[index(vector.as_u8())] = Some(legacy.gsi);
+                    }
+                    shared
+                });
+                if shared {
+                    return Err(DeviceError::SharedInterrupt);
+                }
+                apic::route_pci(legacy, vector.as_u8(), false);
+                pci::enable_intx(access, self.address);
+            }
+        }
+        let vector = Arc::new(vector);
+        self.vector = Some(Arc::clone(&vector));
+        Ok(InterruptStream { vector, seen: 0 })
+    }
+
+    /// Allocates physically contiguous memory that the device can access,
+    /// and maps it in the process. Returns its virtual and physical
+    /// addresses.
+    pub fn allocate_dma(&mut self, len: usize) -> Result<(u64, u64), DeviceError> {
+        let pages = len
+            .checked_add(DmaPage::SIZE - 1)
+            .ok_or(DeviceError::NoMemory)?
+            / DmaPage::SIZE;
+        let allocated: usize = self.dma.iter().map(|r| r.size() / DmaPage::SIZE).sum();
+        if allocated + pages > MAX_DMA_PAGES {
+            return Err(DeviceError::NoMemory);
+        }
+        let region = DmaRegion::new(pages).ok_or(DeviceError::NoMemory)?;
+        let phys = region.phys();
+        let addr = self.map(phys, region.size())?;
+        self.dma.push(region);
+        self.enable(pci::enable_bus_master)?;
+        Ok((addr.as_u64(), phys))
+    }
+
+    fn map(&mut self, phys: u64, len: usize) -> Result<VirtAddr, DeviceError> {
+        let mut memory = MEMORY.lock();
+        let memory = memory.as_mut().ok_or(DeviceError::NoMemory)?;
+        let addr = self
+            .space
+            .lock()
+            .map_physical(&mut memory.frames, PhysAddr::new(phys), len)
+            .map_err(|_| DeviceError::NoMemory)?;
+        self.mappings.push((addr, len));
+        Ok(addr)
+    }
+
+    fn enable(&self, f: fn(&pci::ConfigAccess, PciAddress)) -> Result<(), DeviceError> {
+        let access = pci::ACCESS.lock();
+        let access = access.as_ref().ok_or(DeviceError::NoAccess)?;
+        f(access, self.address);
+        Ok(())
+    }
+}
+
+impl Drop for DeviceGrant {
+    fn drop(&mut self) {
+        // the device must stop using the DMA memory before it is given back
+        if let Some(access) = pci::ACCESS.lock().as_ref() {
+            pci::disable(access, self.address);
+        }
+        if let Some(ref vector) = self.vector {
+            // the stream may live longer, but the line is not unmasked again
+            without_interrupts(|| {
+                let mut gsis = LEGACY.lock();
+                if let Some(gsi) = gsis[index(vector.as_u8())].take() {
+                    apic::set_masked(gsi, true);
+                }
+            });
+        }
+        // no CPU can still access them when this returns
+        let mut space = self.space.lock();
+        for &(addr, len) in self.mappings.iter() {
+            space.unmap(addr, len);
+        }
+        drop(space);
+        memory::shootdown();
+        self.dma.clear();
+        without_interrupts(|| {
+            let mut process_ports = self.process_ports.lock();
+            for ports in self.ports.iter() {
+                if let Some(i) = process_ports.iter().position(|p| p == ports) {
+                    process_ports.remove(i);
+                }
+            }
+        });
+        // the process may still run, if it closed the handle itself
+        for cpu in percpu::all() {
+            if let Some(bitmap) = cpu.io_bitmap() {
+                for &(first, count) in self.ports.iter() {
+                    bitmap.deny(first, count);
+                }
+            }
+        }
+        set_binding(self.address, self.previous);
+    }
+}
@@ -0,0 +1,368 @@
+//! Drivers, and the PCI devices they are bound to
+//!
+//! Drivers say which devices they handle with [`Match`]es, and are
+//! [`register`]ed at boot. When a device is found (at boot, or later by
+//! [`rescan`]), it is bound to the driver that matches it best, and the
+//! future returned by the driver runs as a task: if it fails, the device is
+//! marked as failed. Each device and its binding are written to the database
+//! (`Os.Pci.Device` and `Os.Pci.Binding`), unless the database already has
+//! them (a database loaded from a disk may): the database can't update
+//! objects, so a binding that changed is written again, and the last one is
+//! the current one.
+//!
+//! Devices that no kernel driver uses can instead be granted to a process,
+//! see [`grant`].
+
+use crate::db::{self, types};
+use crate::pci::{self, PciDevice, PciResolver};
+use crate::println;
+use crate::task::{self, Task};
+use adb::{DbObject, DbValue};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use futures_util::future::LocalBoxFuture;
+use pci_types::PciAddress;
+
+pub mod grant;
+
+/// The devices a driver handles
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Match {
+    /// A specific device
+    Device { vendor: u16, device: u16 },
+    /// Any device of a class, with a given interface or any interface
+    Class {
+        class: u8,
+        sub_class: u8,
+        interface: Option<u8>,
+    },
+}
+
+impl Match {
+    /// How well it matches a device (more is better), or `None` if it doesn't
+    fn score(&self, device: &PciDevice) -> Option<u8> {
+        match *self {
+            Match::Device { vendor, device: id }
+                if (vendor, id) == (device.vendor_id, device.device_id) =>
+            {
+                Some(3)
+            }
+            Match::Class {
+                class,
+                sub_class,
+                interface,
+            } if (class, sub_class) == (device.class, device.sub_class) => match interface {
+                Some(interface) if interface == device.interface => Some(2),
+                Some(_) => None,
+                None => Some(1),
+            },
+            _ => None,
+        }
+    }
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    /// Prepares the device, and returns what runs the driver, or `None` if
+    /// the device can't be used. The future returns why it failed, if it
+    /// stops.
+    pub start: fn(PciAddress, &PciDevice) -> Option<DriverFuture>,
+}
+
+pub type DriverFuture = LocalBoxFuture<'static, Result<(), &'static str>>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Bound,
+    /// The driver could not start
+    Failed,
+    /// No driver matches the device
+    Unsupported,
+}
+
+impl State {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            State::Bound => "bound",
+            State::Failed => "failed",
+            State::Unsupported => "unsupported",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Binding {
+    pub driver: Option<&'static str>,
+    pub state: State,
+}
+
+static DRIVERS: spin::RwLock<Vec<&'static Driver>> = spin::RwLock::new(Vec::new());
+
+lazy_static::lazy_static! {
+    static ref BINDINGS: spin::Mutex<BTreeMap<PciAddress, Binding>> =
+        spin::Mutex::new(BTreeMap::new());
+    /// How many devices of each kind were found since the boot, see
+    /// [`record_device`]
+    static ref FOUND: spin::Mutex<BTreeMap<[u64; 4], usize>> = spin::Mutex::new(BTreeMap::new());
+}
+
+/// Makes a driver available for the devices found from now on.
+pub fn register(driver: &'static Driver) {
+    DRIVERS.write().push(driver);
+}
+
+/// The driver that matches a device best. The first one registered wins
+/// ties.
+fn best_driver<'a>(drivers: &[&'a Driver], device: &PciDevice) -> Option<&'a Driver> {
+    let mut best: Option<(&'a Driver, u8)> = None;
+    for &driver in drivers {
+        let score = driver.matches.iter().filter_map(|m| m.score(device)).max();
+        match (score, best) {
+            (Some(score), Some((_, best_score))) if score <= best_score => {}
+            (Some(score), _) => best = Some((driver, score)),
+            (None, _) => {}
+        }
+    }
+    best.map(|(driver, _)| driver)
+}
+
+/// Records a device that was just found, and binds it to a driver.
+pub fn device_added(address: PciAddress, device: &PciDevice) -> Binding {
+    println!(
+        "PCI device {:?}: {:04x}:{:04x}, {:02x}/{:02x} ({})",
+        address,
+        device.vendor_id,
+        device.device_id,
+        device.class,
+        device.sub_class,
+        device.class_info()
+    );
+    let driver = best_driver(&DRIVERS.read(), device);
+    let binding = match driver {
+        Some(driver) => match (driver.start)(address, device) {
+            Some(future) => {
+                let name = driver.name;
+                task::spawn(Task::new(async move {
+                    if let Err(reason) = future.await {
+                        println!("{}: {}", name, reason);
+                        let binding = Binding {
+                            driver: Some(name),
+                            state: State::Failed,
+                        };
+                        set_binding(address, binding);
+                    }
+                }));
+                Binding {
+                    driver: Some(driver.name),
+                    state: State::Bound,
+                }
+            }
+            None => Binding {
+                driver: Some(driver.name),
+                state: State::Failed,
+            },
+        },
+        None => Binding {
+            driver: None,
+            state: State::Unsupported,
+        },
+    };
+    if let Some(name) = binding.driver {
+        println!("  driver {}: {}", name, binding.state.as_str());
+    }
+    record_device(device);
+    set_binding(address, binding);
+    binding
+}
+
+fn number(n: u64) -> Arc<DbValue> {
+    Arc::new(DbValue::U64(n))
+}
+
+/// The numbers at the start of an object
+fn numbers(value: &DbValue) -> Vec<u64> {
+    match *value {
+        DbValue::Product { ref fields } => fields
+            .iter()
+            .map_while(|field| match **field {
+                DbValue::U64(n) => Some(n),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Writes an `Os.Pci.Device`, unless the database has as many of them as
+/// devices of this kind were found.
+fn record_device(device: &PciDevice) {
+    let key = [
+        device.vendor_id as u64,
+        device.device_id as u64,
+        device.class as u64,
+        device.sub_class as u64,
+    ];
+    let found = {
+        let mut found = FOUND.lock();
+        let count = found.entry(key).or_insert(0);
+        *count += 1;
+        *count
+    };
+    let mut db = db::DB.lock();
+    let db = match db.as_mut() {
+        Some(db) => db,
+        None => return,
+    };
+    let recorded = db
+        .iter_type(types::PCI_DEVICE)
+        .filter(|object| numbers(&object.value) == key)
+        .count();
+    if recorded >= found {
+        return;
+    }
+    let object = DbObject {
+        type_info: types::pci_device(),
+        value: Arc::new(DbValue::Product {
+            fields: key.iter().map(|&n| number(n)).collect(),
+        }),
+    };
+    if db.write_object(object).is_err() {
+        println!(
+            "Could not record the PCI device {:04x}:{:04x}",
+            key[0], key[1]
+        );
+    }
+}
+
+/// Changes the binding of a device, and records it if it changed.
+fn set_binding(address: PciAddress, binding: Binding) {
+    BINDINGS.lock().insert(address, binding);
+    let mut db = db::DB.lock();
+    let db = match db.as_mut() {
+        Some(db) => db,
+        None => return,
+    };
+    let key = [
+        address.segment() as u64,
+        address.bus() as u64,
+        address.device() as u64,
+        address.function() as u64,
+    ];
+    let driver = binding.driver.unwrap_or("");
+    let last = db
+        .iter_type(types::PCI_BINDING)
+        .filter(|object| numbers(&object.value) == key)
+        .last();
+    let unchanged = last.map_or(false, |object| match *object.value {
+        DbValue::Product { ref fields } if fields.len() == 6 => {
+            db::as_string(&fields[4]).as_deref() == Some(driver)
+                && db::as_string(&fields[5]).as_deref() == Some(binding.state.as_str())
+        }
+        _ => false,
+    });
+    if unchanged {
+        return;
+    }
+    let mut fields: Vec<_> = key.iter().map(|&n| number(n)).collect();
+    fields.push(db::string_value(driver));
+    fields.push(db::string_value(binding.state.as_str()));
+    let object = DbObject {
+        type_info: types::pci_binding(),
+        value: Arc::new(DbValue::Product { fields }),
+    };
+    if db.write_object(object).is_err() {
+        println!("Could not record the binding of {:?}", address);
+    }
+}
+
+pub fn binding(address: PciAddress) -> Option<Binding> {
+    BINDINGS.lock().get(&address).copied()
+}
+
+/// Enumerates the devices again, and binds the new ones (for hotplug).
+/// Returns how many were found.
+pub fn rescan() -> usize {
+    let devices = {
+        let access = pci::ACCESS.lock();
+        match access.as_ref() {
+            Some(access) => PciResolver::get_info(access).devices,
+            None => return 0,
+        }
+    };
+    let mut added = 0;
+    for (address, device) in devices {
+        if binding(address).is_none() {
+            device_added(address, &device);
+            added += 1;
+        }
+    }
+    added
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pci::Capabilities;
+
+    fn no_start(_: PciAddress, _: &PciDevice) -> Option<DriverFuture> {
+        None
+    }
+
+    static XHCI: Driver = Driver {
+        name: "xhci",
+        matches: &[Match::Class {
+            class: 0x0c,
+            sub_class: 0x03,
+            interface: Some(0x30),
+        }],
+        start: no_start,
+    };
+
+    static USB: Driver = Driver {
+        name: "usb",
+        matches: &[Match::Class {
+            class: 0x0c,
+            sub_class: 0x03,
+            interface: None,
+        }],
+        start: no_start,
+    };
+
+    static QEMU_XHCI: Driver = Driver {
+        name: "qemu-xhci",
+        matches: &[Match::Device {
+            vendor: 0x1b36,
+            device: 0x000d,
+        }],
+        start: no_start,
+    };
+
+    #[test_case]
+    fn best_match() {
+        let mut device = PciDevice {
+            vendor_id: 0x1b36,
+            device_id: 0x000d,
+            revision: 0,
+            class: 0x0c,
+            sub_class: 0x03,
+            interface: 0x30,
+            bars: [None; 6],
+            interrupt_pin: 0,
+            interrupt_line: 0,
+            legacy_interrupt: None,
+            capabilities: Capabilities::default(),
+            secondary_bus: None,
+        };
+        fn name(drivers: &[&'static Driver], device: &PciDevice) -> Option<&'static str> {
+            best_driver(drivers, device).map(|driver| driver.name)
+        }
+        assert!(name(&[&USB, &XHCI, &QEMU_XHCI], &device) == Some("qemu-xhci"));
+        assert!(name(&[&USB, &XHCI], &device) == Some("xhci"));
+        device.interface = 0x20;
+        assert!(name(&[&USB, &XHCI], &device) == Some("usb"));
+        device.class = 0x02;
+        assert!(name(&[&USB, &XHCI], &device).is_none());
+    }
+}
@@ -0,0 +1,351 @@
+//! Text console on the framebuffer
+//!
+//! It understands the most common ANSI escape sequences: colors (`ESC[…m`),
+//! cursor moves (`ESC[…H`, `ESC[…A` to `ESC[…D`) and erasing (`ESC[…J`,
+//! `ESC[…K`). The other ones are ignored.
+
+use super::{font, Color, FrameBuffer, FRAMEBUFFER};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+/// The 16 ANSI colors: black, red, green, yellow, blue, magenta, cyan, white,
+/// and their bright versions
+const PALETTE: [Color; 16] = [
+    Color::rgb(0x00, 0x00, 0x00),
+    Color::rgb(0xaa, 0x00, 0x00),
+    Color::rgb(0x00, 0xaa, 0x00),
+    Color::rgb(0xaa, 0x55, 0x00),
+    Color::rgb(0x00, 0x00, 0xaa),
+    Color::rgb(0xaa, 0x00, 0xaa),
+    Color::rgb(0x00, 0xaa, 0xaa),
+    Color::rgb(0xaa, 0xaa, 0xaa),
+    Color::rgb(0x55, 0x55, 0x55),
+    Color::rgb(0xff, 0x55, 0x55),
+    Color::rgb(0x55, 0xff, 0x55),
+    Color::rgb(0xff, 0xff, 0x55),
+    Color::rgb(0x55, 0x55, 0xff),
+    Color::rgb(0xff, 0x55, 0xff),
+    Color::rgb(0x55, 0xff, 0xff),
+    Color::rgb(0xff, 0xff, 0xff),
+];
+
+const DEFAULT_FG: usize = 7;
+const DEFAULT_BG: usize = 0;
+const TAB_WIDTH: usize = 8;
+
+const MAX_PARAMS: usize = 4;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Print(char),
+    /// A control sequence (`ESC[` parameters command)
+    Csi {
+        command: char,
+        params: [u16; MAX_PARAMS],
+        count: usize,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    Normal,
+    Escape,
+    Csi,
+}
+
+/// Splits the text into characters and escape sequences
+struct Parser {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    count: usize,
+}
+
+impl Parser {
+    const fn new() -> Parser {
+        Parser {
+            state: State::Normal,
+            params: [0; MAX_PARAMS],
+            count: 0,
+        }
+    }
+
+    fn feed(&mut self, c: char) -> Option<Action> {
+        match (self.state, c) {
+            (State::Normal, '\x1b') => self.state = State::Escape,
+            (State::Normal, _) => return Some(Action::Print(c)),
+            (State::Escape, '[') => {
+                self.state = State::Csi;
+                self.params = [0; MAX_PARAMS];
+                self.count = 0;
+            }
+            (State::Escape, _) => self.state = State::Normal,
+            (State::Csi, '0'..='9') => {
+                self.count = self.count.max(1);
+                let param = &mut self.params[self.count - 1];
+                let digit = c as u16 - '0' as u16;
+                *param = param.saturating_mul(10).saturating_add(digit);
+            }
+            (State::Csi, ';') => self.count = (self.count.max(1) + 1).min(MAX_PARAMS),
+            (State::Csi, '\x40'..='\x7e') => {
+                self.state = State::Normal;
+                return Some(Action::Csi {
+                    command: c,
+                    params: self.params,
+                    count: self.count,
+                });
+            }
+            // intermediate bytes, such as the `?` of private sequences
+            (State::Csi, _) => {}
+        }
+        None
+    }
+}
+
+struct Console {
+    columns: usize,
+    rows: usize,
+    column: usize,
+    row: usize,
+    /// Size of the pixels of the font
+    scale: usize,
+    fg: usize,
+    bg: usize,
+    bold: bool,
+    parser: Parser,
+}
+
+static CONSOLE: Mutex<Option<Console>> = Mutex::new(None);
+/// Whether the console draws on the screen (it doesn't when the screen is
+/// used by something else, like the compositor)
+static SHOWN: AtomicBool = AtomicBool::new(true);
+
+pub(super) fn init(fb: &mut FrameBuffer) {
+    let scale = if fb.height() >= 600 { 2 } else { 1 };
+    let console = Console {
+        columns: fb.width() / (font::WIDTH * scale),
+        rows: fb.height() / (font::HEIGHT * scale),
+        column: 0,
+        row: 0,
+        scale,
+        fg: DEFAULT_FG,
+        bg: DEFAULT_BG,
+        bold: false,
+        parser: Parser::new(),
+    };
+    let (width, height) = (fb.width(), fb.height());
+    fb.fill_rect(0, 0, width, height, console.background());
+    *CONSOLE.lock() = Some(console);
+}
+
+/// Stops drawing on the screen, or starts again on a cleared screen.
+pub fn set_shown(shown: bool) {
+    SHOWN.store(shown, Ordering::SeqCst);
+    if shown {
+        _print(format_args!("\x1b[2J"));
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    if !SHOWN.load(Ordering::SeqCst) {
+        return;
+    }
+    let mut console = CONSOLE.lock();
+    let mut fb = FRAMEBUFFER.lock();
+    if let (Some(console), Some(fb)) = (console.as_mut(), fb.as_mut()) {
+        Writer { console, fb }.write_fmt(args).unwrap();
+    }
+}
+
+impl Console {
+    fn foreground(&self) -> Color {
+        match self.fg {
+            fg if self.bold && fg < 8 => PALETTE[fg + 8],
+            fg => PALETTE[fg],
+        }
+    }
+
+    fn background(&self) -> Color {
+        PALETTE[self.bg]
+    }
+
+    fn cell_width(&self) -> usize {
+        font::WIDTH * self.scale
+    }
+
+    fn cell_height(&self) -> usize {
+        font::HEIGHT * self.scale
+    }
+
+    /// Erases `count` cells, starting at the given one.
+    fn clear_cells(&self, fb: &mut FrameBuffer, column: usize, row: usize, count: usize) {
+        let (x, y) = (column * self.cell_width(), row * self.cell_height());
+        let width = count * self.cell_width();
+        fb.fill_rect(x, y, width, self.cell_height(), self.background());
+    }
+
+    fn draw_char(&self, fb: &mut FrameBuffer, c: char) {
+        let (x, y) = (
+            self.column * self.cell_width(),
+            self.row * self.cell_height(),
+        );
+        let (fg, bg) = (self.foreground(), self.background());
+        fb.draw_char(x, y, c, self.scale, fg, bg);
+    }
+
+    fn new_line(&mut self, fb: &mut FrameBuffer) {
+        self.column = 0;
+        if self.row + 1 < self.rows {
+            self.row += 1;
+        } else {
+            fb.scroll_up(self.cell_height(), self.background());
+        }
+    }
+
+    fn write_char(&mut self, fb: &mut FrameBuffer, c: char) {
+        match self.parser.feed(c) {
+            Some(Action::Print(c)) => self.print(fb, c),
+            Some(Action::Csi {
+                command,
+                params,
+                count,
+            }) => self.control(fb, command, &params[..count]),
+            None => {}
+        }
+    }
+
+    fn print(&mut self, fb: &mut FrameBuffer, c: char) {
+        match c {
+            '\n' => self.new_line(fb),
+            '\r' => self.column = 0,
+            '\x08' => self.column = self.column.saturating_sub(1),
+            '\t' => {
+                let next = (self.column / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.column < next.min(self.columns) {
+                    self.print(fb, ' ');
+                }
+            }
+            c if c.is_control() => {}
+            c => {
+                if self.column >= self.columns {
+                    self.new_line(fb);
+                }
+                self.draw_char(fb, c);
+                self.column += 1;
+            }
+        }
+    }
+
+    fn control(&mut self, fb: &mut FrameBuffer, command: char, params: &[u16]) {
+        // missing parameters are 0, and most commands treat 0 as 1
+        let param = |i: usize| params.get(i).copied().unwrap_or(0) as usize;
+        let count = param(0).max(1);
+        match command {
+            'm' if params.is_empty() => self.set_graphics(0),
+            'm' => params.iter().for_each(|&p| self.set_graphics(p)),
+            'A' => self.row = self.row.saturating_sub(count),
+            'B' => self.row = (self.row + count).min(self.rows - 1),
+            'C' => self.column = (self.column + count).min(self.columns - 1),
+            'D' => self.column = self.column.saturating_sub(count),
+            'H' | 'f' => {
+                self.row = (param(0).max(1) - 1).min(self.rows - 1);
+                self.column = (param(1).max(1) - 1).min(self.columns - 1);
+            }
+            'J' => {
+                let (start, end) = match param(0) {
+                    0 => (
+                        self.row * self.columns + self.column,
+                        self.rows * self.columns,
+                    ),
+                    1 => (0, self.row * self.columns + self.column + 1),
+                    _ => (0, self.rows * self.columns),
+                };
+                let mut cell = start;
+                while cell < end {
+                    let (row, column) = (cell / self.columns, cell % self.columns);
+                    let count = (self.columns - column).min(end - cell);
+                    self.clear_cells(fb, column, row, count);
+                    cell += count;
+                }
+                if param(0) == 2 {
+                    self.row = 0;
+                    self.column = 0;
+                }
+            }
+            'K' => {
+                let (start, end) = match param(0) {
+                    0 => (self.column, self.columns),
+                    1 => (0, self.column + 1),
+                    _ => (0, self.columns),
+                };
+                self.clear_cells(fb, start, self.row, end.min(self.columns) - start);
+            }
+            _ => {}
+        }
+    }
+
+    /// Select Graphic Rendition
+    fn set_graphics(&mut self, param: u16) {
+        let param = param as usize;
+        match param {
+            0 => {
+                self.fg = DEFAULT_FG;
+                self.bg = DEFAULT_BG;
+                self.bold = false;
+            }
+            1 => self.bold = true,
+            22 => self.bold = false,
+            30..=37 => self.fg = param - 30,
+            39 => self.fg = DEFAULT_FG,
+            40..=47 => self.bg = param - 40,
+            49 => self.bg = DEFAULT_BG,
+            90..=97 => self.fg = param - 90 + 8,
+            100..=107 => self.bg = param - 100 + 8,
+            _ => {}
+        }
+    }
+}
+
+struct Writer<'a> {
+    console: &'a mut Console,
+    fb: &'a mut FrameBuffer,
+}
+
+impl Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            self.console.write_char(self.fb, c);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn parse_escape_sequences() {
+        let mut parser = Parser::new();
+        let mut actions = "\x1b[1;31mA\x1b[m".chars().filter_map(|c| parser.feed(c));
+        assert!(
+            actions.next()
+                == Some(Action::Csi {
+                    command: 'm',
+                    params: [1, 31, 0, 0],
+                    count: 2
+                })
+        );
+        assert!(actions.next() == Some(Action::Print('A')));
+        assert!(
+            actions.next()
+                == Some(Action::Csi {
+                    command: 'm',
+                    params: [0; MAX_PARAMS],
+                    count: 0
+                })
+        );
+        assert!(actions.next().is_none());
+    }
+}
@@ -0,0 +1,169 @@
+//! The framebuffer set up by the bootloader
+//!
+//! [`FrameBuffer`] has the drawing primitives (pixels, rectangles, blits), and
+//! converts colors to the pixel format of the screen. The kernel console
+//! ([`console`]) draws text on it.
+
+use bootloader::boot_info::{FrameBufferInfo, PixelFormat};
+use spin::Mutex;
+
+pub mod console;
+pub(crate) mod font;
+pub mod surface;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const BLACK: Color = Color::rgb(0, 0, 0);
+    pub const WHITE: Color = Color::rgb(0xff, 0xff, 0xff);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Color {
+        Color { r, g, b }
+    }
+
+    /// From a `0x00RRGGBB` value
+    pub const fn from_xrgb(value: u32) -> Color {
+        Color::rgb((value >> 16) as u8, (value >> 8) as u8, value as u8)
+    }
+
+    pub const fn to_xrgb(self) -> u32 {
+        (self.r as u32) << 16 | (self.g as u32) << 8 | self.b as u32
+    }
+}
+
+pub struct FrameBuffer {
+    buffer: &'static mut [u8],
+    info: FrameBufferInfo,
+}
+
+pub static FRAMEBUFFER: Mutex<Option<FrameBuffer>> = Mutex::new(None);
+
+/// Takes the framebuffer of the bootloader, and starts the console on it.
+pub fn init(fb: &'static mut bootloader::boot_info::FrameBuffer) {
+    let info = fb.info();
+    let mut fb = FrameBuffer {
+        buffer: fb.buffer_mut(),
+        info,
+    };
+    console::init(&mut fb);
+    *FRAMEBUFFER.lock() = Some(fb);
+}
+
+impl FrameBuffer {
+    pub fn width(&self) -> usize {
+        self.info.horizontal_resolution
+    }
+
+    pub fn height(&self) -> usize {
+        self.info.vertical_resolution
+    }
+
+    fn offset(&self, x: usize, y: usize) -> usize {
+        (y * self.info.stride + x) * self.info.bytes_per_pixel
+    }
+
+    /// The bytes of a pixel of this color
+    fn encode(&self, color: Color) -> [u8; 4] {
+        match self.info.pixel_format {
+            PixelFormat::RGB => [color.r, color.g, color.b, 0],
+            PixelFormat::BGR => [color.b, color.g, color.r, 0],
+            _ => {
+                let gray = (color.r as u16 * 77 + color.g as u16 * 150 + color.b as u16 * 29) >> 8;
+                [gray as u8; 4]
+            }
+        }
+    }
+
+    /// Writes a row of `count` pixels of the same color.
+    fn write_run(&mut self, x: usize, y: usize, count: usize, color: Color) {
+        let bpp = self.info.bytes_per_pixel;
+        let pixel = self.encode(color);
+        let start = self.offset(x, y);
+        let row = &mut self.buffer[start..start + count * bpp];
+        for chunk in row.chunks_exact_mut(bpp) {
+            chunk.copy_from_slice(&pixel[..bpp.min(4)]);
+        }
+    }
+
+    pub fn pixel(&mut self, x: usize, y: usize, color: Color) {
+        if x < self.width() && y < self.height() {
+            self.write_run(x, y, 1, color);
+        }
+    }
+
+    /// Fills a rectangle. The parts outside of the screen are ignored.
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        if x >= self.width() || y >= self.height() {
+            return;
+        }
+        let width = width.min(self.width() - x);
+        let height = height.min(self.height() - y);
+        for row in y..y + height {
+            self.write_run(x, row, width, color);
+        }
+    }
+
+    /// Copies a `width` pixels wide image (row by row) to the screen.
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, pixels: &[Color]) {
+        if width == 0 {
+            return;
+        }
+        for (dy, row) in pixels.chunks(width).enumerate() {
+            for (dx, &color) in row.iter().enumerate() {
+                self.pixel(x + dx, y + dy, color);
+            }
+        }
+    }
+
+    /// Draws a character of the built-in font, each pixel of the font being
+    /// a `scale`×`scale` square.
+    pub fn draw_char(&mut self, x: usize, y: usize, c: char, scale: usize, fg: Color, bg: Color) {
+        for (dy, bits) in font::glyph(c).iter().enumerate() {
+            for dx in 0..font::WIDTH {
+                let color = if bits & (1 << dx) != 0 { fg } else { bg };
+                self.fill_rect(x + dx * scale, y + dy * scale, scale, scale, color);
+            }
+        }
+    }
+
+    /// Copies a line of `0x00RRGGBB` pixels to the screen.
+    pub fn write_line(&mut self, x: usize, y: usize, pixels: &[u32]) {
+        if x >= self.width() || y >= self.height() {
+            return;
+        }
+        let pixels = &pixels[..pixels.len().min(self.width() - x)];
+        let start = self.offset(x, y);
+        if self.info.pixel_format == PixelFormat::BGR && self.info.bytes_per_pixel == 4 {
+            // same format: no conversion
+            let line = &mut self.buffer[start..start + pixels.len() * 4];
+            for (dst, src) in line.chunks_exact_mut(4).zip(pixels) {
+                dst.copy_from_slice(&src.to_le_bytes());
+            }
+        } else {
+            for (i, &pixel) in pixels.iter().enumerate() {
+                self.write_run(x + i, y, 1, Color::from_xrgb(pixel));
+            }
+        }
+    }
+
+    /// Moves everything up by `rows` lines of pixels, and fills the bottom
+    /// with `color`.
+    pub fn scroll_up(&mut self, rows: usize, color: Color) {
+        let rows = rows.min(self.height());
+        let start = self.offset(0, rows);
+        let end = self.offset(0, self.height());
+        self.buffer.copy_within(start..end, 0);
+        let (width, height) = (self.width(), self.height());
+        self.fill_rect(0, height - rows, width, rows, color);
+    }
+
+    /// Sets all the bytes of the framebuffer.
+    pub fn fill_bytes(&mut self, value: u8) {
+        self.buffer.fill(value);
+    }
+}
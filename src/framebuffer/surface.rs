@@ -0,0 +1,268 @@
+//! Surfaces: images that processes draw on
+//!
+//! A surface is either the screen itself (the framebuffer, in its own pixel
+//! format), or an off-screen buffer of `0x00RRGGBB` pixels. Processes draw on
+//! the memory of the surface, and then [`Surface::present`] the parts that
+//! changed (the damage): for off-screen surfaces, only these parts are copied
+//! to the screen.
+//!
+//! Surfaces are mapped in the address space of the process that created
+//! them. The pixels of off-screen surfaces are also mapped for the kernel,
+//! that copies them to the screen, and freed with the surface. A process can
+//! only have [`MAX_SHARED`] bytes of them, and only privileged processes can
+//! map the screen (see [`security::check_screen`]).
+
+use super::FRAMEBUFFER;
+use crate::memory::{self, AddressSpace, MAX_SHARED, MEMORY};
+use crate::security::{self, Denied};
+use alloc::sync::Arc;
+use bootloader::boot_info::PixelFormat;
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+/// Off-screen surfaces can't be bigger than that (in pixels, in each direction)
+pub const MAX_SIZE: usize = 4096;
+
+#[derive(Clone, Debug)]
+pub enum SurfaceError {
+    /// The bootloader didn't give us a framebuffer
+    NoScreen,
+    InvalidSize,
+    /// The pixels could not be mapped
+    OutOfMemory,
+    /// The damage rectangles are not in the memory of the process
+    InvalidDamage,
+    /// The process already has [`MAX_SHARED`] bytes of off-screen surfaces
+    TooMuchMemory,
+    Denied(Denied),
+}
+
+impl From<Denied> for SurfaceError {
+    fn from(denied: Denied) -> Self {
+        SurfaceError::Denied(denied)
+    }
+}
+
+/// A rectangle that changed, as processes give it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// The part of this rectangle that is in a `width`×`height` area
+    fn clip(&self, width: usize, height: usize) -> Option<(usize, usize, usize, usize)> {
+        let (x, y) = (self.x as usize, self.y as usize);
+        if x >= width || y >= height {
+            return None;
+        }
+        let w = (self.width as usize).min(width - x);
+        let h = (self.height as usize).min(height - y);
+        if w == 0 || h == 0 {
+            return None;
+        }
+        Some((x, y, w, h))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Screen,
+    OffScreen,
+}
+
+pub struct Surface {
+    pub kind: Kind,
+    pub width: usize,
+    pub height: usize,
+    /// Pixels between the starts of two lines
+    pub stride: usize,
+    pub bytes_per_pixel: usize,
+    /// 0 for RGB (red in the first byte), 1 for BGR, 2 for grayscale
+    pub format: u8,
+    /// Where the pixels are mapped for the process (or for the kernel, if
+    /// the surface is not mapped in a process)
+    pub address: VirtAddr,
+    /// Where the pixels are mapped for the kernel
+    pixels: VirtAddr,
+    /// The address space where the surface is mapped, and the size of the
+    /// mapping, to unmap it when the surface is dropped
+    mapping: Option<(Arc<Mutex<AddressSpace>>, usize)>,
+}
+
+impl Surface {
+    /// Maps the framebuffer in the address space of a process.
+    pub fn screen(space: &Arc<Mutex<AddressSpace>>) -> Result<Surface, SurfaceError> {
+        security::check_screen()?;
+        let (start, len, mut surface) = {
+            let fb = FRAMEBUFFER.lock();
+            let fb = fb.as_ref().ok_or(SurfaceError::NoScreen)?;
+            let format = match fb.info.pixel_format {
+                PixelFormat::RGB => 0,
+                PixelFormat::BGR => 1,
+                _ => 2,
+            };
+            let pixels = VirtAddr::from_ptr(fb.buffer.as_ptr());
+            let surface = Surface {
+                kind: Kind::Screen,
+                width: fb.width(),
+                height: fb.height(),
+                stride: fb.info.stride,
+                bytes_per_pixel: fb.info.bytes_per_pixel,
+                format,
+                address: VirtAddr::zero(),
+                pixels,
+                mapping: None,
+            };
+            (pixels, fb.buffer.len(), surface)
+        };
+
+        let mut memory = MEMORY.lock();
+        let memory = memory.as_mut().ok_or(SurfaceError::OutOfMemory)?;
+        let phys = memory.physical(start).ok_or(SurfaceError::NoScreen)?;
+        surface.address = space
+            .lock()
+            .map_physical(&mut memory.frames, phys, len)
+            .map_err(|_| SurfaceError::OutOfMemory)?;
+        surface.mapping = Some((Arc::clone(space), len));
+        Ok(surface)
+    }
+
+    /// Allocates an off-screen surface, and maps it in the address space of
+    /// a process, if there is one.
+    pub fn off_screen(
+        space: Option<&Arc<Mutex<AddressSpace>>>,
+        width: usize,
+        height: usize,
+    ) -> Result<Surface, SurfaceError> {
+        if width == 0 || height == 0 || width > MAX_SIZE || height > MAX_SIZE {
+            return Err(SurfaceError::InvalidSize);
+        }
+        let len = width * height * 4;
+        if let Some(space) = space {
+            if !space.lock().can_share(len) {
+                return Err(SurfaceError::TooMuchMemory);
+            }
+        }
+        let mut memory_lock = MEMORY.lock();
+        let memory = memory_lock.as_mut().ok_or(SurfaceError::OutOfMemory)?;
+        let pixels = memory
+            .map_shared_new(len)
+            .map_err(|_| SurfaceError::OutOfMemory)?;
+        let (address, mapping) = match space {
+            Some(space) => match space.lock().map_shared(memory, pixels, len) {
+                Ok(address) => (address, Some((Arc::clone(space), len))),
+                Err(_) => {
+                    drop(memory_lock);
+                    memory::free_shared(pixels, len);
+                    return Err(SurfaceError::OutOfMemory);
+                }
+            },
+            None => (pixels, None),
+        };
+        Ok(Surface {
+            kind: Kind::OffScreen,
+            width,
+            height,
+            stride: width,
+            bytes_per_pixel: 4,
+            // 0x00RRGGBB, in little endian
+            format: 1,
+            address,
+            pixels,
+            mapping,
+        })
+    }
+
+    /// Everything a process needs to draw on the surface, in one value:
+    /// width, height, stride (16 bits each), bytes per pixel and format (8
+    /// bits each).
+    pub fn info(&self) -> u64 {
+        self.width as u64
+            | (self.height as u64) << 16
+            | (self.stride as u64) << 32
+            | (self.bytes_per_pixel as u64) << 48
+            | (self.format as u64) << 56
+    }
+
+    /// A line of pixels of an off-screen surface
+    pub fn line(&self, y: usize) -> &[u32] {
+        let start = (self.pixels.as_u64() as *const u32).wrapping_add(y * self.stride);
+        unsafe { core::slice::from_raw_parts(start, self.width) }
+    }
+
+    /// Shows the parts of the surface that changed. An empty `damage` means
+    /// that everything changed.
+    ///
+    /// Off-screen surfaces are shown in the top-left corner of the screen.
+    pub fn present(&self, damage: &[Rect]) -> Result<(), SurfaceError> {
+        if self.kind == Kind::Screen {
+            // the process drew directly on the screen
+            return Ok(());
+        }
+        let mut fb = FRAMEBUFFER.lock();
+        let fb = fb.as_mut().ok_or(SurfaceError::NoScreen)?;
+
+        let everything = [Rect {
+            x: 0,
+            y: 0,
+            width: self.width as u32,
+            height: self.height as u32,
+        }];
+        let damage = if damage.is_empty() {
+            &everything[..]
+        } else {
+            damage
+        };
+        for rect in damage {
+            if let Some((x, y, width, height)) = rect.clip(self.width, self.height) {
+                for row in y..y + height {
+                    fb.write_line(x, row, &self.line(row)[x..x + width]);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Surface {
+    fn drop(&mut self) {
+        let mapping = self.mapping.take();
+        match self.kind {
+            Kind::Screen => {
+                if let Some((space, len)) = mapping {
+                    space.lock().unmap(self.address, len);
+                    memory::shootdown();
+                }
+            }
+            Kind::OffScreen => {
+                if let Some((space, len)) = mapping {
+                    space.lock().unmap_shared(self.address, len);
+                }
+                // its shootdown also flushes the mapping of the process
+                memory::free_shared(self.pixels, self.width * self.height * 4);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn clip_damage() {
+        let rect = Rect {
+            x: 10,
+            y: 20,
+            width: 100,
+            height: 5,
+        };
+        assert!(rect.clip(50, 50) == Some((10, 20, 40, 5)));
+        assert!(rect.clip(10, 50).is_none());
+    }
+}
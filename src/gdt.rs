@@ -1,3 +1,6 @@
+use alloc::alloc::Layout;
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use x86_64::structures::{
     gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
@@ -5,21 +8,121 @@ use x86_64::structures::{
 };
 use x86_64::VirtAddr;
 
+pub const SYSCALL_IST_INDEX: u16 = 0;
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 1;
 pub const PAGE_FAULT_IST_INDEX: u16 = 2;
 pub const GENERAL_PROTECTION_FAULT_IST_INDEX: u16 = 3;
 
+/// One bit per I/O port
+const IO_BITMAP_SIZE: usize = 65536 / 8;
+
+/// Which I/O ports the process running on a CPU can use (a set bit means
+/// that the port can't be used)
+///
+/// The CPU reads it right after the TSS, see [`Tss`].
+#[repr(C)]
+pub struct IoBitmap {
+    bits: [AtomicU8; IO_BITMAP_SIZE],
+    /// The CPU may read one byte past the bitmap, that must be 0xff
+    end: u8,
+    /// The bytes of `bits` that allow ports, since [`IoBitmap::deny_allowed`]
+    /// was last called (empty if both are equal)
+    allowed_start: AtomicUsize,
+    allowed_end: AtomicUsize,
+}
+
+impl IoBitmap {
+    fn new() -> IoBitmap {
+        const DENIED: AtomicU8 = AtomicU8::new(0xff);
+        IoBitmap {
+            bits: [DENIED; IO_BITMAP_SIZE],
+            end: 0xff,
+            allowed_start: AtomicUsize::new(0),
+            allowed_end: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn deny_all(&self) {
+        for bits in self.bits.iter() {
+            bits.store(0xff, Ordering::Relaxed);
+        }
+    }
+
+    /// Denies the ports that were allowed since the last call, which is
+    /// faster than [`IoBitmap::deny_all`] when there are only a few of them.
+    pub fn deny_allowed(&self) {
+        let start = self.allowed_start.swap(0, Ordering::Relaxed);
+        let end = self.allowed_end.swap(0, Ordering::Relaxed);
+        for bits in self.bits.get(start..end).unwrap_or(&[]) {
+            bits.store(0xff, Ordering::Relaxed);
+        }
+    }
+
+    /// Lets the running process use `count` ports, starting at `first`.
+    pub fn allow(&self, first: u16, count: u16) {
+        if count == 0 {
+            return;
+        }
+        let start = first as usize / 8;
+        let end = ((first as usize + count as usize).min(65536) + 7) / 8;
+        if self.allowed_start.load(Ordering::Relaxed) == self.allowed_end.load(Ordering::Relaxed) {
+            self.allowed_start.store(start, Ordering::Relaxed);
+        } else {
+            self.allowed_start.fetch_min(start, Ordering::Relaxed);
+        }
+        self.allowed_end.fetch_max(end, Ordering::Relaxed);
+        self.set(first, count, false);
+    }
+
+    pub fn deny(&self, first: u16, count: u16) {
+        self.set(first, count, true);
+    }
+
+    fn set(&self, first: u16, count: u16, denied: bool) {
+        let end = (first as usize + count as usize).min(65536);
+        for port in first as usize..end {
+            let bit = 1 << (port % 8);
+            if denied {
+                self.bits[port / 8].fetch_or(bit, Ordering::Relaxed);
+            } else {
+                self.bits[port / 8].fetch_and(!bit, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// A TSS, followed by its I/O permission bitmap
+#[repr(C)]
+struct Tss {
+    tss: TaskStateSegment,
+    io_bitmap: IoBitmap,
+}
+
+impl Tss {
+    /// Where the bitmap is, relative to the start of the TSS
+    const IO_BITMAP_OFFSET: u16 = core::mem::size_of::<TaskStateSegment>() as u16;
+}
+
 pub struct Selectors {
-    code_selector: SegmentSelector,
-    data_selector: SegmentSelector,
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
     pub user_code_selector: SegmentSelector,
     pub user_data_selector: SegmentSelector,
 }
 
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
+    static ref TSS: Tss = {
         let mut tss = TaskStateSegment::new();
+        tss.iomap_base = Tss::IO_BITMAP_OFFSET;
+        tss.interrupt_stack_table[SYSCALL_IST_INDEX as usize] = {
+            const STACK_SIZE: usize = 8192;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            let stack_end = stack_start + STACK_SIZE;
+            stack_end
+        };
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
             const STACK_SIZE: usize = 8192;
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
@@ -44,37 +147,101 @@ lazy_static! {
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
-        tss
-    };
-    pub static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let cs_sel = gdt.add_entry(Descriptor::kernel_code_segment());
-        let data_sel = gdt.add_entry(Descriptor::kernel_data_segment());
-        let tss_sel = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        let user_data_sel = gdt.add_entry(Descriptor::user_data_segment());
-        let user_code_sel = gdt.add_entry(Descriptor::user_code_segment());
-        (
-            gdt,
-            Selectors {
-                code_selector: cs_sel,
-                data_selector: data_sel,
-                tss_selector: tss_sel,
-                user_code_selector: user_code_sel,
-                user_data_selector: user_data_sel,
-            },
-        )
+        Tss {
+            tss,
+            io_bitmap: IoBitmap::new(),
+        }
     };
+    /// The GDT of the bootstrap processor
+    ///
+    /// The GDTs of the other processors have the same layout, so the selectors
+    /// in this one are valid everywhere.
+    pub static ref GDT: (GlobalDescriptorTable, Selectors) = build(&TSS);
+}
+
+/// Like `Descriptor::tss_segment`, but the segment also covers the I/O
+/// permission bitmap
+fn tss_descriptor(tss: &'static Tss) -> Descriptor {
+    let base = tss as *const Tss as u64;
+    let limit = (core::mem::size_of::<Tss>() - 1) as u64;
+    // present, available 64-bit TSS
+    let mut low: u64 = 0x89 << 40;
+    low |= limit & 0xffff;
+    low |= (limit >> 16 & 0xf) << 48;
+    low |= (base & 0xff_ffff) << 16;
+    low |= (base >> 24 & 0xff) << 56;
+    Descriptor::SystemSegment(low, base >> 32)
+}
+
+fn build(tss: &'static Tss) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let cs_sel = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_sel = gdt.add_entry(Descriptor::kernel_data_segment());
+    let tss_sel = gdt.add_entry(tss_descriptor(tss));
+    let user_data_sel = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_sel = gdt.add_entry(Descriptor::user_code_segment());
+    (
+        gdt,
+        Selectors {
+            code_selector: cs_sel,
+            data_selector: data_sel,
+            tss_selector: tss_sel,
+            user_code_selector: user_code_sel,
+            user_data_selector: user_data_sel,
+        },
+    )
 }
 
 pub fn init() {
+    load(&*GDT);
+}
+
+/// The I/O permission bitmap of the bootstrap processor
+pub fn bsp_io_bitmap() -> &'static IoBitmap {
+    &TSS.io_bitmap
+}
+
+/// Loads a new GDT and TSS on an application processor.
+///
+/// `ist` contains the top of the stacks to use for system calls, double faults,
+/// page faults and general protection faults (in this order).
+///
+/// Must be called after `percpu::init`, that keeps a pointer to the I/O
+/// permission bitmap.
+pub fn init_ap(ist: [VirtAddr; 4]) {
+    // the bitmap is too big for the stack of the AP: the TSS is built in
+    // place, from zeroed memory
+    let layout = Layout::new::<Tss>();
+    let tss = unsafe { alloc::alloc::alloc_zeroed(layout) } as *mut Tss;
+    if tss.is_null() {
+        alloc::alloc::handle_alloc_error(layout);
+    }
+    let tss = unsafe { &mut *tss };
+    tss.tss = TaskStateSegment::new();
+    tss.tss.iomap_base = Tss::IO_BITMAP_OFFSET;
+    tss.tss.interrupt_stack_table[SYSCALL_IST_INDEX as usize] = ist[0];
+    tss.tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = ist[1];
+    tss.tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = ist[2];
+    tss.tss.interrupt_stack_table[GENERAL_PROTECTION_FAULT_IST_INDEX as usize] = ist[3];
+    tss.io_bitmap.deny_all();
+    tss.io_bitmap.end = 0xff;
+    let tss: &'static Tss = tss;
+    if let Some(cpu) = crate::percpu::current() {
+        cpu.set_io_bitmap(&tss.io_bitmap);
+    }
+    let gdt = Box::leak(Box::new(build(tss)));
+    load(gdt);
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
     use x86_64::instructions::{segmentation::*, tables::load_tss};
 
-    GDT.0.load();
+    gdt.0.load();
     unsafe {
-        CS::set_reg(GDT.1.code_selector);
-        DS::set_reg(GDT.1.data_selector);
-        ES::set_reg(GDT.1.data_selector);
-        SS::set_reg(GDT.1.data_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(gdt.1.code_selector);
+        DS::set_reg(gdt.1.data_selector);
+        ES::set_reg(gdt.1.data_selector);
+        SS::set_reg(gdt.1.data_selector);
+        load_tss(gdt.1.tss_selector);
     }
 }
@@ -0,0 +1,207 @@
+//! User identities and sessions (see `docs/core-types.md`).
+//!
+//! Identities are stored in the database as `Os.Identity` objects. Each
+//! time someone logs in, a new session is opened (and saved as an
+//! `Os.Session` object). Processes belong to a session, which they inherit
+//! from their parent.
+
+use crate::db::{self, types};
+use crate::println;
+use crate::process::{self, PId};
+use crate::task::keyboard::Console;
+use adb::{Db, DbObject, DbValue};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::RwLock;
+
+/// Name of the identity created at first boot
+pub const DEFAULT_IDENTITY: &str = "root";
+
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub id: u64,
+    pub name: String,
+}
+
+impl Identity {
+    fn from_value(value: &DbValue) -> Option<Identity> {
+        match *value {
+            DbValue::Product { ref fields } => match (fields.get(0)?.as_ref(), fields.get(1)?) {
+                (DbValue::U64(id), name) => Some(Identity {
+                    id: *id,
+                    name: db::as_string(name)?,
+                }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn to_object(&self) -> DbObject {
+        DbObject {
+            type_info: types::identity(),
+            value: Arc::new(DbValue::Product {
+                fields: alloc::vec![
+                    Arc::new(DbValue::U64(self.id)),
+                    db::string_value(&self.name),
+                ],
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SessionId(u64);
+
+impl SessionId {
+    /// An id that no session has, in the database (that may come from a
+    /// previous boot) or in this boot.
+    fn new(db: &mut Db<Vec<u8>>) -> Self {
+        let saved = db
+            .iter_type(types::SESSION)
+            .filter_map(|obj| match *obj.value {
+                DbValue::Product { ref fields } => match fields.get(0).map(|id| &**id) {
+                    Some(DbValue::U64(id)) => Some(*id),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .max();
+        let opened = SESSIONS.read().keys().next_back().map(|id| id.0);
+        SessionId(saved.max(opened).map_or(0, |id| id + 1))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub id: SessionId,
+    pub identity: Identity,
+}
+
+lazy_static::lazy_static! {
+    static ref SESSIONS: RwLock<BTreeMap<SessionId, Session>> = RwLock::new(BTreeMap::new());
+
+    /// The session of the person using the keyboard and the screen
+    static ref CONSOLE_SESSION: RwLock<Option<SessionId>> = RwLock::new(None);
+}
+
+/// The database could not save an identity or a session
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteError;
+
+/// Creates the default identity if there is none yet.
+pub fn init(db: &mut Db<Vec<u8>>) -> Result<(), WriteError> {
+    if all(db).is_empty() {
+        create(db, DEFAULT_IDENTITY)?;
+    }
+    Ok(())
+}
+
+/// Lists all the known identities.
+pub fn all(db: &mut Db<Vec<u8>>) -> Vec<Identity> {
+    db.iter_type(types::IDENTITY)
+        .filter_map(|obj| Identity::from_value(&obj.value))
+        .collect()
+}
+
+pub fn find(db: &mut Db<Vec<u8>>, name: &str) -> Option<Identity> {
+    all(db).into_iter().find(|id| id.name == name)
+}
+
+/// Saves a new identity in the database.
+pub fn create(db: &mut Db<Vec<u8>>, name: &str) -> Result<Identity, WriteError> {
+    let id = all(db).iter().map(|id| id.id + 1).max().unwrap_or(0);
+    let identity = Identity {
+        id,
+        name: String::from(name),
+    };
+    db.write_object(identity.to_object())
+        .map_err(|_| WriteError)?;
+    Ok(identity)
+}
+
+/// Opens a new session for the given identity.
+pub fn open_session(db: &mut Db<Vec<u8>>, identity: Identity) -> Result<SessionId, WriteError> {
+    let id = SessionId::new(db);
+    db.write_object(DbObject {
+        type_info: types::session(),
+        value: Arc::new(DbValue::Product {
+            fields: alloc::vec![
+                Arc::new(DbValue::U64(id.0)),
+                Arc::new(DbValue::U64(identity.id)),
+            ],
+        }),
+    })
+    .map_err(|_| WriteError)?;
+    SESSIONS.write().insert(id, Session { id, identity });
+    Ok(id)
+}
+
+pub fn session(id: SessionId) -> Option<Session> {
+    SESSIONS.read().get(&id).cloned()
+}
+
+/// Opens the console session for the default identity, for the processes
+/// that the kernel starts before someone logs in.
+pub fn open_boot_session(db: &mut Db<Vec<u8>>) -> Result<(), WriteError> {
+    if let Some(identity) = find(db, DEFAULT_IDENTITY) {
+        let session = open_session(db, identity)?;
+        *CONSOLE_SESSION.write() = Some(session);
+    }
+    Ok(())
+}
+
+/// The session that processes started by the kernel belong to.
+pub fn console_session() -> Option<SessionId> {
+    *CONSOLE_SESSION.read()
+}
+
+/// Finds which user started a process, by going up in the chain of parent
+/// processes until one of them has a session.
+pub fn user_of(pid: PId) -> Option<Identity> {
+    let mut pid = Some(pid);
+    while let Some(p) = pid {
+        let proc = process::get(p)?;
+        if let Some(session_id) = proc.session() {
+            return session(session_id).map(|s| s.identity);
+        }
+        pid = proc.parent();
+    }
+    None
+}
+
+/// Asks for an identity on the console, opens a session for it, and then
+/// starts the shell.
+pub async fn login_console() {
+    let mut console = Console::new();
+
+    loop {
+        crate::print!("login: ");
+        let name = match console.read_line().await {
+            Some(name) => name,
+            None => return,
+        };
+
+        let mut db = db::DB.lock();
+        let datab = match db.as_mut() {
+            Some(datab) => datab,
+            None => return,
+        };
+        match find(datab, name.trim()) {
+            Some(identity) => match open_session(datab, identity.clone()) {
+                Ok(session) => {
+                    println!("Welcome, {}!", identity.name);
+                    *CONSOLE_SESSION.write() = Some(session);
+                    break;
+                }
+                Err(_) => println!("Could not open a session for {}", identity.name),
+            },
+            None => println!("Unknown identity: {}", name.trim()),
+        }
+    }
+
+    drop(console);
+    crate::shell::run().await
+}
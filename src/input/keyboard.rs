@@ -0,0 +1,349 @@
+//! PS/2 keyboard driver
+//!
+//! Scancodes come from the interrupt handler (see `crate::task::keyboard`).
+//! [`run`] decodes them, and sends the resulting [`KeyEvent`]s to every
+//! listener: kernel tasks, and processes that opened a stream of
+//! `Os.Input.KeyEvent`. Other keyboards (USB) give their keys to [`send_raw`],
+//! so that they share the layout, modifiers and repetition.
+//!
+//! The repetition of the keyboard controller is ignored: keys that are held
+//! are repeated by [`run`] instead, as configured with [`set_repeat`].
+
+use super::Modifiers;
+use crate::db::{self, events::EventSource};
+use crate::task::broadcast::{Channel, Receiver};
+use crate::task::keyboard::ScancodeStream;
+use crate::time::{self, Duration, Instant};
+use adb::DbValue;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::future::poll_fn;
+use futures_util::stream::StreamExt;
+use futures_util::task::AtomicWaker;
+use pc_keyboard::{
+    layouts, DecodedKey, HandleControl, KeyCode, Keyboard, KeyboardLayout, ScancodeSet1,
+};
+use spin::Mutex;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyState {
+    Up,
+    Down,
+    /// The key is still down
+    Repeat,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    pub modifiers: Modifiers,
+    /// The character this key gives with the current layout and modifiers
+    /// (always `None` when the key is released)
+    pub text: Option<char>,
+}
+
+impl KeyEvent {
+    /// The value of the corresponding `Os.Input.KeyEvent`
+    pub fn to_value(&self) -> Arc<DbValue> {
+        let state = match self.state {
+            KeyState::Up => 0,
+            KeyState::Down => 1,
+            KeyState::Repeat => 2,
+        };
+        let mut text = String::new();
+        text.extend(self.text);
+        Arc::new(DbValue::Product {
+            fields: alloc::vec![
+                Arc::new(DbValue::U64(self.code as u64)),
+                Arc::new(DbValue::U64(state)),
+                Arc::new(DbValue::U64(self.modifiers.bits())),
+                db::string_value(&text),
+            ],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    Us104,
+    Uk105,
+    Azerty,
+    Dvorak104,
+    Jis109,
+}
+
+impl Layout {
+    pub fn from_name(name: &str) -> Option<Layout> {
+        match name {
+            "us" => Some(Layout::Us104),
+            "uk" => Some(Layout::Uk105),
+            "fr" | "azerty" => Some(Layout::Azerty),
+            "dvorak" => Some(Layout::Dvorak104),
+            "jp" => Some(Layout::Jis109),
+            _ => None,
+        }
+    }
+
+    fn map(self, code: KeyCode, modifiers: &pc_keyboard::Modifiers) -> DecodedKey {
+        let ctrl = HandleControl::Ignore;
+        match self {
+            Layout::Us104 => layouts::Us104Key::map_keycode(code, modifiers, ctrl),
+            Layout::Uk105 => layouts::Uk105Key::map_keycode(code, modifiers, ctrl),
+            Layout::Azerty => layouts::Azerty::map_keycode(code, modifiers, ctrl),
+            Layout::Dvorak104 => layouts::Dvorak104Key::map_keycode(code, modifiers, ctrl),
+            Layout::Jis109 => layouts::Jis109Key::map_keycode(code, modifiers, ctrl),
+        }
+    }
+}
+
+/// How held keys are repeated
+#[derive(Clone, Copy, Debug)]
+pub struct Repeat {
+    /// Time before the first repetition
+    pub delay: Duration,
+    /// Time between two repetitions
+    pub interval: Duration,
+}
+
+static LAYOUT: Mutex<Layout> = Mutex::new(Layout::Us104);
+static REPEAT: Mutex<Option<Repeat>> = Mutex::new(Some(Repeat {
+    delay: Duration::from_millis(500),
+    interval: Duration::from_millis(33),
+}));
+
+/// Keys from other keyboards than the PS/2 one
+static RAW_QUEUE: OnceCell<ArrayQueue<pc_keyboard::KeyEvent>> = OnceCell::uninit();
+static RAW_WAKER: AtomicWaker = AtomicWaker::new();
+
+lazy_static::lazy_static! {
+    static ref EVENTS: Channel<KeyEvent> = Channel::new(64);
+}
+
+pub fn layout() -> Layout {
+    *LAYOUT.lock()
+}
+
+pub fn set_layout(layout: Layout) {
+    *LAYOUT.lock() = layout;
+}
+
+/// Changes how keys are repeated. `None` disables the repetition.
+pub fn set_repeat(repeat: Option<Repeat>) {
+    *REPEAT.lock() = repeat;
+}
+
+/// Listens to the keyboard.
+pub fn subscribe() -> Receiver<KeyEvent> {
+    EVENTS.subscribe()
+}
+
+/// Gives a key press or release to the driver, as if it came from the PS/2
+/// keyboard.
+pub fn send_raw(event: pc_keyboard::KeyEvent) {
+    let queue = RAW_QUEUE.get_or_init(|| ArrayQueue::new(100));
+    if queue.push(event).is_err() {
+        crate::println!("WARNING: raw key queue full; dropping keyboard input");
+    } else {
+        RAW_WAKER.wake();
+    }
+}
+
+struct DbSource(Receiver<KeyEvent>);
+
+impl EventSource for DbSource {
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Arc<DbValue>> {
+        self.0.poll_recv(cx).map(|event| event.to_value())
+    }
+}
+
+/// Listens to the keyboard, for a stream of `Os.Input.KeyEvent`.
+pub fn subscribe_db() -> Box<dyn EventSource> {
+    Box::new(DbSource(subscribe()))
+}
+
+/// State of the modifier keys
+#[derive(Default)]
+struct Held {
+    lshift: bool,
+    rshift: bool,
+    lctrl: bool,
+    rctrl: bool,
+    alt: bool,
+    alt_gr: bool,
+    logo: bool,
+    caps_lock: bool,
+    num_lock: bool,
+}
+
+impl Held {
+    /// Returns whether the key is a modifier.
+    fn update(&mut self, code: KeyCode, state: KeyState) -> bool {
+        let down = state != KeyState::Up;
+        match code {
+            KeyCode::ShiftLeft => self.lshift = down,
+            KeyCode::ShiftRight => self.rshift = down,
+            KeyCode::ControlLeft => self.lctrl = down,
+            KeyCode::ControlRight => self.rctrl = down,
+            KeyCode::AltLeft => self.alt = down,
+            KeyCode::AltRight => self.alt_gr = down,
+            KeyCode::WindowsLeft | KeyCode::WindowsRight => self.logo = down,
+            KeyCode::CapsLock if state == KeyState::Down => self.caps_lock = !self.caps_lock,
+            KeyCode::NumpadLock if state == KeyState::Down => self.num_lock = !self.num_lock,
+            KeyCode::CapsLock | KeyCode::NumpadLock => {}
+            _ => return false,
+        }
+        true
+    }
+
+    fn modifiers(&self) -> Modifiers {
+        let flags = [
+            (self.lshift || self.rshift, Modifiers::SHIFT),
+            (self.lctrl || self.rctrl, Modifiers::CTRL),
+            (self.alt, Modifiers::ALT),
+            (self.alt_gr, Modifiers::ALT_GR),
+            (self.logo, Modifiers::LOGO),
+            (self.caps_lock, Modifiers::CAPS_LOCK),
+            (self.num_lock, Modifiers::NUM_LOCK),
+        ];
+        flags
+            .iter()
+            .filter(|(set, _)| *set)
+            .fold(Modifiers::NONE, |all, (_, flag)| all | *flag)
+    }
+
+    fn for_layout(&self) -> pc_keyboard::Modifiers {
+        pc_keyboard::Modifiers {
+            lshift: self.lshift,
+            rshift: self.rshift,
+            lctrl: self.lctrl,
+            rctrl: self.rctrl,
+            numlock: self.num_lock,
+            capslock: self.caps_lock,
+            alt_gr: self.alt_gr,
+        }
+    }
+}
+
+struct Driver {
+    held: Held,
+    /// All the keys that are down
+    down: Vec<KeyCode>,
+    /// The key to repeat, and when to repeat it
+    repeat: Option<(KeyCode, Instant)>,
+}
+
+impl Driver {
+    fn event(&self, code: KeyCode, state: KeyState) -> KeyEvent {
+        let text = match state {
+            KeyState::Up => None,
+            _ => match layout().map(code, &self.held.for_layout()) {
+                DecodedKey::Unicode(c) => Some(c),
+                DecodedKey::RawKey(_) => None,
+            },
+        };
+        KeyEvent {
+            code,
+            state,
+            modifiers: self.held.modifiers(),
+            text,
+        }
+    }
+
+    fn on_key(&mut self, raw: pc_keyboard::KeyEvent) -> Option<KeyEvent> {
+        let code = raw.code;
+        let state = match raw.state {
+            pc_keyboard::KeyState::Up => KeyState::Up,
+            pc_keyboard::KeyState::Down => KeyState::Down,
+        };
+
+        match state {
+            // repeated by the controller
+            KeyState::Down if self.down.contains(&code) => return None,
+            KeyState::Down => self.down.push(code),
+            _ => self.down.retain(|&key| key != code),
+        }
+
+        if !self.held.update(code, state) {
+            match state {
+                KeyState::Down => {
+                    let delay = REPEAT.lock().map(|repeat| repeat.delay);
+                    self.repeat = delay.map(|delay| (code, Instant::now() + delay));
+                }
+                _ if self.repeat.map(|(key, _)| key) == Some(code) => self.repeat = None,
+                _ => {}
+            }
+        }
+        Some(self.event(code, state))
+    }
+
+    fn on_repeat(&mut self) -> Option<KeyEvent> {
+        let (code, _) = self.repeat?;
+        let interval = REPEAT.lock().map(|repeat| repeat.interval);
+        self.repeat = interval.map(|interval| (code, Instant::now() + interval));
+        Some(self.event(code, KeyState::Repeat))
+    }
+}
+
+enum Input {
+    Scancode(u8),
+    Raw(pc_keyboard::KeyEvent),
+    Repeat,
+}
+
+/// Waits for a scancode, a key from another keyboard, or for the time to
+/// repeat a key.
+async fn next_input(scancodes: &mut ScancodeStream, repeat_at: Option<Instant>) -> Input {
+    let mut sleep = repeat_at.map(time::sleep_until);
+    let raw = RAW_QUEUE.get_or_init(|| ArrayQueue::new(100));
+    poll_fn(|cx| {
+        if let Poll::Ready(Some(scancode)) = scancodes.poll_next_unpin(cx) {
+            return Poll::Ready(Input::Scancode(scancode));
+        }
+        RAW_WAKER.register(cx.waker());
+        if let Ok(event) = raw.pop() {
+            return Poll::Ready(Input::Raw(event));
+        }
+        match sleep {
+            Some(ref mut sleep) if Pin::new(sleep).poll(cx).is_ready() => {
+                Poll::Ready(Input::Repeat)
+            }
+            _ => Poll::Pending,
+        }
+    })
+    .await
+}
+
+/// The keyboard driver: decodes scancodes, and sends the key events.
+pub async fn run() {
+    let mut scancodes = ScancodeStream::new();
+    // only used to decode scancodes: the layout is applied by `Driver`
+    let mut decoder = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+    let mut driver = Driver {
+        held: Held::default(),
+        down: Vec::new(),
+        repeat: None,
+    };
+
+    loop {
+        let repeat_at = driver.repeat.map(|(_, at)| at);
+        let event = match next_input(&mut scancodes, repeat_at).await {
+            Input::Scancode(scancode) => match decoder.add_byte(scancode) {
+                Ok(Some(raw)) => driver.on_key(raw),
+                _ => None,
+            },
+            Input::Raw(raw) => driver.on_key(raw),
+            Input::Repeat => driver.on_repeat(),
+        };
+        if let Some(event) = event {
+            EVENTS.send(event);
+        }
+    }
+}
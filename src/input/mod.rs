@@ -0,0 +1,54 @@
+//! Input devices
+//!
+//! Drivers turn what the hardware sends into events, that are published in the
+//! database (see `crate::db::events`). The kernel console listens to the same
+//! events as processes.
+
+use core::ops::{BitAnd, BitOr};
+
+pub mod keyboard;
+pub mod mouse;
+
+/// Keys that are held (or toggled, for the locks) when an event happens
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers(0);
+    pub const SHIFT: Modifiers = Modifiers(1 << 0);
+    pub const CTRL: Modifiers = Modifiers(1 << 1);
+    pub const ALT: Modifiers = Modifiers(1 << 2);
+    pub const ALT_GR: Modifiers = Modifiers(1 << 3);
+    /// The Windows key
+    pub const LOGO: Modifiers = Modifiers(1 << 4);
+    pub const CAPS_LOCK: Modifiers = Modifiers(1 << 5);
+    pub const NUM_LOCK: Modifiers = Modifiers(1 << 6);
+
+    pub fn from_bits(bits: u64) -> Modifiers {
+        Modifiers(bits as u8 & 0x7f)
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0 as u64
+    }
+
+    pub fn contains(self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Modifiers {
+    type Output = Modifiers;
+
+    fn bitor(self, rhs: Modifiers) -> Modifiers {
+        Modifiers(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Modifiers {
+    type Output = Modifiers;
+
+    fn bitand(self, rhs: Modifiers) -> Modifiers {
+        Modifiers(self.0 & rhs.0)
+    }
+}
@@ -0,0 +1,392 @@
+//! PS/2 mouse driver
+//!
+//! The mouse is connected to the second port of the 8042 controller, and
+//! raises IRQ 12. Its packets are decoded in the interrupt handler, and the
+//! resulting [`PointerEvent`]s go through a queue (like scancodes, see
+//! `crate::task::keyboard`) to [`run`], that sends them to every listener.
+//!
+//! Mice that support the IntelliMouse extension send 4-byte packets, with the
+//! movement of the wheel.
+//!
+//! https://wiki.osdev.org/PS/2_Mouse
+//! https://wiki.osdev.org/%228042%22_PS/2_Controller
+
+use crate::db::{self, events::EventSource};
+use crate::interrupt::PICS;
+use crate::println;
+use crate::task::broadcast::{Channel, Receiver};
+use adb::DbValue;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use conquer_once::spin::OnceCell;
+use core::ops::BitOr;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+/// Status when read, commands when written
+const CONTROLLER_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+const ENABLE_SECOND_PORT: u8 = 0xa8;
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+/// The next data byte goes to the second port
+const WRITE_SECOND_PORT: u8 = 0xd4;
+
+/// Configuration byte: interrupts of the second port
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+/// Configuration byte: the clock of the second port is disabled
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+
+const MOUSE_SET_DEFAULTS: u8 = 0xf6;
+const MOUSE_ENABLE_REPORTING: u8 = 0xf4;
+const MOUSE_SET_SAMPLE_RATE: u8 = 0xf3;
+const MOUSE_GET_ID: u8 = 0xf2;
+const MOUSE_ACK: u8 = 0xfa;
+/// Device ID of mice with a wheel
+const INTELLIMOUSE_ID: u8 = 3;
+
+const MOUSE_IRQ: u8 = 12;
+
+/// Number of polls before giving up on the controller
+const TIMEOUT: usize = 100_000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Buttons(u8);
+
+impl Buttons {
+    pub const NONE: Buttons = Buttons(0);
+    pub const LEFT: Buttons = Buttons(1 << 0);
+    pub const RIGHT: Buttons = Buttons(1 << 1);
+    pub const MIDDLE: Buttons = Buttons(1 << 2);
+
+    pub fn bits(self) -> u64 {
+        self.0 as u64
+    }
+
+    pub fn contains(self, other: Buttons) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Buttons {
+    type Output = Buttons;
+
+    fn bitor(self, rhs: Buttons) -> Buttons {
+        Buttons(self.0 | rhs.0)
+    }
+}
+
+/// A movement of the mouse, or a change of its buttons
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PointerEvent {
+    /// Horizontal movement, positive to the right
+    pub dx: i32,
+    /// Vertical movement, positive to the bottom (like screen coordinates)
+    pub dy: i32,
+    /// Positive when the wheel is turned towards the user
+    pub wheel: i8,
+    /// The buttons that are down
+    pub buttons: Buttons,
+}
+
+impl PointerEvent {
+    /// The value of the corresponding `Os.Input.PointerEvent`
+    pub fn to_value(&self) -> Arc<DbValue> {
+        // signed values are stored in two's complement
+        let fields = [
+            self.dx as i64 as u64,
+            self.dy as i64 as u64,
+            self.wheel as i64 as u64,
+            self.buttons.bits(),
+        ];
+        Arc::new(DbValue::Product {
+            fields: fields.iter().map(|&x| Arc::new(DbValue::U64(x))).collect(),
+        })
+    }
+}
+
+/// Puts packets back together
+struct Decoder {
+    bytes: [u8; 4],
+    len: usize,
+    /// 3 or 4
+    packet_size: usize,
+}
+
+impl Decoder {
+    const fn new() -> Decoder {
+        Decoder {
+            bytes: [0; 4],
+            len: 0,
+            packet_size: 3,
+        }
+    }
+
+    fn add_byte(&mut self, byte: u8) -> Option<PointerEvent> {
+        // the first byte always has its bit 3 set: if it doesn't, we are not
+        // in sync with the mouse
+        if self.len == 0 && byte & (1 << 3) == 0 {
+            return None;
+        }
+
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.packet_size {
+            return None;
+        }
+        self.len = 0;
+
+        let [flags, x, y, z] = self.bytes;
+        // the packet is garbage when there is an overflow
+        if flags & 0xc0 != 0 {
+            return None;
+        }
+        // 9-bit two's complement values, the sign being in the flags
+        let dx = x as i32 - (((flags as i32) << 4) & 0x100);
+        let dy = y as i32 - (((flags as i32) << 3) & 0x100);
+        // 4-bit two's complement
+        let wheel = if self.packet_size == 4 {
+            ((z << 4) as i8) >> 4
+        } else {
+            0
+        };
+        Some(PointerEvent {
+            dx,
+            dy: -dy,
+            wheel,
+            buttons: Buttons(flags & 0b111),
+        })
+    }
+}
+
+static DECODER: Mutex<Decoder> = Mutex::new(Decoder::new());
+static EVENT_QUEUE: OnceCell<ArrayQueue<PointerEvent>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+/// Called by the mouse interrupt handler
+///
+/// Must not block or allocate.
+pub(crate) fn add_byte(byte: u8) {
+    let event = match DECODER.try_lock() {
+        Some(mut decoder) => decoder.add_byte(byte),
+        None => None,
+    };
+
+    if let (Some(event), Ok(queue)) = (event, EVENT_QUEUE.try_get()) {
+        if queue.push(event).is_err() {
+            println!("WARNING: mouse event queue full; dropping mouse input");
+        } else {
+            WAKER.wake();
+        }
+    }
+}
+
+pub struct PointerEventStream {
+    _private: (),
+}
+
+impl PointerEventStream {
+    pub fn new() -> Self {
+        EVENT_QUEUE
+            .try_init_once(|| ArrayQueue::new(100))
+            .expect("PointerEventStream::new should only be called once");
+        PointerEventStream { _private: () }
+    }
+}
+
+impl Stream for PointerEventStream {
+    type Item = PointerEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<PointerEvent>> {
+        let queue = EVENT_QUEUE.try_get().unwrap();
+
+        if let Ok(event) = queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+
+        WAKER.register(&cx.waker());
+        match queue.pop() {
+            Ok(event) => {
+                WAKER.take();
+                Poll::Ready(Some(event))
+            }
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+fn wait_input_empty() -> bool {
+    let mut status: Port<u8> = Port::new(CONTROLLER_PORT);
+    (0..TIMEOUT).any(|_| unsafe { status.read() } & STATUS_INPUT_FULL == 0)
+}
+
+fn wait_output_full() -> bool {
+    let mut status: Port<u8> = Port::new(CONTROLLER_PORT);
+    (0..TIMEOUT).any(|_| unsafe { status.read() } & STATUS_OUTPUT_FULL != 0)
+}
+
+fn command(cmd: u8) -> Option<()> {
+    if !wait_input_empty() {
+        return None;
+    }
+    unsafe { Port::new(CONTROLLER_PORT).write(cmd) };
+    Some(())
+}
+
+fn write_data(data: u8) -> Option<()> {
+    if !wait_input_empty() {
+        return None;
+    }
+    unsafe { Port::new(DATA_PORT).write(data) };
+    Some(())
+}
+
+fn read_data() -> Option<u8> {
+    if !wait_output_full() {
+        return None;
+    }
+    Some(unsafe { Port::new(DATA_PORT).read() })
+}
+
+/// Sends a byte to the mouse, and waits for it to acknowledge it.
+fn mouse_write(data: u8) -> Option<()> {
+    command(WRITE_SECOND_PORT)?;
+    write_data(data)?;
+    if read_data()? == MOUSE_ACK {
+        Some(())
+    } else {
+        None
+    }
+}
+
+/// Knocks on the door of the IntelliMouse extension, and returns the size of
+/// the packets the mouse will send.
+fn enable_wheel() -> Option<usize> {
+    for rate in [200, 100, 80] {
+        mouse_write(MOUSE_SET_SAMPLE_RATE)?;
+        mouse_write(rate)?;
+    }
+    mouse_write(MOUSE_GET_ID)?;
+    if read_data()? == INTELLIMOUSE_ID {
+        Some(4)
+    } else {
+        Some(3)
+    }
+}
+
+fn init_controller() -> Option<usize> {
+    command(ENABLE_SECOND_PORT)?;
+    command(READ_CONFIG)?;
+    let config = read_data()?;
+    command(WRITE_CONFIG)?;
+    write_data((config | CONFIG_SECOND_IRQ) & !CONFIG_SECOND_CLOCK_DISABLED)?;
+
+    mouse_write(MOUSE_SET_DEFAULTS)?;
+    let packet_size = enable_wheel()?;
+    mouse_write(MOUSE_ENABLE_REPORTING)?;
+    Some(packet_size)
+}
+
+/// Enables the mouse, if there is one.
+///
+/// Must be called before the mouse interrupt is unmasked.
+pub fn init() -> bool {
+    let packet_size = match interrupts::without_interrupts(init_controller) {
+        Some(packet_size) => packet_size,
+        None => {
+            println!("No PS/2 mouse");
+            return false;
+        }
+    };
+    DECODER.lock().packet_size = packet_size;
+    PRESENT.store(true, Ordering::SeqCst);
+
+    if crate::apic::is_enabled() {
+        crate::apic::set_isa_masked(MOUSE_IRQ, false);
+    } else {
+        interrupts::without_interrupts(|| unsafe {
+            let mut pics = PICS.lock();
+            let [mask1, mask2] = pics.read_masks();
+            // IRQ 2 is where the second PIC is connected
+            pics.write_masks(mask1 & !(1 << 2), mask2 & !(1 << (MOUSE_IRQ - 8)));
+        });
+    }
+    println!("PS/2 mouse ready ({}-byte packets)", packet_size);
+    true
+}
+
+pub fn is_present() -> bool {
+    PRESENT.load(Ordering::SeqCst)
+}
+
+lazy_static::lazy_static! {
+    static ref EVENTS: Channel<PointerEvent> = Channel::new(64);
+}
+
+/// Sends an event from another mouse (USB) to the listeners.
+pub fn send(event: PointerEvent) {
+    EVENTS.send(event);
+}
+
+/// Listens to the mouse.
+pub fn subscribe() -> Receiver<PointerEvent> {
+    EVENTS.subscribe()
+}
+
+struct DbSource(Receiver<PointerEvent>);
+
+impl EventSource for DbSource {
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Arc<DbValue>> {
+        self.0.poll_recv(cx).map(|event| event.to_value())
+    }
+}
+
+/// Listens to the mouse, for a stream of `Os.Input.PointerEvent`.
+pub fn subscribe_db() -> Box<dyn EventSource> {
+    Box::new(DbSource(subscribe()))
+}
+
+/// The mouse driver: sends the events decoded by the interrupt handler.
+pub async fn run() {
+    let mut events = PointerEventStream::new();
+    while let Some(event) = events.next().await {
+        send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn decode_packets() {
+        let mut decoder = Decoder::new();
+        // left button, moving left (x = -1) and up (y = +1)
+        assert_eq!(decoder.add_byte(0b0001_1001), None);
+        assert_eq!(decoder.add_byte(0xff), None);
+        let event = decoder.add_byte(0x01).unwrap();
+        assert_eq!((event.dx, event.dy), (-1, -1));
+        assert!(event.buttons.contains(Buttons::LEFT));
+
+        // out of sync: the first byte is dropped
+        assert_eq!(decoder.add_byte(0), None);
+
+        decoder.packet_size = 4;
+        decoder.add_byte(0b0000_1000);
+        decoder.add_byte(0);
+        decoder.add_byte(0);
+        assert_eq!(decoder.add_byte(0x0f).unwrap().wheel, -1);
+    }
+}
@@ -1,7 +1,8 @@
 use crate::gdt;
+use crate::percpu::KernelGs;
 use crate::println;
-use crate::process;
 use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use pic8259::ChainedPics;
 use spin;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
@@ -13,13 +14,22 @@ pub static PICS: spin::Mutex<ChainedPics> =
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
-enum InterruptIndex {
+pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    /// Real time clock (IRQ 8)
+    Rtc = PIC_2_OFFSET,
+    /// PS/2 mouse (IRQ 12)
+    Mouse = PIC_2_OFFSET + 4,
+    /// Local APIC timer of the application processors
+    ApicTimer = PIC_2_OFFSET + 8,
+    /// Sent by a CPU that unmapped pages, see `memory::shootdown`
+    Shootdown,
+    Spurious = crate::apic::SPURIOUS_VECTOR,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_u8(self) -> u8 {
         self as u8
     }
 
@@ -28,6 +38,74 @@ impl InterruptIndex {
     }
 }
 
+/// The vectors that [`allocate_vector`] hands out
+pub const DYNAMIC_START: u8 = 0x90;
+pub const DYNAMIC_COUNT: usize = 64;
+
+/// The handlers of the dynamic vectors, as `fn(u8)` pointers (0 when the
+/// vector is free)
+static HANDLERS: [AtomicUsize; DYNAMIC_COUNT] = {
+    const FREE: AtomicUsize = AtomicUsize::new(0);
+    [FREE; DYNAMIC_COUNT]
+};
+
+/// An IDT vector with a handler, freed when dropped
+#[derive(Debug)]
+pub struct Vector(u8);
+
+impl Vector {
+    pub fn as_u8(&self) -> u8 {
+        self.0
+    }
+}
+
+impl Drop for Vector {
+    fn drop(&mut self) {
+        HANDLERS[(self.0 - DYNAMIC_START) as usize].store(0, Ordering::SeqCst);
+    }
+}
+
+/// Finds a free vector, and calls `handler` (with the vector) when it is
+/// signaled. The handler runs in the interrupt, with interrupts disabled: it
+/// should only wake a task. Returns `None` if all the vectors are used.
+pub fn allocate_vector(handler: fn(u8)) -> Option<Vector> {
+    let handler = handler as usize;
+    HANDLERS.iter().enumerate().find_map(|(i, slot)| {
+        slot.compare_exchange(0, handler, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| Vector(DYNAMIC_START + i as u8))
+    })
+}
+
+fn on_dynamic(vector: u8) {
+    let handler = HANDLERS[(vector - DYNAMIC_START) as usize].load(Ordering::SeqCst);
+    if handler != 0 {
+        let handler: fn(u8) = unsafe { core::mem::transmute(handler) };
+        handler(vector);
+    }
+    // message signaled interrupts go through the local APIC, and the legacy
+    // PCI interrupts through the I/O APIC
+    crate::apic::end_of_interrupt();
+}
+
+/// One handler per dynamic vector, that calls [`on_dynamic`] with its vector
+macro_rules! dynamic_handlers {
+    ($($i:literal)*) => {
+        [$({
+            extern "x86-interrupt" fn handler(mut stack: InterruptStackFrame) {
+                let _gs = KernelGs::enter(&mut stack);
+                on_dynamic(DYNAMIC_START + $i);
+            }
+            handler as extern "x86-interrupt" fn(InterruptStackFrame)
+        }),*]
+    };
+}
+
+static DYNAMIC_HANDLERS: [extern "x86-interrupt" fn(InterruptStackFrame); DYNAMIC_COUNT] = dynamic_handlers!(
+    0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+    32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59 60 61 62 63
+);
+
 lazy_static::lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
@@ -44,8 +122,9 @@ lazy_static::lazy_static! {
 
             idt[0x80]
                 .set_handler_fn(syscall)
-                // TODO: give it its own stack
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX)
+                // system calls can be interrupted (when they sleep), so they
+                // can't share a stack with other interrupts
+                .set_stack_index(gdt::SYSCALL_IST_INDEX)
                 .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
             // PIC interrupts
             idt[InterruptIndex::Timer.as_usize()]
@@ -54,7 +133,27 @@ lazy_static::lazy_static! {
             idt[InterruptIndex::Keyboard.as_usize()]
                 .set_handler_fn(keyboard_interrupt_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt[InterruptIndex::Mouse.as_usize()]
+                .set_handler_fn(mouse_interrupt_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt[InterruptIndex::Rtc.as_usize()]
+                .set_handler_fn(rtc_interrupt_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            // APIC interrupts
+            idt[InterruptIndex::ApicTimer.as_usize()]
+                .set_handler_fn(apic_timer_interrupt_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt[InterruptIndex::Shootdown.as_usize()]
+                .set_handler_fn(shootdown_interrupt_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            // vectors for MSI and MSI-X, with handlers registered later
+            for (i, handler) in DYNAMIC_HANDLERS.iter().enumerate() {
+                idt[DYNAMIC_START as usize + i]
+                    .set_handler_fn(*handler)
+                    .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            }
         }
+        idt[InterruptIndex::Spurious.as_usize()].set_handler_fn(spurious_interrupt_handler);
         idt.stack_segment_fault.set_handler_fn(ss_fault_handler);
         idt.segment_not_present.set_handler_fn(segment_not_present);
 
@@ -67,7 +166,19 @@ pub fn init_idt() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn syscall(_stack: InterruptStackFrame) {
+/// Notifies the interrupt controller (the local APIC, or the PICs if it is not
+/// enabled yet) that an interrupt has been handled.
+pub fn end_of_interrupt(index: InterruptIndex) {
+    if crate::apic::is_enabled() {
+        crate::apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(index.as_u8());
+        }
+    }
+}
+
+extern "x86-interrupt" fn syscall(mut stack: InterruptStackFrame) {
     let rax: usize;
     let rbx: usize;
     let rcx: usize;
@@ -85,32 +196,18 @@ extern "x86-interrupt" fn syscall(_stack: InterruptStackFrame) {
         );
         asm!("mov rsi, rbx", out("rsi") rbx)
     }
+    // after reading the registers of the process, and dropped before writing
+    // them back
+    let gs = KernelGs::enter(&mut stack);
     let code = rax;
     let arg1 = rbx;
-    println!("system call: {} {}", code, arg1);
-    match code {
-        0 => {
-            let mut fb = crate::FB.lock();
-            if let Some(ref mut fb) = *fb {
-                let buff = unsafe { core::slice::from_raw_parts_mut(fb.0 as *mut u8, fb.1) };
-                let arg1 = arg1 as u8;
-                buff.fill(arg1)
-            }
-        }
-        1 => {
-            let proc = process::get_mut(process::current().unwrap()).unwrap();
-            let mut db = crate::db::DB.try_lock().unwrap();
-            let mut db = (*db).as_mut().unwrap();
-            proc.open_stream(&mut db, adb::TypeId(arg1 as u64))
-        }
-        _ => {}
-    }
-    println!("done with syscall");
+    let ret = crate::syscall::dispatch(code as u64, arg1 as u64, rcx as u64, rdx as u64);
+    drop(gs);
     unsafe {
         asm!("mov rbx, rsi", in("rsi") rbx);
         asm!(
             "nop",
-            in("rax") rax,
+            in("rax") ret,
             in("rcx") rcx,
             in("rdx") rdx,
             in("rsi") rsi,
@@ -119,7 +216,8 @@ extern "x86-interrupt" fn syscall(_stack: InterruptStackFrame) {
     }
 }
 
-extern "x86-interrupt" fn segment_not_present(stack: InterruptStackFrame, code: u64) {
+extern "x86-interrupt" fn segment_not_present(mut stack: InterruptStackFrame, code: u64) {
+    let _gs = KernelGs::enter(&mut stack);
     let ip = stack.instruction_pointer.as_ptr();
     let inst: [u8; 8] = unsafe { core::ptr::read(ip) };
     println!("Code: {:?}", inst);
@@ -127,9 +225,10 @@ extern "x86-interrupt" fn segment_not_present(stack: InterruptStackFrame, code:
     loop {}
 }
 extern "x86-interrupt" fn page_fault_handler(
-    stack: InterruptStackFrame,
+    mut stack: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
+    let _gs = KernelGs::enter(&mut stack);
     println!("PAGE FAULT");
     let ip = stack.instruction_pointer.as_ptr();
     let inst: [u8; 8] = unsafe { core::ptr::read(ip) };
@@ -138,7 +237,8 @@ extern "x86-interrupt" fn page_fault_handler(
     loop {}
 }
 
-extern "x86-interrupt" fn gp_handler(stack: InterruptStackFrame, code: u64) {
+extern "x86-interrupt" fn gp_handler(mut stack: InterruptStackFrame, code: u64) {
+    let _gs = KernelGs::enter(&mut stack);
     let ip = stack.instruction_pointer.as_ptr();
     let inst: [u8; 8] = unsafe { core::ptr::read(ip) };
     println!("Code: {:?}", inst);
@@ -156,45 +256,78 @@ extern "x86-interrupt" fn gp_handler(stack: InterruptStackFrame, code: u64) {
     loop {}
 }
 
-extern "x86-interrupt" fn ss_fault_handler(_stack: InterruptStackFrame, code: u64) {
+extern "x86-interrupt" fn ss_fault_handler(mut stack: InterruptStackFrame, code: u64) {
+    let _gs = KernelGs::enter(&mut stack);
     println!("STACK SEGMENT FAULT ({})", code);
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack: InterruptStackFrame) {
+extern "x86-interrupt" fn keyboard_interrupt_handler(mut stack: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&mut stack);
     use x86_64::instructions::port::Port;
 
     let mut port = Port::new(0x60); // Keyboard I/O port
     let scancode: u8 = unsafe { port.read() };
     crate::task::keyboard::add_scancode(scancode);
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+extern "x86-interrupt" fn mouse_interrupt_handler(mut stack: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&mut stack);
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(0x60);
+    let byte: u8 = unsafe { port.read() };
+    crate::input::mouse::add_byte(byte);
+
+    end_of_interrupt(InterruptIndex::Mouse);
+}
+
+extern "x86-interrupt" fn rtc_interrupt_handler(mut stack: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&mut stack);
+    crate::cmos::on_interrupt();
+    end_of_interrupt(InterruptIndex::Rtc);
+}
+
+/// Spurious interrupts must not be acknowledged
+extern "x86-interrupt" fn spurious_interrupt_handler(mut stack: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&mut stack);
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(mut stack: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&mut stack);
+    crate::pit::on_interrupt();
+    on_timer(InterruptIndex::Timer);
+}
+
+extern "x86-interrupt" fn apic_timer_interrupt_handler(mut stack: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&mut stack);
+    on_timer(InterruptIndex::ApicTimer);
+}
+
+extern "x86-interrupt" fn shootdown_interrupt_handler(mut stack: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&mut stack);
+    if let Some(cpu) = crate::percpu::current() {
+        cpu.flush_if_requested();
     }
+    crate::apic::end_of_interrupt();
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack: InterruptStackFrame) {
+/// Wakes the tasks that were sleeping, and runs the tasks and processes of
+/// the current CPU
+fn on_timer(index: InterruptIndex) {
     if crate::allocator::is_ready() {
-        if let Some(mut exec) = crate::task::executor::EXECUTOR.try_lock() {
-            exec.run_ready_tasks();
-        }
+        crate::time::timer::wake_expired();
+        crate::percpu::run_tasks();
     }
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+    end_of_interrupt(index);
 
-    // start PID 0 if needed
-    if crate::process::current().is_none() {
-        let pid0 = crate::process::PId::new(0);
-        if crate::process::get_mut(pid0).is_some() {
-            crate::process::switch_to(pid0)
-        }
-    }
+    crate::process::schedule();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack: InterruptStackFrame) {
+extern "x86-interrupt" fn breakpoint_handler(mut stack: InterruptStackFrame) {
+    let _gs = KernelGs::enter(&mut stack);
     crate::println!("BREAKPOINT: {:#?}", stack);
     let ip = stack.instruction_pointer.as_ptr();
     let inst: [u8; 8] = unsafe { core::ptr::read(ip) };
@@ -206,16 +339,36 @@ extern "x86-interrupt" fn breakpoint_handler(stack: InterruptStackFrame) {
         crate::println!("{:#018x} ({:#065b})", s, s);
     }
     if let Some(pid) = crate::process::current() {
-        if let Some(proc) = crate::process::get_mut(pid) {
+        if let Some(proc) = crate::process::get(pid) {
             crate::println!("--------------\nCurrent process: {:?}", pid);
-            for (i, stream) in proc.streams.iter().enumerate() {
-                crate::println!("  - Stream {} of {}", i, stream.ty().0);
+            let handles = proc
+                .with_handles(|handles| handles.iter().collect::<alloc::vec::Vec<_>>())
+                .unwrap_or_default();
+            for (handle, rights, object) in handles {
+                match *object.lock() {
+                    crate::process::handle::Object::Stream(ref stream) => crate::println!(
+                        "  - Handle {:#x} ({:?}): stream of {}",
+                        handle.as_raw(),
+                        rights,
+                        stream.ty().0
+                    ),
+                    ref other => crate::println!(
+                        "  - Handle {:#x} ({:?}): {}",
+                        handle.as_raw(),
+                        rights,
+                        other.kind()
+                    ),
+                }
             }
         }
     }
 }
 
-extern "x86-interrupt" fn double_fault_handler(stack: InterruptStackFrame, error_code: u64) -> ! {
+extern "x86-interrupt" fn double_fault_handler(
+    mut stack: InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    let _gs = KernelGs::enter(&mut stack);
     let ip = stack.instruction_pointer.as_ptr();
     let ip: [u8; 8] = unsafe { core::ptr::read(ip) };
     println!("Code: {:?}", ip);
@@ -224,8 +377,22 @@ extern "x86-interrupt" fn double_fault_handler(stack: InterruptStackFrame, error
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test_case]
     fn test_breakpoint() {
         x86_64::instructions::interrupts::int3();
     }
+
+    #[test_case]
+    fn dynamic_vectors() {
+        fn handler(_: u8) {}
+        let first = allocate_vector(handler).unwrap();
+        let second = allocate_vector(handler).unwrap();
+        assert!(first.as_u8() != second.as_u8());
+        assert!(first.as_u8() >= DYNAMIC_START);
+        let freed = first.as_u8();
+        drop(first);
+        assert!(allocate_vector(handler).unwrap().as_u8() == freed);
+    }
 }
@@ -14,19 +14,33 @@ extern crate alloc;
 use core::panic::PanicInfo;
 
 pub mod allocator;
+pub mod apic;
 pub mod cmos;
 pub mod db;
+pub mod driver;
+pub mod framebuffer;
 pub mod gdt;
+pub mod identity;
+pub mod input;
 pub mod interrupt;
 pub mod memory;
+pub mod net;
 pub mod pci;
+pub mod percpu;
+pub mod pipeline;
+pub mod pit;
 pub mod process;
+pub mod prt;
+pub mod security;
 pub mod serial;
+pub mod shell;
+pub mod smp;
+pub mod storage;
+pub mod syscall;
 pub mod task;
-
-lazy_static::lazy_static! {
-    pub static ref FB: spin::Mutex<Option<(u64, usize)>> = spin::Mutex::new(None);
-}
+pub mod time;
+pub mod ui;
+pub mod usb;
 
 pub fn init() {
     gdt::init();
@@ -42,6 +56,11 @@ pub fn init() {
 
 // Re-enable timer interrupts when the OS is ready
 pub fn ready() {
+    if apic::is_enabled() {
+        apic::set_isa_masked(0, false);
+        return;
+    }
+
     // interrupts have to be disabled, because as soon
     // as the timer interrupts will be re-enabled, the ISR
     // will be called, and it needs to lock the PICS too
@@ -86,8 +105,17 @@ bootloader::entry_point!(kernel_main_test);
 
 /// Entry point for `cargo xtest`
 #[cfg(test)]
-fn kernel_main_test(_boot_info: &'static mut bootloader::BootInfo) -> ! {
+fn kernel_main_test(boot_info: &'static mut bootloader::BootInfo) -> ! {
+    use core::ops::DerefMut;
+
     init();
+    // some tests need the heap
+    let phys_mem_offset =
+        x86_64::VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator =
+        unsafe { memory::BootInfoFrameAllocator::init(boot_info.memory_regions.deref_mut()) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).unwrap();
     test_main();
     halt_loop()
 }
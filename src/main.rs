@@ -6,8 +6,6 @@
 
 extern crate alloc;
 
-use alloc::string::ToString;
-use alloc::sync::Arc;
 use core::{ops::DerefMut, panic::PanicInfo};
 use os::println;
 
@@ -30,14 +28,8 @@ fn kernel_main(boot_info: &'static mut bootloader::BootInfo) -> ! {
     use os::memory;
     use x86_64::VirtAddr;
 
-    if let bootloader::boot_info::Optional::Some(ref fb) = boot_info.framebuffer {
-        let fb = fb.buffer();
-        let fb_len = fb.len();
-        let fb_start = (&fb[0] as *const _) as u64;
-        {
-            let mut fb = os::FB.lock();
-            *fb = Some((fb_start, fb_len));
-        }
+    if let Some(fb) = boot_info.framebuffer.as_mut() {
+        os::framebuffer::init(fb);
     }
 
     os::init();
@@ -47,6 +39,13 @@ fn kernel_main(boot_info: &'static mut bootloader::BootInfo) -> ! {
     let mut frame_allocator =
         unsafe { memory::BootInfoFrameAllocator::init(boot_info.memory_regions.deref_mut()) };
     os::allocator::init_heap(&mut mapper, &mut frame_allocator).unwrap();
+    memory::reserve_dma_frames(&mut frame_allocator, 256);
+    os::percpu::init(0);
+
+    let acpi_tables = boot_info.rsdp_addr.into_option().map(|rsdp| {
+        unsafe { acpi::AcpiTables::from_rsdp(os::memory::AcpiHandler, rsdp as usize) }.unwrap()
+    });
+    os::time::init(acpi_tables.as_ref());
 
     let x = alloc::boxed::Box::new(19);
     println!("box: {}", x);
@@ -55,85 +54,78 @@ fn kernel_main(boot_info: &'static mut bootloader::BootInfo) -> ! {
     {
         let mut db = os::db::DB.lock();
         if let Some(datab) = db.as_mut() {
+            // the test process below belongs to the boot session
+            let identities =
+                os::identity::init(datab).and_then(|_| os::identity::open_boot_session(datab));
+            if identities.is_err() {
+                println!("Could not save the default identity and its session");
+            }
             os::db::display_contents(datab);
         }
     }
 
-    let dt = os::cmos::get_datetime();
-    println!("Date: {}/{}/{}", dt.day, dt.month, dt.year);
-    println!("Time: {}:{}:{}", dt.hours, dt.minutes, dt.seconds);
-
     {
-        use os::task::executor::EXECUTOR;
-
-        let mut exec = EXECUTOR.lock();
-        exec.spawn(os::task::Task::new(example_task()));
-        exec.spawn(os::task::Task::new(os::task::keyboard::print_keypresses()));
+        let db = os::db::DB.lock();
+        let now = os::db::generated::read(os::db::types::DATETIME);
+        if let (Some(datab), Some(now)) = (db.as_ref(), now) {
+            println!(
+                "{}",
+                os::db::DbValueDisplay::new(datab, now.value, now.type_info)
+            );
+        }
     }
 
+    os::task::spawn(os::task::Task::new(example_task()));
+    os::task::spawn(os::task::Task::new(os::input::keyboard::run()));
+    os::task::spawn(os::task::Task::new(os::identity::login_console()));
+
     // simple program that changes the color of the screen
     // with a system call
     // it also opens a stream of PCI devices and calls the debugger
     // to print info about its state
-    let proc = os::process::Process::create(
-        &mut mapper,
-        &mut frame_allocator,
-        include_bytes!("../test.bin"),
-    );
-    os::process::spawn(proc).unwrap();
-
-    if let bootloader::boot_info::Optional::Some(rsdp) = boot_info.rsdp_addr {
-        let acpi_tables =
-            unsafe { acpi::AcpiTables::from_rsdp(os::memory::AcpiHandler, rsdp as usize) }.unwrap();
-
-        let pci_config_regions = acpi::mcfg::PciConfigRegions::new(&acpi_tables).unwrap();
-        let config_access = os::pci::ConfigAccess(pci_config_regions);
-
-        let pci_type = Arc::new(adb::TypeInfo {
-            name: "Os.Pci.Device".to_string(),
-            id: adb::TypeId(0xC1),
-            definition: adb::TypeDef::Product {
-                fields: alloc::vec![
-                    ("vendor".to_string(), adb::type_ids::TYPE_ID),
-                    ("device".to_string(), adb::type_ids::TYPE_ID),
-                    ("class".to_string(), adb::type_ids::TYPE_ID),
-                    ("subclass".to_string(), adb::type_ids::TYPE_ID),
-                ],
-            },
-        });
+    // the shell can start it again with `spawn test`
+    let test = include_bytes!("../test.bin");
+    os::process::register_program("test", test);
+    let proc = os::process::Process::create(&mut frame_allocator, "test", test).unwrap();
+    os::process::spawn_with_parent(proc, None).unwrap();
+
+    if let Some(ref acpi_tables) = acpi_tables {
+        if os::apic::init(acpi_tables) {
+            println!("Using the APIC");
+            os::prt::init(acpi_tables);
+            os::smp::init(acpi_tables, &mut mapper, &mut frame_allocator);
+        }
+    }
 
-        let mut db = os::db::DB.lock();
-        for (_address, device) in os::pci::PciResolver::get_info(config_access).devices {
-            println!(
-                "PCI device: {:04x?}:{:04x?}, 0x{:02x?}/0x{:02x?} ({})",
-                device.vendor_id,
-                device.device_id,
-                device.class,
-                device.sub_class,
-                device.class_info()
-            );
-            if let Some(datab) = db.as_mut() {
-                datab
-                    .write_object(adb::DbObject {
-                        type_info: alloc::sync::Arc::clone(&pci_type),
-                        value: Arc::new(adb::DbValue::Product {
-                            fields: alloc::vec![
-                                Arc::new(adb::DbValue::U64(device.vendor_id as u64)),
-                                Arc::new(adb::DbValue::U64(device.device_id as u64)),
-                                Arc::new(adb::DbValue::U64(device.class as u64)),
-                                Arc::new(adb::DbValue::U64(device.sub_class as u64)),
-                            ],
-                        }),
-                    })
-                    .unwrap();
-            }
+    {
+        let config_access = os::pci::ConfigAccess::new(acpi_tables.as_ref());
+        let devices = os::pci::PciResolver::get_info(&config_access).devices;
+        // drivers use it when they start
+        *os::pci::ACCESS.lock() = Some(config_access);
+
+        os::driver::register(&os::usb::xhci::DRIVER);
+        os::driver::register(&os::storage::nvme::DRIVER);
+        os::driver::register(&os::net::e1000::DRIVER);
+        for (address, device) in devices {
+            os::driver::device_added(address, &device);
         }
 
+        let mut db = os::db::DB.lock();
         if let Some(db) = db.as_mut() {
             os::db::display_contents(db);
         }
     }
 
+    if os::input::mouse::init() {
+        os::task::spawn(os::task::Task::new(os::input::mouse::run()));
+    }
+
+    // from now on, system calls map memory for processes
+    *memory::MEMORY.lock() = Some(memory::Memory {
+        mapper,
+        frames: frame_allocator,
+    });
+
     os::ready();
 
     #[cfg(test)]
@@ -1,22 +1,44 @@
+use crate::interrupt::InterruptIndex;
+use crate::percpu;
 use acpi::PhysicalMapping;
+use alloc::vec::Vec;
 use bootloader::boot_info::{MemoryRegion, MemoryRegionKind};
+use core::ops::Range;
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::{interrupts, tlb};
+use x86_64::registers::control::{Cr3, Cr3Flags};
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        mapper::{MapToError, TranslateResult},
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableEntry, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
+    },
     PhysAddr, VirtAddr,
 };
 
 pub const MEM_OFFSET: u64 = 0x0000_4000_0000_0000;
 
+/// Where kernel stacks (other than the ones of the bootstrap processor) are mapped
+pub const STACKS_START: u64 = 0x_5555_0000_0000;
+static NEXT_STACK: AtomicU64 = AtomicU64::new(STACKS_START);
+
 pub unsafe fn init(phys_mem_offset: VirtAddr) -> OffsetPageTable<'static> {
     let l4_table = active_page_level_4_table(phys_mem_offset);
+    // the private part of the processes must not hide anything
+    assert!(
+        USER_ENTRIES.all(|i| l4_table[i].is_unused()),
+        "The kernel uses the addresses of processes"
+    );
     OffsetPageTable::new(l4_table, phys_mem_offset)
 }
 
 unsafe fn active_page_level_4_table(physical_mem_offset: VirtAddr) -> &'static mut PageTable {
-    use x86_64::registers::control::Cr3;
-
     let (level_4_table_frame, _) = Cr3::read();
+    KERNEL_L4.store(
+        level_4_table_frame.start_address().as_u64(),
+        Ordering::SeqCst,
+    );
     let phys = level_4_table_frame.start_address();
     let virt = physical_mem_offset + phys.as_u64();
     let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
@@ -24,9 +46,535 @@ unsafe fn active_page_level_4_table(physical_mem_offset: VirtAddr) -> &'static m
     &mut *page_table_ptr
 }
 
+/// Maps a new kernel stack of `pages` pages, and returns its top.
+///
+/// An unmapped page is left below each stack, so that overflows
+/// cause a page fault instead of silently corrupting memory.
+pub fn map_stack(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+    pages: u64,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let guard = NEXT_STACK.fetch_add((pages + 1) * 4096, Ordering::SeqCst);
+    let bottom = guard + 4096;
+    for i in 0..pages {
+        let page = Page::containing_address(VirtAddr::new(bottom + i * 4096));
+        let frame = frame_alloc
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { mapper.map_to(page, frame, flags, frame_alloc)?.flush() };
+    }
+    Ok(VirtAddr::new(bottom + pages * 4096))
+}
+
+/// The part of the address space that is private to each process (the
+/// entries 192 to 255 of the level 4 table). The rest is the same in all the
+/// address spaces, and only the kernel can access it.
+pub const USER_START: u64 = 0x0000_6000_0000_0000;
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+const USER_ENTRIES: Range<usize> = 192..256;
+
+/// Where memory is mapped for a process, when it asks for it
+const USER_MAPPINGS_START: u64 = 0x0000_7000_0000_0000;
+
+/// Where the kernel maps memory that it shares with processes
+///
+/// It is in the same entry of the level 4 table as the heap, that exists
+/// since the boot: all the address spaces see the new mappings right away.
+/// Addresses are not reused once the memory is freed, but there is room for
+/// terabytes.
+const SHARED_START: u64 = 0x_4444_8000_0000;
+const SHARED_END: u64 = 0x_4480_0000_0000;
+static NEXT_SHARED: AtomicU64 = AtomicU64::new(SHARED_START);
+
+/// How much shared memory a process can map (see [`AddressSpace::map_shared`])
+pub const MAX_SHARED: usize = 32 * 1024 * 1024;
+
+/// The level 4 table of the kernel (its physical address)
+static KERNEL_L4: AtomicU64 = AtomicU64::new(0);
+
+/// The page table and the frame allocator, once the kernel has booted
+pub struct Memory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frames: BootInfoFrameAllocator,
+}
+
+/// Set at the end of the boot, for system calls that need to map memory
+pub static MEMORY: spin::Mutex<Option<Memory>> = spin::Mutex::new(None);
+
+fn page_count(len: usize) -> Option<u64> {
+    Some((len as u64).checked_add(4095)? / 4096)
+}
+
+impl Memory {
+    /// Maps `len` bytes of new zeroed memory in the part of the address
+    /// space that the kernel shares with processes, and returns where they
+    /// are. Processes can then map them with [`AddressSpace::map_shared`].
+    ///
+    /// They must be freed with [`free_shared`].
+    pub fn map_shared_new(&mut self, len: usize) -> Result<VirtAddr, MapToError<Size4KiB>> {
+        let pages = page_count(len).ok_or(MapToError::FrameAllocationFailed)?;
+        let start = NEXT_SHARED.fetch_add(pages * 4096, Ordering::SeqCst);
+        if start + pages * 4096 > SHARED_END {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        for i in 0..pages {
+            let page = Page::containing_address(VirtAddr::new(start + i * 4096));
+            let frame = self
+                .frames
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let frame_ptr = (MEM_OFFSET + frame.start_address().as_u64()) as *mut u8;
+            unsafe {
+                core::ptr::write_bytes(frame_ptr, 0, 4096);
+                self.mapper
+                    .map_to(page, frame, flags, &mut self.frames)?
+                    .flush()
+            };
+        }
+        Ok(VirtAddr::new(start))
+    }
+
+    /// Unmaps memory mapped with [`Memory::map_shared_new`], and returns
+    /// its frames, to be freed once the TLBs are flushed (see
+    /// [`free_shared`]).
+    fn unmap_shared_new(&mut self, addr: VirtAddr, len: usize) -> Vec<PhysFrame> {
+        let first = Page::<Size4KiB>::containing_address(addr);
+        let last = Page::containing_address(addr + (len.max(1) - 1));
+        let mut frames = Vec::new();
+        for page in Page::range_inclusive(first, last) {
+            if let Ok((frame, flush)) = self.mapper.unmap(page) {
+                flush.ignore();
+                frames.push(frame);
+            }
+        }
+        frames
+    }
+
+    /// The physical address of a kernel virtual address
+    pub fn physical(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper.translate_addr(addr)
+    }
+}
+
+/// Unmaps memory mapped with [`Memory::map_shared_new`], and gives its
+/// frames back to the frame allocator. The processes must have unmapped it
+/// first.
+///
+/// [`MEMORY`] must not be locked: it is unlocked during the TLB shootdown,
+/// since another CPU may wait for it with its interrupts disabled.
+pub fn free_shared(addr: VirtAddr, len: usize) {
+    let frames = match MEMORY.lock().as_mut() {
+        Some(memory) => memory.unmap_shared_new(addr, len),
+        None => return,
+    };
+    // no CPU may still write to the frames when they are reused
+    shootdown();
+    if let Some(memory) = MEMORY.lock().as_mut() {
+        for frame in frames {
+            memory.frames.free(frame);
+        }
+    }
+}
+
+/// The table in `frame`, through the mapping of the physical memory
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *((MEM_OFFSET + frame.start_address().as_u64()) as *mut PageTable)
+}
+
+fn kernel_l4() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_L4.load(Ordering::SeqCst)))
+}
+
+/// Loads the page table of the kernel on the current CPU (when it doesn't
+/// run a process anymore).
+pub fn activate_kernel() {
+    unsafe { Cr3::write(kernel_l4(), Cr3Flags::empty()) };
+}
+
+/// The page table of a process
+///
+/// Only the private part (from [`USER_START`] to [`USER_END`]) is its own:
+/// the other entries are copied from the table of the kernel each time it is
+/// loaded.
+pub struct AddressSpace {
+    l4: PhysFrame,
+    /// Where the next mapping goes
+    next_mapping: u64,
+    /// Bytes of shared memory mapped, at most [`MAX_SHARED`]
+    shared: usize,
+    /// Whether [`AddressSpace::free`] was called: nothing can be mapped
+    /// anymore
+    freed: bool,
+}
+
+impl AddressSpace {
+    pub fn new(frame_alloc: &mut impl FrameAllocator<Size4KiB>) -> Option<AddressSpace> {
+        let space = AddressSpace {
+            l4: frame_alloc.allocate_frame()?,
+            next_mapping: USER_MAPPINGS_START,
+            shared: 0,
+            freed: false,
+        };
+        unsafe { table_at(space.l4) }.zero();
+        Some(space)
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        unsafe { OffsetPageTable::new(table_at(self.l4), VirtAddr::new(MEM_OFFSET)) }
+    }
+
+    fn user_flags() -> PageTableFlags {
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE
+    }
+
+    /// Loads it on the current CPU, with the latest entries of the kernel.
+    pub fn activate(&self) {
+        let kernel = unsafe { table_at(kernel_l4()) };
+        let table = unsafe { table_at(self.l4) };
+        for i in (0..512).filter(|i| !USER_ENTRIES.contains(i)) {
+            table[i] = kernel[i].clone();
+        }
+        unsafe { Cr3::write(self.l4, Cr3Flags::empty()) };
+    }
+
+    /// Maps a page at `addr` (in the private part), with `frame`.
+    pub fn map_page(
+        &mut self,
+        frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+        addr: VirtAddr,
+        frame: PhysFrame,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        let page = Page::containing_address(addr);
+        let flags = AddressSpace::user_flags();
+        // the table is not loaded anywhere yet: there is nothing to flush
+        unsafe {
+            self.mapper()
+                .map_to(page, frame, flags, frame_alloc)?
+                .ignore()
+        };
+        Ok(())
+    }
+
+    /// Reserves room for `len` bytes, and returns where it starts and how
+    /// many pages it has.
+    fn reserve(&mut self, len: usize) -> Result<(VirtAddr, u64), MapToError<Size4KiB>> {
+        let pages = page_count(len).ok_or(MapToError::FrameAllocationFailed)?;
+        if self.freed {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+        let start = self.next_mapping;
+        match start.checked_add(pages * 4096) {
+            Some(end) if end <= USER_END => self.next_mapping = end,
+            _ => return Err(MapToError::FrameAllocationFailed),
+        }
+        Ok((VirtAddr::new(start), pages))
+    }
+
+    /// Maps `len` bytes of physical memory (usually device memory), and
+    /// returns where they are.
+    pub fn map_physical(
+        &mut self,
+        frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+        start: PhysAddr,
+        len: usize,
+    ) -> Result<VirtAddr, MapToError<Size4KiB>> {
+        let offset = start.as_u64() % 4096;
+        let first = PhysFrame::<Size4KiB>::containing_address(start);
+        let (base, pages) = self.reserve(len.saturating_add(offset as usize))?;
+        let flags = AddressSpace::user_flags() | PageTableFlags::NO_CACHE;
+        let mut mapper = self.mapper();
+        for i in 0..pages {
+            let page = Page::containing_address(base + i * 4096);
+            unsafe { mapper.map_to(page, first + i, flags, frame_alloc)?.flush() };
+        }
+        Ok(base + offset)
+    }
+
+    /// Whether `len` more bytes of shared memory can be mapped
+    pub fn can_share(&self, len: usize) -> bool {
+        self.shared
+            .checked_add(len)
+            .map_or(false, |total| total <= MAX_SHARED)
+    }
+
+    /// Maps `len` bytes that the kernel mapped with
+    /// [`Memory::map_shared_new`], and returns where they are. They are
+    /// unmapped with [`AddressSpace::unmap_shared`].
+    pub fn map_shared(
+        &mut self,
+        memory: &mut Memory,
+        shared: VirtAddr,
+        len: usize,
+    ) -> Result<VirtAddr, MapToError<Size4KiB>> {
+        if !self.can_share(len) {
+            return Err(MapToError::FrameAllocationFailed);
+        }
+        let (base, pages) = self.reserve(len)?;
+        self.shared += len;
+        let mut mapper = self.mapper();
+        for i in 0..pages {
+            let page = Page::containing_address(base + i * 4096);
+            let frame = memory
+                .physical(shared + i * 4096)
+                .map(PhysFrame::containing_address)
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let flags = AddressSpace::user_flags();
+            unsafe {
+                mapper
+                    .map_to(page, frame, flags, &mut memory.frames)?
+                    .flush()
+            };
+        }
+        Ok(base)
+    }
+
+    /// Unmaps `len` bytes at `addr`. The caller must then call [`shootdown`],
+    /// once the address space is unlocked (another CPU may wait for it with
+    /// its interrupts disabled).
+    ///
+    /// The frames are not freed: they belong to whoever asked for the
+    /// mapping.
+    pub fn unmap(&mut self, addr: VirtAddr, len: usize) {
+        if self.freed {
+            return;
+        }
+        let first = Page::<Size4KiB>::containing_address(addr);
+        let last = Page::containing_address(addr + (len.max(1) - 1));
+        let mut mapper = self.mapper();
+        for page in Page::range_inclusive(first, last) {
+            if let Ok((_, flush)) = mapper.unmap(page) {
+                flush.ignore();
+            }
+        }
+    }
+
+    /// Unmaps shared memory mapped with [`AddressSpace::map_shared`] (see
+    /// [`AddressSpace::unmap`]).
+    pub fn unmap_shared(&mut self, addr: VirtAddr, len: usize) {
+        self.unmap(addr, len);
+        self.shared = self.shared.saturating_sub(len);
+    }
+
+    /// Checks that the `len` bytes at `addr` are mapped, and that the process
+    /// can access them.
+    pub fn is_accessible(&mut self, addr: VirtAddr, len: usize) -> bool {
+        if self.freed {
+            return false;
+        }
+        let last = match addr.as_u64().checked_add(len.max(1) as u64 - 1) {
+            Some(last) if addr.as_u64() >= USER_START && last < USER_END => last,
+            _ => return false,
+        };
+        let mut pages = Page::<Size4KiB>::range_inclusive(
+            Page::containing_address(addr),
+            Page::containing_address(VirtAddr::new(last)),
+        );
+        let mapper = self.mapper();
+        pages.all(|page| match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. } => {
+                flags.contains(PageTableFlags::USER_ACCESSIBLE)
+            }
+            _ => false,
+        })
+    }
+
+    /// Frees the page tables, and the pages of the private part that are not
+    /// mappings (the code and the stack). The table must not be loaded on
+    /// any CPU anymore.
+    pub fn free(&mut self, frame_alloc: &mut BootInfoFrameAllocator) {
+        if self.freed {
+            return;
+        }
+        self.freed = true;
+        let mappings = usize::from(VirtAddr::new(USER_MAPPINGS_START).p4_index());
+        let table = unsafe { table_at(self.l4) };
+        let mut frames = Vec::new();
+        for i in USER_ENTRIES {
+            collect_frames(&table[i], 3, i < mappings, &mut frames);
+        }
+        frames.push(self.l4);
+        for frame in frames {
+            frame_alloc.free(frame);
+        }
+    }
+}
+
+/// Collects the frames of the page tables under `entry` (of a table of
+/// `level`, 0 being the pages themselves), and of the pages if they are
+/// `owned`.
+fn collect_frames(entry: &PageTableEntry, level: u8, owned: bool, frames: &mut Vec<PhysFrame>) {
+    let frame = match entry.frame() {
+        Ok(frame) => frame,
+        Err(_) => return,
+    };
+    if level == 0 {
+        if owned {
+            frames.push(frame);
+        }
+        return;
+    }
+    for entry in unsafe { table_at(frame) }.iter() {
+        collect_frames(entry, level - 1, owned, frames);
+    }
+    frames.push(frame);
+}
+
+/// Flushes the TLB of all the CPUs, after pages were unmapped.
+///
+/// The other CPUs are interrupted, and this waits until they flushed theirs.
+/// Since they were interrupted, none of them still runs a process that was
+/// killed before (see [`crate::process::kill`]).
+pub fn shootdown() {
+    tlb::flush_all();
+    if !crate::apic::is_enabled() {
+        return;
+    }
+    let this = percpu::current();
+    let others: Vec<_> = percpu::all()
+        .into_iter()
+        .filter(|cpu| this.map_or(true, |this| this.index != cpu.index))
+        .collect();
+    if others.is_empty() {
+        return;
+    }
+    for cpu in others.iter() {
+        cpu.request_flush();
+    }
+    crate::apic::send_to_others(InterruptIndex::Shootdown.as_u8());
+    while others.iter().any(|cpu| cpu.flush_requested()) {
+        // another CPU may be waiting for this one, with its interrupts
+        // disabled
+        if let Some(this) = this {
+            this.flush_if_requested();
+        }
+        core::hint::spin_loop();
+    }
+}
+
+lazy_static::lazy_static! {
+    /// Frames set aside for devices, see [`DmaPage`]
+    static ref DMA_FRAMES: spin::Mutex<Vec<PhysFrame>> = spin::Mutex::new(Vec::new());
+}
+
+/// Sets aside `count` frames that drivers can later use with [`DmaPage`].
+///
+/// Drivers run in tasks, that don't have access to the frame allocator.
+pub fn reserve_dma_frames(frame_alloc: &mut impl FrameAllocator<Size4KiB>, count: usize) {
+    let mut frames = DMA_FRAMES.lock();
+    frames.extend((0..count).filter_map(|_| frame_alloc.allocate_frame()));
+}
+
+/// Frames left for [`DmaPage`]s
+pub fn free_dma_frames() -> usize {
+    interrupts::without_interrupts(|| DMA_FRAMES.lock().len())
+}
+
+/// A page of physical memory that devices can access directly
+///
+/// It is accessed through the mapping of all the physical memory, and given
+/// back to the pool when it is dropped.
+pub struct DmaPage {
+    frame: PhysFrame,
+}
+
+impl DmaPage {
+    pub const SIZE: usize = 4096;
+
+    /// Takes a zeroed page from the pool.
+    pub fn new() -> Option<DmaPage> {
+        let frame = interrupts::without_interrupts(|| DMA_FRAMES.lock().pop())?;
+        let page = DmaPage { frame };
+        unsafe { core::ptr::write_bytes(page.as_ptr::<u8>(), 0, DmaPage::SIZE) };
+        Some(page)
+    }
+
+    /// The physical address, to give to devices
+    pub fn phys(&self) -> u64 {
+        self.frame.start_address().as_u64()
+    }
+
+    pub fn as_ptr<T>(&self) -> *mut T {
+        (MEM_OFFSET + self.phys()) as *mut T
+    }
+}
+
+impl Drop for DmaPage {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| DMA_FRAMES.lock().push(self.frame));
+    }
+}
+
+/// Physically contiguous pages that devices can access directly
+///
+/// Like [`DmaPage`]s, they come from the frames set aside at boot, and are
+/// given back when dropped.
+pub struct DmaRegion {
+    first: PhysFrame,
+    pages: u64,
+}
+
+impl DmaRegion {
+    /// Takes `pages` zeroed contiguous pages from the pool, if it has them.
+    pub fn new(pages: usize) -> Option<DmaRegion> {
+        if pages == 0 {
+            return None;
+        }
+        let first = interrupts::without_interrupts(|| {
+            let mut frames = DMA_FRAMES.lock();
+            if frames.len() < pages {
+                return None;
+            }
+            frames.sort_unstable();
+            // the frames are all different: a run of `pages` sorted frames is
+            // contiguous if its ends are
+            let start = (0..=frames.len() - pages)
+                .find(|&i| frames[i + pages - 1] == frames[i] + (pages as u64 - 1))?;
+            let first = frames[start];
+            frames.drain(start..start + pages);
+            Some(first)
+        })?;
+        let region = DmaRegion {
+            first,
+            pages: pages as u64,
+        };
+        unsafe { core::ptr::write_bytes(region.as_ptr::<u8>(), 0, region.size()) };
+        Some(region)
+    }
+
+    /// The physical address, to give to devices
+    pub fn phys(&self) -> u64 {
+        self.first.start_address().as_u64()
+    }
+
+    /// In bytes
+    pub fn size(&self) -> usize {
+        self.pages as usize * DmaPage::SIZE
+    }
+
+    pub fn as_ptr<T>(&self) -> *mut T {
+        (MEM_OFFSET + self.phys()) as *mut T
+    }
+}
+
+impl Drop for DmaRegion {
+    fn drop(&mut self) {
+        let frames = (0..self.pages).map(|i| self.first + i);
+        interrupts::without_interrupts(|| DMA_FRAMES.lock().extend(frames));
+    }
+}
+
+/// Gives the usable frames of the memory map, one after the other, and then
+/// the ones that were freed
+///
+/// The frame of the AP trampoline ([`crate::smp::TRAMPOLINE_ADDR`]) is never
+/// given.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static mut [MemoryRegion],
     next: usize,
+    freed: Vec<PhysFrame>,
 }
 
 impl BootInfoFrameAllocator {
@@ -34,9 +582,28 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map: map,
             next: 0,
+            freed: Vec::new(),
         }
     }
 
+    /// Frames given so far, and all the usable frames
+    pub fn usage(&self) -> (usize, usize) {
+        let usable = self
+            .memory_map
+            .iter()
+            .filter(|r| r.kind == MemoryRegionKind::Usable)
+            .map(|r| ((r.end - r.start) / 4096) as usize);
+        (self.next - self.freed.len(), usable.sum())
+    }
+
+    /// Takes back a frame that is not used anymore. It is zeroed, so that its
+    /// next owner can't read anything from the previous one.
+    pub fn free(&mut self, frame: PhysFrame) {
+        let frame_ptr = (MEM_OFFSET + frame.start_address().as_u64()) as *mut u8;
+        unsafe { core::ptr::write_bytes(frame_ptr, 0, 4096) };
+        self.freed.push(frame);
+    }
+
     /// Returns an iterator over the usable frames specified in the memory map.
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
         // get usable regions from memory map
@@ -46,6 +613,8 @@ impl BootInfoFrameAllocator {
         let addr_ranges = usable_regions.map(|r| r.start..r.end);
         // transform to an iterator of frame start addresses
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        // the trampoline of the APs is copied there, and stays there
+        let frame_addresses = frame_addresses.filter(|&addr| addr != crate::smp::TRAMPOLINE_ADDR);
         // create `PhysFrame` types from the start addresses
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
@@ -53,6 +622,9 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.freed.pop() {
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
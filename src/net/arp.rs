@@ -0,0 +1,128 @@
+//! Address Resolution Protocol: finds the MAC address of an IPv4 address on
+//! the local network
+//!
+//! The addresses are learned from the requests and replies that are
+//! received (and from the IPv4 packets of the local network), and never
+//! expire.
+
+use super::{ethernet, read16, Ipv4Address, MacAddress};
+use crate::time::{self, Duration};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::task::{Poll, Waker};
+use futures_util::future::poll_fn;
+
+const HARDWARE_ETHERNET: u16 = 1;
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+const PACKET_SIZE: usize = 28;
+
+const ATTEMPTS: usize = 3;
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+lazy_static::lazy_static! {
+    static ref CACHE: spin::Mutex<BTreeMap<Ipv4Address, MacAddress>> =
+        spin::Mutex::new(BTreeMap::new());
+    /// Tasks waiting for a new address
+    static ref WAITING: spin::Mutex<Vec<Waker>> = spin::Mutex::new(Vec::new());
+}
+
+pub fn lookup(address: Ipv4Address) -> Option<MacAddress> {
+    CACHE.lock().get(&address).copied()
+}
+
+/// The addresses that are known
+pub fn entries() -> Vec<(Ipv4Address, MacAddress)> {
+    CACHE.lock().iter().map(|(&ip, &mac)| (ip, mac)).collect()
+}
+
+fn packet(
+    operation: u16,
+    sender: (MacAddress, Ipv4Address),
+    target: (MacAddress, Ipv4Address),
+) -> [u8; PACKET_SIZE] {
+    let mut packet = [0; PACKET_SIZE];
+    packet[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+    packet[2..4].copy_from_slice(&ethernet::TYPE_IPV4.to_be_bytes());
+    packet[4] = 6;
+    packet[5] = 4;
+    packet[6..8].copy_from_slice(&operation.to_be_bytes());
+    packet[8..14].copy_from_slice(&sender.0 .0);
+    packet[14..18].copy_from_slice(&sender.1 .0);
+    packet[18..24].copy_from_slice(&target.0 .0);
+    packet[24..28].copy_from_slice(&target.1 .0);
+    packet
+}
+
+/// Remembers the MAC address of an address.
+pub fn learn(address: Ipv4Address, mac: MacAddress) {
+    if CACHE.lock().insert(address, mac) != Some(mac) {
+        for waker in WAITING.lock().drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Handles a request or a reply.
+pub fn receive(packet: &[u8]) {
+    if packet.len() < PACKET_SIZE
+        || read16(packet, 0) != HARDWARE_ETHERNET
+        || read16(packet, 2) != ethernet::TYPE_IPV4
+        || packet[4] != 6
+        || packet[5] != 4
+    {
+        return;
+    }
+    let mut sender_mac = [0; 6];
+    sender_mac.copy_from_slice(&packet[8..14]);
+    let sender = (
+        MacAddress(sender_mac),
+        Ipv4Address::from_slice(&packet[14..18]),
+    );
+    let target = Ipv4Address::from_slice(&packet[24..28]);
+
+    if sender.1 != Ipv4Address::UNSPECIFIED {
+        learn(sender.1, sender.0);
+    }
+
+    let config = super::config();
+    if read16(packet, 6) == OPERATION_REQUEST && config.is_configured() && target == config.address
+    {
+        if let Some(device) = super::device() {
+            let reply = self::packet(OPERATION_REPLY, (device.mac(), config.address), sender);
+            ethernet::send(sender.0, ethernet::TYPE_ARP, &reply);
+        }
+    }
+}
+
+/// Asks the local network who has an address.
+pub fn request(address: Ipv4Address) {
+    if let Some(device) = super::device() {
+        let sender = (device.mac(), super::config().address);
+        let request = packet(OPERATION_REQUEST, sender, (MacAddress::default(), address));
+        ethernet::send(MacAddress::BROADCAST, ethernet::TYPE_ARP, &request);
+    }
+}
+
+/// Finds the MAC address of an address on the local network, asking for it
+/// if it isn't known yet.
+pub async fn resolve(address: Ipv4Address) -> Option<MacAddress> {
+    for _ in 0..ATTEMPTS {
+        if let Some(mac) = lookup(address) {
+            return Some(mac);
+        }
+        request(address);
+        let reply = poll_fn(|cx| {
+            // registered first, so that a reply can't be missed
+            WAITING.lock().push(cx.waker().clone());
+            match lookup(address) {
+                Some(mac) => Poll::Ready(mac),
+                None => Poll::Pending,
+            }
+        });
+        if let Ok(mac) = time::timeout(REPLY_TIMEOUT, reply).await {
+            return Some(mac);
+        }
+    }
+    None
+}
@@ -0,0 +1,208 @@
+//! DHCP client: asks the local network for an address
+//!
+//! The lease is not renewed: the address is kept until the next boot.
+
+use super::udp::UdpSocket;
+use super::{read32, Config, Endpoint, Ipv4Address, MacAddress, NetError};
+use crate::time::{self, Duration};
+use alloc::vec::Vec;
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const MAGIC: u32 = 0x6382_5363;
+/// The server replies to the broadcast address, since there is no address
+/// yet
+const FLAG_BROADCAST: u16 = 0x8000;
+/// Where the options start
+const OPTIONS: usize = 240;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_NETMASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_END: u8 = 255;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+
+const ATTEMPTS: usize = 4;
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Finds an option in the options of a message.
+fn option(options: &[u8], code: u8) -> Option<&[u8]> {
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            OPTION_PAD => i += 1,
+            OPTION_END => return None,
+            found => {
+                let len = *options.get(i + 1)? as usize;
+                let value = options.get(i + 2..i + 2 + len)?;
+                if found == code {
+                    return Some(value);
+                }
+                i += 2 + len;
+            }
+        }
+    }
+    None
+}
+
+fn address_option(options: &[u8], code: u8) -> Option<Ipv4Address> {
+    option(options, code)
+        .filter(|value| value.len() >= 4)
+        .map(Ipv4Address::from_slice)
+}
+
+fn message(
+    xid: u32,
+    mac: MacAddress,
+    ty: u8,
+    offer: Option<(Ipv4Address, Ipv4Address)>,
+) -> Vec<u8> {
+    let mut message = alloc::vec![0; OPTIONS];
+    message[0] = OP_REQUEST;
+    // Ethernet addresses, of 6 bytes
+    message[1] = 1;
+    message[2] = 6;
+    message[4..8].copy_from_slice(&xid.to_be_bytes());
+    message[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    message[28..34].copy_from_slice(&mac.0);
+    message[236..240].copy_from_slice(&MAGIC.to_be_bytes());
+    message.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, ty]);
+    if let Some((address, server)) = offer {
+        message.extend_from_slice(&[OPTION_REQUESTED_ADDRESS, 4]);
+        message.extend_from_slice(&address.0);
+        message.extend_from_slice(&[OPTION_SERVER, 4]);
+        message.extend_from_slice(&server.0);
+    }
+    message.extend_from_slice(&[
+        OPTION_PARAMETERS,
+        3,
+        OPTION_NETMASK,
+        OPTION_ROUTER,
+        OPTION_DNS,
+        OPTION_END,
+    ]);
+    message
+}
+
+/// A reply of a server
+struct Reply {
+    ty: u8,
+    address: Ipv4Address,
+    server: Option<Ipv4Address>,
+    config: Config,
+}
+
+fn parse(message: &[u8], xid: u32) -> Option<Reply> {
+    if message.len() < OPTIONS
+        || message[0] != OP_REPLY
+        || read32(message, 4) != xid
+        || read32(message, 236) != MAGIC
+    {
+        return None;
+    }
+    let options = &message[OPTIONS..];
+    let address = Ipv4Address::from_slice(&message[16..20]);
+    Some(Reply {
+        ty: *option(options, OPTION_MESSAGE_TYPE)?.first()?,
+        address,
+        server: address_option(options, OPTION_SERVER),
+        config: Config {
+            address,
+            netmask: address_option(options, OPTION_NETMASK)
+                .unwrap_or_else(|| Ipv4Address::netmask(24)),
+            gateway: address_option(options, OPTION_ROUTER),
+            dns: address_option(options, OPTION_DNS),
+        },
+    })
+}
+
+/// Sends a message to all the servers, and waits for a reply of one of the
+/// given types.
+async fn exchange(
+    socket: &UdpSocket,
+    message: &[u8],
+    xid: u32,
+    types: &[u8],
+) -> Result<Reply, NetError> {
+    let servers = Endpoint::new(Ipv4Address::BROADCAST, SERVER_PORT);
+    for _ in 0..ATTEMPTS {
+        socket.send_to(message, servers).await?;
+        let reply = async {
+            loop {
+                let (reply, _) = socket.recv_from().await;
+                match parse(&reply, xid) {
+                    Some(reply) if types.contains(&reply.ty) => return reply,
+                    _ => {}
+                }
+            }
+        };
+        if let Ok(reply) = time::timeout(REPLY_TIMEOUT, reply).await {
+            return Ok(reply);
+        }
+    }
+    Err(NetError::TimedOut)
+}
+
+/// Asks for an address, and returns the configuration that the server gave.
+pub async fn configure() -> Result<Config, NetError> {
+    let mac = super::device().ok_or(NetError::NoInterface)?.mac();
+    let socket = UdpSocket::bind(CLIENT_PORT)?;
+    let [_, _, a, b, c, d] = mac.0;
+    let xid = u32::from_be_bytes([a, b, c, d]) ^ time::uptime().as_nanos() as u32;
+
+    let offer = exchange(&socket, &message(xid, mac, DISCOVER, None), xid, &[OFFER]).await?;
+    let server = offer.server.ok_or(NetError::Unreachable)?;
+    let request = message(xid, mac, REQUEST, Some((offer.address, server)));
+    let ack = exchange(&socket, &request, xid, &[ACK, NAK]).await?;
+    if ack.ty == NAK {
+        return Err(NetError::ConnectionRefused);
+    }
+    Ok(ack.config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn options() {
+        let options = [
+            OPTION_MESSAGE_TYPE,
+            1,
+            OFFER,
+            OPTION_PAD,
+            OPTION_ROUTER,
+            4,
+            10,
+            0,
+            2,
+            2,
+            OPTION_END,
+            OPTION_DNS,
+            4,
+            10,
+            0,
+            2,
+            3,
+        ];
+        assert!(option(&options, OPTION_MESSAGE_TYPE) == Some(&[OFFER][..]));
+        assert!(address_option(&options, OPTION_ROUTER) == Some(Ipv4Address([10, 0, 2, 2])));
+        // after the end
+        assert!(option(&options, OPTION_DNS).is_none());
+        // too long
+        assert!(option(&[OPTION_ROUTER, 4, 10, 0], OPTION_ROUTER).is_none());
+    }
+}
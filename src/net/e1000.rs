@@ -0,0 +1,382 @@
+//! Intel 8254x (e1000) network cards, like the one QEMU emulates by default
+//!
+//! The descriptor rings and the buffers are [`DmaPage`]s. The card signals
+//! received frames with MSI when it has it, and is polled otherwise.
+//!
+//! https://www.intel.com/content/dam/doc/manual/pci-pci-x-family-gbe-controllers-software-dev-manual.pdf
+
+use super::{MacAddress, NetworkDevice};
+use crate::driver::{Driver, DriverFuture, Match};
+use crate::interrupt::{self, Vector, DYNAMIC_COUNT, DYNAMIC_START};
+use crate::memory::{DmaPage, MEM_OFFSET};
+use crate::pci::{self, PciDevice};
+use crate::println;
+use crate::time::{self, Duration};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use core::task::Poll;
+use futures_util::future::{poll_fn, FutureExt};
+use futures_util::task::AtomicWaker;
+use pci_types::PciAddress;
+
+// Registers
+const CTRL: u64 = 0x0000;
+const EERD: u64 = 0x0014;
+const ICR: u64 = 0x00c0;
+const IMS: u64 = 0x00d0;
+const IMC: u64 = 0x00d8;
+const RCTL: u64 = 0x0100;
+const TCTL: u64 = 0x0400;
+const TIPG: u64 = 0x0410;
+const RDBAL: u64 = 0x2800;
+const RDBAH: u64 = 0x2804;
+const RDLEN: u64 = 0x2808;
+const RDH: u64 = 0x2810;
+const RDT: u64 = 0x2818;
+const TDBAL: u64 = 0x3800;
+const TDBAH: u64 = 0x3804;
+const TDLEN: u64 = 0x3808;
+const TDH: u64 = 0x3810;
+const TDT: u64 = 0x3818;
+const MTA: u64 = 0x5200;
+const RAL: u64 = 0x5400;
+const RAH: u64 = 0x5404;
+
+const CTRL_SET_LINK_UP: u32 = 1 << 6;
+const CTRL_RESET: u32 = 1 << 26;
+
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+
+const RCTL_ENABLE: u32 = 1 << 1;
+const RCTL_BROADCAST: u32 = 1 << 15;
+/// The card removes the CRC of received frames
+const RCTL_STRIP_CRC: u32 = 1 << 26;
+
+const TCTL_ENABLE: u32 = 1 << 1;
+const TCTL_PAD_SHORT: u32 = 1 << 3;
+const TCTL_COLLISION_THRESHOLD: u32 = 0x0f << 4;
+const TCTL_COLLISION_DISTANCE: u32 = 0x40 << 12;
+/// The recommended inter packet gaps
+const TIPG_DEFAULT: u32 = 10 | 8 << 10 | 6 << 20;
+
+/// Receive timer, link status change
+const INTERRUPTS: u32 = 1 << 7 | 1 << 2;
+
+const TX_END_OF_PACKET: u8 = 1 << 0;
+const TX_INSERT_CRC: u8 = 1 << 1;
+const TX_REPORT_STATUS: u8 = 1 << 3;
+const DESCRIPTOR_DONE: u8 = 1 << 0;
+const RX_END_OF_PACKET: u8 = 1 << 1;
+
+/// Descriptors in each ring
+const RING_LEN: usize = 32;
+const BUFFER_SIZE: usize = 2048;
+const BUFFERS_PER_PAGE: usize = DmaPage::SIZE / BUFFER_SIZE;
+
+const RESET_TIMEOUT: Duration = Duration::from_millis(100);
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct RxDescriptor {
+    address: u64,
+    length: u16,
+    _checksum: u16,
+    status: u8,
+    errors: u8,
+    _special: u16,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct TxDescriptor {
+    address: u64,
+    length: u16,
+    _checksum_offset: u8,
+    command: u8,
+    status: u8,
+    _checksum_start: u8,
+    _special: u16,
+}
+
+/// The descriptors of a ring, and their buffers
+struct Ring {
+    descriptors: DmaPage,
+    buffers: Vec<DmaPage>,
+    /// The next descriptor to use
+    next: usize,
+}
+
+impl Ring {
+    fn new() -> Option<Ring> {
+        let buffers = (0..RING_LEN / BUFFERS_PER_PAGE)
+            .map(|_| DmaPage::new())
+            .collect::<Option<Vec<_>>>()?;
+        Some(Ring {
+            descriptors: DmaPage::new()?,
+            buffers,
+            next: 0,
+        })
+    }
+
+    /// The physical address of the buffer of a descriptor
+    fn buffer_phys(&self, index: usize) -> u64 {
+        let page = &self.buffers[index / BUFFERS_PER_PAGE];
+        page.phys() + ((index % BUFFERS_PER_PAGE) * BUFFER_SIZE) as u64
+    }
+
+    fn buffer(&self, index: usize) -> *mut u8 {
+        (MEM_OFFSET + self.buffer_phys(index)) as *mut u8
+    }
+
+    fn descriptor<T>(&self, index: usize) -> *mut T {
+        unsafe { self.descriptors.as_ptr::<T>().add(index) }
+    }
+}
+
+pub struct E1000 {
+    registers: u64,
+    mac: MacAddress,
+    tx: spin::Mutex<Ring>,
+}
+
+unsafe fn read32(addr: u64) -> u32 {
+    core::ptr::read_volatile(addr as *const u32)
+}
+
+unsafe fn write32(addr: u64, value: u32) {
+    core::ptr::write_volatile(addr as *mut u32, value);
+}
+
+impl E1000 {
+    fn read(&self, register: u64) -> u32 {
+        unsafe { read32(self.registers + register) }
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        unsafe { write32(self.registers + register, value) }
+    }
+
+    /// Reads a word of the EEPROM.
+    fn read_eeprom(&self, word: u8) -> Option<u16> {
+        self.write(EERD, (word as u32) << 8 | EERD_START);
+        for _ in 0..10_000 {
+            let value = self.read(EERD);
+            if value & EERD_DONE != 0 {
+                return Some((value >> 16) as u16);
+            }
+            core::hint::spin_loop();
+        }
+        None
+    }
+
+    /// The address the firmware loaded in the first receive address
+    /// registers, or the one in the EEPROM.
+    fn read_mac(&self) -> Option<MacAddress> {
+        let (low, high) = (self.read(RAL), self.read(RAH));
+        if low != 0 {
+            let [a, b, c, d] = low.to_le_bytes();
+            let [e, f, _, _] = high.to_le_bytes();
+            return Some(MacAddress([a, b, c, d, e, f]));
+        }
+        let mut mac = [0; 6];
+        for i in 0..3 {
+            let word = self.read_eeprom(i as u8)?.to_le_bytes();
+            mac[2 * i..2 * i + 2].copy_from_slice(&word);
+        }
+        Some(MacAddress(mac))
+    }
+}
+
+impl NetworkDevice for E1000 {
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn send(&self, frame: &[u8]) -> bool {
+        if frame.len() > BUFFER_SIZE {
+            return false;
+        }
+        let mut tx = self.tx.lock();
+        let index = tx.next;
+        let descriptor = tx.descriptor::<TxDescriptor>(index);
+        unsafe {
+            // the descriptor is still being sent, if it was used and isn't
+            // done
+            let previous = descriptor.read_volatile();
+            if previous.command != 0 && previous.status & DESCRIPTOR_DONE == 0 {
+                return false;
+            }
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), tx.buffer(index), frame.len());
+            descriptor.write_volatile(TxDescriptor {
+                address: tx.buffer_phys(index),
+                length: frame.len() as u16,
+                command: TX_END_OF_PACKET | TX_INSERT_CRC | TX_REPORT_STATUS,
+                ..TxDescriptor::default()
+            });
+        }
+        tx.next = (index + 1) % RING_LEN;
+        fence(Ordering::SeqCst);
+        self.write(TDT, tx.next as u32);
+        true
+    }
+}
+
+/// Wakes the task of the card, by vector
+static WAKERS: [AtomicWaker; DYNAMIC_COUNT] = {
+    const NONE: AtomicWaker = AtomicWaker::new();
+    [NONE; DYNAMIC_COUNT]
+};
+
+fn on_interrupt(vector: u8) {
+    WAKERS[(vector - DYNAMIC_START) as usize].wake();
+}
+
+/// Resets the card, and sets up the rings.
+async fn init(registers: u64) -> Option<(Arc<E1000>, Ring)> {
+    unsafe {
+        write32(registers + IMC, !0);
+        let ctrl = read32(registers + CTRL);
+        write32(registers + CTRL, ctrl | CTRL_RESET);
+    }
+    time::sleep(RESET_TIMEOUT).await;
+    if unsafe { read32(registers + CTRL) } & CTRL_RESET != 0 {
+        println!("e1000: the card does not reset");
+        return None;
+    }
+    unsafe {
+        write32(registers + IMC, !0);
+        read32(registers + ICR);
+        let ctrl = read32(registers + CTRL);
+        write32(registers + CTRL, ctrl | CTRL_SET_LINK_UP);
+        for i in 0..128 {
+            write32(registers + MTA + i * 4, 0);
+        }
+    }
+
+    let rx = Ring::new()?;
+    let mut card = E1000 {
+        registers,
+        mac: MacAddress::default(),
+        tx: spin::Mutex::new(Ring::new()?),
+    };
+    card.mac = card.read_mac()?;
+
+    for i in 0..RING_LEN {
+        let descriptor = RxDescriptor {
+            address: rx.buffer_phys(i),
+            ..RxDescriptor::default()
+        };
+        unsafe { rx.descriptor::<RxDescriptor>(i).write_volatile(descriptor) };
+    }
+    let ring_size = (RING_LEN * 16) as u32;
+    let tx_phys = card.tx.lock().descriptors.phys();
+    card.write(RDBAL, rx.descriptors.phys() as u32);
+    card.write(RDBAH, (rx.descriptors.phys() >> 32) as u32);
+    card.write(RDLEN, ring_size);
+    card.write(RDH, 0);
+    // all the descriptors but one belong to the card
+    card.write(RDT, RING_LEN as u32 - 1);
+    card.write(RCTL, RCTL_ENABLE | RCTL_BROADCAST | RCTL_STRIP_CRC);
+
+    card.write(TDBAL, tx_phys as u32);
+    card.write(TDBAH, (tx_phys >> 32) as u32);
+    card.write(TDLEN, ring_size);
+    card.write(TDH, 0);
+    card.write(TDT, 0);
+    card.write(TIPG, TIPG_DEFAULT);
+    card.write(
+        TCTL,
+        TCTL_ENABLE | TCTL_PAD_SHORT | TCTL_COLLISION_THRESHOLD | TCTL_COLLISION_DISTANCE,
+    );
+    Some((Arc::new(card), rx))
+}
+
+/// Gives the received frames to the network stack, and the descriptors back
+/// to the card.
+fn receive(card: &E1000, rx: &mut Ring) {
+    loop {
+        let index = rx.next;
+        let descriptor = rx.descriptor::<RxDescriptor>(index);
+        let received = unsafe { descriptor.read_volatile() };
+        if received.status & DESCRIPTOR_DONE == 0 {
+            break;
+        }
+        // frames are never split, since the buffers can hold the largest ones
+        if received.status & RX_END_OF_PACKET != 0 && received.errors == 0 {
+            let len = (received.length as usize).min(BUFFER_SIZE);
+            let frame = unsafe { core::slice::from_raw_parts(rx.buffer(index), len) };
+            super::receive(frame);
+        }
+        unsafe {
+            descriptor.write_volatile(RxDescriptor {
+                address: rx.buffer_phys(index),
+                ..RxDescriptor::default()
+            })
+        };
+        rx.next = (index + 1) % RING_LEN;
+        fence(Ordering::SeqCst);
+        card.write(RDT, index as u32);
+    }
+}
+
+pub static DRIVER: Driver = Driver {
+    name: "e1000",
+    matches: &[
+        // 82540EM, emulated by QEMU
+        Match::Device {
+            vendor: 0x8086,
+            device: 0x100e,
+        },
+        // 82545EM, emulated by VMware and VirtualBox
+        Match::Device {
+            vendor: 0x8086,
+            device: 0x100f,
+        },
+    ],
+    start,
+};
+
+fn start(address: PciAddress, device: &PciDevice) -> Option<DriverFuture> {
+    let mmio = device.bar_address(0)?;
+    let vector = {
+        let access = pci::ACCESS.lock();
+        let access = access.as_ref()?;
+        pci::enable_bus_master(access, address);
+        interrupt::allocate_vector(on_interrupt).and_then(|vector| {
+            pci::enable_msi(access, address, device, &vector)
+                .ok()
+                .map(|_| vector)
+        })
+    };
+    Some(run(MEM_OFFSET + mmio, vector).boxed_local())
+}
+
+/// Drives the card whose registers are at `registers`: gives it to the
+/// network stack, and receives frames forever.
+async fn run(registers: u64, vector: Option<Vector>) -> Result<(), &'static str> {
+    let (card, mut rx) = init(registers).await.ok_or("could not start the card")?;
+    if !super::add(Arc::clone(&card) as Arc<dyn NetworkDevice>) {
+        return Err("another card is used");
+    }
+    match vector {
+        Some(vector) => {
+            let waker = &WAKERS[(vector.as_u8() - DYNAMIC_START) as usize];
+            card.write(IMS, INTERRUPTS);
+            poll_fn(|cx| {
+                waker.register(cx.waker());
+                // reading the causes acknowledges them
+                card.read(ICR);
+                receive(&card, &mut rx);
+                Poll::Pending
+            })
+            .await
+        }
+        None => loop {
+            receive(&card, &mut rx);
+            time::sleep(POLL_INTERVAL).await;
+        },
+    }
+}
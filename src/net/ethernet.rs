@@ -0,0 +1,62 @@
+//! Ethernet frames
+//!
+//! The card adds the preamble and the CRC, frames are given to it with their
+//! header: the destination and source addresses, and the type of the payload.
+
+use super::{arp, ipv4, read16, MacAddress};
+use alloc::vec::Vec;
+
+pub const HEADER_SIZE: usize = 14;
+/// The largest payload
+pub const MTU: usize = 1500;
+/// Shorter frames are padded
+const MIN_SIZE: usize = 60;
+
+pub const TYPE_IPV4: u16 = 0x0800;
+pub const TYPE_ARP: u16 = 0x0806;
+
+fn mac(bytes: &[u8]) -> MacAddress {
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&bytes[..6]);
+    MacAddress(mac)
+}
+
+/// Handles a frame, if it is for this card.
+pub fn receive(frame: &[u8]) {
+    let device = match super::device() {
+        Some(device) => device,
+        None => return,
+    };
+    if frame.len() < HEADER_SIZE {
+        return;
+    }
+    let destination = mac(&frame[0..6]);
+    if destination != device.mac() && destination != MacAddress::BROADCAST {
+        return;
+    }
+    let source = mac(&frame[6..12]);
+    let payload = &frame[HEADER_SIZE..];
+    match read16(frame, 12) {
+        TYPE_ARP => arp::receive(payload),
+        TYPE_IPV4 => ipv4::receive(source, payload),
+        _ => {}
+    }
+}
+
+/// Sends a frame. Returns `false` if there is no card, or if it dropped the
+/// frame.
+pub fn send(destination: MacAddress, ty: u16, payload: &[u8]) -> bool {
+    let device = match super::device() {
+        Some(device) => device,
+        None => return false,
+    };
+    let mut frame = Vec::with_capacity((HEADER_SIZE + payload.len()).max(MIN_SIZE));
+    frame.extend_from_slice(&destination.0);
+    frame.extend_from_slice(&device.mac().0);
+    frame.extend_from_slice(&ty.to_be_bytes());
+    frame.extend_from_slice(payload);
+    if frame.len() < MIN_SIZE {
+        frame.resize(MIN_SIZE, 0);
+    }
+    device.send(&frame)
+}
@@ -0,0 +1,353 @@
+//! IPv4, and ICMP echo (ping)
+//!
+//! Packets are not fragmented: the ones that are fragments are dropped, and
+//! the payloads that don't fit in a frame are refused.
+
+use super::{arp, ethernet, read16, tcp, udp, Config, MacAddress, NetError};
+use crate::time::{self, Duration, Instant};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU16, Ordering};
+use core::task::{Poll, Waker};
+use futures_util::future::poll_fn;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ipv4Address(pub [u8; 4]);
+
+impl Ipv4Address {
+    pub const UNSPECIFIED: Ipv4Address = Ipv4Address([0; 4]);
+    pub const BROADCAST: Ipv4Address = Ipv4Address([0xff; 4]);
+
+    pub fn from_u32(address: u32) -> Ipv4Address {
+        Ipv4Address(address.to_be_bytes())
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    /// Reads an address from the first 4 bytes.
+    pub fn from_slice(bytes: &[u8]) -> Ipv4Address {
+        let mut address = [0; 4];
+        address.copy_from_slice(&bytes[..4]);
+        Ipv4Address(address)
+    }
+
+    /// Parses `a.b.c.d`.
+    pub fn parse(s: &str) -> Option<Ipv4Address> {
+        let mut address = [0; 4];
+        let mut parts = s.split('.');
+        for byte in address.iter_mut() {
+            *byte = parts.next()?.parse().ok()?;
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Ipv4Address(address))
+    }
+
+    /// A netmask with `bits` ones
+    pub fn netmask(bits: u8) -> Ipv4Address {
+        match bits {
+            0 => Ipv4Address::UNSPECIFIED,
+            bits => Ipv4Address::from_u32(!0 << (32 - bits.min(32) as u32)),
+        }
+    }
+}
+
+impl fmt::Display for Ipv4Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d] = self.0;
+        write!(f, "{}.{}.{}.{}", a, b, c, d)
+    }
+}
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+pub const HEADER_SIZE: usize = 20;
+/// The largest payload
+pub const MAX_PAYLOAD: usize = ethernet::MTU - HEADER_SIZE;
+const TTL: u8 = 64;
+const DONT_FRAGMENT: u16 = 1 << 14;
+const MORE_FRAGMENTS: u16 = 1 << 13;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+/// What is needed from the header of a received packet
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    pub source: Ipv4Address,
+    pub destination: Ipv4Address,
+    pub protocol: u8,
+}
+
+impl Header {
+    /// Reads the header of a packet, and returns it with the payload, if the
+    /// packet is valid and isn't a fragment.
+    pub fn parse(packet: &[u8]) -> Option<(Header, &[u8])> {
+        if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let header_size = (packet[0] & 0xf) as usize * 4;
+        let total = read16(packet, 2) as usize;
+        if header_size < HEADER_SIZE || total < header_size || total > packet.len() {
+            return None;
+        }
+        if super::checksum(&[&packet[..header_size]]) != 0 {
+            return None;
+        }
+        let fragment = read16(packet, 6);
+        if fragment & MORE_FRAGMENTS != 0 || fragment & 0x1fff != 0 {
+            return None;
+        }
+        let header = Header {
+            source: Ipv4Address::from_slice(&packet[12..16]),
+            destination: Ipv4Address::from_slice(&packet[16..20]),
+            protocol: packet[9],
+        };
+        Some((header, &packet[header_size..total]))
+    }
+}
+
+/// Handles a packet, if it is for this computer.
+pub fn receive(source_mac: MacAddress, packet: &[u8]) {
+    let (header, payload) = match Header::parse(packet) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let config = super::config();
+    // without an address, everything is accepted, for DHCP
+    let for_us = !config.is_configured()
+        || header.destination == config.address
+        || header.destination == Ipv4Address::BROADCAST
+        || header.destination == config.broadcast();
+    if !for_us {
+        return;
+    }
+    // the replies go to the same place
+    if config.is_configured() && config.is_local(header.source) {
+        arp::learn(header.source, source_mac);
+    }
+    match header.protocol {
+        PROTOCOL_ICMP => icmp(&header, payload),
+        PROTOCOL_UDP => udp::receive(&header, payload),
+        PROTOCOL_TCP => tcp::receive(&header, payload),
+        _ => {}
+    }
+}
+
+/// Builds a packet, with the address of the interface as the source.
+fn packet(config: &Config, destination: Ipv4Address, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+    let mut packet = Vec::with_capacity(HEADER_SIZE + payload.len());
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&((HEADER_SIZE + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&NEXT_ID.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    packet.extend_from_slice(&DONT_FRAGMENT.to_be_bytes());
+    packet.extend_from_slice(&[TTL, protocol, 0, 0]);
+    packet.extend_from_slice(&config.address.0);
+    packet.extend_from_slice(&destination.0);
+    let checksum = super::checksum(&[&packet]);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// Where packets for an address go: to itself on the local network, and to
+/// the gateway otherwise
+fn next_hop(config: &Config, destination: Ipv4Address) -> Result<Ipv4Address, NetError> {
+    if !config.is_configured() || config.is_local(destination) {
+        Ok(destination)
+    } else {
+        config.gateway.ok_or(NetError::Unreachable)
+    }
+}
+
+fn is_broadcast(config: &Config, destination: Ipv4Address) -> bool {
+    destination == Ipv4Address::BROADCAST
+        || (config.is_configured() && destination == config.broadcast())
+}
+
+fn check_size(payload: &[u8]) -> Result<(), NetError> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(NetError::TooLarge);
+    }
+    if super::device().is_none() {
+        return Err(NetError::NoInterface);
+    }
+    Ok(())
+}
+
+/// Sends a packet, finding the MAC address of the next hop first.
+pub async fn send(destination: Ipv4Address, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    check_size(payload)?;
+    let config = super::config();
+    let mac = if is_broadcast(&config, destination) {
+        MacAddress::BROADCAST
+    } else {
+        let hop = next_hop(&config, destination)?;
+        arp::resolve(hop).await.ok_or(NetError::Unreachable)?
+    };
+    let packet = packet(&config, destination, protocol, payload);
+    ethernet::send(mac, ethernet::TYPE_IPV4, &packet);
+    Ok(())
+}
+
+/// Sends a packet now, if the MAC address of the next hop is known. If it
+/// isn't, it is asked for and the packet is dropped: this is for replies and
+/// for protocols that send again what is lost.
+pub fn send_now(destination: Ipv4Address, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    check_size(payload)?;
+    let config = super::config();
+    let mac = if is_broadcast(&config, destination) {
+        MacAddress::BROADCAST
+    } else {
+        let hop = next_hop(&config, destination)?;
+        match arp::lookup(hop) {
+            Some(mac) => mac,
+            None => {
+                arp::request(hop);
+                return Err(NetError::Unreachable);
+            }
+        }
+    };
+    let packet = packet(&config, destination, protocol, payload);
+    ethernet::send(mac, ethernet::TYPE_IPV4, &packet);
+    Ok(())
+}
+
+/// The pseudo header that the checksums of UDP and TCP cover
+pub fn pseudo_header(
+    source: Ipv4Address,
+    destination: Ipv4Address,
+    protocol: u8,
+    len: usize,
+) -> [u8; 12] {
+    let mut header = [0; 12];
+    header[0..4].copy_from_slice(&source.0);
+    header[4..8].copy_from_slice(&destination.0);
+    header[9] = protocol;
+    header[10..12].copy_from_slice(&(len as u16).to_be_bytes());
+    header
+}
+
+lazy_static::lazy_static! {
+    /// The echo requests waiting for a reply, by identifier and sequence
+    /// number
+    static ref ECHOES: spin::Mutex<BTreeMap<(u16, u16), Echo>> =
+        spin::Mutex::new(BTreeMap::new());
+}
+
+struct Echo {
+    replied: bool,
+    waker: Option<Waker>,
+}
+
+fn echo(ty: u8, id: u16, sequence: u16, data: &[u8]) -> Vec<u8> {
+    let mut message = alloc::vec![ty, 0, 0, 0];
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&sequence.to_be_bytes());
+    message.extend_from_slice(data);
+    let checksum = super::checksum(&[&message]);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    message
+}
+
+fn icmp(header: &Header, message: &[u8]) {
+    if message.len() < 8 || super::checksum(&[message]) != 0 {
+        return;
+    }
+    let (id, sequence) = (read16(message, 4), read16(message, 6));
+    match message[0] {
+        ICMP_ECHO_REQUEST => {
+            let reply = echo(ICMP_ECHO_REPLY, id, sequence, &message[8..]);
+            let _ = send_now(header.source, PROTOCOL_ICMP, &reply);
+        }
+        ICMP_ECHO_REPLY => {
+            if let Some(echo) = ECHOES.lock().get_mut(&(id, sequence)) {
+                echo.replied = true;
+                if let Some(waker) = echo.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Sends an echo request, and returns how long the reply took.
+pub async fn ping(
+    destination: Ipv4Address,
+    sequence: u16,
+    timeout: Duration,
+) -> Result<Duration, NetError> {
+    const ID: u16 = 0x0adb;
+    let key = (ID, sequence);
+    ECHOES.lock().insert(
+        key,
+        Echo {
+            replied: false,
+            waker: None,
+        },
+    );
+    let start = Instant::now();
+    let reply = async {
+        send(
+            destination,
+            PROTOCOL_ICMP,
+            &echo(ICMP_ECHO_REQUEST, ID, sequence, b"ananos"),
+        )
+        .await?;
+        poll_fn(|cx| {
+            let mut echoes = ECHOES.lock();
+            match echoes.get_mut(&key) {
+                Some(echo) if echo.replied => Poll::Ready(Ok(start.elapsed())),
+                Some(echo) => {
+                    echo.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+                None => Poll::Ready(Err(NetError::Closed)),
+            }
+        })
+        .await
+    };
+    let result = time::timeout(timeout, reply).await;
+    ECHOES.lock().remove(&key);
+    result.unwrap_or(Err(NetError::TimedOut))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn parse_header() {
+        let mut packet = [
+            0x45, 0x00, 0x00, 0x18, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0x0a, 0x00,
+            0x02, 0x02, 0x0a, 0x00, 0x02, 0x0f, 1, 2, 3, 4, 5, 6,
+        ];
+        let checksum = crate::net::checksum(&[&packet[..HEADER_SIZE]]);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        let (header, payload) = Header::parse(&packet).unwrap();
+        assert!(header.source == Ipv4Address([10, 0, 2, 2]));
+        assert!(header.destination == Ipv4Address([10, 0, 2, 15]));
+        assert!(header.protocol == PROTOCOL_UDP);
+        // the total length leaves the padding out
+        assert!(payload == [1, 2, 3, 4]);
+        packet[15] = 3;
+        assert!(Header::parse(&packet).is_none());
+    }
+
+    #[test_case]
+    fn addresses() {
+        assert!(Ipv4Address::parse("10.0.2.15") == Some(Ipv4Address([10, 0, 2, 15])));
+        assert!(Ipv4Address::parse("10.0.2").is_none());
+        assert!(Ipv4Address::parse("10.0.2.256").is_none());
+        assert!(Ipv4Address::netmask(24) == Ipv4Address([255, 255, 255, 0]));
+        assert!(Ipv4Address::netmask(32) == Ipv4Address::BROADCAST);
+    }
+}
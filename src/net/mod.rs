@@ -0,0 +1,236 @@
+//! Networking
+//!
+//! A network card is a [`NetworkDevice`]: it sends Ethernet frames, and gives
+//! the frames it receives to [`receive`]. Only the first card that is found
+//! is used, with a single IPv4 address that [`dhcp`] asks for.
+//!
+//! Each protocol has its own module: [`ethernet`], [`arp`], [`ipv4`] (with
+//! ICMP), [`udp`], [`tcp`] and [`dhcp`]. Frames are handled as soon as they
+//! are received, in the task of the driver; tasks use the network with the
+//! sockets of [`udp`] and [`tcp`].
+
+use crate::println;
+use crate::task::{self, Task};
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+pub mod arp;
+pub mod dhcp;
+pub mod e1000;
+pub mod ethernet;
+pub mod ipv4;
+pub mod tcp;
+pub mod udp;
+
+pub use ipv4::Ipv4Address;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// An address and a port
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Endpoint {
+    pub address: Ipv4Address,
+    pub port: u16,
+}
+
+impl Endpoint {
+    pub fn new(address: Ipv4Address, port: u16) -> Endpoint {
+        Endpoint { address, port }
+    }
+
+    /// Parses `a.b.c.d:port`.
+    pub fn parse(s: &str) -> Option<Endpoint> {
+        let (address, port) = s.split_once(':')?;
+        Some(Endpoint::new(
+            Ipv4Address::parse(address)?,
+            port.parse().ok()?,
+        ))
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.address, self.port)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// There is no network card, or it has no address yet
+    NoInterface,
+    /// The address of the next hop could not be found
+    Unreachable,
+    AddressInUse,
+    ConnectionRefused,
+    ConnectionReset,
+    TimedOut,
+    /// The socket was closed
+    Closed,
+    /// The data doesn't fit in a packet
+    TooLarge,
+    /// There are too many connections, or no memory for their buffers
+    NoMemory,
+}
+
+/// A network card
+pub trait NetworkDevice: Send + Sync {
+    fn mac(&self) -> MacAddress;
+
+    /// Sends a frame (with its Ethernet header). Returns `false` if the card
+    /// dropped it.
+    fn send(&self, frame: &[u8]) -> bool;
+}
+
+/// The configuration of the interface
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Config {
+    pub address: Ipv4Address,
+    pub netmask: Ipv4Address,
+    pub gateway: Option<Ipv4Address>,
+    pub dns: Option<Ipv4Address>,
+}
+
+impl Config {
+    pub fn is_configured(&self) -> bool {
+        self.address != Ipv4Address::UNSPECIFIED
+    }
+
+    /// Whether an address is on the local network
+    pub fn is_local(&self, address: Ipv4Address) -> bool {
+        address.to_u32() & self.netmask.to_u32() == self.address.to_u32() & self.netmask.to_u32()
+    }
+
+    /// The broadcast address of the local network
+    pub fn broadcast(&self) -> Ipv4Address {
+        Ipv4Address::from_u32(self.address.to_u32() | !self.netmask.to_u32())
+    }
+}
+
+static DEVICE: spin::RwLock<Option<Arc<dyn NetworkDevice>>> = spin::RwLock::new(None);
+static CONFIG: spin::RwLock<Config> = spin::RwLock::new(Config {
+    address: Ipv4Address::UNSPECIFIED,
+    netmask: Ipv4Address::UNSPECIFIED,
+    gateway: None,
+    dns: None,
+});
+
+/// Uses a network card, if there is none yet, and asks for an address.
+/// Returns `false` if another card is already used.
+pub fn add(device: Arc<dyn NetworkDevice>) -> bool {
+    {
+        let mut current = DEVICE.write();
+        if current.is_some() {
+            return false;
+        }
+        println!("Network card {}", device.mac());
+        *current = Some(device);
+    }
+    task::spawn(Task::new(tcp::run()));
+    task::spawn(Task::new(async {
+        match dhcp::configure().await {
+            Ok(config) => {
+                println!(
+                    "DHCP: address {}, netmask {}, gateway {:?}",
+                    config.address, config.netmask, config.gateway
+                );
+                set_config(config);
+            }
+            Err(err) => println!("DHCP: no address ({:?})", err),
+        }
+    }));
+    true
+}
+
+pub fn device() -> Option<Arc<dyn NetworkDevice>> {
+    DEVICE.read().clone()
+}
+
+pub fn config() -> Config {
+    *CONFIG.read()
+}
+
+pub fn set_config(config: Config) {
+    *CONFIG.write() = config;
+}
+
+/// Handles a frame that the card received.
+pub fn receive(frame: &[u8]) {
+    ethernet::receive(frame);
+}
+
+/// A port for the local end of a socket
+pub fn ephemeral_port() -> u16 {
+    const FIRST: u16 = 49152;
+    static NEXT: AtomicU16 = AtomicU16::new(FIRST);
+    let port = NEXT.fetch_add(1, Ordering::Relaxed);
+    if port == u16::MAX {
+        NEXT.store(FIRST, Ordering::Relaxed);
+    }
+    port
+}
+
+/// The Internet checksum of some bytes, given in parts (that may have odd
+/// lengths)
+pub fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    let mut odd = None;
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        match odd.take() {
+            Some(high) => sum += u16::from_be_bytes([high, byte]) as u32,
+            None => odd = Some(byte),
+        }
+    }
+    if let Some(high) = odd {
+        sum += u16::from_be_bytes([high, 0]) as u32;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Reads a big endian `u16`.
+pub(crate) fn read16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+/// Reads a big endian `u32`.
+pub(crate) fn read32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_be_bytes(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn internet_checksum() {
+        // an IPv4 header, with its checksum zeroed
+        let header = [
+            0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ];
+        assert!(checksum(&[&header]) == 0xb861);
+        // the same bytes, cut at an odd place
+        assert!(checksum(&[&header[..5], &header[5..]]) == 0xb861);
+    }
+}
@@ -0,0 +1,902 @@
+//! TCP connections
+//!
+//! This is a simple implementation: segments that arrive out of order are
+//! dropped (the peer sends them again), what isn't acknowledged in time is
+//! all sent again, and the window doesn't scale. The only option that is
+//! understood is the maximum segment size.
+//!
+//! [`run`] sends again what was lost, and forgets the connections that are
+//! closed.
+//!
+//! The heap is small, so the buffers are small too, and so is the number of
+//! connections. Their buffers are allocated when they are created: when
+//! there is no room for them, the SYN is dropped (the peer sends it again
+//! later).
+
+use super::ipv4::{self, Header, PROTOCOL_TCP};
+use super::{read16, read32, Endpoint, NetError};
+use crate::allocator::HEAP_SIZE;
+use crate::time::{self, Duration, Instant};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::task::{Poll, Waker};
+use futures_util::future::poll_fn;
+
+pub const HEADER_SIZE: usize = 20;
+
+const FIN: u8 = 1 << 0;
+const SYN: u8 = 1 << 1;
+const RST: u8 = 1 << 2;
+const PSH: u8 = 1 << 3;
+const ACK: u8 = 1 << 4;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+/// The segment size the peer uses, if it doesn't say
+const DEFAULT_MSS: usize = 536;
+/// The segment size this side accepts
+const MSS: usize = ipv4::MAX_PAYLOAD - HEADER_SIZE;
+/// What can wait to be read (two full segments)
+const RECEIVE_BUFFER: usize = 2 * MSS;
+/// What can wait to be sent (or acknowledged)
+const SEND_BUFFER: usize = 2 * MSS;
+/// Connections that can exist at the same time. Their buffers take less
+/// than half of the heap.
+const MAX_CONNECTIONS: usize = 8;
+const _: () = assert!(MAX_CONNECTIONS * (RECEIVE_BUFFER + SEND_BUFFER) < HEAP_SIZE / 2);
+/// Connections that received a SYN and wait for the end of the handshake,
+/// on each port
+const MAX_HALF_OPEN: usize = 4;
+/// Established connections that wait to be accepted, on each port
+const BACKLOG: usize = 4;
+
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Retransmissions before giving up on a connection
+const MAX_RETRIES: u32 = 6;
+/// How long a closed connection stays in `TimeWait`
+const TIME_WAIT: Duration = Duration::from_secs(2);
+/// How long a connection waits in `FinWait2` for the other end to close it
+/// too, before it is closed anyway
+const FIN_WAIT_2: Duration = Duration::from_secs(60);
+const TICK: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+impl State {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            State::SynSent => "syn-sent",
+            State::SynReceived => "syn-received",
+            State::Established => "established",
+            State::FinWait1 => "fin-wait-1",
+            State::FinWait2 => "fin-wait-2",
+            State::CloseWait => "close-wait",
+            State::Closing => "closing",
+            State::LastAck => "last-ack",
+            State::TimeWait => "time-wait",
+            State::Closed => "closed",
+        }
+    }
+}
+
+/// `a < b`, for sequence numbers (that wrap around)
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    a == b || seq_lt(a, b)
+}
+
+/// A sequence number to start with, that changes with the time
+fn initial_sequence() -> u32 {
+    (time::uptime().as_micros() / 4) as u32
+}
+
+/// The header of a received segment
+#[derive(Clone, Copy, Debug)]
+struct Segment {
+    source_port: u16,
+    destination_port: u16,
+    sequence: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    /// The segment size of the peer, in SYN segments
+    mss: Option<usize>,
+}
+
+impl Segment {
+    /// Reads the header, and returns it with the data.
+    fn parse(segment: &[u8]) -> Option<(Segment, &[u8])> {
+        if segment.len() < HEADER_SIZE {
+            return None;
+        }
+        let header_size = (segment[12] >> 4) as usize * 4;
+        if header_size < HEADER_SIZE || header_size > segment.len() {
+            return None;
+        }
+        let mut mss = None;
+        let mut options = &segment[HEADER_SIZE..header_size];
+        while let Some(&kind) = options.first() {
+            match kind {
+                OPTION_END => break,
+                OPTION_NOP => options = &options[1..],
+                _ => {
+                    let len = *options.get(1)? as usize;
+                    if len < 2 || len > options.len() {
+                        return None;
+                    }
+                    if kind == OPTION_MSS && len == 4 {
+                        mss = Some(read16(options, 2) as usize);
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+        let header = Segment {
+            source_port: read16(segment, 0),
+            destination_port: read16(segment, 2),
+            sequence: read32(segment, 4),
+            ack: read32(segment, 8),
+            flags: segment[13],
+            window: read16(segment, 14),
+            mss,
+        };
+        Some((header, &segment[header_size..]))
+    }
+}
+
+/// Builds a segment.
+fn segment(
+    local: Endpoint,
+    remote: Endpoint,
+    (sequence, ack): (u32, u32),
+    flags: u8,
+    window: u16,
+    data: &[u8],
+) -> Vec<u8> {
+    // SYN segments say how large the segments can be
+    let options: &[u8] = if flags & SYN != 0 {
+        &[OPTION_MSS, 4, (MSS >> 8) as u8, MSS as u8]
+    } else {
+        &[]
+    };
+    let header_size = HEADER_SIZE + options.len();
+    let mut segment = Vec::with_capacity(header_size + data.len());
+    segment.extend_from_slice(&local.port.to_be_bytes());
+    segment.extend_from_slice(&remote.port.to_be_bytes());
+    segment.extend_from_slice(&sequence.to_be_bytes());
+    segment.extend_from_slice(&ack.to_be_bytes());
+    segment.extend_from_slice(&[(header_size / 4) as u8 * 16, flags]);
+    segment.extend_from_slice(&window.to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    segment.extend_from_slice(options);
+    segment.extend_from_slice(data);
+    let pseudo = ipv4::pseudo_header(local.address, remote.address, PROTOCOL_TCP, segment.len());
+    let checksum = super::checksum(&[&pseudo, &segment]);
+    segment[16..18].copy_from_slice(&checksum.to_be_bytes());
+    segment
+}
+
+struct Connection {
+    local: Endpoint,
+    remote: Endpoint,
+    state: State,
+    /// The first sequence number that isn't acknowledged
+    send_unacked: u32,
+    /// The next sequence number to send
+    send_next: u32,
+    send_window: u16,
+    mss: usize,
+    /// The data that is written and not acknowledged, from `send_unacked`
+    /// (once the SYN is acknowledged)
+    send_buffer: VecDeque<u8>,
+    /// A FIN is sent after the data
+    closing: bool,
+    /// The sequence number after the FIN, once it is sent
+    fin_sent: Option<u32>,
+    receive_next: u32,
+    receive_buffer: VecDeque<u8>,
+    /// The connection was reset, or timed out
+    error: Option<NetError>,
+    retransmit_at: Option<Instant>,
+    retries: u32,
+    /// When a connection in `FinWait2` or `TimeWait` is closed
+    close_at: Option<Instant>,
+    /// Tasks waiting for data, for room or for a new state
+    waiting: Vec<Waker>,
+}
+
+impl Connection {
+    /// Returns `None` if there is no memory for the buffers.
+    fn new(local: Endpoint, remote: Endpoint, state: State) -> Option<Connection> {
+        let sequence = initial_sequence();
+        let mut send_buffer = VecDeque::new();
+        send_buffer.try_reserve_exact(SEND_BUFFER).ok()?;
+        let mut receive_buffer = VecDeque::new();
+        receive_buffer.try_reserve_exact(RECEIVE_BUFFER).ok()?;
+        Some(Connection {
+            local,
+            remote,
+            state,
+            send_unacked: sequence,
+            send_next: sequence,
+            send_window: 0,
+            mss: DEFAULT_MSS,
+            send_buffer,
+            closing: false,
+            fin_sent: None,
+            receive_next: 0,
+            receive_buffer,
+            error: None,
+            retransmit_at: None,
+            retries: 0,
+            close_at: None,
+            waiting: Vec::new(),
+        })
+    }
+
+    fn wake(&mut self) {
+        for waker in self.waiting.drain(..) {
+            waker.wake();
+        }
+    }
+
+    fn window(&self) -> u16 {
+        (RECEIVE_BUFFER - self.receive_buffer.len()).min(u16::MAX as usize) as u16
+    }
+
+    fn send(&self, sequence: u32, flags: u8, data: &[u8]) {
+        let ack = if flags & ACK != 0 {
+            self.receive_next
+        } else {
+            0
+        };
+        let segment = segment(
+            self.local,
+            self.remote,
+            (sequence, ack),
+            flags,
+            self.window(),
+            data,
+        );
+        // lost segments are sent again
+        let _ = ipv4::send_now(self.remote.address, PROTOCOL_TCP, &segment);
+    }
+
+    fn send_ack(&self) {
+        self.send(self.send_next, ACK, &[]);
+    }
+
+    /// Sends the SYN (or SYN-ACK) again.
+    fn send_syn(&self) {
+        let flags = match self.state {
+            State::SynReceived => SYN | ACK,
+            _ => SYN,
+        };
+        self.send(self.send_unacked, flags, &[]);
+    }
+
+    /// Sends the data that fits in the window of the peer, and the FIN once
+    /// all the data is sent.
+    fn transmit(&mut self) {
+        if matches!(
+            self.state,
+            State::SynSent | State::SynReceived | State::Closed
+        ) {
+            return;
+        }
+        // a closed window is probed with a byte, sent again until it opens
+        let window = (self.send_window as usize).max(1);
+        loop {
+            let sent = self.send_next.wrapping_sub(self.send_unacked) as usize;
+            let sent = sent.min(self.send_buffer.len());
+            let len = (self.send_buffer.len() - sent)
+                .min(self.mss)
+                .min(window.saturating_sub(sent));
+            if len == 0 {
+                break;
+            }
+            let data: Vec<u8> = self.send_buffer.range(sent..sent + len).copied().collect();
+            self.send(self.send_next, ACK | PSH, &data);
+            self.send_next = self.send_next.wrapping_add(len as u32);
+        }
+        let all_sent =
+            self.send_next.wrapping_sub(self.send_unacked) as usize == self.send_buffer.len();
+        if self.closing && self.fin_sent.is_none() && all_sent {
+            self.send(self.send_next, FIN | ACK, &[]);
+            self.send_next = self.send_next.wrapping_add(1);
+            self.fin_sent = Some(self.send_next);
+            self.state = match self.state {
+                State::CloseWait => State::LastAck,
+                _ => State::FinWait1,
+            };
+        }
+        if self.send_next != self.send_unacked && self.retransmit_at.is_none() {
+            self.retransmit_at = Some(Instant::now() + RETRANSMIT_TIMEOUT);
+        }
+    }
+
+    /// Sends again everything that isn't acknowledged.
+    fn retransmit(&mut self) {
+        self.retries += 1;
+        if self.retries > MAX_RETRIES {
+            self.send(self.send_next, RST, &[]);
+            self.fail(NetError::TimedOut);
+            return;
+        }
+        self.retransmit_at = Some(Instant::now() + RETRANSMIT_TIMEOUT);
+        match self.state {
+            State::SynSent | State::SynReceived => self.send_syn(),
+            _ => {
+                self.send_next = self.send_unacked;
+                if self.fin_sent.take().is_some() {
+                    self.state = match self.state {
+                        State::LastAck => State::CloseWait,
+                        _ => State::Established,
+                    };
+                }
+                self.transmit();
+            }
+        }
+    }
+
+    fn fail(&mut self, error: NetError) {
+        self.error = Some(error);
+        self.state = State::Closed;
+        self.retransmit_at = None;
+        self.wake();
+    }
+
+    /// Handles the acknowledgment of a segment.
+    fn acknowledge(&mut self, ack: u32) {
+        if !seq_lt(self.send_unacked, ack) || !seq_le(ack, self.send_next) {
+            return;
+        }
+        let acked = ack.wrapping_sub(self.send_unacked) as usize;
+        let data = acked.min(self.send_buffer.len());
+        self.send_buffer.drain(..data);
+        self.send_unacked = ack;
+        self.retries = 0;
+        self.retransmit_at = if ack == self.send_next {
+            None
+        } else {
+            Some(Instant::now() + RETRANSMIT_TIMEOUT)
+        };
+        if self.fin_sent == Some(ack) {
+            match self.state {
+                State::FinWait1 => {
+                    self.state = State::FinWait2;
+                    self.close_at = Some(Instant::now() + FIN_WAIT_2);
+                }
+                State::Closing => self.time_wait(),
+                State::LastAck => self.state = State::Closed,
+                _ => {}
+            }
+        }
+        self.wake();
+    }
+
+    fn time_wait(&mut self) {
+        self.state = State::TimeWait;
+        self.close_at = Some(Instant::now() + TIME_WAIT);
+        self.retransmit_at = None;
+    }
+
+    /// Handles a segment. Returns `true` if it established the connection.
+    fn receive(&mut self, header: &Segment, data: &[u8]) -> bool {
+        if header.flags & RST != 0 {
+            match self.state {
+                State::SynSent if header.ack == self.send_next => {
+                    self.fail(NetError::ConnectionRefused)
+                }
+                State::SynSent => {}
+                _ if header.sequence == self.receive_next => self.fail(NetError::ConnectionReset),
+                _ => {}
+            }
+            return false;
+        }
+
+        if self.state == State::SynSent {
+            if header.flags & ACK != 0 && header.ack != self.send_next {
+                self.send(header.ack, RST, &[]);
+            } else if header.flags & (SYN | ACK) == SYN | ACK {
+                self.receive_next = header.sequence.wrapping_add(1);
+                self.send_unacked = header.ack;
+                self.send_window = header.window;
+                self.mss = header.mss.unwrap_or(DEFAULT_MSS).min(MSS);
+                self.state = State::Established;
+                self.retransmit_at = None;
+                self.retries = 0;
+                self.send_ack();
+                // the stream may have been written to, or dropped, meanwhile
+                self.transmit();
+                self.wake();
+            }
+            return false;
+        }
+
+        if header.flags & SYN != 0 {
+            // the SYN-ACK was lost
+            if self.state == State::SynReceived {
+                self.send_syn();
+            } else {
+                self.send_ack();
+            }
+            return false;
+        }
+        if header.flags & ACK == 0 {
+            return false;
+        }
+
+        let mut established = false;
+        if self.state == State::SynReceived {
+            if header.ack != self.send_next {
+                self.send(header.ack, RST, &[]);
+                return false;
+            }
+            self.send_unacked = header.ack;
+            self.retransmit_at = None;
+            self.retries = 0;
+            self.state = State::Established;
+            established = true;
+        }
+        self.acknowledge(header.ack);
+        self.send_window = header.window;
+
+        let mut ack = false;
+        let receiving = matches!(
+            self.state,
+            State::Established | State::FinWait1 | State::FinWait2
+        );
+        if receiving && header.sequence == self.receive_next {
+            let len = data.len().min(RECEIVE_BUFFER - self.receive_buffer.len());
+            self.receive_buffer.extend(&data[..len]);
+            self.receive_next = self.receive_next.wrapping_add(len as u32);
+            ack = !data.is_empty();
+            if header.flags & FIN != 0 && len == data.len() {
+                self.receive_next = self.receive_next.wrapping_add(1);
+                match self.state {
+                    State::Established => self.state = State::CloseWait,
+                    State::FinWait1 => self.state = State::Closing,
+                    _ => self.time_wait(),
+                }
+                ack = true;
+            }
+            if len > 0 || header.flags & FIN != 0 {
+                self.wake();
+            }
+        } else if !data.is_empty() || header.flags & FIN != 0 {
+            // out of order, or already received: says what is expected
+            ack = true;
+        }
+        if ack {
+            self.send_ack();
+        }
+        self.transmit();
+        established
+    }
+}
+
+type Key = (Endpoint, Endpoint);
+
+#[derive(Default)]
+struct Listener {
+    /// Established connections, waiting to be accepted
+    ready: VecDeque<Arc<spin::Mutex<Connection>>>,
+    waker: Option<Waker>,
+}
+
+lazy_static::lazy_static! {
+    /// By local and remote ends
+    static ref CONNECTIONS: spin::Mutex<BTreeMap<Key, Arc<spin::Mutex<Connection>>>> =
+        spin::Mutex::new(BTreeMap::new());
+    /// By port
+    static ref LISTENERS: spin::Mutex<BTreeMap<u16, Listener>> =
+        spin::Mutex::new(BTreeMap::new());
+}
+
+/// Answers a segment that belongs to no connection.
+fn reset(header: &Header, segment: &Segment, len: usize) {
+    let local = Endpoint::new(header.destination, segment.destination_port);
+    let remote = Endpoint::new(header.source, segment.source_port);
+    let (sequence, ack, flags) = if segment.flags & ACK != 0 {
+        (segment.ack, 0, RST)
+    } else {
+        let syn_fin = (segment.flags & (SYN | FIN) != 0) as u32;
+        let ack = segment.sequence.wrapping_add(len as u32 + syn_fin);
+        (0, ack, RST | ACK)
+    };
+    let reply = self::segment(local, remote, (sequence, ack), flags, 0, &[]);
+    let _ = ipv4::send_now(remote.address, PROTOCOL_TCP, &reply);
+}
+
+/// Gives a segment to its connection, or to a listener.
+pub fn receive(header: &Header, segment: &[u8]) {
+    let pseudo = ipv4::pseudo_header(
+        header.source,
+        header.destination,
+        PROTOCOL_TCP,
+        segment.len(),
+    );
+    if super::checksum(&[&pseudo, segment]) != 0 {
+        return;
+    }
+    let (parsed, data) = match Segment::parse(segment) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let local = Endpoint::new(header.destination, parsed.destination_port);
+    let remote = Endpoint::new(header.source, parsed.source_port);
+
+    let connection = CONNECTIONS.lock().get(&(local, remote)).cloned();
+    if let Some(connection) = connection {
+        let established = connection.lock().receive(&parsed, data);
+        if established {
+            let mut listeners = LISTENERS.lock();
+            match listeners.get_mut(&local.port) {
+                Some(listener) if listener.ready.len() < BACKLOG => {
+                    listener.ready.push_back(connection);
+                    if let Some(waker) = listener.waker.take() {
+                        waker.wake();
+                    }
+                }
+                // not listening anymore, or too many connections wait
+                _ => {
+                    let mut connection = connection.lock();
+                    connection.send(connection.send_next, RST, &[]);
+                    connection.fail(NetError::ConnectionReset);
+                }
+            }
+        }
+        return;
+    }
+
+    if parsed.flags & RST != 0 {
+        return;
+    }
+    let listening = LISTENERS.lock().contains_key(&local.port);
+    if !listening || parsed.flags & (SYN | ACK) != SYN {
+        reset(header, &parsed, data.len());
+        return;
+    }
+    if !accepts_connection(local.port) {
+        return;
+    }
+    let mut connection = match Connection::new(local, remote, State::SynReceived) {
+        Some(connection) => connection,
+        None => return,
+    };
+    connection.receive_next = parsed.sequence.wrapping_add(1);
+    connection.send_window = parsed.window;
+    connection.mss = parsed.mss.unwrap_or(DEFAULT_MSS).min(MSS);
+    connection.send_syn();
+    connection.send_next = connection.send_unacked.wrapping_add(1);
+    connection.retransmit_at = Some(Instant::now() + RETRANSMIT_TIMEOUT);
+    CONNECTIONS
+        .lock()
+        .insert((local, remote), Arc::new(spin::Mutex::new(connection)));
+}
+
+/// Is there room for a new connection on a port?
+fn accepts_connection(port: u16) -> bool {
+    let connections: Vec<_> = CONNECTIONS.lock().values().cloned().collect();
+    if connections.len() >= MAX_CONNECTIONS {
+        return false;
+    }
+    let half_open = connections
+        .iter()
+        .filter(|connection| {
+            let connection = connection.lock();
+            connection.local.port == port && connection.state == State::SynReceived
+        })
+        .count();
+    let ready = LISTENERS
+        .lock()
+        .get(&port)
+        .map_or(0, |listener| listener.ready.len());
+    half_open < MAX_HALF_OPEN && ready < BACKLOG
+}
+
+/// Sends again what was lost, and forgets the closed connections, forever.
+pub async fn run() {
+    loop {
+        time::sleep(TICK).await;
+        let now = Instant::now();
+        let connections: Vec<_> = CONNECTIONS.lock().values().cloned().collect();
+        for connection in connections {
+            let mut connection = connection.lock();
+            if connection.retransmit_at.map_or(false, |at| at <= now) {
+                connection.retransmit();
+            }
+            if connection.close_at.map_or(false, |at| at <= now) {
+                connection.state = State::Closed;
+                connection.wake();
+            }
+            if connection.state == State::Closed {
+                let key = (connection.local, connection.remote);
+                CONNECTIONS.lock().remove(&key);
+            }
+        }
+    }
+}
+
+/// The ends and states of the connections
+pub fn connections() -> Vec<(Endpoint, Endpoint, State)> {
+    let connections: Vec<_> = CONNECTIONS.lock().values().cloned().collect();
+    connections
+        .iter()
+        .map(|connection| {
+            let connection = connection.lock();
+            (connection.local, connection.remote, connection.state)
+        })
+        .collect()
+}
+
+/// A connection, that is closed when it is dropped
+pub struct TcpStream {
+    connection: Arc<spin::Mutex<Connection>>,
+}
+
+impl TcpStream {
+    /// Connects to a remote end.
+    pub async fn connect(remote: Endpoint) -> Result<TcpStream, NetError> {
+        let config = super::config();
+        if !config.is_configured() {
+            return Err(NetError::NoInterface);
+        }
+        // the SYN is sent right away, once the next hop is known
+        let hop = if config.is_local(remote.address) {
+            remote.address
+        } else {
+            config.gateway.ok_or(NetError::Unreachable)?
+        };
+        super::arp::resolve(hop)
+            .await
+            .ok_or(NetError::Unreachable)?;
+
+        let connection = {
+            let mut connections = CONNECTIONS.lock();
+            if connections.len() >= MAX_CONNECTIONS {
+                return Err(NetError::NoMemory);
+            }
+            let local = (0..u16::MAX)
+                .map(|_| Endpoint::new(config.address, super::ephemeral_port()))
+                .find(|&local| !connections.contains_key(&(local, remote)))
+                .ok_or(NetError::AddressInUse)?;
+            let mut connection =
+                Connection::new(local, remote, State::SynSent).ok_or(NetError::NoMemory)?;
+            connection.send_syn();
+            connection.send_next = connection.send_unacked.wrapping_add(1);
+            connection.retransmit_at = Some(Instant::now() + RETRANSMIT_TIMEOUT);
+            let connection = Arc::new(spin::Mutex::new(connection));
+            connections.insert((local, remote), Arc::clone(&connection));
+            connection
+        };
+        let stream = TcpStream { connection };
+        poll_fn(|cx| {
+            let mut connection = stream.connection.lock();
+            match (connection.state, connection.error) {
+                (State::SynSent, _) => {
+                    connection.waiting.push(cx.waker().clone());
+                    Poll::Pending
+                }
+                (_, Some(error)) => Poll::Ready(Err(error)),
+                _ => Poll::Ready(Ok(())),
+            }
+        })
+        .await?;
+        Ok(stream)
+    }
+
+    pub fn local(&self) -> Endpoint {
+        self.connection.lock().local
+    }
+
+    pub fn remote(&self) -> Endpoint {
+        self.connection.lock().remote
+    }
+
+    /// Waits for some data, and returns how many bytes were read (0 when the
+    /// peer closed the connection).
+    pub async fn read(&mut self, buffer: &mut [u8]) -> Result<usize, NetError> {
+        poll_fn(|cx| {
+            let mut connection = self.connection.lock();
+            if !connection.receive_buffer.is_empty() {
+                let len = buffer.len().min(connection.receive_buffer.len());
+                for (byte, received) in buffer
+                    .iter_mut()
+                    .zip(connection.receive_buffer.drain(..len))
+                {
+                    *byte = received;
+                }
+                // the window opened
+                connection.send_ack();
+                return Poll::Ready(Ok(len));
+            }
+            if let Some(error) = connection.error {
+                return Poll::Ready(Err(error));
+            }
+            match connection.state {
+                State::Established | State::FinWait1 | State::FinWait2 => {
+                    connection.waiting.push(cx.waker().clone());
+                    Poll::Pending
+                }
+                _ => Poll::Ready(Ok(0)),
+            }
+        })
+        .await
+    }
+
+    /// Reads exactly enough bytes to fill `buffer`.
+    pub async fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), NetError> {
+        let mut read = 0;
+        while read < buffer.len() {
+            match self.read(&mut buffer[read..]).await? {
+                0 => return Err(NetError::Closed),
+                len => read += len,
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends all the data, waiting for room in the send buffer.
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<(), NetError> {
+        while !data.is_empty() {
+            let written = poll_fn(|cx| {
+                let mut connection = self.connection.lock();
+                if let Some(error) = connection.error {
+                    return Poll::Ready(Err(error));
+                }
+                if connection.closing
+                    || !matches!(connection.state, State::Established | State::CloseWait)
+                {
+                    return Poll::Ready(Err(NetError::Closed));
+                }
+                let room = SEND_BUFFER - connection.send_buffer.len();
+                if room == 0 {
+                    connection.waiting.push(cx.waker().clone());
+                    return Poll::Pending;
+                }
+                let len = room.min(data.len());
+                connection.send_buffer.extend(&data[..len]);
+                connection.transmit();
+                Poll::Ready(Ok(len))
+            })
+            .await?;
+            data = &data[written..];
+        }
+        Ok(())
+    }
+
+    /// Sends a FIN once everything is sent: nothing can be written anymore,
+    /// but the peer can still send data.
+    pub fn close(&self) {
+        let mut connection = self.connection.lock();
+        if !connection.closing {
+            connection.closing = true;
+            connection.transmit();
+        }
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Accepts connections on a port, until it is dropped
+pub struct TcpListener {
+    port: u16,
+}
+
+impl TcpListener {
+    pub fn bind(port: u16) -> Result<TcpListener, NetError> {
+        let mut listeners = LISTENERS.lock();
+        if listeners.contains_key(&port) {
+            return Err(NetError::AddressInUse);
+        }
+        listeners.insert(port, Listener::default());
+        Ok(TcpListener { port })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Waits for an established connection.
+    pub async fn accept(&self) -> TcpStream {
+        poll_fn(|cx| {
+            let mut listeners = LISTENERS.lock();
+            let listener = listeners.entry(self.port).or_default();
+            match listener.ready.pop_front() {
+                Some(connection) => Poll::Ready(TcpStream { connection }),
+                None => {
+                    listener.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let listener = LISTENERS.lock().remove(&self.port);
+        // the connections that were not accepted are closed
+        for connection in listener.into_iter().flat_map(|listener| listener.ready) {
+            drop(TcpStream { connection });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn sequence_numbers() {
+        assert!(seq_lt(1, 2));
+        assert!(!seq_lt(2, 2));
+        assert!(seq_le(2, 2));
+        assert!(seq_lt(u32::MAX, 0));
+        assert!(!seq_lt(0, u32::MAX));
+    }
+
+    #[test_case]
+    fn parse_segment() {
+        let segment = [
+            0x1f,
+            0x90,
+            0xc0,
+            0x00,
+            0,
+            0,
+            0,
+            1,
+            0,
+            0,
+            0,
+            2,
+            0x60,
+            SYN | ACK,
+            0x20,
+            0x00,
+            0,
+            0,
+            0,
+            0,
+            OPTION_MSS,
+            4,
+            0x05,
+            0xb4,
+            42,
+        ];
+        let (header, data) = Segment::parse(&segment).unwrap();
+        assert!(header.source_port == 8080);
+        assert!(header.destination_port == 0xc000);
+        assert!((header.sequence, header.ack) == (1, 2));
+        assert!(header.flags == SYN | ACK);
+        assert!(header.window == 0x2000);
+        assert!(header.mss == Some(1460));
+        assert!(data == [42]);
+    }
+}
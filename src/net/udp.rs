@@ -0,0 +1,149 @@
+//! UDP sockets
+//!
+//! A socket is bound to a local port, and receives the datagrams sent to it
+//! from any address. Datagrams that arrive while too many are waiting, or
+//! when there is no memory for them, are dropped.
+
+use super::ipv4::{self, Header, PROTOCOL_UDP};
+use super::{read16, Endpoint, NetError};
+use crate::allocator::HEAP_SIZE;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::task::{Poll, Waker};
+use futures_util::future::poll_fn;
+
+pub const HEADER_SIZE: usize = 8;
+/// The largest payload
+pub const MAX_PAYLOAD: usize = ipv4::MAX_PAYLOAD - HEADER_SIZE;
+/// Datagrams that can wait in a socket
+const QUEUE_LEN: usize = 16;
+/// Bytes that can wait in a socket (a few full datagrams)
+const QUEUE_SIZE: usize = HEAP_SIZE / 16;
+
+#[derive(Default)]
+struct Socket {
+    received: VecDeque<(Endpoint, Vec<u8>)>,
+    /// The size of the received datagrams
+    queued: usize,
+    waker: Option<Waker>,
+}
+
+lazy_static::lazy_static! {
+    static ref SOCKETS: spin::Mutex<BTreeMap<u16, Socket>> = spin::Mutex::new(BTreeMap::new());
+}
+
+/// A socket, that is unbound when it is dropped
+pub struct UdpSocket {
+    port: u16,
+}
+
+impl UdpSocket {
+    /// Binds a port, or any free port if `port` is 0.
+    pub fn bind(port: u16) -> Result<UdpSocket, NetError> {
+        let mut sockets = SOCKETS.lock();
+        let port = match port {
+            0 => (0..u16::MAX)
+                .map(|_| super::ephemeral_port())
+                .find(|port| !sockets.contains_key(port))
+                .ok_or(NetError::AddressInUse)?,
+            port if sockets.contains_key(&port) => return Err(NetError::AddressInUse),
+            port => port,
+        };
+        sockets.insert(port, Socket::default());
+        Ok(UdpSocket { port })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub async fn send_to(&self, data: &[u8], destination: Endpoint) -> Result<(), NetError> {
+        if data.len() > MAX_PAYLOAD {
+            return Err(NetError::TooLarge);
+        }
+        let source = super::config().address;
+        let datagram = datagram(self.port, source, destination, data);
+        ipv4::send(destination.address, PROTOCOL_UDP, &datagram).await
+    }
+
+    /// Waits for a datagram, and returns it with where it comes from.
+    pub async fn recv_from(&self) -> (Vec<u8>, Endpoint) {
+        poll_fn(|cx| {
+            let mut sockets = SOCKETS.lock();
+            let socket = sockets.entry(self.port).or_default();
+            match socket.received.pop_front() {
+                Some((source, data)) => {
+                    socket.queued -= data.len();
+                    Poll::Ready((data, source))
+                }
+                None => {
+                    socket.waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+    }
+}
+
+fn datagram(port: u16, source: super::Ipv4Address, destination: Endpoint, data: &[u8]) -> Vec<u8> {
+    let len = HEADER_SIZE + data.len();
+    let mut datagram = Vec::with_capacity(len);
+    datagram.extend_from_slice(&port.to_be_bytes());
+    datagram.extend_from_slice(&destination.port.to_be_bytes());
+    datagram.extend_from_slice(&(len as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(data);
+    let pseudo = ipv4::pseudo_header(source, destination.address, PROTOCOL_UDP, len);
+    // 0 means that there is no checksum
+    let checksum = match super::checksum(&[&pseudo, &datagram]) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+    datagram
+}
+
+/// Gives a datagram to the socket of its port.
+pub fn receive(header: &Header, datagram: &[u8]) {
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+    let len = read16(datagram, 4) as usize;
+    if len < HEADER_SIZE || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    if read16(datagram, 6) != 0 {
+        let pseudo = ipv4::pseudo_header(header.source, header.destination, PROTOCOL_UDP, len);
+        if super::checksum(&[&pseudo, datagram]) != 0 {
+            return;
+        }
+    }
+    let source = Endpoint::new(header.source, read16(datagram, 0));
+    let mut sockets = SOCKETS.lock();
+    let socket = match sockets.get_mut(&read16(datagram, 2)) {
+        Some(socket) => socket,
+        None => return,
+    };
+    let payload = &datagram[HEADER_SIZE..];
+    if socket.received.len() >= QUEUE_LEN || socket.queued + payload.len() > QUEUE_SIZE {
+        return;
+    }
+    let mut data = Vec::new();
+    if data.try_reserve_exact(payload.len()).is_err() || socket.received.try_reserve(1).is_err() {
+        return;
+    }
+    data.extend_from_slice(payload);
+    socket.queued += data.len();
+    socket.received.push_back((source, data));
+    if let Some(waker) = socket.waker.take() {
+        waker.wake();
+    }
+}
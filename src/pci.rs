@@ -1,51 +1,296 @@
-use crate::memory::MEM_OFFSET;
-use acpi::PciConfigRegions;
-use alloc::collections::BTreeMap;
+//! PCI configuration space and device enumeration
+//!
+//! The configuration space is memory-mapped (PCIe) when the ACPI tables have
+//! an MCFG, and otherwise accessed through the legacy 0xCF8/0xCFC ports
+//! (segment 0 and the first 256 bytes of each function only). Buses behind
+//! PCI-to-PCI bridges are found by following their secondary bus numbers.
+
+use crate::interrupt::Vector;
+use crate::memory::{AcpiHandler, MEM_OFFSET};
+use crate::prt::LegacyInterrupt;
+use acpi::sdt::Signature;
+use acpi::{AcpiTables, PciConfigRegions};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::vec::Vec;
 use pci_types::{Bar, ConfigRegionAccess, EndpointHeader, PciAddress, PciHeader};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
 use x86_64::VirtAddr;
 
+/// A PCI segment group, and the buses it has
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
 /// S/O https://github.com/IsaacWoods/pebble/blob/main/kernel/kernel_x86_64/src/pci.rs
+pub enum ConfigAccess {
+    /// Memory-mapped, as described by the MCFG table
+    Mcfg {
+        regions: PciConfigRegions,
+        segments: Vec<Segment>,
+    },
+    /// Through the 0xCF8 and 0xCFC I/O ports
+    Legacy,
+}
+
+/// The configuration space, once the ACPI tables have been read
+pub static ACCESS: spin::Mutex<Option<ConfigAccess>> = spin::Mutex::new(None);
+
+/// The address and data ports have to be used one after the other
+static LEGACY_PORTS: spin::Mutex<()> = spin::Mutex::new(());
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// Where the entries of the MCFG start: after the SDT header and 8 reserved
+/// bytes
+const MCFG_ENTRIES: usize = 44;
+const MCFG_ENTRY_SIZE: usize = 16;
+
+impl ConfigAccess {
+    /// Uses the MCFG if there is one, and the legacy ports otherwise.
+    pub fn new(tables: Option<&AcpiTables<AcpiHandler>>) -> ConfigAccess {
+        let mcfg = tables.and_then(|tables| {
+            let regions = PciConfigRegions::new(tables).ok()?;
+            let segments = mcfg_segments(tables);
+            if segments.is_empty() {
+                None
+            } else {
+                Some(ConfigAccess::Mcfg { regions, segments })
+            }
+        });
+        mcfg.unwrap_or(ConfigAccess::Legacy)
+    }
+
+    pub fn segments(&self) -> Vec<Segment> {
+        match self {
+            ConfigAccess::Mcfg { segments, .. } => segments.clone(),
+            ConfigAccess::Legacy => alloc::vec![Segment {
+                group: 0,
+                start_bus: 0,
+                end_bus: 0xff,
+            }],
+        }
+    }
+
+    fn mmio(&self, address: PciAddress, offset: u16) -> Option<VirtAddr> {
+        match self {
+            ConfigAccess::Mcfg { regions, .. } => {
+                let phys = regions.physical_address(
+                    address.segment(),
+                    address.bus(),
+                    address.device(),
+                    address.function(),
+                )?;
+                Some(VirtAddr::new(MEM_OFFSET + phys + offset as u64))
+            }
+            ConfigAccess::Legacy => None,
+        }
+    }
+}
 
-pub struct ConfigAccess(pub PciConfigRegions);
+/// Reads the segment groups of the MCFG table.
+fn mcfg_segments(tables: &AcpiTables<AcpiHandler>) -> Vec<Segment> {
+    let sdt = match tables.sdts.get(&Signature::MCFG) {
+        Some(sdt) => sdt,
+        None => return Vec::new(),
+    };
+    let count = (sdt.length as usize).saturating_sub(MCFG_ENTRIES) / MCFG_ENTRY_SIZE;
+    (0..count)
+        .map(|i| {
+            let entry =
+                MEM_OFFSET as usize + sdt.physical_address + MCFG_ENTRIES + i * MCFG_ENTRY_SIZE;
+            // the entries are not aligned: base address (8 bytes), segment
+            // group (2), start and end bus (1 each), and 4 reserved bytes
+            unsafe {
+                Segment {
+                    group: core::ptr::read_unaligned((entry + 8) as *const u16),
+                    start_bus: core::ptr::read_unaligned((entry + 10) as *const u8),
+                    end_bus: core::ptr::read_unaligned((entry + 11) as *const u8),
+                }
+            }
+        })
+        .collect()
+}
+
+fn legacy_address(address: PciAddress, offset: u16) -> u32 {
+    0x8000_0000
+        | (address.bus() as u32) << 16
+        | (address.device() as u32) << 11
+        | (address.function() as u32) << 8
+        | (offset as u32 & 0xfc)
+}
 
 impl ConfigRegionAccess for ConfigAccess {
-    fn function_exists(&self, address: pci_types::PciAddress) -> bool {
-        self.0
-            .physical_address(
-                address.segment(),
-                address.bus(),
-                address.device(),
-                address.device(),
-            )
-            .is_some()
-    }
-
-    unsafe fn read(&self, address: pci_types::PciAddress, offset: u16) -> u32 {
-        let phys = self
-            .0
-            .physical_address(
-                address.segment(),
-                address.bus(),
-                address.device(),
-                address.function(),
-            )
-            .unwrap();
-        let ptr = VirtAddr::new(MEM_OFFSET + phys + offset as u64).as_ptr();
-        core::ptr::read_volatile(ptr)
-    }
-
-    unsafe fn write(&self, address: pci_types::PciAddress, offset: u16, value: u32) {
-        let phys = self
-            .0
-            .physical_address(
-                address.segment(),
-                address.bus(),
-                address.device(),
-                address.function(),
-            )
-            .unwrap();
-        let ptr = VirtAddr::new(MEM_OFFSET + phys + offset as u64).as_mut_ptr();
-        core::ptr::write_volatile(ptr, value);
+    fn function_exists(&self, address: PciAddress) -> bool {
+        match self {
+            ConfigAccess::Mcfg { .. } => self.mmio(address, 0).is_some(),
+            ConfigAccess::Legacy => {
+                address.segment() == 0 && unsafe { self.read(address, 0) } & 0xffff != 0xffff
+            }
+        }
+    }
+
+    unsafe fn read(&self, address: PciAddress, offset: u16) -> u32 {
+        match self {
+            ConfigAccess::Mcfg { .. } => {
+                let ptr = self.mmio(address, offset).unwrap().as_ptr();
+                core::ptr::read_volatile(ptr)
+            }
+            // the legacy ports only reach the first 256 bytes
+            ConfigAccess::Legacy if offset >= 0x100 => 0xffff_ffff,
+            ConfigAccess::Legacy => interrupts::without_interrupts(|| {
+                let _ports = LEGACY_PORTS.lock();
+                Port::<u32>::new(CONFIG_ADDRESS).write(legacy_address(address, offset));
+                Port::<u32>::new(CONFIG_DATA).read()
+            }),
+        }
+    }
+
+    unsafe fn write(&self, address: PciAddress, offset: u16, value: u32) {
+        match self {
+            ConfigAccess::Mcfg { .. } => {
+                let ptr = self.mmio(address, offset).unwrap().as_mut_ptr();
+                core::ptr::write_volatile(ptr, value);
+            }
+            ConfigAccess::Legacy if offset >= 0x100 => {}
+            ConfigAccess::Legacy => interrupts::without_interrupts(|| {
+                let _ports = LEGACY_PORTS.lock();
+                Port::<u32>::new(CONFIG_ADDRESS).write(legacy_address(address, offset));
+                Port::<u32>::new(CONFIG_DATA).write(value);
+            }),
+        }
+    }
+}
+
+/// MSI: the device writes to an address to signal an interrupt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Msi {
+    pub offset: u16,
+    pub is_64bit: bool,
+    pub per_vector_masking: bool,
+    /// How many vectors the device can use (a power of two, up to 32)
+    pub max_vectors: u8,
+}
+
+/// MSI-X: like MSI, but each vector has its own entry in a table in a BAR
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MsiX {
+    pub offset: u16,
+    pub table_size: u16,
+    pub table_bar: u8,
+    pub table_offset: u32,
+    /// The pending bit array
+    pub pba_bar: u8,
+    pub pba_offset: u32,
+}
+
+/// PCI Express
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Express {
+    pub offset: u16,
+    pub version: u8,
+    /// Endpoint (0), root port (4), switch port (5 or 6), …
+    pub device_type: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PowerManagement {
+    pub offset: u16,
+    pub version: u8,
+    pub d1: bool,
+    pub d2: bool,
+}
+
+/// The capabilities we know about, from the capability list
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub power_management: Option<PowerManagement>,
+    pub msi: Option<Msi>,
+    pub express: Option<Express>,
+    pub msix: Option<MsiX>,
+}
+
+const CAP_POWER_MANAGEMENT: u8 = 0x01;
+const CAP_MSI: u8 = 0x05;
+const CAP_EXPRESS: u8 = 0x10;
+const CAP_MSIX: u8 = 0x11;
+
+const STATUS_CAPABILITIES: u32 = 1 << 20;
+const CAPABILITIES_POINTER: u16 = 0x34;
+/// 48 capabilities of 4 bytes fill the configuration space after the
+/// header: more means the list has a loop
+const MAX_CAPABILITIES: usize = 48;
+
+impl Capabilities {
+    /// Follows the capability list of a function, given a way to read its
+    /// configuration space.
+    pub fn parse(read: impl Fn(u16) -> u32) -> Capabilities {
+        let mut capabilities = Capabilities::default();
+        if read(COMMAND) & STATUS_CAPABILITIES == 0 {
+            return capabilities;
+        }
+        let mut offset = (read(CAPABILITIES_POINTER) & 0xfc) as u16;
+        for _ in 0..MAX_CAPABILITIES {
+            if offset < 0x40 {
+                break;
+            }
+            let header = read(offset);
+            match header as u8 {
+                CAP_POWER_MANAGEMENT => {
+                    capabilities.power_management = Some(PowerManagement {
+                        offset,
+                        version: (header >> 16) as u8 & 0x7,
+                        d1: header & (1 << 25) != 0,
+                        d2: header & (1 << 26) != 0,
+                    })
+                }
+                CAP_MSI => {
+                    capabilities.msi = Some(Msi {
+                        offset,
+                        is_64bit: header & (1 << 23) != 0,
+                        per_vector_masking: header & (1 << 24) != 0,
+                        max_vectors: 1 << ((header >> 17) & 0x7).min(5),
+                    })
+                }
+                CAP_EXPRESS => {
+                    capabilities.express = Some(Express {
+                        offset,
+                        version: (header >> 16) as u8 & 0xf,
+                        device_type: (header >> 20) as u8 & 0xf,
+                    })
+                }
+                CAP_MSIX => {
+                    let table = read(offset + 4);
+                    let pba = read(offset + 8);
+                    capabilities.msix = Some(MsiX {
+                        offset,
+                        table_size: ((header >> 16) & 0x7ff) as u16 + 1,
+                        table_bar: table as u8 & 0x7,
+                        table_offset: table & !0x7,
+                        pba_bar: pba as u8 & 0x7,
+                        pba_offset: pba & !0x7,
+                    })
+                }
+                _ => {}
+            }
+            offset = ((header >> 8) & 0xfc) as u16;
+        }
+        capabilities
+    }
+
+    /// Short names of the capabilities, for listings
+    pub fn names(&self) -> Vec<&'static str> {
+        let names = [
+            self.power_management.map(|_| "pm"),
+            self.msi.map(|_| "msi"),
+            self.express.map(|_| "pcie"),
+            self.msix.map(|_| "msix"),
+        ];
+        names.iter().flatten().copied().collect()
     }
 }
 
@@ -57,6 +302,16 @@ pub struct PciDevice {
     pub sub_class: u8,
     pub interface: u8,
     pub bars: [Option<Bar>; 6],
+    /// 0 if the device doesn't use legacy interrupts, 1 to 4 for INTA# to INTD#
+    pub interrupt_pin: u8,
+    /// The legacy IRQ the firmware assigned to this device (only meaningful
+    /// with the legacy PICs)
+    pub interrupt_line: u8,
+    /// Where the legacy interrupt goes, with the APIC (see [`crate::prt`])
+    pub legacy_interrupt: Option<LegacyInterrupt>,
+    pub capabilities: Capabilities,
+    /// For PCI-to-PCI bridges, the bus behind them
+    pub secondary_bus: Option<u8>,
 }
 
 impl PciDevice {
@@ -64,6 +319,7 @@ impl PciDevice {
         match (self.class, self.sub_class) {
             (0x00, _) => "Unclassified",
             (0x01, 0x06) => "SATA controller (Mass storage controller)",
+            (0x01, 0x08) => "NVMe controller (Mass storage controller)",
             (0x01, _) => "Mass storage controller",
             (0x02, 0x00) => "Ethernet controller (Network controller)",
             (0x02, _) => "Network controller",
@@ -88,109 +344,420 @@ impl PciDevice {
             _ => "Unknown",
         }
     }
+
+    /// The physical address of a memory BAR
+    pub fn bar_address(&self, index: usize) -> Option<u64> {
+        match self.bars.get(index).copied().flatten() {
+            Some(Bar::Memory64 { address, .. }) => Some(address),
+            Some(Bar::Memory32 { address, .. }) => Some(address as u64),
+            _ => None,
+        }
+    }
+}
+
+const COMMAND: u16 = 0x04;
+const COMMAND_IO: u32 = 1 << 0;
+const COMMAND_MEMORY: u32 = 1 << 1;
+const COMMAND_BUS_MASTER: u32 = 1 << 2;
+const COMMAND_INTX_DISABLE: u32 = 1 << 10;
+
+fn update_command(access: &impl ConfigRegionAccess, address: PciAddress, set: u32, clear: u32) {
+    unsafe {
+        // the upper half is the status register: writing 1 would clear its bits
+        let command = access.read(address, COMMAND) & 0xffff;
+        access.write(address, COMMAND, (command | set) & !clear);
+    }
+}
+
+/// Lets the device answer to memory accesses, and access memory itself (DMA).
+pub fn enable_bus_master(access: &impl ConfigRegionAccess, address: PciAddress) {
+    update_command(access, address, COMMAND_MEMORY | COMMAND_BUS_MASTER, 0);
+}
+
+/// Lets the device answer to accesses to its I/O ports.
+pub fn enable_io(access: &impl ConfigRegionAccess, address: PciAddress) {
+    update_command(access, address, COMMAND_IO, 0);
+}
+
+/// Stops the device from answering to memory and I/O accesses, and from
+/// accessing memory itself (which includes sending MSIs).
+pub fn disable(access: &impl ConfigRegionAccess, address: PciAddress) {
+    update_command(
+        access,
+        address,
+        0,
+        COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER,
+    );
+}
+
+/// The number of ports of an I/O BAR, found by writing ones to it (the
+/// bits that stay 0 give the size)
+pub fn io_bar_size(access: &impl ConfigRegionAccess, address: PciAddress, index: u8) -> u16 {
+    let offset = 0x10 + 4 * index as u16;
+    let mask = unsafe {
+        let original = access.read(address, offset);
+        access.write(address, offset, 0xffff_ffff);
+        let mask = access.read(address, offset);
+        access.write(address, offset, original);
+        mask
+    };
+    // the upper half may read as 0, when only 16 bits of address are decoded
+    (!(mask as u16 & !0x3)).wrapping_add(1)
+}
+
+/// Parses an address written `segment:bus:device.function` (in
+/// hexadecimal), the segment being optional.
+pub fn parse_address(s: &str) -> Option<PciAddress> {
+    let (rest, function) = s.rsplit_once('.')?;
+    let mut parts = rest.rsplit(':');
+    let device = u8::from_str_radix(parts.next()?, 16).ok()?;
+    let bus = u8::from_str_radix(parts.next()?, 16).ok()?;
+    let segment = match parts.next() {
+        Some(segment) => u16::from_str_radix(segment, 16).ok()?,
+        None => 0,
+    };
+    let function = function.parse().ok()?;
+    if parts.next().is_some() || device >= 32 || function >= 8 {
+        return None;
+    }
+    Some(PciAddress::new(segment, bus, device, function))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MsiError {
+    /// The device has neither MSI nor MSI-X
+    Unsupported,
+    /// Message signaled interrupts go to a local APIC
+    NoApic,
+    /// The MSI-X table doesn't have this entry
+    NoEntry,
+    /// The MSI-X table is in a BAR that isn't mapped in memory
+    InvalidBar,
+}
+
+/// The local APIC that receives the messages
+const MSI_ADDRESS: u32 = 0xfee0_0000;
+
+const MSI_ENABLE: u32 = 1 << 16;
+/// How many vectors are enabled (as a power of two)
+const MSI_MULTIPLE_MESSAGE: u32 = 0x7 << 20;
+const MSIX_FUNCTION_MASK: u32 = 1 << 30;
+const MSIX_ENABLE: u32 = 1 << 31;
+const MSIX_ENTRY_SIZE: u64 = 16;
+const MSIX_ENTRY_MASKED: u32 = 1;
+
+fn msi_address() -> u32 {
+    MSI_ADDRESS | crate::apic::local_apic_id() << 12
+}
+
+fn disable_intx(access: &impl ConfigRegionAccess, address: PciAddress) {
+    update_command(access, address, COMMAND_INTX_DISABLE, 0);
+}
+
+/// Lets the device assert its legacy interrupt.
+pub fn enable_intx(access: &impl ConfigRegionAccess, address: PciAddress) {
+    update_command(access, address, 0, COMMAND_INTX_DISABLE);
+}
+
+/// Makes the device signal its interrupts with MSI, on a vector of
+/// [`crate::interrupt::allocate_vector`], sent to the current CPU.
+pub fn enable_msi(
+    access: &impl ConfigRegionAccess,
+    address: PciAddress,
+    device: &PciDevice,
+    vector: &Vector,
+) -> Result<(), MsiError> {
+    let msi = device.capabilities.msi.ok_or(MsiError::Unsupported)?;
+    if !crate::apic::is_enabled() {
+        return Err(MsiError::NoApic);
+    }
+    let offset = msi.offset;
+    unsafe {
+        access.write(address, offset + 4, msi_address());
+        let data = if msi.is_64bit {
+            access.write(address, offset + 8, 0);
+            offset + 12
+        } else {
+            offset + 8
+        };
+        access.write(address, data, vector.as_u8() as u32);
+        if msi.per_vector_masking {
+            access.write(address, data + 4, 0);
+        }
+        // only one vector
+        let control = access.read(address, offset) & !MSI_MULTIPLE_MESSAGE;
+        access.write(address, offset, control | MSI_ENABLE);
+    }
+    disable_intx(access, address);
+    Ok(())
+}
+
+/// Makes one entry of the MSI-X table signal a vector, and enables MSI-X.
+/// The other entries stay as they were (masked, after a reset).
+pub fn enable_msix(
+    access: &impl ConfigRegionAccess,
+    address: PciAddress,
+    device: &PciDevice,
+    entry: u16,
+    vector: &Vector,
+) -> Result<(), MsiError> {
+    let msix = device.capabilities.msix.ok_or(MsiError::Unsupported)?;
+    if !crate::apic::is_enabled() {
+        return Err(MsiError::NoApic);
+    }
+    if entry >= msix.table_size {
+        return Err(MsiError::NoEntry);
+    }
+    let bar = device
+        .bar_address(msix.table_bar as usize)
+        .ok_or(MsiError::InvalidBar)?;
+    let table = MEM_OFFSET + bar + msix.table_offset as u64 + entry as u64 * MSIX_ENTRY_SIZE;
+    unsafe {
+        // the table can't be written while MSI-X is disabled on some
+        // devices: enable it with all the vectors masked first
+        let control = access.read(address, msix.offset);
+        access.write(
+            address,
+            msix.offset,
+            control | MSIX_ENABLE | MSIX_FUNCTION_MASK,
+        );
+
+        let entry = table as *mut u32;
+        core::ptr::write_volatile(entry, msi_address());
+        core::ptr::write_volatile(entry.add(1), 0);
+        core::ptr::write_volatile(entry.add(2), vector.as_u8() as u32);
+        let vector_control = core::ptr::read_volatile(entry.add(3));
+        core::ptr::write_volatile(entry.add(3), vector_control & !MSIX_ENTRY_MASKED);
+
+        access.write(
+            address,
+            msix.offset,
+            (control | MSIX_ENABLE) & !MSIX_FUNCTION_MASK,
+        );
+    }
+    disable_intx(access, address);
+    Ok(())
+}
+
+/// Uses MSI-X (with its first entry) if the device has it, and MSI
+/// otherwise.
+pub fn enable_message_interrupts(
+    access: &impl ConfigRegionAccess,
+    address: PciAddress,
+    device: &PciDevice,
+    vector: &Vector,
+) -> Result<(), MsiError> {
+    if device.capabilities.msix.is_some() {
+        enable_msix(access, address, device, 0, vector)
+    } else {
+        enable_msi(access, address, device, vector)
+    }
 }
 
 pub struct PciInfo {
     pub devices: BTreeMap<PciAddress, PciDevice>,
 }
 
-pub struct PciResolver<A>
-where
-    A: ConfigRegionAccess,
-{
-    access: A,
+impl PciInfo {
+    /// Finds where the legacy interrupt of a device goes. Each bridge
+    /// between the device and the root bus rotates the pin by the device
+    /// number, and then the routing table of the root bus gives the GSI.
+    fn route_interrupt(&self, address: PciAddress) -> Option<LegacyInterrupt> {
+        let pin = self.devices.get(&address)?.interrupt_pin;
+        if pin == 0 || pin > 4 {
+            return None;
+        }
+        let mut pin = pin - 1;
+        let mut address = address;
+        // there can't be more bridges than buses
+        for _ in 0..256 {
+            let bridge = self.devices.iter().find(|(bridge, device)| {
+                bridge.segment() == address.segment()
+                    && device.secondary_bus != Some(0)
+                    && device.secondary_bus == Some(address.bus())
+            });
+            match bridge {
+                Some((&bridge, _)) => {
+                    pin = (pin + address.device()) % 4;
+                    address = bridge;
+                }
+                None => return crate::prt::route(address.device(), pin),
+            }
+        }
+        None
+    }
+}
+
+pub struct PciResolver<'a> {
+    access: &'a ConfigAccess,
     info: PciInfo,
+    /// The buses that were already checked, in each segment
+    visited: BTreeSet<(u16, u8)>,
 }
 
-impl<A> PciResolver<A>
-where
-    A: ConfigRegionAccess,
-{
-    pub fn get_info(access: A) -> PciInfo {
+impl<'a> PciResolver<'a> {
+    pub fn get_info(access: &'a ConfigAccess) -> PciInfo {
         let mut resolver = PciResolver {
             access,
             info: PciInfo {
                 devices: BTreeMap::new(),
             },
+            visited: BTreeSet::new(),
         };
 
-        if PciHeader::new(PciAddress::new(0, 0, 0, 0)).has_multiple_functions(&resolver.access) {
-            for i in 0..8 {
-                resolver.check_bus(i);
+        for segment in access.segments() {
+            // the buses behind the host bridge, and then behind the bridges
+            // found there
+            resolver.check_bus(segment.group, segment.start_bus);
+            // other host bridges may have their own buses in the segment
+            for bus in segment.start_bus..=segment.end_bus {
+                resolver.check_bus(segment.group, bus);
             }
-        } else {
-            resolver.check_bus(0);
         }
 
+        let routes: Vec<_> = resolver
+            .info
+            .devices
+            .keys()
+            .map(|&address| (address, resolver.info.route_interrupt(address)))
+            .collect();
+        for (address, route) in routes {
+            if let Some(device) = resolver.info.devices.get_mut(&address) {
+                device.legacy_interrupt = route;
+            }
+        }
         resolver.info
     }
 
-    fn check_bus(&mut self, bus: u8) {
+    fn check_bus(&mut self, segment: u16, bus: u8) {
+        if !self.visited.insert((segment, bus)) {
+            return;
+        }
         for device in 0..32 {
-            let address = PciAddress::new(0, bus, device, 0);
+            let address = PciAddress::new(segment, bus, device, 0);
             if self.access.function_exists(address) {
-                self.check_function(bus, device, 0);
+                self.check_function(address);
                 let header = PciHeader::new(address);
-                if header.has_multiple_functions(&self.access) {
+                if header.has_multiple_functions(self.access) {
                     // The device is multi-function. We need to check the rest.
                     for function in 1..8 {
-                        self.check_function(bus, device, function);
+                        self.check_function(PciAddress::new(segment, bus, device, function));
                     }
                 }
             }
         }
     }
 
-    fn check_function(&mut self, bus: u8, device: u8, function: u8) {
-        let address = PciAddress::new(0, bus, device, function);
-        if self.access.function_exists(address) {
-            let header = PciHeader::new(address);
-            let (vendor_id, device_id) = header.id(&self.access);
-            let (revision, class, sub_class, interface) = header.revision_and_class(&self.access);
-
-            if vendor_id == 0xffff {
-                return;
-            }
-
-            match header.header_type(&self.access) {
-                pci_types::HEADER_TYPE_ENDPOINT => {
-                    let endpoint_header =
-                        EndpointHeader::from_header(header, &self.access).unwrap();
-                    let bars = {
-                        let mut bars = [None; 6];
-
-                        let mut skip_next = false;
-                        for i in 0..6 {
-                            if skip_next {
-                                continue;
-                            }
-
-                            let bar = endpoint_header.bar(i, &self.access);
-                            skip_next = match bar {
-                                Some(Bar::Memory64 { .. }) => true,
-                                _ => false,
-                            };
-                            bars[i as usize] = bar;
-                        }
-
-                        bars
-                    };
-
-                    self.info.devices.insert(
-                        address,
-                        PciDevice {
-                            vendor_id,
-                            device_id,
-                            revision,
-                            class,
-                            sub_class,
-                            interface,
-                            bars,
-                        },
-                    );
+    fn check_function(&mut self, address: PciAddress) {
+        if !self.access.function_exists(address) {
+            return;
+        }
+        let header = PciHeader::new(address);
+        let (vendor_id, device_id) = header.id(self.access);
+        let (revision, class, sub_class, interface) = header.revision_and_class(self.access);
+
+        if vendor_id == 0xffff {
+            return;
+        }
+
+        let mut bars = [None; 6];
+        let mut secondary_bus = None;
+        let header_type = header.header_type(self.access);
+        match header_type {
+            pci_types::HEADER_TYPE_ENDPOINT => {
+                let endpoint_header = EndpointHeader::from_header(header, self.access).unwrap();
+                let mut skip_next = false;
+                for i in 0..6 {
+                    // the upper half of a 64-bit BAR
+                    if skip_next {
+                        skip_next = false;
+                        continue;
+                    }
+
+                    let bar = endpoint_header.bar(i, self.access);
+                    skip_next = matches!(bar, Some(Bar::Memory64 { .. }));
+                    bars[i as usize] = bar;
                 }
-                reserved => panic!("PCI function has reserved header type: {:#x}", reserved),
+            }
+            pci_types::HEADER_TYPE_PCI_PCI_BRIDGE => {
+                // primary, secondary and subordinate bus numbers
+                let buses = unsafe { self.access.read(address, 0x18) };
+                secondary_bus = Some((buses >> 8) as u8);
+            }
+            // CardBus bridges, or reserved header types: only the common
+            // part of the header can be read
+            _ => {}
+        }
+        let interrupt = unsafe { self.access.read(address, 0x3c) };
+        let capabilities = match header_type {
+            pci_types::HEADER_TYPE_ENDPOINT | pci_types::HEADER_TYPE_PCI_PCI_BRIDGE => {
+                Capabilities::parse(|offset| unsafe { self.access.read(address, offset) })
+            }
+            _ => Capabilities::default(),
+        };
+
+        self.info.devices.insert(
+            address,
+            PciDevice {
+                vendor_id,
+                device_id,
+                revision,
+                class,
+                sub_class,
+                interface,
+                bars,
+                interrupt_pin: (interrupt >> 8) as u8,
+                interrupt_line: interrupt as u8,
+                legacy_interrupt: None,
+                capabilities,
+                secondary_bus,
+            },
+        );
+
+        if let Some(bus) = secondary_bus {
+            // unconfigured bridges have 0 here
+            if bus != 0 {
+                self.check_bus(address.segment(), bus);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn capability_list() {
+        let mut config = [0u32; 64];
+        config[1] = STATUS_CAPABILITIES;
+        config[0x34 / 4] = 0x40;
+        // MSI, 64-bit, 4 vectors, then MSI-X
+        config[0x40 / 4] = 0x05 | 0x50 << 8 | 2 << 17 | 1 << 23;
+        // 8 entries, table in BAR 0 at 0x2000, PBA in BAR 0 at 0x3000
+        config[0x50 / 4] = 0x11 | 0x60 << 8 | 7 << 16;
+        config[0x54 / 4] = 0x2000;
+        config[0x58 / 4] = 0x3000;
+        // a list that loops back to the start
+        config[0x60 / 4] = 0x42 | 0x40 << 8;
+
+        let capabilities = Capabilities::parse(|offset| config[offset as usize / 4]);
+        assert!(
+            capabilities.msi
+                == Some(Msi {
+                    offset: 0x40,
+                    is_64bit: true,
+                    per_vector_masking: false,
+                    max_vectors: 4,
+                })
+        );
+        let msix = capabilities.msix.unwrap();
+        assert!(msix.table_size == 8 && msix.table_offset == 0x2000 && msix.pba_offset == 0x3000);
+        assert!(capabilities.express.is_none());
+
+        config[1] = 0;
+        assert!(
+            Capabilities::parse(|offset| config[offset as usize / 4]) == Capabilities::default()
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Data that exists once per CPU
+//!
+//! While the kernel runs, the `GS` base of each CPU points to its [`PerCpu`]
+//! structure. Processes can change their own `GS` base, so the pointer is
+//! moved to `KERNEL_GS_BASE` with `swapgs` when going to ring 3, and back when
+//! an interrupt comes from ring 3 (see [`KernelGs`]).
+
+use crate::gdt::IoBitmap;
+use crate::process::PId;
+use crate::task::{executor::Executor, Task};
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use spin::{Mutex, RwLock};
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+#[repr(C)]
+pub struct PerCpu {
+    /// Pointer to this structure, so that it can be read from `gs:[0]`
+    ///
+    /// Must stay the first field.
+    self_ptr: *const PerCpu,
+    /// 0 for the bootstrap processor, the index in the MADT + 1 for the others
+    pub index: usize,
+    pub apic_id: u32,
+    executor: Mutex<Executor>,
+    /// Tasks that were spawned on this CPU, but that the executor doesn't know
+    /// about yet (it may be locked when they are spawned).
+    new_tasks: Mutex<VecDeque<Task>>,
+    pub current_pid: RwLock<Option<PId>>,
+    /// The executor is running tasks, in an interrupt of `current_pid`: they
+    /// run for the kernel, not for that process (see `process::current`)
+    pub running_tasks: AtomicBool,
+    /// Processes waiting to be run on this CPU
+    pub run_queue: Mutex<VecDeque<PId>>,
+    /// The I/O permission bitmap of the TSS of this CPU
+    io_bitmap: AtomicPtr<IoBitmap>,
+    /// Set by another CPU that unmapped pages (see `memory::shootdown`)
+    flush_tlb: AtomicBool,
+    /// The stack of the CPU when its process was stopped (see
+    /// `process::stop_if_killed`)
+    pub idle_stack: VirtAddr,
+}
+
+// The tasks (in `executor` and `new_tasks`) are not `Send`, so they must
+// stay on the CPU that spawned them: only that CPU touches them, through
+// `spawn` and `run_tasks`.
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    pub fn set_io_bitmap(&self, bitmap: &'static IoBitmap) {
+        self.io_bitmap
+            .store(bitmap as *const _ as *mut _, Ordering::SeqCst);
+    }
+
+    /// Which I/O ports the process running on this CPU can use
+    pub fn io_bitmap(&self) -> Option<&'static IoBitmap> {
+        unsafe { self.io_bitmap.load(Ordering::SeqCst).as_ref() }
+    }
+
+    pub fn request_flush(&self) {
+        self.flush_tlb.store(true, Ordering::SeqCst);
+    }
+
+    pub fn flush_requested(&self) -> bool {
+        self.flush_tlb.load(Ordering::SeqCst)
+    }
+
+    /// Flushes the TLB, if another CPU asked for it. Must be called on this
+    /// CPU.
+    pub fn flush_if_requested(&self) {
+        if self.flush_tlb.load(Ordering::SeqCst) {
+            x86_64::instructions::tlb::flush_all();
+            self.flush_tlb.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Gives a task to the current CPU, that will be the only one to run it.
+pub fn spawn(task: Task) {
+    let cpu = current().expect("Tasks can't be spawned before the per-CPU data is ready");
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        cpu.new_tasks.lock().push_back(task);
+    });
+}
+
+/// Runs the tasks of the current CPU that are ready, if its executor isn't
+/// already doing it.
+pub fn run_tasks() {
+    let cpu = match current() {
+        Some(cpu) => cpu,
+        None => return,
+    };
+    if let Some(mut exec) = cpu.executor.try_lock() {
+        if let Some(mut new_tasks) = cpu.new_tasks.try_lock() {
+            for task in new_tasks.drain(..) {
+                exec.spawn(task);
+            }
+        }
+        let running = cpu.running_tasks.swap(true, Ordering::SeqCst);
+        exec.run_ready_tasks();
+        cpu.running_tasks.store(running, Ordering::SeqCst);
+    }
+}
+
+static READY: AtomicBool = AtomicBool::new(false);
+
+/// The idle stack only runs `halt_loop`, and the interrupts that don't have
+/// their own stack
+const IDLE_STACK_SIZE: usize = 2048;
+
+lazy_static::lazy_static! {
+    static ref CPUS: RwLock<Vec<&'static PerCpu>> = RwLock::new(Vec::new());
+}
+
+/// Sets up the per-CPU data of the current CPU.
+///
+/// Must be called once on each CPU, starting with the bootstrap processor,
+/// once the heap is ready.
+pub fn init(index: usize) -> &'static PerCpu {
+    let idle_stack = alloc::vec![0u8; IDLE_STACK_SIZE].leak();
+    // aligned like after a call
+    let idle_stack = VirtAddr::from_ptr(idle_stack.as_ptr_range().end).align_down(16u64) - 8u64;
+    let cpu = Box::leak(Box::new(PerCpu {
+        self_ptr: core::ptr::null(),
+        index,
+        apic_id: apic_id(),
+        executor: Mutex::new(Executor::new()),
+        new_tasks: Mutex::new(VecDeque::new()),
+        current_pid: RwLock::new(None),
+        running_tasks: AtomicBool::new(false),
+        run_queue: Mutex::new(VecDeque::new()),
+        io_bitmap: AtomicPtr::new(core::ptr::null_mut()),
+        flush_tlb: AtomicBool::new(false),
+        idle_stack,
+    }));
+    cpu.self_ptr = cpu as *const _;
+    // the bootstrap processor loaded its TSS before it had per-CPU data
+    if index == 0 {
+        cpu.set_io_bitmap(crate::gdt::bsp_io_bitmap());
+    }
+    GsBase::write(VirtAddr::from_ptr(cpu.self_ptr));
+    KernelGsBase::write(VirtAddr::zero());
+
+    CPUS.write().push(cpu);
+    READY.store(true, Ordering::SeqCst);
+    cpu
+}
+
+/// The data of the CPU running this function, if [`init`] was called
+pub fn current() -> Option<&'static PerCpu> {
+    if !READY.load(Ordering::SeqCst) {
+        return None;
+    }
+
+    let ptr: *const PerCpu;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, readonly, preserves_flags));
+        ptr.as_ref()
+    }
+}
+
+/// Makes `GS` point to the per-CPU data while an interrupt handler runs
+///
+/// Every interrupt handler must create one before anything else, and keep it
+/// until it returns. If the interrupt came from ring 3, the `GS` base is the
+/// one of the process: it is swapped with `KERNEL_GS_BASE`, and swapped back
+/// when this is dropped, unless the process was killed in the meantime (the
+/// CPU then stays in the kernel).
+pub struct KernelGs {
+    stack: *mut InterruptStackFrame,
+    swapped: bool,
+}
+
+impl KernelGs {
+    pub fn enter(stack: &mut InterruptStackFrame) -> KernelGs {
+        let swapped = stack.code_segment & 3 != 0;
+        if swapped {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+        KernelGs { stack, swapped }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.swapped && !crate::process::stop_if_killed(unsafe { &mut *self.stack }) {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+    }
+}
+
+pub fn all() -> Vec<&'static PerCpu> {
+    CPUS.read().clone()
+}
+
+pub fn count() -> usize {
+    CPUS.read().len()
+}
+
+/// Reads the ID of the local APIC of this CPU with `cpuid`, which works
+/// even if the APIC is not enabled yet.
+fn apic_id() -> u32 {
+    unsafe { core::arch::x86_64::__cpuid(1).ebx >> 24 }
+}
@@ -0,0 +1,431 @@
+//! Pipelines: programs whose output is the input of the next one
+//!
+//! A pipeline is written `objects Os.Identity | filter name=root | show`.
+//! Each stage reads objects of one type and writes objects of another type
+//! (or reads or writes nothing, at the ends of the pipeline). The types are
+//! checked when the stages are connected, and the objects then go through
+//! bounded [`pipe`]s: a stage that writes faster than the next one reads
+//! waits for it.
+//!
+//! Most stages are kernel programs (see [`stages`]), but the `process` stage
+//! runs a program in a process, that reads its input and writes its output
+//! with system calls. A pipeline can be saved as an `Os.Executable`, and then
+//! used as a stage of other pipelines.
+
+use crate::db::{self, types};
+use crate::task::{self, Task};
+use adb::{DbObject, DbValue, TypeId, TypeInfo};
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+use crossbeam_queue::{ArrayQueue, PushError};
+use futures_util::future::{poll_fn, LocalBoxFuture};
+use futures_util::task::AtomicWaker;
+
+pub mod stages;
+
+/// Objects that can wait in a pipe
+pub const CAPACITY: usize = 16;
+/// The input or output type of saved executables that read or write nothing
+pub const NO_TYPE: u64 = u64::MAX;
+/// Saved pipelines can use other saved pipelines, but not that deep
+const MAX_DEPTH: usize = 8;
+
+#[derive(Debug)]
+pub enum PipelineError {
+    Empty,
+    UnknownProgram(String),
+    InvalidArguments {
+        program: String,
+        usage: &'static str,
+    },
+    /// A stage doesn't read what the previous one writes (`None` meaning
+    /// nothing)
+    TypeMismatch {
+        program: String,
+        expected: Option<TypeId>,
+        found: Option<TypeId>,
+    },
+    /// A stage that reads objects is first
+    NoInput(String),
+    /// The first stage needs an input, or the last one has an output
+    Unterminated,
+    /// A saved pipeline uses itself
+    TooDeep,
+    NameTaken(String),
+    NoDatabase,
+    /// The database could not save the pipeline
+    Database,
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = |ty: &Option<TypeId>| match ty {
+            Some(ty) => type_name(*ty),
+            None => String::from("nothing"),
+        };
+        match self {
+            PipelineError::Empty => write!(f, "empty stage"),
+            PipelineError::UnknownProgram(name) => write!(f, "unknown program: {}", name),
+            PipelineError::InvalidArguments { usage, .. } => write!(f, "usage: {}", usage),
+            PipelineError::TypeMismatch {
+                program,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} reads {}, but gets {}",
+                program,
+                name(expected),
+                name(found)
+            ),
+            PipelineError::NoInput(program) => write!(f, "{} needs something to read", program),
+            PipelineError::Unterminated => write!(f, "the pipeline has unconnected ends"),
+            PipelineError::TooDeep => write!(f, "saved pipelines are nested too deep"),
+            PipelineError::NameTaken(name) => write!(f, "{} already exists", name),
+            PipelineError::NoDatabase => write!(f, "the database is not loaded"),
+            PipelineError::Database => write!(f, "the database could not save it"),
+        }
+    }
+}
+
+fn type_name(ty: TypeId) -> String {
+    let db = db::DB.lock();
+    let info = db.as_ref().and_then(|db| db.get_type_info(ty));
+    match info {
+        Some(info) => info.name.clone(),
+        None => alloc::format!("{:#x}", ty.0),
+    }
+}
+
+/// The pipe was closed by the other end
+#[derive(Debug)]
+pub struct Closed;
+
+struct Pipe {
+    queue: ArrayQueue<DbObject>,
+    closed: AtomicBool,
+    /// Waiting for an object
+    reader: AtomicWaker,
+    /// Waiting for some room
+    writer: AtomicWaker,
+}
+
+/// Creates a pipe for objects of a type.
+pub fn pipe(ty: Arc<TypeInfo>) -> (Writer, Reader) {
+    let pipe = Arc::new(Pipe {
+        queue: ArrayQueue::new(CAPACITY),
+        closed: AtomicBool::new(false),
+        reader: AtomicWaker::new(),
+        writer: AtomicWaker::new(),
+    });
+    let writer = Writer {
+        pipe: Arc::clone(&pipe),
+        ty: Arc::clone(&ty),
+    };
+    (writer, Reader { pipe, ty })
+}
+
+/// The end of a pipe that objects are written to
+///
+/// The pipe is closed when it is dropped.
+pub struct Writer {
+    pipe: Arc<Pipe>,
+    ty: Arc<TypeInfo>,
+}
+
+impl Writer {
+    pub fn ty(&self) -> &Arc<TypeInfo> {
+        &self.ty
+    }
+
+    /// Writes an object, waiting for some room in the pipe if it is full.
+    pub async fn send(&self, value: Arc<DbValue>) -> Result<(), Closed> {
+        let mut object = Some(DbObject {
+            type_info: Arc::clone(&self.ty),
+            value,
+        });
+        poll_fn(|cx| loop {
+            if self.pipe.closed.load(Ordering::SeqCst) {
+                return Poll::Ready(Err(Closed));
+            }
+            match self.pipe.queue.push(object.take().unwrap()) {
+                Ok(()) => {
+                    self.pipe.reader.wake();
+                    return Poll::Ready(Ok(()));
+                }
+                Err(PushError(back)) => object = Some(back),
+            }
+            self.pipe.writer.register(cx.waker());
+            // the reader may have made some room meanwhile
+            if self.pipe.queue.is_full() && !self.pipe.closed.load(Ordering::SeqCst) {
+                return Poll::Pending;
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        self.pipe.closed.store(true, Ordering::SeqCst);
+        self.pipe.reader.wake();
+    }
+}
+
+/// The end of a pipe that objects are read from
+///
+/// The writer stops when it is dropped.
+pub struct Reader {
+    pipe: Arc<Pipe>,
+    ty: Arc<TypeInfo>,
+}
+
+impl Reader {
+    pub fn ty(&self) -> &Arc<TypeInfo> {
+        &self.ty
+    }
+
+    /// Waits for the next object. Returns `None` once the writer is done
+    /// and everything was read.
+    pub async fn recv(&mut self) -> Option<DbObject> {
+        poll_fn(|cx| {
+            if let Ok(object) = self.pipe.queue.pop() {
+                self.pipe.writer.wake();
+                return Poll::Ready(Some(object));
+            }
+            self.pipe.reader.register(cx.waker());
+            let closed = self.pipe.closed.load(Ordering::SeqCst);
+            match self.pipe.queue.pop() {
+                Ok(object) => {
+                    self.pipe.writer.wake();
+                    Poll::Ready(Some(object))
+                }
+                Err(_) if closed => Poll::Ready(None),
+                Err(_) => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        self.pipe.closed.store(true, Ordering::SeqCst);
+        self.pipe.writer.wake();
+    }
+}
+
+/// A program that can be used in a pipeline
+pub trait Stage {
+    fn name(&self) -> &str;
+
+    /// What it reads, `None` if it reads nothing
+    fn input_type(&self) -> Option<TypeId>;
+
+    /// What it writes, `None` if it writes nothing
+    fn output_type(&self) -> Option<Arc<TypeInfo>>;
+
+    /// `input` and `output` are given if it has an input or output type.
+    fn run(
+        self: Box<Self>,
+        input: Option<Reader>,
+        output: Option<Writer>,
+    ) -> LocalBoxFuture<'static, ()>;
+}
+
+/// Stages, connected one after the other
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+    /// The text of the stages, to save it
+    text: String,
+}
+
+impl Pipeline {
+    /// Creates the stages of a pipeline, and checks that they can be
+    /// connected.
+    pub fn parse(text: &str) -> Result<Pipeline, PipelineError> {
+        let mut pipeline = Pipeline {
+            stages: Vec::new(),
+            text: String::from(text.trim()),
+        };
+        pipeline.add(text, 0)?;
+        Ok(pipeline)
+    }
+
+    fn add(&mut self, text: &str, depth: usize) -> Result<(), PipelineError> {
+        if depth > MAX_DEPTH {
+            return Err(PipelineError::TooDeep);
+        }
+        for part in text.split('|') {
+            let args: Vec<&str> = part.split_whitespace().collect();
+            let (name, args) = match args.split_first() {
+                Some((name, args)) => (*name, args),
+                None => return Err(PipelineError::Empty),
+            };
+            let input = self.output_type();
+            if let Some(builtin) = stages::find(name) {
+                let stage = (builtin.create)(args, input)?;
+                self.push(stage)?;
+            } else if let Some(saved) = find_saved(name)? {
+                if !args.is_empty() {
+                    return Err(PipelineError::InvalidArguments {
+                        program: String::from(name),
+                        usage: "saved pipelines take no arguments",
+                    });
+                }
+                self.add(&saved, depth + 1)?;
+            } else {
+                return Err(PipelineError::UnknownProgram(String::from(name)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a stage at the end, if it reads what the last stage writes.
+    pub fn push(&mut self, stage: Box<dyn Stage>) -> Result<(), PipelineError> {
+        if !self.stages.is_empty() {
+            let found = self.output_type().map(|ty| ty.id);
+            if stage.input_type() != found {
+                return Err(PipelineError::TypeMismatch {
+                    program: stage.name().to_string(),
+                    expected: stage.input_type(),
+                    found,
+                });
+            }
+        }
+        self.stages.push(stage);
+        Ok(())
+    }
+
+    pub fn input_type(&self) -> Option<TypeId> {
+        self.stages.first().and_then(|stage| stage.input_type())
+    }
+
+    pub fn output_type(&self) -> Option<Arc<TypeInfo>> {
+        self.stages.last().and_then(|stage| stage.output_type())
+    }
+
+    /// Runs all the stages (in their own tasks), and waits for the last one.
+    pub async fn run(mut self) -> Result<(), PipelineError> {
+        if self.input_type().is_some() || self.output_type().is_some() {
+            return Err(PipelineError::Unterminated);
+        }
+        let last = match self.stages.pop() {
+            Some(last) => last,
+            None => return Err(PipelineError::Empty),
+        };
+        let mut input = None;
+        for stage in self.stages {
+            let (writer, reader) = match stage.output_type() {
+                Some(ty) => {
+                    let (writer, reader) = pipe(ty);
+                    (Some(writer), Some(reader))
+                }
+                None => (None, None),
+            };
+            task::spawn(Task::new(stage.run(input.take(), writer)));
+            input = reader;
+        }
+        last.run(input, None).await;
+        Ok(())
+    }
+
+    /// Saves the pipeline as an `Os.Executable`, so that it can be used
+    /// by name.
+    pub fn save(&self, name: &str) -> Result<(), PipelineError> {
+        if stages::find(name).is_some() || find_saved(name)?.is_some() {
+            return Err(PipelineError::NameTaken(String::from(name)));
+        }
+        let type_id = |ty: Option<TypeId>| Arc::new(DbValue::U64(ty.map_or(NO_TYPE, |ty| ty.0)));
+        let object = DbObject {
+            type_info: types::executable(),
+            value: Arc::new(DbValue::Product {
+                fields: alloc::vec![
+                    db::string_value(name),
+                    type_id(self.input_type()),
+                    type_id(self.output_type().map(|ty| ty.id)),
+                    db::string_value(&self.text),
+                ],
+            }),
+        };
+        let mut db = db::DB.lock();
+        let db = db.as_mut().ok_or(PipelineError::NoDatabase)?;
+        db.write_object(object).map_err(|_| PipelineError::Database)
+    }
+}
+
+/// The text of a saved pipeline
+fn find_saved(name: &str) -> Result<Option<String>, PipelineError> {
+    let mut db = db::DB.lock();
+    let db = db.as_mut().ok_or(PipelineError::NoDatabase)?;
+    let found = db
+        .iter_type(types::EXECUTABLE)
+        .find_map(|obj| match *obj.value {
+            DbValue::Product { ref fields } if fields.len() == 4 => {
+                let saved_name = db::as_string(&fields[0])?;
+                if saved_name != name {
+                    return None;
+                }
+                db::as_string(&fields[3])
+            }
+            _ => None,
+        });
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::FutureExt;
+
+    #[test_case]
+    fn pipe_backpressure() {
+        let (writer, mut reader) = pipe(types::string());
+        for _ in 0..CAPACITY {
+            let sent = writer.send(db::string_value("a")).now_or_never();
+            assert!(matches!(sent, Some(Ok(()))));
+        }
+        // the pipe is full: the writer waits
+        assert!(writer.send(db::string_value("b")).now_or_never().is_none());
+        assert!(matches!(reader.recv().now_or_never(), Some(Some(_))));
+        let sent = writer.send(db::string_value("c")).now_or_never();
+        assert!(matches!(sent, Some(Ok(()))));
+
+        // what was written can still be read once the writer is done
+        drop(writer);
+        let mut count = 0;
+        while let Some(Some(_)) = reader.recv().now_or_never() {
+            count += 1;
+        }
+        assert!(count == CAPACITY);
+    }
+
+    #[test_case]
+    fn stage_types() {
+        let stage = |name: &str, ty: Arc<TypeInfo>| {
+            (stages::find(name).unwrap().create)(&[], Some(ty)).unwrap()
+        };
+        let mut pipeline = Pipeline {
+            stages: Vec::new(),
+            text: String::new(),
+        };
+        pipeline.push(stage("show", types::string())).unwrap();
+        assert!(pipeline.input_type() == Some(types::STRING));
+        assert!(pipeline.output_type().is_none());
+
+        let res = pipeline.push(stage("show", types::identity()));
+        assert!(matches!(
+            res,
+            Err(PipelineError::TypeMismatch {
+                expected: Some(types::IDENTITY),
+                found: None,
+                ..
+            })
+        ));
+    }
+}
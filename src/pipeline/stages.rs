@@ -0,0 +1,423 @@
+//! The programs that pipelines are made of
+
+use super::{PipelineError, Reader, Stage, Writer};
+use crate::db::query::{field_matches, find_type, has_field, parse_condition};
+use crate::db::{self, types, DbValueDisplay};
+use crate::memory::MEMORY;
+use crate::println;
+use crate::process::handle::{Object, Rights};
+use crate::process::{self, Process, Stream};
+use adb::{TypeId, TypeInfo};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use futures_util::future::{FutureExt, LocalBoxFuture};
+
+pub struct Builtin {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    /// Creates the stage, for the given input type (the output of the
+    /// previous stage)
+    pub create: fn(&[&str], Option<Arc<TypeInfo>>) -> Result<Box<dyn Stage>, PipelineError>,
+}
+
+pub const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "objects",
+        usage: "objects <type>",
+        help: "writes the objects of a type",
+        create: Objects::create,
+    },
+    Builtin {
+        name: "pci-devices",
+        usage: "pci-devices",
+        help: "writes the PCI devices (Os.Pci.Device)",
+        create: Objects::pci_devices,
+    },
+    Builtin {
+        name: "filter",
+        usage: "filter <field>=<value> ...",
+        help: "only keeps the objects whose fields have these values",
+        create: Filter::create,
+    },
+    Builtin {
+        name: "take",
+        usage: "take <count>",
+        help: "only keeps the first objects",
+        create: Take::create,
+    },
+    Builtin {
+        name: "show",
+        usage: "show",
+        help: "prints the objects",
+        create: Show::create,
+    },
+    Builtin {
+        name: "process",
+        usage: "process <program> <output type, or ->",
+        help: "runs a program, that reads and writes objects with system calls",
+        create: Program::create,
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
+
+fn invalid(name: &str) -> PipelineError {
+    PipelineError::InvalidArguments {
+        program: String::from(name),
+        usage: find(name).map_or("", |builtin| builtin.usage),
+    }
+}
+
+/// Checks that there is something to read.
+fn needs_input(name: &str, input: Option<Arc<TypeInfo>>) -> Result<Arc<TypeInfo>, PipelineError> {
+    input.ok_or_else(|| PipelineError::NoInput(String::from(name)))
+}
+
+/// Writes the objects of a type that are in the database
+struct Objects {
+    ty: Arc<TypeInfo>,
+}
+
+impl Objects {
+    fn create(
+        args: &[&str],
+        _input: Option<Arc<TypeInfo>>,
+    ) -> Result<Box<dyn Stage>, PipelineError> {
+        let name = match args {
+            [name] => name,
+            _ => return Err(invalid("objects")),
+        };
+        let db = db::DB.lock();
+        let db = db.as_ref().ok_or(PipelineError::NoDatabase)?;
+        match find_type(db, name) {
+            Some(ty) => Ok(Box::new(Objects { ty })),
+            None => Err(invalid("objects")),
+        }
+    }
+
+    fn pci_devices(
+        args: &[&str],
+        input: Option<Arc<TypeInfo>>,
+    ) -> Result<Box<dyn Stage>, PipelineError> {
+        if !args.is_empty() {
+            return Err(invalid("pci-devices"));
+        }
+        let id = alloc::format!("{:#x}", types::PCI_DEVICE.0);
+        Objects::create(&[&id], input)
+    }
+}
+
+impl Stage for Objects {
+    fn name(&self) -> &str {
+        "objects"
+    }
+
+    fn input_type(&self) -> Option<TypeId> {
+        None
+    }
+
+    fn output_type(&self) -> Option<Arc<TypeInfo>> {
+        Some(Arc::clone(&self.ty))
+    }
+
+    fn run(
+        self: Box<Self>,
+        _: Option<Reader>,
+        output: Option<Writer>,
+    ) -> LocalBoxFuture<'static, ()> {
+        async move {
+            let output = match output {
+                Some(output) => output,
+                None => return,
+            };
+            // the database can't stay locked while waiting for the next stage
+            let objects: Vec<_> = {
+                let mut db = db::DB.lock();
+                match db.as_mut() {
+                    Some(_) if db::generated::is_generated(self.ty.id) => {
+                        db::generated::read(self.ty.id).into_iter().collect()
+                    }
+                    Some(db) => db.iter_type(self.ty.id).collect(),
+                    None => Vec::new(),
+                }
+            };
+            for object in objects {
+                if output.send(object.value).await.is_err() {
+                    break;
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
+
+/// Keeps the objects that match all the conditions
+struct Filter {
+    ty: Arc<TypeInfo>,
+    conditions: Vec<(String, String)>,
+}
+
+impl Filter {
+    fn create(
+        args: &[&str],
+        input: Option<Arc<TypeInfo>>,
+    ) -> Result<Box<dyn Stage>, PipelineError> {
+        let ty = needs_input("filter", input)?;
+        let mut conditions = Vec::new();
+        for arg in args {
+            match parse_condition(arg) {
+                Some((field, value)) if has_field(&ty, field) => {
+                    conditions.push((String::from(field), String::from(value)));
+                }
+                _ => return Err(invalid("filter")),
+            }
+        }
+        if conditions.is_empty() {
+            return Err(invalid("filter"));
+        }
+        Ok(Box::new(Filter { ty, conditions }))
+    }
+}
+
+impl Stage for Filter {
+    fn name(&self) -> &str {
+        "filter"
+    }
+
+    fn input_type(&self) -> Option<TypeId> {
+        Some(self.ty.id)
+    }
+
+    fn output_type(&self) -> Option<Arc<TypeInfo>> {
+        Some(Arc::clone(&self.ty))
+    }
+
+    fn run(
+        self: Box<Self>,
+        input: Option<Reader>,
+        output: Option<Writer>,
+    ) -> LocalBoxFuture<'static, ()> {
+        async move {
+            let (mut input, output) = match (input, output) {
+                (Some(input), Some(output)) => (input, output),
+                _ => return,
+            };
+            while let Some(object) = input.recv().await {
+                let matches = {
+                    let db = db::DB.lock();
+                    db.as_ref().map_or(false, |db| {
+                        self.conditions.iter().all(|(field, value)| {
+                            field_matches(db, &self.ty, &object.value, field, value)
+                        })
+                    })
+                };
+                if matches && output.send(object.value).await.is_err() {
+                    break;
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
+
+/// Keeps the first objects, and then stops the previous stages
+struct Take {
+    ty: Arc<TypeInfo>,
+    count: usize,
+}
+
+impl Take {
+    fn create(
+        args: &[&str],
+        input: Option<Arc<TypeInfo>>,
+    ) -> Result<Box<dyn Stage>, PipelineError> {
+        let ty = needs_input("take", input)?;
+        match args {
+            [count] => match count.parse() {
+                Ok(count) => Ok(Box::new(Take { ty, count })),
+                Err(_) => Err(invalid("take")),
+            },
+            _ => Err(invalid("take")),
+        }
+    }
+}
+
+impl Stage for Take {
+    fn name(&self) -> &str {
+        "take"
+    }
+
+    fn input_type(&self) -> Option<TypeId> {
+        Some(self.ty.id)
+    }
+
+    fn output_type(&self) -> Option<Arc<TypeInfo>> {
+        Some(Arc::clone(&self.ty))
+    }
+
+    fn run(
+        self: Box<Self>,
+        input: Option<Reader>,
+        output: Option<Writer>,
+    ) -> LocalBoxFuture<'static, ()> {
+        async move {
+            let (mut input, output) = match (input, output) {
+                (Some(input), Some(output)) => (input, output),
+                _ => return,
+            };
+            for _ in 0..self.count {
+                match input.recv().await {
+                    Some(object) if output.send(object.value).await.is_ok() => {}
+                    _ => break,
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
+
+/// Prints the objects
+struct Show {
+    ty: Arc<TypeInfo>,
+}
+
+impl Show {
+    fn create(
+        args: &[&str],
+        input: Option<Arc<TypeInfo>>,
+    ) -> Result<Box<dyn Stage>, PipelineError> {
+        let ty = needs_input("show", input)?;
+        if !args.is_empty() {
+            return Err(invalid("show"));
+        }
+        Ok(Box::new(Show { ty }))
+    }
+}
+
+impl Stage for Show {
+    fn name(&self) -> &str {
+        "show"
+    }
+
+    fn input_type(&self) -> Option<TypeId> {
+        Some(self.ty.id)
+    }
+
+    fn output_type(&self) -> Option<Arc<TypeInfo>> {
+        None
+    }
+
+    fn run(
+        self: Box<Self>,
+        input: Option<Reader>,
+        _: Option<Writer>,
+    ) -> LocalBoxFuture<'static, ()> {
+        async move {
+            let mut input = match input {
+                Some(input) => input,
+                None => return,
+            };
+            let mut count = 0;
+            while let Some(object) = input.recv().await {
+                let db = db::DB.lock();
+                if let Some(db) = db.as_ref() {
+                    let display = DbValueDisplay::new(db, object.value, object.type_info);
+                    println!("{}", display.compact().max_width(16));
+                }
+                count += 1;
+            }
+            println!("{} object(s)", count);
+        }
+        .boxed_local()
+    }
+}
+
+/// Runs a program in a process
+///
+/// Its input is its first handle, and its output the next one (or the first
+/// one if it reads nothing). The stage ends when the process exits.
+struct Program {
+    name: String,
+    code: &'static [u8],
+    input: Option<Arc<TypeInfo>>,
+    output: Option<Arc<TypeInfo>>,
+}
+
+impl Program {
+    fn create(
+        args: &[&str],
+        input: Option<Arc<TypeInfo>>,
+    ) -> Result<Box<dyn Stage>, PipelineError> {
+        let (name, output) = match args {
+            [name, output] => (*name, *output),
+            _ => return Err(invalid("process")),
+        };
+        let code = process::program(name).ok_or_else(|| invalid("process"))?;
+        let output = match output {
+            "-" => None,
+            output => {
+                let db = db::DB.lock();
+                let db = db.as_ref().ok_or(PipelineError::NoDatabase)?;
+                Some(find_type(db, output).ok_or_else(|| invalid("process"))?)
+            }
+        };
+        Ok(Box::new(Program {
+            name: String::from(name),
+            code,
+            input,
+            output,
+        }))
+    }
+}
+
+impl Stage for Program {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn input_type(&self) -> Option<TypeId> {
+        self.input.as_ref().map(|ty| ty.id)
+    }
+
+    fn output_type(&self) -> Option<Arc<TypeInfo>> {
+        self.output.clone()
+    }
+
+    fn run(
+        self: Box<Self>,
+        input: Option<Reader>,
+        output: Option<Writer>,
+    ) -> LocalBoxFuture<'static, ()> {
+        async move {
+            let proc = MEMORY
+                .lock()
+                .as_mut()
+                .and_then(|memory| Process::create(&mut memory.frames, &self.name, self.code));
+            let mut proc = match proc {
+                Some(proc) => proc,
+                None => return println!("{}: not enough memory", self.name),
+            };
+            // the table of a new process has room for them
+            if let Some(input) = input {
+                let stream = Object::Stream(Stream::pipe(input));
+                let _ = proc.insert_handle(stream, Rights::READ);
+            }
+            if let Some(output) = output {
+                let stream = Object::Stream(Stream::output(output));
+                let _ = proc.insert_handle(stream, Rights::WRITE);
+            }
+            let pid = match process::spawn_with_parent(proc, None) {
+                Some(pid) => pid,
+                None => return println!("{}: could not start", self.name),
+            };
+            // its pipes are closed when it exits
+            process::wait(pid).await;
+        }
+        .boxed_local()
+    }
+}
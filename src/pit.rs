@@ -0,0 +1,59 @@
+//! Programmable Interval Timer
+//!
+//! Channel 0 drives the timer interrupt (we keep the BIOS settings): its
+//! interrupts are counted, as the clock of last resort. Channel 2 is used
+//! for busy waiting, when no better clock is available.
+//!
+//! https://wiki.osdev.org/Programmable_Interval_Timer
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+/// Frequency of the PIT oscillator, in Hz
+pub const FREQUENCY: u64 = 1_193_182;
+/// The divisor of channel 0 set by the BIOS: about 18 interrupts per second
+const DIVISOR: u64 = 65536;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts an interrupt of channel 0.
+pub fn on_interrupt() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Interrupts of channel 0 since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn ticks_to_ns(ticks: u64) -> u64 {
+    (ticks as u128 * DIVISOR as u128 * 1_000_000_000 / FREQUENCY as u128) as u64
+}
+
+/// Busy waits for `us` microseconds (at most ~54ms).
+pub fn wait_us(us: u64) {
+    let ticks = (FREQUENCY * us / 1_000_000).clamp(1, 0xffff) as u16;
+
+    let mut speaker: Port<u8> = Port::new(0x61);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel2: Port<u8> = Port::new(0x42);
+
+    unsafe {
+        // Disable the speaker, and stop channel 2 while we program it
+        let control = speaker.read() & !0b11;
+        speaker.write(control);
+
+        // channel 2, lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+        command.write(0b1011_0000);
+        channel2.write(ticks as u8);
+        channel2.write((ticks >> 8) as u8);
+
+        // start counting
+        speaker.write(control | 1);
+        // the output of channel 2 goes high once the count reaches 0
+        while speaker.read() & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        speaker.write(control);
+    }
+}
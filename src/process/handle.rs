@@ -0,0 +1,395 @@
+//! Capabilities: the only way for a process to refer to a kernel object.
+//!
+//! Each process has its own handle table. A handle is only meaningful in
+//! the table it was created in, and it carries a set of rights that are
+//! checked each time it is used. Handles contain a generation counter, so
+//! that a closed handle can't be used to access whatever object later
+//! reuses its slot.
+
+use super::{PId, Stream};
+use crate::driver::grant::DeviceGrant;
+use crate::framebuffer::surface::Surface;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::{BitAnd, BitOr};
+use spin::Mutex;
+
+/// How many handles a process can have open at once
+pub const MAX_HANDLES: usize = 256;
+
+/// What can be done with a handle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rights(u8);
+
+impl Rights {
+    pub const NONE: Rights = Rights(0);
+    pub const READ: Rights = Rights(1 << 0);
+    pub const WRITE: Rights = Rights(1 << 1);
+    /// The handle can be copied (with the same or fewer rights)
+    pub const DUPLICATE: Rights = Rights(1 << 2);
+    /// The handle can be given to another process
+    pub const TRANSFER: Rights = Rights(1 << 3);
+    pub const ALL: Rights = Rights(0b1111);
+
+    /// Rebuilds rights from the value a process gave us (unknown bits are ignored).
+    pub fn from_bits(bits: u64) -> Rights {
+        Rights(bits as u8 & Rights::ALL.0)
+    }
+
+    pub fn contains(self, other: Rights) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Rights {
+    type Output = Rights;
+
+    fn bitor(self, rhs: Rights) -> Rights {
+        Rights(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Rights {
+    type Output = Rights;
+
+    fn bitand(self, rhs: Rights) -> Rights {
+        Rights(self.0 & rhs.0)
+    }
+}
+
+/// Something a handle can point to.
+pub enum Object {
+    Stream(Stream),
+    /// A child process
+    Process(PId),
+    Surface(Surface),
+    /// A device that the process drives
+    Device(DeviceGrant),
+}
+
+impl Object {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Object::Stream(_) => "stream",
+            Object::Process(_) => "process",
+            Object::Surface(_) => "surface",
+            Object::Device(_) => "device",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Handle(u64);
+
+impl Handle {
+    fn new(index: usize, generation: u32) -> Self {
+        Handle(((generation as u64) << 32) | index as u64)
+    }
+
+    /// Rebuilds a handle from the value a process gave us.
+    pub fn from_raw(raw: u64) -> Self {
+        Handle(raw)
+    }
+
+    pub fn as_raw(self) -> u64 {
+        self.0
+    }
+
+    fn index(self) -> usize {
+        (self.0 & 0xffff_ffff) as usize
+    }
+
+    fn generation(self) -> u32 {
+        (self.0 >> 32) as u32
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HandleError {
+    /// This handle doesn't exist (or doesn't exist anymore)
+    Invalid,
+    /// The handle doesn't have the required rights
+    Denied { missing: Rights },
+    /// The handle doesn't point to the expected kind of object
+    WrongKind,
+    /// The table already has [`MAX_HANDLES`] handles
+    TooMany,
+}
+
+struct Slot {
+    generation: u32,
+    entry: Option<Entry>,
+}
+
+/// Duplicated handles share the same object
+struct Entry {
+    object: Arc<Mutex<Object>>,
+    rights: Rights,
+}
+
+/// A handle taken out of a table, to be put in another one (see
+/// [`HandleTable::transfer`])
+pub struct Moving {
+    entry: Entry,
+    /// Where it was, to put it back if it can't be moved
+    from: Handle,
+    /// The rights it keeps in the other table
+    rights: Rights,
+}
+
+pub struct HandleTable {
+    slots: Vec<Slot>,
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        HandleTable {
+            slots: Vec::with_capacity(8),
+        }
+    }
+
+    pub fn insert(&mut self, object: Object, rights: Rights) -> Result<Handle, HandleError> {
+        self.insert_entry(Entry {
+            object: Arc::new(Mutex::new(object)),
+            rights,
+        })
+    }
+
+    /// Whether one more handle can be inserted
+    fn has_room(&self) -> bool {
+        self.slots.len() < MAX_HANDLES || self.slots.iter().any(|slot| slot.entry.is_none())
+    }
+
+    fn insert_entry(&mut self, entry: Entry) -> Result<Handle, HandleError> {
+        let free = self.slots.iter().position(|slot| slot.entry.is_none());
+        let index = match free {
+            Some(index) => index,
+            None if self.slots.len() >= MAX_HANDLES => return Err(HandleError::TooMany),
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    entry: None,
+                });
+                self.slots.len() - 1
+            }
+        };
+        let slot = &mut self.slots[index];
+        slot.entry = Some(entry);
+        Ok(Handle::new(index, slot.generation))
+    }
+
+    fn entry(&self, handle: Handle) -> Result<&Entry, HandleError> {
+        match self.slots.get(handle.index()) {
+            Some(Slot {
+                generation,
+                entry: Some(entry),
+            }) if *generation == handle.generation() => Ok(entry),
+            _ => Err(HandleError::Invalid),
+        }
+    }
+
+    /// Checks that `handle` has at least the `required` rights, and returns the
+    /// object it points to.
+    pub fn get(&self, handle: Handle, required: Rights) -> Result<Arc<Mutex<Object>>, HandleError> {
+        let entry = self.entry(handle)?;
+        if entry.rights.contains(required) {
+            Ok(Arc::clone(&entry.object))
+        } else {
+            Err(HandleError::Denied {
+                missing: Rights(required.0 & !entry.rights.0),
+            })
+        }
+    }
+
+    pub fn rights(&self, handle: Handle) -> Result<Rights, HandleError> {
+        self.entry(handle).map(|entry| entry.rights)
+    }
+
+    /// Closes a handle.
+    pub fn remove(&mut self, handle: Handle) -> Result<(), HandleError> {
+        self.close(handle).map(|_| ())
+    }
+
+    /// Closes a handle, and returns its object, so that it can be dropped
+    /// once the table is unlocked (dropping it may shoot down the TLBs).
+    pub fn close(&mut self, handle: Handle) -> Result<Arc<Mutex<Object>>, HandleError> {
+        self.take(handle, Rights::NONE).map(|entry| entry.object)
+    }
+
+    fn take(&mut self, handle: Handle, required: Rights) -> Result<Entry, HandleError> {
+        self.get(handle, required)?;
+        let slot = &mut self.slots[handle.index()];
+        slot.generation = slot.generation.wrapping_add(1);
+        Ok(slot.entry.take().unwrap())
+    }
+
+    /// Creates a new handle to the same object, with the same or fewer rights.
+    pub fn duplicate(&mut self, handle: Handle, rights: Rights) -> Result<Handle, HandleError> {
+        let object = self.get(handle, Rights::DUPLICATE)?;
+        let current = self.rights(handle)?;
+        self.insert_entry(Entry {
+            object,
+            rights: current & rights,
+        })
+    }
+
+    /// Moves a handle to another table (usually, the one of a child process).
+    ///
+    /// The new handle has the same or fewer rights than the original one. If
+    /// the other table is full, the handle stays in this one.
+    pub fn transfer(
+        &mut self,
+        handle: Handle,
+        to: &mut HandleTable,
+        rights: Rights,
+    ) -> Result<Handle, HandleError> {
+        let mut moving = Some(self.take_transfer(handle, rights)?);
+        let moved = to.insert_moved(&mut moving);
+        if let Some(moving) = moving {
+            self.put_back(moving);
+        }
+        moved
+    }
+
+    /// The first half of [`HandleTable::transfer`], when both tables can't
+    /// be borrowed at once.
+    pub fn take_transfer(&mut self, handle: Handle, rights: Rights) -> Result<Moving, HandleError> {
+        let entry = self.take(handle, Rights::TRANSFER)?;
+        Ok(Moving {
+            entry,
+            from: handle,
+            rights,
+        })
+    }
+
+    /// The second half of [`HandleTable::transfer`]: takes the handle out of
+    /// `moving`, unless the table is full.
+    pub fn insert_moved(&mut self, moving: &mut Option<Moving>) -> Result<Handle, HandleError> {
+        if !self.has_room() {
+            return Err(HandleError::TooMany);
+        }
+        match moving.take() {
+            Some(Moving {
+                mut entry, rights, ..
+            }) => {
+                entry.rights = entry.rights & rights;
+                self.insert_entry(entry)
+            }
+            None => Err(HandleError::Invalid),
+        }
+    }
+
+    /// Puts a handle that could not be moved back where it was, as if
+    /// [`HandleTable::take_transfer`] was never called.
+    pub fn put_back(&mut self, moving: Moving) {
+        let Moving { entry, from, .. } = moving;
+        if let Some(slot) = self.slots.get_mut(from.index()) {
+            if slot.entry.is_none() && slot.generation == from.generation().wrapping_add(1) {
+                slot.generation = from.generation();
+                slot.entry = Some(entry);
+            }
+        }
+    }
+
+    /// Lists all the open handles, with the kind of object they point to.
+    pub fn iter(&self) -> impl Iterator<Item = (Handle, Rights, Arc<Mutex<Object>>)> + '_ {
+        self.slots.iter().enumerate().filter_map(|(i, slot)| {
+            slot.entry.as_ref().map(|entry| {
+                (
+                    Handle::new(i, slot.generation),
+                    entry.rights,
+                    Arc::clone(&entry.object),
+                )
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pid(object: &Arc<Mutex<Object>>) -> Option<PId> {
+        match *object.lock() {
+            Object::Process(pid) => Some(pid),
+            _ => None,
+        }
+    }
+
+    #[test_case]
+    fn closed_handles_stay_invalid() {
+        let mut table = HandleTable::new();
+        let first = table
+            .insert(Object::Process(PId::new(1)), Rights::READ)
+            .unwrap();
+        assert!(table.remove(first) == Ok(()));
+        assert!(table.remove(first) == Err(HandleError::Invalid));
+        // the slot is reused, with another generation
+        let second = table
+            .insert(Object::Process(PId::new(2)), Rights::READ)
+            .unwrap();
+        assert!(second.index() == first.index() && second != first);
+        assert!(table.get(first, Rights::NONE).is_err());
+        assert!(pid(&table.get(second, Rights::READ).unwrap()) == Some(PId::new(2)));
+    }
+
+    #[test_case]
+    fn rights_are_checked() {
+        let mut table = HandleTable::new();
+        let handle = table
+            .insert(Object::Process(PId::new(1)), Rights::READ)
+            .unwrap();
+        assert!(
+            table.get(handle, Rights::READ | Rights::WRITE).err()
+                == Some(HandleError::Denied {
+                    missing: Rights::WRITE
+                })
+        );
+        assert!(table.duplicate(handle, Rights::ALL).is_err());
+        assert!(table.get(Handle::from_raw(7), Rights::NONE).err() == Some(HandleError::Invalid));
+    }
+
+    #[test_case]
+    fn duplicate_and_transfer() {
+        let mut table = HandleTable::new();
+        let mut child = HandleTable::new();
+        let handle = table
+            .insert(Object::Process(PId::new(1)), Rights::ALL)
+            .unwrap();
+        let copy = table
+            .duplicate(handle, Rights::READ | Rights::TRANSFER)
+            .unwrap();
+        assert!(table.rights(copy) == Ok(Rights::READ | Rights::TRANSFER));
+
+        let moved = table.transfer(copy, &mut child, Rights::ALL).unwrap();
+        assert!(table.rights(copy) == Err(HandleError::Invalid));
+        // no more rights than the original handle
+        assert!(child.rights(moved) == Ok(Rights::READ | Rights::TRANSFER));
+        // both handles point to the same object
+        let object = table.get(handle, Rights::NONE).unwrap();
+        assert!(Arc::ptr_eq(
+            &object,
+            &child.get(moved, Rights::NONE).unwrap()
+        ));
+    }
+
+    #[test_case]
+    fn full_tables() {
+        let mut table = HandleTable::new();
+        let mut child = HandleTable::new();
+        let handle = table
+            .insert(Object::Process(PId::new(1)), Rights::ALL)
+            .unwrap();
+        while child.has_room() {
+            let copy = table.duplicate(handle, Rights::ALL).unwrap();
+            table.transfer(copy, &mut child, Rights::ALL).unwrap();
+        }
+        assert!(table.duplicate(handle, Rights::ALL).is_ok());
+        assert!(
+            child.insert(Object::Process(PId::new(2)), Rights::ALL) == Err(HandleError::TooMany)
+        );
+        // the handle stays where it was
+        assert!(table.transfer(handle, &mut child, Rights::READ) == Err(HandleError::TooMany));
+        assert!(table.rights(handle) == Ok(Rights::ALL));
+    }
+}
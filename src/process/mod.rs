@@ -1,30 +1,47 @@
-use crate::gdt::GDT;
+use crate::db;
+use crate::driver::grant::InterruptStream;
+use crate::gdt::{IoBitmap, GDT};
+use crate::identity::{self, SessionId};
+use crate::memory::{self, AddressSpace, MEM_OFFSET};
+use crate::percpu;
+use crate::pipeline;
+use crate::println;
+use crate::security::{self, Access, Denied};
 use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::arch::asm;
-use core::sync::atomic::AtomicU64;
-use core::sync::atomic::Ordering;
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Poll, Waker};
+use futures_util::future::{self, Either};
+use handle::{Handle, HandleError, HandleTable, Object, Rights};
 use x86_64::instructions::interrupts;
-use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
-use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::{FrameAllocator, Size4KiB};
 use x86_64::VirtAddr;
 
-// TODO: use virtual memory better, i.e don't map all
-// processes in the same page table directory
-static STACK_ADDR: AtomicU64 = AtomicU64::new(0x600_000);
-static CODE_ADDR: AtomicU64 = AtomicU64::new(0x400_000);
+pub mod handle;
+
+/// Where the code of each process is, in its own address space
+const CODE_ADDR: u64 = memory::USER_START;
+/// The (single page) stack of each process
+const STACK_ADDR: u64 = memory::USER_START + 0x4000_0000;
+const PAGE_SIZE: usize = 4096;
 
 lazy_static::lazy_static! {
     static ref PROCESSES: spin::RwLock<Vec<u64>> = spin::RwLock::new(
         Vec::with_capacity(8),
     );
 
-    // TODO: one per AP
-    // TODO: process delta queue instead of this (the head being the current proc)
-    static ref CURRENT_PID: spin::RwLock<Option<PId>> = spin::RwLock::new(None);
+    /// Executables that can be started by name
+    static ref PROGRAMS: spin::RwLock<BTreeMap<String, &'static [u8]>> =
+        spin::RwLock::new(BTreeMap::new());
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct PId(usize);
 
 impl PId {
@@ -33,48 +50,450 @@ impl PId {
     }
 }
 
+impl core::fmt::Display for PId {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        core::fmt::Display::fmt(&self.0, f)
+    }
+}
+
+/// The process running on this CPU, if the kernel runs on its behalf
+///
+/// Kernel tasks run in the timer interrupt, whatever process it
+/// interrupted: for them, there is no current process.
 pub fn current() -> Option<PId> {
-    CURRENT_PID.try_read().and_then(|x| *x)
+    let cpu = percpu::current()?;
+    if cpu.running_tasks.load(Ordering::SeqCst) {
+        return None;
+    }
+    cpu.current_pid.try_read().and_then(|x| *x)
 }
 
-pub fn spawn(proc: Process<'_>) -> Option<PId> {
-    if let Some(ref mut proc_list) = PROCESSES.try_write() {
+/// Starts the next process of the run queue of this CPU, if
+/// no process is running yet.
+pub fn schedule() {
+    let cpu = match percpu::current() {
+        Some(cpu) => cpu,
+        None => return,
+    };
+    if current().is_some() {
+        return;
+    }
+
+    // TODO: process delta queue (the head being the current proc)
+    let next = cpu
+        .run_queue
+        .try_lock()
+        .and_then(|mut queue| queue.pop_front());
+    if let Some(pid) = next {
+        switch_to(pid);
+    }
+}
+
+/// Starts a new process as a child of the current one, if it is allowed to
+/// (see [`spawn_with_parent`]).
+pub fn spawn(proc: Process) -> Option<PId> {
+    spawn_with_parent(proc, current())
+}
+
+/// Starts a new process, if the current process is allowed to.
+///
+/// The new process belongs to the same session as its parent, and the
+/// parent gets a handle to its child. Processes started by the kernel (with
+/// no parent) belong to the console session. Kernel tasks pass `None`: they
+/// may run in an interrupt of an unrelated process.
+///
+/// The process is added to the run queue of the least busy CPU.
+pub fn spawn_with_parent(mut proc: Process, parent: Option<PId>) -> Option<PId> {
+    if security::check_run(&proc.name).is_err() {
+        return None;
+    }
+
+    proc.parent = parent;
+    proc.session = match parent {
+        Some(parent) => get(parent).and_then(|p| p.session),
+        None => identity::console_session(),
+    };
+
+    let pid = if let Some(ref mut proc_list) = PROCESSES.try_write() {
         let boxed = Box::new(proc);
         let ptr = Box::leak(boxed) as *const _ as u64;
         proc_list.push(ptr);
-        Some(PId(proc_list.len() - 1))
+        PId(proc_list.len() - 1)
     } else {
-        None
+        return None;
+    };
+
+    if let Some(parent) = parent.and_then(get) {
+        // the child runs anyway, the parent just can't refer to it
+        if parent
+            .insert_handle(Object::Process(pid), Rights::ALL)
+            .is_err()
+        {
+            println!("{}: no handle for the new process {}", parent.name, pid);
+        }
+    }
+
+    let cpu = percpu::all()
+        .into_iter()
+        .min_by_key(|cpu| cpu.run_queue.lock().len());
+    if let Some(cpu) = cpu {
+        interrupts::without_interrupts(|| cpu.run_queue.lock().push_back(pid));
     }
+    Some(pid)
 }
 
 pub fn switch_to(pid: PId) {
     if let Some(proc_list) = PROCESSES.try_read() {
-        if let Some(mut curr_pid) = CURRENT_PID.try_write() {
-            *curr_pid = Some(pid);
+        if let Some(cpu) = percpu::current() {
+            if let Some(mut curr_pid) = cpu.current_pid.try_write() {
+                *curr_pid = Some(pid);
+            }
         }
 
-        let proc = unsafe { &*(proc_list[pid.0] as *mut Process<'static>) };
+        let proc = unsafe { &*(proc_list[pid.0] as *mut Process) };
+        if let Some(bitmap) = percpu::current().and_then(|cpu| cpu.io_bitmap()) {
+            // only the ports of the previous process were allowed
+            bitmap.deny_allowed();
+            proc.allow_io_ports(bitmap);
+        }
+        proc.space.lock().activate();
         proc.switch();
     }
 }
 
-pub fn get_mut<'a>(pid: PId) -> Option<&'a mut Process<'a>> {
+/// All the processes that were started, including the killed ones
+pub fn all() -> Vec<PId> {
+    let count = PROCESSES.read().len();
+    (0..count).map(PId).collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Running,
+    /// Waiting in a run queue
+    Ready,
+    Killed,
+}
+
+pub fn status(pid: PId) -> Option<Status> {
+    if get(pid)?.is_killed() {
+        return Some(Status::Killed);
+    }
+    let running = percpu::all().iter().any(|cpu| {
+        cpu.current_pid
+            .try_read()
+            .map_or(false, |p| *p == Some(pid))
+    });
+    Some(if running {
+        Status::Running
+    } else {
+        Status::Ready
+    })
+}
+
+/// Stops a process: it is removed from the run queues, its handles are
+/// closed, and its memory is freed once no CPU runs it anymore.
+///
+/// A process that is running is stopped first: the other CPUs are
+/// interrupted, and the interrupts never go back to a killed process (see
+/// [`stop_if_killed`]). A process in a system call stops when the call
+/// returns: the calls that wait give up (see [`block_on`]).
+pub fn kill(pid: PId) -> bool {
+    let proc = match get(pid) {
+        Some(proc) => proc,
+        None => return false,
+    };
+    if proc.killed.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    for cpu in percpu::all() {
+        interrupts::without_interrupts(|| cpu.run_queue.lock().retain(|&p| p != pid));
+    }
+    memory::shootdown();
+    // a system call may still use the table: the objects are closed outside
+    // of its lock
+    let handles = interrupts::without_interrupts(|| {
+        core::mem::replace(&mut *proc.handles.lock(), HandleTable::new())
+    });
+    drop(handles);
+    security::forget_process(pid);
+    let waiting = interrupts::without_interrupts(|| proc.waiting.lock().take());
+    for waker in waiting.into_iter().flatten() {
+        waker.wake();
+    }
+    // after the shootdown, a CPU that still runs the process (or one of its
+    // system calls) frees it when it stops it
+    free_if_stopped(pid);
+    true
+}
+
+/// Waits until a process is killed (its handles are closed by then).
+pub async fn wait(pid: PId) {
+    future::poll_fn(|cx| {
+        let proc = match get(pid) {
+            Some(proc) => proc,
+            None => return Poll::Ready(()),
+        };
+        interrupts::without_interrupts(|| match *proc.waiting.lock() {
+            Some(ref mut waiting) => {
+                if !waiting.iter().any(|waker| waker.will_wake(cx.waker())) {
+                    waiting.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            None => Poll::Ready(()),
+        })
+    })
+    .await
+}
+
+/// Frees the address space of a killed process, if no CPU runs it anymore.
+fn free_if_stopped(pid: PId) {
+    let proc = match get(pid) {
+        Some(proc) if proc.is_killed() => proc,
+        _ => return,
+    };
+    let running = percpu::all()
+        .iter()
+        .any(|cpu| *cpu.current_pid.read() == Some(pid));
+    if running {
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        if let Some(memory) = memory::MEMORY.lock().as_mut() {
+            proc.space.lock().free(&mut memory.frames);
+        }
+    });
+}
+
+/// Waits for `future` in a system call, like [`crate::task::block_on`], but
+/// gives up (and returns `None`) if the current process is killed meanwhile.
+pub fn block_on<F: Future>(future: F) -> Option<F::Output> {
+    // `crate::task::block_on` polls again after each interrupt
+    let killed = future::poll_fn(|_| match current().and_then(get) {
+        Some(proc) if proc.is_killed() => Poll::Ready(()),
+        _ => Poll::Pending,
+    });
+    match crate::task::block_on(future::select(Box::pin(future), Box::pin(killed))) {
+        Either::Left((output, _)) => Some(output),
+        Either::Right(_) => None,
+    }
+}
+
+/// If the process that an interrupt came from was killed, makes the
+/// interrupt return to the kernel instead, and returns `true`.
+///
+/// The CPU then waits for the next process to schedule, with the page table
+/// of the kernel.
+pub fn stop_if_killed(stack: &mut InterruptStackFrame) -> bool {
+    let cpu = match percpu::current() {
+        Some(cpu) => cpu,
+        None => return false,
+    };
+    let pid = match current() {
+        Some(pid) if get(pid).map_or(false, Process::is_killed) => pid,
+        _ => return false,
+    };
+    *cpu.current_pid.write() = None;
+    memory::activate_kernel();
+    free_if_stopped(pid);
+    let selectors = &GDT.1;
+    unsafe {
+        stack.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(idle as usize as u64);
+            frame.code_segment = selectors.code_selector.0 as u64;
+            // interrupts enabled
+            frame.cpu_flags = 0x202;
+            frame.stack_pointer = cpu.idle_stack;
+            frame.stack_segment = selectors.data_selector.0 as u64;
+        });
+    }
+    true
+}
+
+/// Where a CPU goes when its process is stopped
+extern "C" fn idle() -> ! {
+    crate::halt_loop()
+}
+
+/// Makes an executable available to [`program`].
+pub fn register_program(name: &str, code: &'static [u8]) {
+    PROGRAMS.write().insert(String::from(name), code);
+}
+
+pub fn program(name: &str) -> Option<&'static [u8]> {
+    PROGRAMS.read().get(name).copied()
+}
+
+pub fn programs() -> Vec<String> {
+    PROGRAMS.read().keys().cloned().collect()
+}
+
+/// A process, for the fields that other CPUs may access at the same time
+/// (the handles, and whether it was killed)
+pub fn get<'a>(pid: PId) -> Option<&'a Process> {
+    let proc_list = PROCESSES.try_read()?;
+    let proc = unsafe { &*(*proc_list.get(pid.0)? as *const Process) };
+    Some(proc)
+}
+
+pub fn get_mut<'a>(pid: PId) -> Option<&'a mut Process> {
     if let Some(proc_list) = PROCESSES.try_read() {
-        let proc = unsafe { &mut *(proc_list[pid.0] as *mut Process<'a>) };
+        let proc = unsafe { &mut *(*proc_list.get(pid.0)? as *mut Process) };
         Some(proc)
     } else {
         None
     }
 }
 
-pub struct Stream<'a> {
-    iter: adb::TypeIterator<'a, alloc::vec::Vec<u8>>,
+/// Where the objects of a stream come from
+enum Source {
+    /// The objects of the database, read one after the other (the database
+    /// is only locked while an object is read)
+    Db { ty: adb::TypeId, next: usize },
+    /// See `crate::db::generated`
+    Generated(adb::TypeId),
+    /// See `crate::db::events`
+    Events(db::events::Subscription),
+    /// See `crate::driver::grant`
+    Interrupts(InterruptStream),
+    /// The output of the previous stage of a pipeline
+    Pipe(pipeline::Reader),
+    /// The input of the next stage of a pipeline: it can only be written
+    Output(pipeline::Writer),
 }
 
-impl<'a> Stream<'a> {
+/// Why a stream couldn't be opened, read or written
+#[derive(Debug)]
+pub enum StreamError {
+    Denied(Denied),
+    /// The database isn't loaded
+    NoDatabase,
+    UnknownType,
+    /// The object doesn't have the type of the stream
+    WrongType,
+    /// The database could not write the object
+    Database,
+    /// Objects can't be written to this stream
+    ReadOnly,
+    /// Objects can't be read from this stream
+    WriteOnly,
+    /// The next stage of the pipeline stopped reading
+    Closed,
+    /// Nothing was read from the stream yet
+    NothingRead,
+    /// The process was killed while it waited
+    Killed,
+    /// The process has too many handles for a new stream
+    TooManyHandles,
+}
+
+impl From<Denied> for StreamError {
+    fn from(denied: Denied) -> Self {
+        StreamError::Denied(denied)
+    }
+}
+
+pub struct Stream {
+    source: Source,
+    /// The last object read, that the process can write to another stream
+    last: Option<adb::DbObject>,
+}
+
+impl Stream {
     pub fn ty(&self) -> adb::TypeId {
-        self.iter.ty().id
+        match self.source {
+            Source::Db { ty, .. } => ty,
+            Source::Generated(ty) => ty,
+            Source::Events(ref events) => events.ty(),
+            Source::Interrupts(_) => db::types::PCI_INTERRUPT,
+            Source::Pipe(ref reader) => reader.ty().id,
+            Source::Output(ref writer) => writer.ty().id,
+        }
+    }
+
+    fn new(source: Source) -> Stream {
+        Stream { source, last: None }
+    }
+
+    /// A stream of the interrupts of a device.
+    pub fn interrupts(interrupts: InterruptStream) -> Stream {
+        Stream::new(Source::Interrupts(interrupts))
+    }
+
+    /// A stream of the objects written by the previous stage of a pipeline.
+    pub fn pipe(reader: pipeline::Reader) -> Stream {
+        Stream::new(Source::Pipe(reader))
+    }
+
+    /// A stream that gives objects to the next stage of a pipeline.
+    pub fn output(writer: pipeline::Writer) -> Stream {
+        Stream::new(Source::Output(writer))
+    }
+
+    /// The last object that was read
+    pub fn last(&self) -> Option<adb::DbObject> {
+        self.last.as_ref().map(|object| adb::DbObject {
+            type_info: Arc::clone(&object.type_info),
+            value: Arc::clone(&object.value),
+        })
+    }
+
+    /// Reads the next object of this stream.
+    ///
+    /// Streams of generated types never end. Streams of events (or pipes)
+    /// wait for the next event (with interrupts enabled).
+    pub fn read(&mut self) -> Result<Option<adb::DbObject>, StreamError> {
+        let object = self.read_next()?;
+        self.last = object.as_ref().map(|object| adb::DbObject {
+            type_info: Arc::clone(&object.type_info),
+            value: Arc::clone(&object.value),
+        });
+        Ok(object)
+    }
+
+    fn read_next(&mut self) -> Result<Option<adb::DbObject>, StreamError> {
+        security::check(self.ty(), Access::Read)?;
+        match self.source {
+            Source::Db { ty, ref mut next } => {
+                let mut db = db::DB.lock();
+                let db = db.as_mut().ok_or(StreamError::NoDatabase)?;
+                let object = db.iter_type(ty).nth(*next);
+                if object.is_some() {
+                    *next += 1;
+                }
+                Ok(object)
+            }
+            Source::Generated(ty) => Ok(db::generated::read(ty)),
+            Source::Events(ref mut events) => {
+                Ok(Some(block_on(events.next()).ok_or(StreamError::Killed)?))
+            }
+            Source::Interrupts(ref mut interrupts) => Ok(Some(
+                block_on(interrupts.next()).ok_or(StreamError::Killed)?,
+            )),
+            Source::Pipe(ref mut reader) => block_on(reader.recv()).ok_or(StreamError::Killed),
+            Source::Output(_) => Err(StreamError::WriteOnly),
+        }
+    }
+
+    /// Writes a new object to the database, or to the next stage of a
+    /// pipeline (waiting for it to have some room).
+    pub fn write(&self, obj: adb::DbObject) -> Result<(), StreamError> {
+        if obj.type_info.id != self.ty() {
+            return Err(StreamError::WrongType);
+        }
+        match self.source {
+            Source::Db { .. } => {
+                security::check(self.ty(), Access::Write)?;
+                let mut db = db::DB.lock();
+                let db = db.as_mut().ok_or(StreamError::NoDatabase)?;
+                db.write_object(obj).map_err(|_| StreamError::Database)
+            }
+            Source::Output(ref writer) => block_on(writer.send(obj.value))
+                .ok_or(StreamError::Killed)?
+                .map_err(|_| StreamError::Closed),
+            _ => Err(StreamError::ReadOnly),
+        }
     }
 }
 
@@ -90,60 +509,156 @@ struct State {
     rbp: u64,
 }
 
-pub struct Process<'a> {
+pub struct Process {
+    pub name: String,
+    parent: Option<PId>,
+    session: Option<SessionId>,
+    /// Shared with the objects that are mapped in it (grants and surfaces),
+    /// that unmap themselves when they are dropped
+    space: Arc<spin::Mutex<AddressSpace>>,
+    /// The I/O ports of the devices granted to the process. The scheduler
+    /// reads them in interrupts: they are only locked with interrupts
+    /// disabled.
+    io_ports: Arc<spin::Mutex<Vec<(u16, u16)>>>,
     stack_addr: u64,
     code_addr: u64,
-    pub streams: alloc::vec::Vec<Stream<'a>>,
+    /// Locked with interrupts disabled, since tasks may kill the process
+    /// during one of its system calls (see [`Process::with_handles`])
+    handles: spin::Mutex<HandleTable>,
     state: State,
+    killed: AtomicBool,
+    /// Tasks waiting for the process to be killed (see [`wait`]), or `None`
+    /// once it was. Locked with interrupts disabled, like the handles.
+    waiting: spin::Mutex<Option<Vec<Waker>>>,
 }
 
-impl<'a> Process<'a> {
+impl Process {
+    /// Loads a new process, in its own address space. Returns `None` if
+    /// there is not enough memory.
     pub fn create(
-        mapper: &mut impl Mapper<Size4KiB>,
         frame_alloc: &mut impl FrameAllocator<Size4KiB>,
-        asm: &[u8],
-    ) -> Process<'a> {
-        const PAGE_SIZE: u64 = 1024 * 4;
-        let frame = frame_alloc.allocate_frame().unwrap();
-        let stack = STACK_ADDR.fetch_add(PAGE_SIZE, Ordering::SeqCst);
-        let page = Page::containing_address(VirtAddr::new(stack));
-        let flags =
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
-        unsafe {
-            mapper
-                .map_to(page, frame, flags, frame_alloc)
-                .unwrap()
-                .flush();
-        }
-
-        let frame = frame_alloc.allocate_frame().unwrap();
-        let code = CODE_ADDR.fetch_add(PAGE_SIZE, Ordering::SeqCst);
-        let page = Page::containing_address(VirtAddr::new(code));
-        unsafe {
-            mapper
-                .map_to(page, frame, flags, frame_alloc)
-                .unwrap()
-                .flush();
-        }
+        name: &str,
+        code: &[u8],
+    ) -> Option<Process> {
+        let mut space = AddressSpace::new(frame_alloc)?;
+        let stack = frame_alloc.allocate_frame()?;
+        space
+            .map_page(frame_alloc, VirtAddr::new(STACK_ADDR), stack)
+            .ok()?;
 
-        unsafe {
-            let code = code as *mut u8;
-            for (i, op) in asm.iter().enumerate() {
-                core::ptr::write(code.add(i), *op);
+        for (i, chunk) in code.chunks(PAGE_SIZE).enumerate() {
+            let frame = frame_alloc.allocate_frame()?;
+            let addr = VirtAddr::new(CODE_ADDR + (i * PAGE_SIZE) as u64);
+            space.map_page(frame_alloc, addr, frame).ok()?;
+            // the address space is not loaded: the code is written through
+            // the mapping of the physical memory
+            let page = (MEM_OFFSET + frame.start_address().as_u64()) as *mut u8;
+            unsafe {
+                core::ptr::write_bytes(page, 0, PAGE_SIZE);
+                core::ptr::copy_nonoverlapping(chunk.as_ptr(), page, chunk.len());
             }
         }
 
-        Process {
-            stack_addr: stack,
-            code_addr: code,
-            streams: alloc::vec::Vec::with_capacity(8),
+        Some(Process {
+            name: String::from(name),
+            parent: None,
+            session: None,
+            space: Arc::new(spin::Mutex::new(space)),
+            io_ports: Arc::new(spin::Mutex::new(Vec::new())),
+            stack_addr: STACK_ADDR + PAGE_SIZE as u64,
+            code_addr: CODE_ADDR,
+            handles: spin::Mutex::new(HandleTable::new()),
             state: State::default(),
-        }
+            killed: AtomicBool::new(false),
+            waiting: spin::Mutex::new(Some(Vec::new())),
+        })
     }
 
-    pub fn open_stream(&mut self, db: &'a mut adb::Db<alloc::vec::Vec<u8>>, ty: adb::TypeId) {
-        self.streams.push(Stream {
-            iter: db.iter_type(ty),
+    /// Where memory is mapped for this process
+    pub fn space(&self) -> &Arc<spin::Mutex<AddressSpace>> {
+        &self.space
+    }
+
+    /// The I/O ports the process can use (the first one and how many)
+    pub fn io_ports(&self) -> &Arc<spin::Mutex<Vec<(u16, u16)>>> {
+        &self.io_ports
+    }
+
+    /// The process that started this one, if it wasn't the kernel
+    pub fn parent(&self) -> Option<PId> {
+        self.parent
+    }
+
+    pub fn session(&self) -> Option<SessionId> {
+        self.session
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::SeqCst)
+    }
+
+    /// Runs `f` on the handle table, or returns `None` if the process was
+    /// killed (its handles are closed).
+    pub fn with_handles<R>(&self, f: impl FnOnce(&mut HandleTable) -> R) -> Option<R> {
+        interrupts::without_interrupts(|| {
+            let mut handles = self.handles.lock();
+            // checked with the lock held: `kill` closes the handles after
+            // setting the flag
+            if self.is_killed() {
+                return None;
+            }
+            Some(f(&mut handles))
+        })
+    }
+
+    /// Checks that `handle` has at least the `required` rights, and returns
+    /// the object it points to.
+    pub fn handle(
+        &self,
+        handle: Handle,
+        required: Rights,
+    ) -> Result<Arc<spin::Mutex<Object>>, HandleError> {
+        self.with_handles(|handles| handles.get(handle, required))
+            .unwrap_or(Err(HandleError::Invalid))
+    }
+
+    /// Gives a new handle to the process, unless it was killed (the handle
+    /// is then [`HandleError::Invalid`]) or has too many.
+    pub fn insert_handle(&self, object: Object, rights: Rights) -> Result<Handle, HandleError> {
+        self.with_handles(|handles| handles.insert(object, rights))
+            .unwrap_or(Err(HandleError::Invalid))
+    }
+
+    /// Opens a new stream and returns a handle to it.
+    pub fn open_stream(&mut self, ty: adb::TypeId) -> Result<Handle, StreamError> {
+        security::check(ty, Access::See)?;
+        let source = if db::generated::is_generated(ty) {
+            Source::Generated(ty)
+        } else if let Some(events) = db::events::subscribe(ty) {
+            Source::Events(events)
+        } else {
+            let db = db::DB.lock();
+            let db = db.as_ref().ok_or(StreamError::NoDatabase)?;
+            if db.get_type_info(ty).is_none() {
+                return Err(StreamError::UnknownType);
+            }
+            Source::Db { ty, next: 0 }
+        };
+        let stream = Stream::new(source);
+        self.insert_handle(Object::Stream(stream), Rights::ALL)
+            .map_err(|err| match err {
+                HandleError::TooMany => StreamError::TooManyHandles,
+                _ => StreamError::Killed,
+            })
+    }
+
+    /// Lets the process use the I/O ports of the devices it was granted
+    /// (with the right to write to them).
+    fn allow_io_ports(&self, bitmap: &IoBitmap) {
+        interrupts::without_interrupts(|| {
+            for &(first, count) in self.io_ports.lock().iter() {
+                bitmap.allow(first, count);
+            }
         });
     }
 
@@ -154,11 +669,12 @@ impl<'a> Process<'a> {
         unsafe {
             interrupts::disable();
 
+            // the per-CPU data goes to KERNEL_GS_BASE, and the process gets
+            // the GS base that was there (see `percpu::KernelGs`)
             asm!(
                 "mov ds, ax",
                 "mov es, ax",
                 "mov fs, ax",
-                "mov gs, ax",
 
                 "push rax",
                 "push rsi",
@@ -170,6 +686,7 @@ impl<'a> Process<'a> {
 
                 "push rcx",
                 "push rdx",
+                "swapgs",
                 "iretq",
                 in("rax") data_sel,
                 in("rsi") self.stack_addr,
@@ -0,0 +1,382 @@
+//! PCI interrupt routing
+//!
+//! The GSI used by the legacy interrupt (INTx) of a PCI device is described
+//! by the `_PRT` objects of the ACPI namespace. There is no AML interpreter
+//! here: routing tables are only found if they are plain packages in the
+//! DSDT or the SSDTs (as on QEMU). Each entry gives a GSI directly, or names
+//! a link device with a single interrupt in its `_PRS` or `_CRS`. Links that
+//! the firmware expects us to program (with `_SRS`) are not supported.
+//!
+//! The tables are used for the devices of the root buses. Behind a bridge,
+//! the pins are swizzled as the PCI-to-PCI bridge specification says (see
+//! [`crate::pci::PciInfo`]).
+
+use crate::memory::{AcpiHandler, MEM_OFFSET};
+use crate::println;
+use acpi::AcpiTables;
+use alloc::vec::Vec;
+
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const BUFFER_OP: u8 = 0x11;
+const EXT_OP_PREFIX: u8 = 0x5b;
+const DEVICE_OP: u8 = 0x82;
+
+/// Where the legacy interrupt of a device goes, and how it is signaled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LegacyInterrupt {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level: bool,
+}
+
+/// What a routing table entry points to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Source {
+    Gsi(u32),
+    /// The last segment of the name of a link device
+    Link([u8; 4]),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Entry {
+    /// The device number on the bus
+    device: u8,
+    /// 0 to 3 for INTA# to INTD#
+    pin: u8,
+    source: Source,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Route {
+    device: u8,
+    pin: u8,
+    interrupt: LegacyInterrupt,
+}
+
+static ROUTES: spin::Mutex<Vec<Route>> = spin::Mutex::new(Vec::new());
+
+/// Reads the routing tables of the root buses.
+pub fn init(tables: &AcpiTables<AcpiHandler>) {
+    let aml: Vec<&[u8]> = tables
+        .dsdt
+        .iter()
+        .chain(tables.ssdts.iter())
+        .map(|table| unsafe {
+            core::slice::from_raw_parts(
+                (MEM_OFFSET + table.address as u64) as *const u8,
+                table.length as usize,
+            )
+        })
+        .collect();
+    let routes = routes(&aml);
+    if routes.is_empty() {
+        println!("No PCI routing table: legacy PCI interrupts can't be used");
+    }
+    *ROUTES.lock() = routes;
+}
+
+fn routes(aml: &[&[u8]]) -> Vec<Route> {
+    let entries = aml.iter().flat_map(|bytes| routing_tables(bytes));
+    entries
+        .filter_map(|entry| {
+            let interrupt = match entry.source {
+                // the default for PCI interrupts
+                Source::Gsi(gsi) => LegacyInterrupt {
+                    gsi,
+                    active_low: true,
+                    level: true,
+                },
+                Source::Link(name) => aml.iter().find_map(|bytes| link(bytes, name))?,
+            };
+            Some(Route {
+                device: entry.device,
+                pin: entry.pin,
+                interrupt,
+            })
+        })
+        .collect()
+}
+
+/// The interrupt of a pin of a device on a root bus
+pub fn route(device: u8, pin: u8) -> Option<LegacyInterrupt> {
+    ROUTES
+        .lock()
+        .iter()
+        .find(|route| route.device == device && route.pin == pin)
+        .map(|route| route.interrupt)
+}
+
+/// Decodes a package length, and returns it with the number of bytes it
+/// was encoded on.
+fn pkg_length(bytes: &[u8], pos: usize) -> Option<(usize, usize)> {
+    let lead = *bytes.get(pos)?;
+    let count = (lead >> 6) as usize;
+    if count == 0 {
+        return Some(((lead & 0x3f) as usize, 1));
+    }
+    let mut length = (lead & 0x0f) as usize;
+    for i in 0..count {
+        length |= (*bytes.get(pos + 1 + i)? as usize) << (4 + 8 * i);
+    }
+    Some((length, count + 1))
+}
+
+/// Decodes a constant integer, and returns it with its size.
+fn integer(bytes: &[u8], pos: usize) -> Option<(u64, usize)> {
+    let size = match *bytes.get(pos)? {
+        0x00 => return Some((0, 1)),
+        0x01 => return Some((1, 1)),
+        0xff => return Some((u64::MAX, 1)),
+        0x0a => 1,
+        0x0b => 2,
+        0x0c => 4,
+        0x0e => 8,
+        _ => return None,
+    };
+    let value = bytes
+        .get(pos + 1..pos + 1 + size)?
+        .iter()
+        .rev()
+        .fold(0, |value, &byte| (value << 8) | byte as u64);
+    Some((value, size + 1))
+}
+
+fn name_seg(bytes: &[u8], pos: usize) -> Option<[u8; 4]> {
+    let seg = bytes.get(pos..pos + 4)?;
+    let valid = seg.iter().enumerate().all(|(i, &c)| match c {
+        b'A'..=b'Z' | b'_' => true,
+        b'0'..=b'9' => i > 0,
+        _ => false,
+    });
+    if valid {
+        Some([seg[0], seg[1], seg[2], seg[3]])
+    } else {
+        None
+    }
+}
+
+/// Decodes a name, and returns its last segment and its size.
+fn name_string(bytes: &[u8], pos: usize) -> Option<([u8; 4], usize)> {
+    let mut start = pos;
+    while matches!(bytes.get(start), Some(b'\\') | Some(b'^')) {
+        start += 1;
+    }
+    let segs = match *bytes.get(start)? {
+        0x2e => {
+            start += 1;
+            2
+        }
+        0x2f => {
+            start += 2;
+            *bytes.get(start - 1)? as usize
+        }
+        _ => 1,
+    };
+    if segs == 0 {
+        return None;
+    }
+    let last = start + 4 * (segs - 1);
+    for i in 0..segs {
+        name_seg(bytes, start + 4 * i)?;
+    }
+    Some((name_seg(bytes, last)?, last + 4 - pos))
+}
+
+/// Decodes the start of a package, and returns the position of its first
+/// element, its end, and how many elements it has.
+fn package(bytes: &[u8], pos: usize) -> Option<(usize, usize, u8)> {
+    if *bytes.get(pos)? != PACKAGE_OP {
+        return None;
+    }
+    let (length, size) = pkg_length(bytes, pos + 1)?;
+    let end = pos + 1 + length;
+    let count = *bytes.get(pos + 1 + size)?;
+    if end > bytes.len() {
+        return None;
+    }
+    Some((pos + 2 + size, end, count))
+}
+
+/// A `Package { Address, Pin, Source, SourceIndex }`
+fn entry(bytes: &[u8], pos: usize) -> Option<(Entry, usize)> {
+    let (mut pos, end, count) = package(bytes, pos)?;
+    if count != 4 {
+        return None;
+    }
+    let (address, size) = integer(bytes, pos)?;
+    pos += size;
+    let (pin, size) = integer(bytes, pos)?;
+    if pin > 3 {
+        return None;
+    }
+    pos += size;
+    let source = match name_string(bytes, pos) {
+        Some((name, size)) => {
+            pos += size;
+            integer(bytes, pos)?;
+            Source::Link(name)
+        }
+        None => {
+            let (_, size) = integer(bytes, pos)?;
+            let (gsi, _) = integer(bytes, pos + size)?;
+            Source::Gsi(gsi as u32)
+        }
+    };
+    // the function number is always 0xffff (all the functions)
+    let entry = Entry {
+        device: (address >> 16) as u8,
+        pin: pin as u8,
+        source,
+    };
+    Some((entry, end))
+}
+
+/// A package that only contains routing table entries
+fn routing_table(bytes: &[u8], pos: usize) -> Option<Vec<Entry>> {
+    let (mut pos, end, count) = package(bytes, pos)?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let (entry, next) = entry(bytes, pos)?;
+        entries.push(entry);
+        pos = next;
+    }
+    if pos == end && !entries.is_empty() {
+        Some(entries)
+    } else {
+        None
+    }
+}
+
+/// All the `Name(XXXX, Package { ... })` that look like routing tables
+fn routing_tables(bytes: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos < bytes.len() {
+        if bytes[pos] == NAME_OP && name_seg(bytes, pos + 1).is_some() {
+            if let Some(table) = routing_table(bytes, pos + 5) {
+                entries.extend(table);
+            }
+        }
+        pos += 1;
+    }
+    entries
+}
+
+/// The interrupt of a link device, if it has a single one
+fn link(bytes: &[u8], name: [u8; 4]) -> Option<LegacyInterrupt> {
+    (0..bytes.len()).find_map(|pos| {
+        if bytes[pos] != EXT_OP_PREFIX || bytes.get(pos + 1) != Some(&DEVICE_OP) {
+            return None;
+        }
+        let (length, size) = pkg_length(bytes, pos + 2)?;
+        let (device, _) = name_string(bytes, pos + 2 + size)?;
+        let end = (pos + 2 + length).min(bytes.len());
+        if device != name {
+            return None;
+        }
+        let body = &bytes[pos..end];
+        [b"_CRS", b"_PRS"]
+            .iter()
+            .find_map(|&resources| link_resources(body, *resources))
+    })
+}
+
+/// Finds `Name(_CRS, Buffer { ... })` (or `_PRS`) in a device, and the
+/// interrupt it lists.
+fn link_resources(body: &[u8], name: [u8; 4]) -> Option<LegacyInterrupt> {
+    (0..body.len()).find_map(|pos| {
+        if body[pos] != NAME_OP || name_seg(body, pos + 1) != Some(name) {
+            return None;
+        }
+        if *body.get(pos + 5)? != BUFFER_OP {
+            return None;
+        }
+        let (length, size) = pkg_length(body, pos + 6)?;
+        let end = (pos + 6 + length).min(body.len());
+        let (_, buffer_size) = integer(body, pos + 6 + size)?;
+        interrupt_descriptor(body.get(pos + 6 + size + buffer_size..end)?)
+    })
+}
+
+/// Reads an "extended interrupt" resource descriptor with a single
+/// interrupt.
+fn interrupt_descriptor(resources: &[u8]) -> Option<LegacyInterrupt> {
+    let mut pos = 0;
+    while let Some(&tag) = resources.get(pos) {
+        if tag & 0x80 == 0 {
+            // small descriptors, until the end tag
+            if (tag >> 3) & 0xf == 0xf {
+                return None;
+            }
+            pos += 1 + (tag & 7) as usize;
+            continue;
+        }
+        let length = u16::from_le_bytes([*resources.get(pos + 1)?, *resources.get(pos + 2)?]);
+        if tag & 0x7f == 0x09 {
+            let flags = *resources.get(pos + 3)?;
+            let count = *resources.get(pos + 4)?;
+            let gsi = resources.get(pos + 5..pos + 9)?;
+            if count != 1 {
+                return None;
+            }
+            return Some(LegacyInterrupt {
+                gsi: u32::from_le_bytes([gsi[0], gsi[1], gsi[2], gsi[3]]),
+                level: flags & 0b10 == 0,
+                active_low: flags & 0b100 != 0,
+            });
+        }
+        pos += 3 + length as usize;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn routing_table_with_links() {
+        #[rustfmt::skip]
+        let aml: &[u8] = &[
+            // Name (PRTA, Package (2) {
+            NAME_OP, b'P', b'R', b'T', b'A', PACKAGE_OP, 0x1c, 2,
+            // Package (4) { 0x0001FFFF, 0, 0, 0x14 }
+            PACKAGE_OP, 0x0b, 4, 0x0c, 0xff, 0xff, 0x01, 0x00, 0x00, 0x00, 0x0a, 0x14,
+            // Package (4) { 0x0002FFFF, 1, GSIB, 0 }
+            PACKAGE_OP, 0x0d, 4, 0x0c, 0xff, 0xff, 0x02, 0x00, 0x01, b'G', b'S', b'I', b'B', 0x00,
+            // Device (GSIB) { Name (_PRS, ResourceTemplate () {
+            //     Interrupt (ResourceConsumer, Level, ActiveHigh, Shared) { 0x11 }
+            // }) }
+            EXT_OP_PREFIX, DEVICE_OP, 0x19, b'G', b'S', b'I', b'B',
+            NAME_OP, b'_', b'P', b'R', b'S', BUFFER_OP, 0x0e, 0x0a, 0x0b,
+            0x89, 0x06, 0x00, 0x09, 0x01, 0x11, 0x00, 0x00, 0x00, 0x79, 0x00,
+        ];
+        let routes = routes(&[aml]);
+        assert!(routes.len() == 2);
+        assert!(
+            routes[0]
+                == Route {
+                    device: 1,
+                    pin: 0,
+                    interrupt: LegacyInterrupt {
+                        gsi: 0x14,
+                        active_low: true,
+                        level: true
+                    }
+                }
+        );
+        assert!(
+            routes[1]
+                == Route {
+                    device: 2,
+                    pin: 1,
+                    interrupt: LegacyInterrupt {
+                        gsi: 0x11,
+                        active_low: false,
+                        level: true
+                    }
+                }
+        );
+    }
+}
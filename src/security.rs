@@ -0,0 +1,347 @@
+//! Per-type security policies, as described in `docs/security.md`.
+//!
+//! By default everything is allowed for everyone: a type (or an executable)
+//! only gets restricted once a policy has been registered for it. Devices are
+//! the exception: only [`Privileged`] processes can drive them, unless
+//! another policy is registered.
+//!
+//! The screen can also only be mapped by [`Privileged`] processes, since
+//! they could read what the other ones show.
+//!
+//! Policies are assumed to be deterministic, so their answers are cached
+//! for each (process, type, access) triple. The cache is cleared when
+//! a policy is (un)registered or when a process exits.
+//!
+//! The kernel itself (i.e. when no process is running, or in kernel tasks,
+//! see [`crate::process::current`]) is always allowed.
+
+use crate::identity;
+use crate::println;
+use crate::process::PId;
+use adb::TypeId;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use pci_types::PciAddress;
+use spin::{Mutex, RwLock};
+
+/// Security policy of a type.
+///
+/// All of these functions should be deterministic, as their results
+/// are cached.
+pub trait Security: Send + Sync {
+    /// Can this process read anything about this type
+    ///
+    /// If this is false, the process won't even be able to
+    /// open a stream of this type.
+    fn can_see(&self, _process: PId) -> bool {
+        true
+    }
+
+    /// Can this process read elements of this type from the DB?
+    fn can_read(&self, _process: PId) -> bool {
+        true
+    }
+
+    /// Can this process write new elements of this type to the DB?
+    fn can_write(&self, _process: PId) -> bool {
+        true
+    }
+}
+
+/// A policy for types that only the kernel creates: processes can read
+/// them, but not write them.
+pub struct ReadOnly;
+
+impl Security for ReadOnly {
+    fn can_write(&self, _process: PId) -> bool {
+        false
+    }
+}
+
+/// Security policy of a callable object (currently, an executable).
+pub trait ExecutionSecurity: Send + Sync {
+    /// Can this process start the executable?
+    fn can_run(&self, process: PId) -> bool;
+}
+
+/// Security policy of devices: which processes can access them directly
+/// (to drive them from userspace).
+pub trait DeviceSecurity: Send + Sync {
+    fn can_drive(&self, process: PId, device: PciAddress) -> bool;
+}
+
+/// The default device policy: only the processes started by the default
+/// identity can drive devices.
+pub struct Privileged;
+
+impl Privileged {
+    fn allows(process: PId) -> bool {
+        identity::user_of(process).map_or(false, |user| user.name == identity::DEFAULT_IDENTITY)
+    }
+}
+
+impl DeviceSecurity for Privileged {
+    fn can_drive(&self, process: PId, _device: PciAddress) -> bool {
+        Privileged::allows(process)
+    }
+}
+
+/// A policy for types that only the kernel creates, and that only
+/// [`Privileged`] processes can read (input events, that would let the other
+/// ones see what is typed).
+pub struct PrivilegedReadOnly;
+
+impl Security for PrivilegedReadOnly {
+    fn can_see(&self, process: PId) -> bool {
+        Privileged::allows(process)
+    }
+
+    fn can_read(&self, process: PId) -> bool {
+        Privileged::allows(process)
+    }
+
+    fn can_write(&self, _process: PId) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    See,
+    Read,
+    Write,
+    Run,
+    Drive,
+}
+
+#[derive(Debug, Clone)]
+pub enum Target {
+    Type(TypeId),
+    Executable(String),
+    Device(PciAddress),
+    Screen,
+}
+
+/// A denied access, as recorded in the audit log.
+#[derive(Debug, Clone)]
+pub struct Denied {
+    pub process: PId,
+    pub access: Access,
+    pub target: Target,
+}
+
+/// Maximum number of entries kept in the audit log. Older entries are
+/// dropped first.
+const AUDIT_LOG_SIZE: usize = 128;
+
+lazy_static::lazy_static! {
+    static ref POLICIES: RwLock<BTreeMap<u64, Box<dyn Security>>> = RwLock::new(BTreeMap::new());
+    static ref EXECUTION_POLICIES: RwLock<BTreeMap<String, Box<dyn ExecutionSecurity>>> =
+        RwLock::new(BTreeMap::new());
+
+    static ref DEVICE_POLICY: RwLock<Box<dyn DeviceSecurity>> = RwLock::new(Box::new(Privileged));
+
+    static ref CACHE: Mutex<BTreeMap<(PId, u64, Access), bool>> = Mutex::new(BTreeMap::new());
+
+    static ref AUDIT_LOG: Mutex<VecDeque<Denied>> = Mutex::new(VecDeque::with_capacity(AUDIT_LOG_SIZE));
+}
+
+/// Registers the security policy of a type, replacing the previous one if any.
+pub fn register(ty: TypeId, policy: impl Security + 'static) {
+    POLICIES.write().insert(ty.0, Box::new(policy));
+    CACHE
+        .lock()
+        .retain(|&(_, cached_ty, _), _| cached_ty != ty.0);
+}
+
+/// Removes the security policy of a type: everyone will be able to access it.
+pub fn unregister(ty: TypeId) {
+    POLICIES.write().remove(&ty.0);
+    CACHE
+        .lock()
+        .retain(|&(_, cached_ty, _), _| cached_ty != ty.0);
+}
+
+/// Registers the security policy of an executable, identified by its name.
+pub fn register_execution(name: &str, policy: impl ExecutionSecurity + 'static) {
+    EXECUTION_POLICIES
+        .write()
+        .insert(String::from(name), Box::new(policy));
+}
+
+/// Replaces the security policy of devices.
+pub fn register_device_policy(policy: impl DeviceSecurity + 'static) {
+    *DEVICE_POLICY.write() = Box::new(policy);
+}
+
+/// Checks that the current process can access a type.
+pub fn check(ty: TypeId, access: Access) -> Result<(), Denied> {
+    match crate::process::current() {
+        Some(pid) => check_process(pid, ty, access),
+        None => Ok(()),
+    }
+}
+
+/// Checks that a given process can access a type.
+pub fn check_process(pid: PId, ty: TypeId, access: Access) -> Result<(), Denied> {
+    let key = (pid, ty.0, access);
+    let cached = CACHE.lock().get(&key).copied();
+    let allowed = match cached {
+        Some(allowed) => allowed,
+        None => {
+            let allowed = match POLICIES.read().get(&ty.0) {
+                Some(policy) => match access {
+                    Access::See => policy.can_see(pid),
+                    // Reading or writing something you can't see doesn't make sense
+                    Access::Read => policy.can_see(pid) && policy.can_read(pid),
+                    Access::Write => policy.can_see(pid) && policy.can_write(pid),
+                    Access::Run | Access::Drive => true,
+                },
+                None => true,
+            };
+            CACHE.lock().insert(key, allowed);
+            allowed
+        }
+    };
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(deny(pid, access, Target::Type(ty)))
+    }
+}
+
+/// Checks that the current process can start the executable called `name`.
+///
+/// Results are not cached, since spawning is not that frequent.
+pub fn check_run(name: &str) -> Result<(), Denied> {
+    let pid = match crate::process::current() {
+        Some(pid) => pid,
+        None => return Ok(()),
+    };
+    let allowed = match EXECUTION_POLICIES.read().get(name) {
+        Some(policy) => policy.can_run(pid),
+        None => true,
+    };
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(deny(
+            pid,
+            Access::Run,
+            Target::Executable(String::from(name)),
+        ))
+    }
+}
+
+/// Checks that a process can be given direct access to a device.
+///
+/// Results are not cached: devices are rarely granted.
+pub fn check_device(pid: PId, device: PciAddress) -> Result<(), Denied> {
+    if DEVICE_POLICY.read().can_drive(pid, device) {
+        Ok(())
+    } else {
+        Err(deny(pid, Access::Drive, Target::Device(device)))
+    }
+}
+
+/// Checks that the current process can map (or fill) the screen.
+pub fn check_screen() -> Result<(), Denied> {
+    match crate::process::current() {
+        Some(pid) if !Privileged::allows(pid) => Err(deny(pid, Access::Drive, Target::Screen)),
+        _ => Ok(()),
+    }
+}
+
+/// Forgets everything we know about a process (to be called when it exits).
+pub fn forget_process(pid: PId) {
+    CACHE
+        .lock()
+        .retain(|&(cached_pid, _, _), _| cached_pid != pid);
+}
+
+/// Returns the most recent denials, the oldest first.
+pub fn audit_log() -> Vec<Denied> {
+    AUDIT_LOG.lock().iter().cloned().collect()
+}
+
+fn deny(process: PId, access: Access, target: Target) -> Denied {
+    let denied = Denied {
+        process,
+        access,
+        target,
+    };
+    println!("SECURITY: denied {:?}", denied);
+
+    let mut log = AUDIT_LOG.lock();
+    if log.len() == AUDIT_LOG_SIZE {
+        log.pop_front();
+    }
+    log.push_back(denied.clone());
+
+    denied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Process 1 can see the type, process 2 can't. Nobody can write it.
+    struct Counted;
+
+    impl Security for Counted {
+        fn can_see(&self, process: PId) -> bool {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            process == PId::new(1)
+        }
+
+        fn can_write(&self, _process: PId) -> bool {
+            false
+        }
+    }
+
+    #[test_case]
+    fn policies() {
+        let ty = TypeId(0xfff0);
+        let (one, two) = (PId::new(1), PId::new(2));
+        assert!(check_process(one, ty, Access::Write).is_ok());
+
+        register(ty, Counted);
+        assert!(check_process(one, ty, Access::Read).is_ok());
+        assert!(check_process(one, ty, Access::Write).is_err());
+        // reading needs to see the type
+        assert!(check_process(two, ty, Access::Read).is_err());
+        let denied = audit_log().pop().unwrap();
+        assert!(denied.process == two && denied.access == Access::Read);
+
+        unregister(ty);
+        assert!(check_process(two, ty, Access::Read).is_ok());
+    }
+
+    #[test_case]
+    fn cache() {
+        let ty = TypeId(0xfff1);
+        let pid = PId::new(1);
+        register(ty, Counted);
+        let calls = CALLS.load(Ordering::SeqCst);
+        assert!(check_process(pid, ty, Access::See).is_ok());
+        assert!(check_process(pid, ty, Access::See).is_ok());
+        assert!(CALLS.load(Ordering::SeqCst) == calls + 1);
+
+        // the answers are asked again once the process is forgotten, or the
+        // policy replaced
+        forget_process(pid);
+        assert!(check_process(pid, ty, Access::See).is_ok());
+        assert!(CALLS.load(Ordering::SeqCst) == calls + 2);
+        register(ty, Counted);
+        assert!(check_process(pid, ty, Access::See).is_ok());
+        assert!(CALLS.load(Ordering::SeqCst) == calls + 3);
+        unregister(ty);
+    }
+}
@@ -10,6 +10,28 @@ lazy_static! {
     };
 }
 
+/// Line status register of the first serial port, and its "data ready" bit
+const LINE_STATUS: u16 = 0x3fd;
+const DATA_READY: u8 = 1;
+
+/// Reads a byte that was received on the serial port, without waiting.
+pub fn try_read() -> Option<u8> {
+    use x86_64::instructions::interrupts;
+    use x86_64::instructions::port::Port;
+
+    interrupts::without_interrupts(|| {
+        // the lock keeps the port from being used by someone else meanwhile
+        let _serial = SERIAL1.lock();
+        unsafe {
+            let status: u8 = Port::new(LINE_STATUS).read();
+            if status & DATA_READY == 0 {
+                return None;
+            }
+            Some(Port::new(0x3f8).read())
+        }
+    })
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
@@ -20,10 +42,11 @@ pub fn _print(args: ::core::fmt::Arguments) {
             .lock()
             .write_fmt(args)
             .expect("Printing to serial failed");
+        crate::framebuffer::console::_print(args);
     });
 }
 
-/// Prints to the host through the serial interface.
+/// Prints to the host through the serial interface, and on the screen.
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => {
@@ -31,7 +54,8 @@ macro_rules! print {
     };
 }
 
-/// Prints to the host through the serial interface, appending a newline.
+/// Prints to the host through the serial interface, and on the screen,
+/// appending a newline.
 #[macro_export]
 macro_rules! println {
     () => ($crate::print!("\n"));
@@ -0,0 +1,582 @@
+//! The commands of the shell
+//!
+//! Types and conditions are given as explained in [`crate::db::query`].
+
+use crate::db::query::{field_matches, find_type, parse_condition};
+use crate::db::remote::{self, Local, Remote};
+use crate::db::{self, DbValueDisplay};
+use crate::driver;
+use crate::identity;
+use crate::memory::{self, MEMORY};
+use crate::net::{self, arp, ipv4, tcp, Endpoint, Ipv4Address};
+use crate::pci::{self, PciResolver};
+use crate::pipeline::{stages, Pipeline};
+use crate::println;
+use crate::process::{self, PId, Process, Status};
+use crate::storage;
+use crate::task::{self, Task};
+use crate::time;
+use crate::ui;
+use adb::{Db, DbObject, TypeDef, TypeInfo};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    run: fn(&[&str]),
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help",
+        help: "lists the commands",
+        run: help,
+    },
+    Command {
+        name: "types",
+        usage: "types",
+        help: "lists the types of the database",
+        run: types,
+    },
+    Command {
+        name: "ls",
+        usage: "ls [type]",
+        help: "lists the objects of a type (or of all types), one per line",
+        run: ls,
+    },
+    Command {
+        name: "show",
+        usage: "show <type> [where <field>=<value> [and ...]]",
+        help: "shows the objects of a type that match the conditions",
+        run: show,
+    },
+    Command {
+        name: "save",
+        usage: "save <name> <pipeline>",
+        help: "saves a pipeline as an executable",
+        run: save,
+    },
+    Command {
+        name: "ps",
+        usage: "ps",
+        help: "lists the processes",
+        run: ps,
+    },
+    Command {
+        name: "kill",
+        usage: "kill <pid>",
+        help: "stops a process",
+        run: kill,
+    },
+    Command {
+        name: "spawn",
+        usage: "spawn <name>",
+        help: "starts a program",
+        run: spawn,
+    },
+    Command {
+        name: "lspci",
+        usage: "lspci",
+        help: "lists the PCI devices, and their drivers",
+        run: lspci,
+    },
+    Command {
+        name: "rescan",
+        usage: "rescan",
+        help: "looks for new PCI devices, and starts their drivers",
+        run: rescan,
+    },
+    Command {
+        name: "grant",
+        usage: "grant <pid> [<segment>:]<bus>:<device>.<function>",
+        help: "lets a process drive a PCI device",
+        run: grant,
+    },
+    Command {
+        name: "disks",
+        usage: "disks",
+        help: "lists the storage devices",
+        run: disks,
+    },
+    Command {
+        name: "net",
+        usage: "net",
+        help: "shows the network configuration and connections",
+        run: net_info,
+    },
+    Command {
+        name: "ping",
+        usage: "ping <address>",
+        help: "sends echo requests to an IPv4 address",
+        run: ping,
+    },
+    Command {
+        name: "pull",
+        usage: "pull <address>[:<port>] <type>",
+        help: "copies the objects of a type from another computer",
+        run: pull,
+    },
+    Command {
+        name: "push",
+        usage: "push <address>[:<port>] <type>",
+        help: "copies the objects of a type to another computer",
+        run: push,
+    },
+    Command {
+        name: "desktop",
+        usage: "desktop",
+        help: "browses the database in windows",
+        run: desktop,
+    },
+    Command {
+        name: "date",
+        usage: "date",
+        help: "shows the date, the time and the uptime",
+        run: date,
+    },
+    Command {
+        name: "mem",
+        usage: "mem",
+        help: "shows how much memory is used",
+        run: mem,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot",
+        help: "restarts the computer",
+        run: reboot,
+    },
+];
+
+pub fn exists(name: &str) -> bool {
+    COMMANDS.iter().any(|command| command.name == name)
+}
+
+/// Runs a line typed in the shell.
+pub fn run(line: &str) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let name = match args.first() {
+        Some(name) => *name,
+        None => return,
+    };
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(&args[1..]),
+        None => println!("Unknown command: {} (try `help`)", name),
+    }
+}
+
+fn usage(name: &str) {
+    if let Some(command) = COMMANDS.iter().find(|command| command.name == name) {
+        println!("Usage: {}", command.usage);
+    }
+}
+
+/// Runs `f` with the database, if it is loaded.
+fn with_db(f: impl FnOnce(&mut Db<Vec<u8>>)) {
+    let mut db = db::DB.lock();
+    match db.as_mut() {
+        Some(db) => f(db),
+        None => println!("The database is not loaded"),
+    }
+}
+
+/// The objects of a type that are stored, or the current one for generated
+/// types. Events can't be listed: they are only received when they happen.
+fn objects(db: &mut Db<Vec<u8>>, ty: &TypeInfo) -> Option<Vec<DbObject>> {
+    if db::generated::is_generated(ty.id) {
+        Some(db::generated::read(ty.id).into_iter().collect())
+    } else if db::events::is_event(ty.id) {
+        println!("{} is a type of events, they are not stored", ty.name);
+        None
+    } else {
+        Some(db.iter_type(ty.id).collect())
+    }
+}
+
+fn help(_args: &[&str]) {
+    for command in COMMANDS {
+        println!("{:<48} {}", command.usage, command.help);
+    }
+    println!("\nPipelines (`a | b | c`) are made of:");
+    for builtin in stages::BUILTINS {
+        println!("{:<48} {}", builtin.usage, builtin.help);
+    }
+    println!("and of the pipelines that were saved (`ls Os.Executable`).");
+}
+
+fn types(_args: &[&str]) {
+    with_db(|db| {
+        for id in db.all_type_ids() {
+            let kind = if db::generated::is_generated(id) {
+                " (generated)"
+            } else if db::events::is_event(id) {
+                " (events)"
+            } else {
+                ""
+            };
+            match db.get_type_info(id) {
+                Some(ty) => println!("{:#06x} {}{}", id.0, ty.name, kind),
+                None => println!("{:#06x} ?", id.0),
+            }
+        }
+    });
+}
+
+fn ls(args: &[&str]) {
+    with_db(|db| {
+        let name = match args {
+            [] => return db::display_contents(db),
+            [name] => name,
+            _ => return usage("ls"),
+        };
+        let ty = match find_type(db, name) {
+            Some(ty) => ty,
+            None => return println!("Unknown type: {}", name),
+        };
+        for obj in objects(db, &ty).unwrap_or_default() {
+            let display = DbValueDisplay::new(db, obj.value, obj.type_info);
+            println!("{}", display.compact().max_width(16));
+        }
+    });
+}
+
+fn show(args: &[&str]) {
+    let (name, conditions) = match args {
+        [name] => (name, &[][..]),
+        [name, "where", conditions @ ..] if !conditions.is_empty() => (name, conditions),
+        _ => return usage("show"),
+    };
+    // `a=1 and b=2`
+    let mut parsed = Vec::new();
+    for (i, condition) in conditions.iter().enumerate() {
+        match (i % 2, parse_condition(condition)) {
+            (0, Some(condition)) => parsed.push(condition),
+            (1, _) if *condition == "and" => {}
+            _ => return usage("show"),
+        }
+    }
+    // a condition is missing after the last `and`
+    if conditions.len() % 2 == 0 && !conditions.is_empty() {
+        return usage("show");
+    }
+
+    with_db(|db| {
+        let ty = match find_type(db, name) {
+            Some(ty) => ty,
+            None => return println!("Unknown type: {}", name),
+        };
+        if !matches!(ty.definition, TypeDef::Product { .. }) && !parsed.is_empty() {
+            return println!("{} has no fields", ty.name);
+        }
+        let mut count = 0;
+        for obj in objects(db, &ty).unwrap_or_default() {
+            let matches = parsed
+                .iter()
+                .all(|(field, value)| field_matches(db, &ty, &obj.value, field, value));
+            if matches {
+                println!("{}", DbValueDisplay::new(db, obj.value, obj.type_info));
+                count += 1;
+            }
+        }
+        println!("{} object(s)", count);
+    });
+}
+
+fn save(args: &[&str]) {
+    let (name, stages) = match args.split_first() {
+        Some((name, stages)) if !stages.is_empty() => (name, stages.join(" ")),
+        _ => return usage("save"),
+    };
+    let saved = Pipeline::parse(&stages).and_then(|pipeline| pipeline.save(name));
+    match saved {
+        Ok(()) => println!("Saved {}", name),
+        Err(err) => println!("{}", err),
+    }
+}
+
+fn ps(_args: &[&str]) {
+    println!(
+        "{:>4} {:>6} {:<8} {:<10} NAME",
+        "PID", "PARENT", "STATUS", "USER"
+    );
+    for pid in process::all() {
+        let proc = match process::get(pid) {
+            Some(proc) => proc,
+            None => continue,
+        };
+        let parent = match proc.parent() {
+            Some(parent) => alloc::format!("{}", parent),
+            None => String::from("-"),
+        };
+        let status = match process::status(pid) {
+            Some(Status::Running) => "running",
+            Some(Status::Ready) => "ready",
+            Some(Status::Killed) | None => "killed",
+        };
+        let user = identity::user_of(pid).map_or(String::from("-"), |user| user.name);
+        println!(
+            "{:>4} {:>6} {:<8} {:<10} {}",
+            pid, parent, status, user, proc.name
+        );
+    }
+}
+
+fn kill(args: &[&str]) {
+    let pid = match args {
+        [pid] => match pid.parse() {
+            Ok(pid) => PId::new(pid),
+            Err(_) => return usage("kill"),
+        },
+        _ => return usage("kill"),
+    };
+    if !process::kill(pid) {
+        println!("No process {} (or it is already stopped)", pid);
+    }
+}
+
+fn spawn(args: &[&str]) {
+    let name = match args {
+        [name] => *name,
+        _ => return usage("spawn"),
+    };
+    let code = match process::program(name) {
+        Some(code) => code,
+        None => {
+            println!("Unknown program: {}", name);
+            return println!("Known programs: {}", process::programs().join(", "));
+        }
+    };
+    let proc = {
+        let mut memory = MEMORY.lock();
+        match memory.as_mut() {
+            Some(memory) => match Process::create(&mut memory.frames, name, code) {
+                Some(proc) => proc,
+                None => return println!("Not enough memory to load {}", name),
+            },
+            None => return println!("Processes can't be started yet"),
+        }
+    };
+    match process::spawn_with_parent(proc, None) {
+        Some(pid) => println!("Started {} as process {}", name, pid),
+        None => println!("Could not start {}", name),
+    }
+}
+
+fn lspci(_args: &[&str]) {
+    let access = pci::ACCESS.lock();
+    let access = match access.as_ref() {
+        Some(access) => access,
+        None => return println!("PCI is not available"),
+    };
+    for (address, device) in PciResolver::get_info(access).devices {
+        println!(
+            "{:04x}:{:02x}:{:02x}.{} {:04x}:{:04x} {:02x}/{:02x}/{:02x} {} [{}]",
+            address.segment(),
+            address.bus(),
+            address.device(),
+            address.function(),
+            device.vendor_id,
+            device.device_id,
+            device.class,
+            device.sub_class,
+            device.interface,
+            device.class_info(),
+            device.capabilities.names().join(" ")
+        );
+        if let Some(binding) = driver::binding(address) {
+            if let Some(name) = binding.driver {
+                println!("  driver: {} ({})", name, binding.state.as_str());
+            }
+        }
+    }
+}
+
+fn rescan(_args: &[&str]) {
+    println!("{} new device(s)", driver::rescan());
+}
+
+fn grant(args: &[&str]) {
+    let (pid, address) = match args {
+        [pid, address] => match (pid.parse(), pci::parse_address(address)) {
+            (Ok(pid), Some(address)) => (PId::new(pid), address),
+            _ => return usage("grant"),
+        },
+        _ => return usage("grant"),
+    };
+    match driver::grant::grant(pid, address) {
+        Ok(handle) => println!(
+            "Granted as handle {:#x} of process {}",
+            handle.as_raw(),
+            pid
+        ),
+        Err(err) => println!("Could not grant the device: {:?}", err),
+    }
+}
+
+fn desktop(_args: &[&str]) {
+    task::spawn(Task::new(ui::desktop::run()));
+}
+
+fn disks(_args: &[&str]) {
+    for device in storage::devices() {
+        println!(
+            "{} {} blocks of {} bytes ({} MiB)",
+            device.name(),
+            device.block_count(),
+            device.block_size(),
+            storage::size(&*device) / (1024 * 1024)
+        );
+    }
+    if db::is_mounted() {
+        println!("The database was loaded from a disk");
+    }
+}
+
+fn net_info(_args: &[&str]) {
+    let device = match net::device() {
+        Some(device) => device,
+        None => return println!("No network card"),
+    };
+    let config = net::config();
+    println!("MAC address {}", device.mac());
+    if !config.is_configured() {
+        return println!("Waiting for an address");
+    }
+    println!("Address {} netmask {}", config.address, config.netmask);
+    if let Some(gateway) = config.gateway {
+        println!("Gateway {}", gateway);
+    }
+    if let Some(dns) = config.dns {
+        println!("DNS server {}", dns);
+    }
+    for (address, mac) in arp::entries() {
+        println!("ARP {} is {}", address, mac);
+    }
+    for (local, remote, state) in tcp::connections() {
+        println!("TCP {} -> {} {}", local, remote, state.as_str());
+    }
+}
+
+fn ping(args: &[&str]) {
+    const COUNT: u16 = 4;
+    let destination = match args {
+        [address] => match Ipv4Address::parse(address) {
+            Some(address) => address,
+            None => return usage("ping"),
+        },
+        _ => return usage("ping"),
+    };
+    task::spawn(Task::new(async move {
+        for sequence in 0..COUNT {
+            match ipv4::ping(destination, sequence, time::Duration::from_secs(1)).await {
+                Ok(time) => println!(
+                    "Reply from {}: seq={} time={} ms",
+                    destination,
+                    sequence,
+                    time.as_millis()
+                ),
+                Err(err) => println!("No reply from {}: seq={} {:?}", destination, sequence, err),
+            }
+            time::sleep(time::Duration::from_secs(1)).await;
+        }
+    }));
+}
+
+/// Parses the server and the type of `pull` and `push`.
+fn remote_args(args: &[&str]) -> Option<(Remote, String)> {
+    let (server, ty) = match args {
+        [server, ty] => (server, ty),
+        _ => return None,
+    };
+    let server = match Ipv4Address::parse(server) {
+        Some(address) => Endpoint::new(address, remote::PORT),
+        None => Endpoint::parse(server)?,
+    };
+    Some((Remote::new(server), String::from(*ty)))
+}
+
+fn pull(args: &[&str]) {
+    let (remote, ty) = match remote_args(args) {
+        Some(args) => args,
+        None => return usage("pull"),
+    };
+    task::spawn(Task::new(async move {
+        match remote::copy(&remote, &Local, &ty).await {
+            Ok(count) => println!("Pulled {} object(s) from {}", count, remote.server),
+            Err(err) => println!("Could not pull from {}: {:?}", remote.server, err),
+        }
+    }));
+}
+
+fn push(args: &[&str]) {
+    let (remote, ty) = match remote_args(args) {
+        Some(args) => args,
+        None => return usage("push"),
+    };
+    task::spawn(Task::new(async move {
+        match remote::copy(&Local, &remote, &ty).await {
+            Ok(count) => println!("Pushed {} object(s) to {}", count, remote.server),
+            Err(err) => println!("Could not push to {}: {:?}", remote.server, err),
+        }
+    }));
+}
+
+fn date(_args: &[&str]) {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    let now = time::now();
+    println!(
+        "{} {:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        DAYS[time::weekday(&now) as usize],
+        now.year,
+        now.month,
+        now.day,
+        now.hours,
+        now.minutes,
+        now.seconds
+    );
+    let uptime = time::uptime().as_secs();
+    println!(
+        "up {}:{:02}:{:02}",
+        uptime / 3600,
+        uptime / 60 % 60,
+        uptime % 60
+    );
+}
+
+fn mem(_args: &[&str]) {
+    use crate::allocator::{self, HEAP_SIZE};
+
+    println!(
+        "heap:   {} / {} KiB",
+        allocator::used() / 1024,
+        HEAP_SIZE / 1024
+    );
+    if let Some(memory) = MEMORY.lock().as_ref() {
+        let (used, total) = memory.frames.usage();
+        println!("frames: {} / {} ({} MiB)", used, total, total * 4 / 1024);
+    }
+    println!("DMA:    {} free pages", memory::free_dma_frames());
+}
+
+fn reboot(_args: &[&str]) {
+    use x86_64::instructions::port::Port;
+
+    println!("Rebooting...");
+    unsafe {
+        // pulse the reset line of the keyboard controller
+        Port::<u8>::new(0x64).write(0xfe);
+    }
+    // if it didn't work, cause a triple fault
+    unsafe {
+        x86_64::instructions::interrupts::disable();
+        let idt = x86_64::structures::DescriptorTablePointer {
+            limit: 0,
+            base: x86_64::VirtAddr::zero(),
+        };
+        x86_64::instructions::tables::lidt(&idt);
+        x86_64::instructions::interrupts::int3();
+    }
+}
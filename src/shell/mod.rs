@@ -0,0 +1,339 @@
+//! Interactive kernel shell
+//!
+//! It reads lines from the keyboard and from the serial port, and answers on
+//! both (like everything that is printed). Lines can be edited with the arrow
+//! keys, Home, End, Backspace and Delete, and the previous ones are recalled
+//! with Up and Down. Ctrl+C gives up the current line.
+//!
+//! See [`commands`] for what it understands. Other lines are pipelines (see
+//! [`crate::pipeline`]): if the last stage writes something, it is shown.
+
+use crate::input::keyboard::{self, KeyEvent, KeyState};
+use crate::input::Modifiers;
+use crate::pipeline::{stages, Pipeline, PipelineError};
+use crate::serial;
+use crate::task::broadcast::Receiver;
+use crate::time::{self, Duration};
+use crate::{print, println};
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::Poll;
+use futures_util::future::poll_fn;
+use pc_keyboard::KeyCode;
+
+pub mod commands;
+
+const PROMPT: &str = "> ";
+const HISTORY_SIZE: usize = 32;
+/// The serial port has no interrupt: it is checked that often
+const SERIAL_POLL: Duration = Duration::from_millis(20);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    /// Ctrl+C
+    Cancel,
+}
+
+impl Key {
+    fn from_event(event: &KeyEvent) -> Option<Key> {
+        if event.state == KeyState::Up {
+            return None;
+        }
+        let key = match (event.code, event.text) {
+            (KeyCode::ArrowLeft, _) => Key::Left,
+            (KeyCode::ArrowRight, _) => Key::Right,
+            (KeyCode::ArrowUp, _) => Key::Up,
+            (KeyCode::ArrowDown, _) => Key::Down,
+            (KeyCode::Home, _) => Key::Home,
+            (KeyCode::End, _) => Key::End,
+            (KeyCode::Delete, _) => Key::Delete,
+            (_, Some('\n')) => Key::Enter,
+            (_, Some('\u{8}')) => Key::Backspace,
+            (_, Some('c')) | (_, Some('C')) if event.modifiers.contains(Modifiers::CTRL) => {
+                Key::Cancel
+            }
+            (_, Some(c)) if !c.is_control() => Key::Char(c),
+            _ => return None,
+        };
+        Some(key)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SerialState {
+    Normal,
+    Escape,
+    /// In `ESC[`, with the number read so far
+    Csi(u8),
+}
+
+/// Turns the bytes a terminal sends into keys
+struct SerialDecoder {
+    state: SerialState,
+}
+
+impl SerialDecoder {
+    const fn new() -> SerialDecoder {
+        SerialDecoder {
+            state: SerialState::Normal,
+        }
+    }
+
+    fn feed(&mut self, byte: u8) -> Option<Key> {
+        let (state, key) = match (self.state, byte) {
+            (SerialState::Normal, 0x1b) => (SerialState::Escape, None),
+            (SerialState::Normal, b'\r') | (SerialState::Normal, b'\n') => {
+                (SerialState::Normal, Some(Key::Enter))
+            }
+            (SerialState::Normal, 0x7f) | (SerialState::Normal, 0x08) => {
+                (SerialState::Normal, Some(Key::Backspace))
+            }
+            (SerialState::Normal, 0x03) => (SerialState::Normal, Some(Key::Cancel)),
+            (SerialState::Normal, 0x20..=0x7e) => {
+                (SerialState::Normal, Some(Key::Char(byte as char)))
+            }
+            (SerialState::Normal, _) => (SerialState::Normal, None),
+            (SerialState::Escape, b'[') => (SerialState::Csi(0), None),
+            (SerialState::Escape, _) => (SerialState::Normal, None),
+            (SerialState::Csi(n), b'0'..=b'9') => {
+                let n = n.saturating_mul(10).saturating_add(byte - b'0');
+                (SerialState::Csi(n), None)
+            }
+            (SerialState::Csi(n), _) => {
+                let key = match (byte, n) {
+                    (b'A', _) => Some(Key::Up),
+                    (b'B', _) => Some(Key::Down),
+                    (b'C', _) => Some(Key::Right),
+                    (b'D', _) => Some(Key::Left),
+                    (b'H', _) | (b'~', 1) | (b'~', 7) => Some(Key::Home),
+                    (b'F', _) | (b'~', 4) | (b'~', 8) => Some(Key::End),
+                    (b'~', 3) => Some(Key::Delete),
+                    _ => None,
+                };
+                (SerialState::Normal, key)
+            }
+        };
+        self.state = state;
+        key
+    }
+}
+
+/// Where keys come from: the keyboards, and the serial port
+struct Input {
+    keys: Receiver<KeyEvent>,
+    serial: SerialDecoder,
+}
+
+impl Input {
+    async fn next(&mut self) -> Key {
+        loop {
+            while let Some(byte) = serial::try_read() {
+                if let Some(key) = self.serial.feed(byte) {
+                    return key;
+                }
+            }
+
+            let mut sleep = time::sleep(SERIAL_POLL);
+            let keys = &mut self.keys;
+            let event = poll_fn(|cx| {
+                if let Poll::Ready(event) = keys.poll_recv(cx) {
+                    return Poll::Ready(Some(event));
+                }
+                Pin::new(&mut sleep).poll(cx).map(|_| None)
+            })
+            .await;
+            if let Some(key) = event.as_ref().and_then(Key::from_event) {
+                return key;
+            }
+        }
+    }
+}
+
+/// The line being typed, and the previous ones
+struct Editor {
+    line: Vec<char>,
+    cursor: usize,
+    history: VecDeque<String>,
+    /// The line of the history being shown, and what was typed before
+    /// going up in the history
+    browsing: Option<(usize, Vec<char>)>,
+}
+
+impl Editor {
+    fn new() -> Editor {
+        Editor {
+            line: Vec::new(),
+            cursor: 0,
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+            browsing: None,
+        }
+    }
+
+    /// Prints the whole line again, and puts the cursor back.
+    fn refresh(&self) {
+        let line: String = self.line.iter().collect();
+        print!("\r{}{}\x1b[K", PROMPT, line);
+        let back = self.line.len() - self.cursor;
+        if back > 0 {
+            print!("\x1b[{}D", back);
+        }
+    }
+
+    fn show_history(&mut self, index: Option<usize>) {
+        let draft = match self.browsing.take() {
+            Some((_, draft)) => draft,
+            None => core::mem::take(&mut self.line),
+        };
+        self.line = match index {
+            Some(index) => self.history[index].chars().collect(),
+            None => draft.clone(),
+        };
+        self.browsing = index.map(|index| (index, draft));
+        self.cursor = self.line.len();
+        self.refresh();
+    }
+
+    /// Applies a key, and returns the line once it is complete.
+    fn key(&mut self, key: Key) -> Option<String> {
+        match key {
+            Key::Char(c) if self.cursor == self.line.len() => {
+                self.line.push(c);
+                self.cursor += 1;
+                print!("{}", c);
+            }
+            Key::Char(c) => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+                self.refresh();
+            }
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+                self.refresh();
+            }
+            Key::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+                self.refresh();
+            }
+            Key::Left if self.cursor > 0 => {
+                self.cursor -= 1;
+                print!("\x1b[D");
+            }
+            Key::Right if self.cursor < self.line.len() => {
+                self.cursor += 1;
+                print!("\x1b[C");
+            }
+            Key::Home => {
+                self.cursor = 0;
+                self.refresh();
+            }
+            Key::End => {
+                self.cursor = self.line.len();
+                self.refresh();
+            }
+            Key::Up => {
+                let index = match self.browsing {
+                    Some((index, _)) => index.checked_sub(1),
+                    None => self.history.len().checked_sub(1),
+                };
+                if index.is_some() {
+                    self.show_history(index);
+                }
+            }
+            Key::Down => {
+                if let Some((index, _)) = self.browsing {
+                    let next = Some(index + 1).filter(|&next| next < self.history.len());
+                    self.show_history(next);
+                }
+            }
+            Key::Cancel => {
+                print!("^C\n");
+                self.line.clear();
+                self.cursor = 0;
+                self.browsing = None;
+                return Some(String::new());
+            }
+            Key::Enter => {
+                print!("\n");
+                let line: String = self.line.drain(..).collect();
+                self.cursor = 0;
+                self.browsing = None;
+                let repeated = self.history.back() == Some(&line);
+                if !line.trim().is_empty() && !repeated {
+                    if self.history.len() == HISTORY_SIZE {
+                        self.history.pop_front();
+                    }
+                    self.history.push_back(line.clone());
+                }
+                return Some(line);
+            }
+            _ => {}
+        }
+        None
+    }
+}
+
+/// Reads commands and runs them, forever.
+pub async fn run() {
+    let mut input = Input {
+        keys: keyboard::subscribe(),
+        serial: SerialDecoder::new(),
+    };
+    let mut editor = Editor::new();
+    println!("Type `help` to see the available commands.");
+    loop {
+        print!("{}", PROMPT);
+        let line = loop {
+            if let Some(line) = editor.key(input.next().await) {
+                break line;
+            }
+        };
+        match line.split_whitespace().next() {
+            Some(first) if !commands::exists(first) => {
+                if let Err(err) = run_pipeline(&line).await {
+                    println!("{}", err);
+                }
+            }
+            _ => commands::run(&line),
+        }
+    }
+}
+
+/// Runs a pipeline, showing what it writes at the end.
+async fn run_pipeline(text: &str) -> Result<(), PipelineError> {
+    let mut pipeline = Pipeline::parse(text)?;
+    if let Some(ty) = pipeline.output_type() {
+        let show = (stages::find("show").unwrap().create)(&[], Some(ty))?;
+        pipeline.push(show)?;
+    }
+    pipeline.run().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn decode_serial_keys() {
+        let mut decoder = SerialDecoder::new();
+        let mut keys = b"a\x1b[D\x1b[3~\r".iter().filter_map(|&b| decoder.feed(b));
+        assert!(keys.next() == Some(Key::Char('a')));
+        assert!(keys.next() == Some(Key::Left));
+        assert!(keys.next() == Some(Key::Delete));
+        assert!(keys.next() == Some(Key::Enter));
+        assert!(keys.next().is_none());
+    }
+}
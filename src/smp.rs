@@ -0,0 +1,254 @@
+//! Starting the application processors (APs)
+//!
+//! Each AP is woken up with the INIT-SIPI-SIPI sequence. It then runs a small
+//! real mode trampoline (copied at [`TRAMPOLINE_ADDR`]) that switches to long
+//! mode with the page table of the bootstrap processor, and jumps to
+//! [`ap_main`].
+//!
+//! APs are started one after the other, since they share the trampoline.
+//!
+//! https://wiki.osdev.org/Symmetric_Multiprocessing
+
+use crate::interrupt::InterruptIndex;
+use crate::memory::{self, AcpiHandler, MEM_OFFSET};
+use crate::{apic, gdt, percpu, pit, println};
+use acpi::platform::ProcessorState;
+use acpi::AcpiTables;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Physical address of the trampoline. It has to be below 1MiB and page
+/// aligned, since APs start in real mode at `vector * 0x1000`.
+pub const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+/// Size of the kernel stack of each AP, in pages
+const STACK_PAGES: u64 = 4;
+/// Size of the interrupt stacks of each AP, in pages
+const IST_PAGES: u64 = 2;
+
+/// Initial count of the local APIC timer of the APs (the BSP uses the PIT)
+///
+/// It is not calibrated yet, but it doesn't matter much: it is only used to
+/// wake up the APs regularly.
+const AP_TIMER_COUNT: u32 = 0x10_0000;
+
+global_asm!(
+    ".section .text.ap_trampoline, \"ax\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".global ap_trampoline_cr3",
+    ".global ap_trampoline_stack",
+    ".global ap_trampoline_entry",
+    ".code16",
+    "ap_trampoline_start:",
+    "    cli",
+    "    cld",
+    "    xor ax, ax",
+    "    mov ds, ax",
+    // PAE
+    "    mov eax, cr4",
+    "    or eax, 1 << 5",
+    "    mov cr4, eax",
+    // page table of the BSP (it is below 4GiB)
+    "    mov eax, dword ptr [0x8000 + ap_trampoline_cr3 - ap_trampoline_start]",
+    "    mov cr3, eax",
+    // long mode and no-execute pages
+    "    mov ecx, 0xc0000080",
+    "    rdmsr",
+    "    or eax, (1 << 8) | (1 << 11)",
+    "    wrmsr",
+    "    lgdt [0x8000 + ap_gdt_ptr - ap_trampoline_start]",
+    // paging, write protection and protected mode, all at once
+    "    mov eax, cr0",
+    "    or eax, 0x80010001",
+    "    mov cr0, eax",
+    // ljmp 0x08:ap_long_mode
+    "    .byte 0x66, 0xea",
+    "    .long 0x8000 + ap_long_mode - ap_trampoline_start",
+    "    .word 0x08",
+    ".code64",
+    "ap_long_mode:",
+    "    mov ax, 0x10",
+    "    mov ds, ax",
+    "    mov es, ax",
+    "    mov ss, ax",
+    "    mov rsp, qword ptr [0x8000 + ap_trampoline_stack - ap_trampoline_start]",
+    "    mov rax, qword ptr [0x8000 + ap_trampoline_entry - ap_trampoline_start]",
+    "    call rax",
+    "2:",
+    "    hlt",
+    "    jmp 2b",
+    ".balign 8",
+    "ap_gdt:",
+    "    .quad 0",
+    "    .quad 0x00af9a000000ffff", // 64-bit code
+    "    .quad 0x00cf92000000ffff", // data
+    "ap_gdt_ptr:",
+    "    .word 3 * 8 - 1",
+    "    .long 0x8000 + ap_gdt - ap_trampoline_start",
+    ".balign 8",
+    "ap_trampoline_cr3:",
+    "    .quad 0",
+    "ap_trampoline_stack:",
+    "    .quad 0",
+    "ap_trampoline_entry:",
+    "    .quad 0",
+    "ap_trampoline_end:",
+    ".text",
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u8;
+    static ap_trampoline_stack: u8;
+    static ap_trampoline_entry: u8;
+}
+
+/// What the next AP needs to know to start
+struct ApBoot {
+    index: usize,
+    ist: [VirtAddr; 4],
+}
+
+static NEXT_AP: spin::Mutex<Option<ApBoot>> = spin::Mutex::new(None);
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Starts all the application processors listed in the MADT.
+///
+/// The APIC must already be enabled.
+pub fn init(
+    tables: &AcpiTables<AcpiHandler>,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+) {
+    if !apic::is_enabled() {
+        return;
+    }
+
+    let processors = match tables.platform_info().ok().and_then(|p| p.processor_info) {
+        Some(processors) => processors,
+        None => return,
+    };
+
+    if let Err(e) = install_trampoline(mapper, frame_alloc) {
+        println!("Couldn't install the AP trampoline: {:?}", e);
+        return;
+    }
+
+    for (i, ap) in processors.application_processors.iter().enumerate() {
+        if let ProcessorState::Disabled = ap.state {
+            continue;
+        }
+
+        let index = i + 1;
+        match start_ap(index, ap.local_apic_id as u32, mapper, frame_alloc) {
+            Ok(true) => println!("CPU {} started", index),
+            Ok(false) => println!("CPU {} didn't start", index),
+            Err(e) => println!("Couldn't allocate stacks for CPU {}: {:?}", index, e),
+        }
+    }
+}
+
+fn install_trampoline(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    // The frame allocator never gives this frame (see
+    // `BootInfoFrameAllocator`).
+    // The trampoline enables paging, so it must be identity mapped to keep
+    // running after that
+    let frame = PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE_ADDR));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.identity_map(frame, flags, frame_alloc) } {
+        Ok(flush) => flush.flush(),
+        Err(MapToError::PageAlreadyMapped(_)) => {}
+        Err(e) => return Err(e),
+    }
+
+    unsafe {
+        let start = &ap_trampoline_start as *const u8;
+        let len = &ap_trampoline_end as *const u8 as usize - start as usize;
+        let dest = (MEM_OFFSET + TRAMPOLINE_ADDR) as *mut u8;
+        core::ptr::copy_nonoverlapping(start, dest, len);
+    }
+
+    Ok(())
+}
+
+/// Writes a value in the data area at the end of the trampoline
+unsafe fn set_trampoline_var(var: &u8, value: u64) {
+    let offset = var as *const u8 as u64 - &ap_trampoline_start as *const u8 as u64;
+    let ptr = (MEM_OFFSET + TRAMPOLINE_ADDR + offset) as *mut u64;
+    core::ptr::write_volatile(ptr, value);
+}
+
+/// Returns whether the AP started
+fn start_ap(
+    index: usize,
+    apic_id: u32,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_alloc: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<bool, MapToError<Size4KiB>> {
+    let stack = memory::map_stack(mapper, frame_alloc, STACK_PAGES)?;
+    let ist = [
+        memory::map_stack(mapper, frame_alloc, IST_PAGES)?,
+        memory::map_stack(mapper, frame_alloc, IST_PAGES)?,
+        memory::map_stack(mapper, frame_alloc, IST_PAGES)?,
+        memory::map_stack(mapper, frame_alloc, IST_PAGES)?,
+    ];
+    *NEXT_AP.lock() = Some(ApBoot { index, ist });
+    AP_STARTED.store(false, Ordering::SeqCst);
+
+    let cr3 = Cr3::read().0.start_address().as_u64();
+    assert!(cr3 < (1 << 32), "The page table must be below 4GiB");
+    unsafe {
+        set_trampoline_var(&ap_trampoline_cr3, cr3);
+        set_trampoline_var(&ap_trampoline_stack, stack.as_u64());
+        set_trampoline_var(&ap_trampoline_entry, ap_main as usize as u64);
+    }
+
+    let page = (TRAMPOLINE_ADDR / 0x1000) as u8;
+    apic::send_init(apic_id);
+    pit::wait_us(10_000);
+    apic::send_startup(apic_id, page);
+    pit::wait_us(200);
+    if !AP_STARTED.load(Ordering::SeqCst) {
+        apic::send_startup(apic_id, page);
+    }
+
+    // wait at most 100ms
+    for _ in 0..100 {
+        if AP_STARTED.load(Ordering::SeqCst) {
+            return Ok(true);
+        }
+        pit::wait_us(1000);
+    }
+    Ok(false)
+}
+
+/// Entry point of the APs, called by the trampoline
+extern "C" fn ap_main() -> ! {
+    // only the AP that was woken up should get here, but a spurious start
+    // must not take down the CPU
+    let boot = match NEXT_AP.lock().take() {
+        Some(boot) => boot,
+        None => crate::halt_loop(),
+    };
+
+    percpu::init(boot.index);
+    gdt::init_ap(boot.ist);
+    crate::interrupt::init_idt();
+    apic::enable_local_apic();
+
+    AP_STARTED.store(true, Ordering::SeqCst);
+
+    apic::start_timer(InterruptIndex::ApicTimer.as_u8(), AP_TIMER_COUNT);
+    x86_64::instructions::interrupts::enable();
+    crate::halt_loop()
+}
@@ -0,0 +1,122 @@
+//! Storage devices
+//!
+//! Disks are [`BlockDevice`]s: they are read and written one block at a time.
+//! Drivers [`add`] the disks they find, and the first disk that holds a
+//! database is loaded in place of the one built in the kernel (see
+//! [`crate::db::mount`]).
+
+use crate::println;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use futures_util::future::LocalBoxFuture;
+
+pub mod nvme;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The blocks are past the end of the device
+    OutOfRange,
+    /// The buffer is not made of whole blocks
+    Unaligned,
+    /// Not enough memory for the transfer
+    NoMemory,
+    /// The device reported an error, with this status
+    Device(u16),
+    /// The device did not answer in time
+    Timeout,
+}
+
+/// A disk, or a part of a disk
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// In bytes
+    fn block_size(&self) -> usize;
+
+    fn block_count(&self) -> u64;
+
+    /// Reads the blocks starting at `first`, as many as `buffer` can hold.
+    fn read<'a>(
+        &'a self,
+        first: u64,
+        buffer: &'a mut [u8],
+    ) -> LocalBoxFuture<'a, Result<(), BlockError>>;
+
+    /// Writes `data` to the blocks starting at `first`.
+    fn write<'a>(
+        &'a self,
+        first: u64,
+        data: &'a [u8],
+    ) -> LocalBoxFuture<'a, Result<(), BlockError>>;
+}
+
+/// Checks that `len` bytes make whole blocks, and that they are on the
+/// device. Returns how many blocks it is.
+pub fn check_range(device: &dyn BlockDevice, first: u64, len: usize) -> Result<u64, BlockError> {
+    let block_size = device.block_size();
+    if len % block_size != 0 {
+        return Err(BlockError::Unaligned);
+    }
+    let count = (len / block_size) as u64;
+    match first.checked_add(count) {
+        Some(end) if end <= device.block_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// Reads `len` bytes from the start of a device (any length, not only whole
+/// blocks).
+pub async fn read_start(device: &dyn BlockDevice, len: usize) -> Result<Vec<u8>, BlockError> {
+    let block_size = device.block_size();
+    let len_in_blocks = (len + block_size - 1) / block_size * block_size;
+    let mut bytes = Vec::new();
+    bytes
+        .try_reserve_exact(len_in_blocks)
+        .map_err(|_| BlockError::NoMemory)?;
+    bytes.resize(len_in_blocks, 0);
+    device.read(0, &mut bytes).await?;
+    bytes.truncate(len);
+    Ok(bytes)
+}
+
+static DEVICES: spin::RwLock<Vec<Arc<dyn BlockDevice>>> = spin::RwLock::new(Vec::new());
+
+/// Makes a device available, and loads the database it holds, if any.
+pub async fn add(device: Arc<dyn BlockDevice>) {
+    println!(
+        "Disk {}: {} blocks of {} bytes ({} MiB)",
+        device.name(),
+        device.block_count(),
+        device.block_size(),
+        size(&*device) / (1024 * 1024)
+    );
+    DEVICES.write().push(Arc::clone(&device));
+    if !crate::db::is_mounted() {
+        match crate::db::mount(&*device).await {
+            Ok(true) => println!("Database loaded from {}", device.name()),
+            Ok(false) => {}
+            Err(err) => println!(
+                "Could not load the database of {}: {:?}",
+                device.name(),
+                err
+            ),
+        }
+    }
+}
+
+pub fn devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES.read().clone()
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES
+        .read()
+        .iter()
+        .find(|device| device.name() == name)
+        .cloned()
+}
+
+/// In bytes
+pub fn size(device: &dyn BlockDevice) -> u64 {
+    device.block_count() * device.block_size() as u64
+}
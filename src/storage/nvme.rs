@@ -0,0 +1,734 @@
+//! NVM Express controllers
+//!
+//! The controller gets an admin queue and a single I/O queue, shared by all
+//! its namespaces. Each namespace is a [`BlockDevice`].
+//!
+//! A task takes the completions from the queues and wakes the tasks that
+//! wait for them: it is woken by MSI-X or MSI when the device has them, and
+//! polls the queues otherwise.
+//!
+//! The queues and the buffers of the transfers are in DMA memory, so a
+//! command transfers at most [`MAX_PAGES`] pages: larger transfers are split.
+//!
+//! https://nvmexpress.org/specifications/
+
+use super::{check_range, BlockDevice, BlockError};
+use crate::driver::{Driver, DriverFuture, Match};
+use crate::interrupt::{self, Vector, DYNAMIC_COUNT, DYNAMIC_START};
+use crate::memory::{DmaPage, DmaRegion, MEM_OFFSET};
+use crate::pci::{self, PciDevice};
+use crate::println;
+use crate::task::{self, Task};
+use crate::time::{self, Duration};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, AtomicUsize, Ordering};
+use core::task::{Poll, Waker};
+use futures_util::future::{poll_fn, FutureExt, LocalBoxFuture};
+use futures_util::task::AtomicWaker;
+use pci_types::PciAddress;
+
+// Controller registers
+const CAP: u64 = 0x00;
+const CC: u64 = 0x14;
+const CSTS: u64 = 0x1c;
+const AQA: u64 = 0x24;
+const ASQ: u64 = 0x28;
+const ACQ: u64 = 0x30;
+const DOORBELLS: u64 = 0x1000;
+
+/// The controller supports the NVM command set
+const CAP_NVM: u64 = 1 << 37;
+const CC_ENABLE: u32 = 1 << 0;
+/// 64 bytes submission entries and 16 bytes completion entries (as powers
+/// of two)
+const CC_ENTRY_SIZES: u32 = 6 << 16 | 4 << 20;
+const CSTS_READY: u32 = 1 << 0;
+const CSTS_FATAL: u32 = 1 << 1;
+
+// Admin commands
+const ADMIN_CREATE_SQ: u8 = 0x01;
+const ADMIN_CREATE_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8 = 0x06;
+const ADMIN_SET_FEATURES: u8 = 0x09;
+
+// I/O commands
+const IO_WRITE: u8 = 0x01;
+const IO_READ: u8 = 0x02;
+
+// What identify commands return
+const IDENTIFY_NAMESPACE: u32 = 0;
+const IDENTIFY_CONTROLLER: u32 = 1;
+const IDENTIFY_ACTIVE_NAMESPACES: u32 = 2;
+
+const FEATURE_QUEUES: u32 = 0x07;
+
+const QUEUE_PHYS_CONTIGUOUS: u32 = 1 << 0;
+const QUEUE_INTERRUPTS: u32 = 1 << 1;
+
+const IO_QUEUE: u16 = 1;
+/// Entries of each queue, so that the submissions fill a page
+const QUEUE_LEN: u16 = (DmaPage::SIZE / 64) as u16;
+/// The most pages a command transfers
+pub const MAX_PAGES: usize = 32;
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// An entry of a submission queue
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct Command {
+    opcode: u8,
+    _flags: u8,
+    id: u16,
+    namespace: u32,
+    _reserved: u64,
+    _metadata: u64,
+    /// Physical Region Page entries: where the data is
+    prp1: u64,
+    prp2: u64,
+    /// Command dwords 10 to 15
+    args: [u32; 6],
+}
+
+impl Command {
+    fn new(opcode: u8, namespace: u32, prp1: u64, prp2: u64, args: [u32; 6]) -> Command {
+        Command {
+            opcode,
+            namespace,
+            prp1,
+            prp2,
+            args,
+            ..Command::default()
+        }
+    }
+
+    fn identify(what: u32, namespace: u32, page: &DmaPage) -> Command {
+        Command::new(
+            ADMIN_IDENTIFY,
+            namespace,
+            page.phys(),
+            0,
+            [what, 0, 0, 0, 0, 0],
+        )
+    }
+}
+
+/// An entry of a completion queue
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct Completion {
+    _result: u32,
+    _reserved: u32,
+    _sq_head: u16,
+    _sq_id: u16,
+    id: u16,
+    /// The phase tag (bit 0) and the status
+    status: u16,
+}
+
+impl Completion {
+    fn phase(&self) -> bool {
+        self.status & 1 != 0
+    }
+
+    fn status(&self) -> u16 {
+        self.status >> 1
+    }
+}
+
+/// A command that was submitted
+enum Slot {
+    Free,
+    /// Not completed yet, with the task to wake when it is
+    Waiting(Option<Waker>),
+    Done(Completion),
+    /// Given up on, with the memory the controller may still access: it is
+    /// freed when the command completes
+    Abandoned(Box<dyn Send>),
+}
+
+/// A submission queue, and the completion queue it uses
+struct QueuePair {
+    submissions: DmaPage,
+    completions: DmaPage,
+    len: u16,
+    tail: u16,
+    head: u16,
+    phase: bool,
+    /// Virtual addresses of the doorbell registers
+    tail_doorbell: u64,
+    head_doorbell: u64,
+    /// The commands, by id. One less than the entries, so that the
+    /// submission queue is never full.
+    slots: Vec<Slot>,
+}
+
+unsafe fn read32(addr: u64) -> u32 {
+    core::ptr::read_volatile(addr as *const u32)
+}
+
+unsafe fn read64(addr: u64) -> u64 {
+    read32(addr) as u64 | (read32(addr + 4) as u64) << 32
+}
+
+unsafe fn write32(addr: u64, value: u32) {
+    core::ptr::write_volatile(addr as *mut u32, value);
+}
+
+unsafe fn write64(addr: u64, value: u64) {
+    write32(addr, value as u32);
+    write32(addr + 4, (value >> 32) as u32);
+}
+
+impl QueuePair {
+    fn new(registers: u64, stride: u64, id: u16, len: u16) -> Option<QueuePair> {
+        let doorbell = |index: u16| registers + DOORBELLS + index as u64 * stride;
+        Some(QueuePair {
+            submissions: DmaPage::new()?,
+            completions: DmaPage::new()?,
+            len,
+            tail: 0,
+            head: 0,
+            phase: true,
+            tail_doorbell: doorbell(2 * id),
+            head_doorbell: doorbell(2 * id + 1),
+            slots: (1..len).map(|_| Slot::Free).collect(),
+        })
+    }
+
+    /// Queues a command, and returns its id (or `None` if too many commands
+    /// are running).
+    fn submit(&mut self, mut command: Command) -> Option<u16> {
+        let id = self
+            .slots
+            .iter()
+            .position(|slot| matches!(slot, Slot::Free))?;
+        self.slots[id] = Slot::Waiting(None);
+        command.id = id as u16;
+        unsafe {
+            let entry = self.submissions.as_ptr::<Command>().add(self.tail as usize);
+            entry.write_volatile(command);
+        }
+        self.tail = (self.tail + 1) % self.len;
+        fence(Ordering::SeqCst);
+        unsafe { write32(self.tail_doorbell, self.tail as u32) };
+        Some(id as u16)
+    }
+
+    /// Takes the completions the controller wrote, and wakes the tasks that
+    /// wait for them.
+    fn reap(&mut self) {
+        let head = self.head;
+        loop {
+            let entry = unsafe {
+                let entry = self.completions.as_ptr::<Completion>();
+                entry.add(self.head as usize).read_volatile()
+            };
+            if entry.phase() != self.phase {
+                break;
+            }
+            self.head += 1;
+            if self.head == self.len {
+                self.head = 0;
+                self.phase = !self.phase;
+            }
+            match self.slots.get_mut(entry.id as usize) {
+                Some(slot @ Slot::Waiting(_)) => {
+                    if let Slot::Waiting(Some(waker)) = core::mem::replace(slot, Slot::Done(entry))
+                    {
+                        waker.wake();
+                    }
+                }
+                Some(slot @ Slot::Abandoned(_)) => *slot = Slot::Free,
+                _ => {}
+            }
+        }
+        if self.head != head {
+            unsafe { write32(self.head_doorbell, self.head as u32) };
+        }
+    }
+
+    /// The completion of a command if it is done. Otherwise, `waker` will be
+    /// woken when it is.
+    fn take(&mut self, id: u16, waker: &Waker) -> Option<Completion> {
+        let slot = &mut self.slots[id as usize];
+        match *slot {
+            Slot::Done(completion) => {
+                *slot = Slot::Free;
+                Some(completion)
+            }
+            _ => {
+                *slot = Slot::Waiting(Some(waker.clone()));
+                None
+            }
+        }
+    }
+
+    /// Stops waiting for a command. `buffers` are kept until it completes.
+    fn abandon(&mut self, id: u16, buffers: Box<dyn Send>) {
+        let slot = &mut self.slots[id as usize];
+        *slot = match *slot {
+            Slot::Done(_) => Slot::Free,
+            _ => Slot::Abandoned(buffers),
+        };
+    }
+}
+
+/// A submitted command, abandoned if it is dropped before it completes
+struct Pending<'a, T: Send + 'static> {
+    queue: &'a spin::Mutex<QueuePair>,
+    id: u16,
+    buffers: Option<T>,
+}
+
+impl<T: Send + 'static> Drop for Pending<'_, T> {
+    fn drop(&mut self) {
+        if let Some(buffers) = self.buffers.take() {
+            self.queue.lock().abandon(self.id, Box::new(buffers));
+        }
+    }
+}
+
+/// Runs a command on a queue, and waits for its completion (at most
+/// [`COMMAND_TIMEOUT`]).
+///
+/// `buffers` hold the memory the command uses: they are given back when it
+/// completes, and kept from being freed until then if it times out.
+async fn run<T: Send + 'static>(
+    queue: &spin::Mutex<QueuePair>,
+    command: Command,
+    buffers: T,
+) -> Result<(Completion, T), BlockError> {
+    let wait = async {
+        let id = loop {
+            if let Some(id) = queue.lock().submit(command) {
+                break id;
+            }
+            time::sleep(POLL_INTERVAL).await;
+        };
+        let mut pending = Pending {
+            queue,
+            id,
+            buffers: Some(buffers),
+        };
+        let completion = poll_fn(|cx| {
+            let mut queue = queue.lock();
+            queue.reap();
+            match queue.take(id, cx.waker()) {
+                Some(completion) => Poll::Ready(completion),
+                None => Poll::Pending,
+            }
+        })
+        .await;
+        (completion, pending.buffers.take().unwrap())
+    };
+    let (completion, buffers) = time::timeout(COMMAND_TIMEOUT, wait)
+        .await
+        .map_err(|_| BlockError::Timeout)?;
+    match completion.status() {
+        0 => Ok((completion, buffers)),
+        status => Err(BlockError::Device(status)),
+    }
+}
+
+/// Wakes the tasks that take the completions, by vector
+static WAKERS: [AtomicWaker; DYNAMIC_COUNT] = {
+    const NONE: AtomicWaker = AtomicWaker::new();
+    [NONE; DYNAMIC_COUNT]
+};
+
+fn on_interrupt(vector: u8) {
+    WAKERS[(vector - DYNAMIC_START) as usize].wake();
+}
+
+/// How many controllers were found, to name the namespaces
+static CONTROLLERS: AtomicUsize = AtomicUsize::new(0);
+
+struct Controller {
+    /// The number of the controller, in the names of the namespaces
+    number: usize,
+    admin: spin::Mutex<QueuePair>,
+    io: spin::Mutex<QueuePair>,
+    /// Signals the completions, if the device has MSI or MSI-X
+    vector: Option<Vector>,
+}
+
+impl Controller {
+    /// Resets the controller, and starts it with its admin queue.
+    ///
+    /// `mmio` is the physical address of the registers (BAR 0).
+    async fn new(mmio: u64, vector: Option<Vector>) -> Option<Controller> {
+        let registers = MEM_OFFSET + mmio;
+        let cap = unsafe { read64(registers + CAP) };
+        // only 4 KiB pages are used
+        if cap & CAP_NVM == 0 || (cap >> 48) & 0xf != 0 {
+            println!("NVMe: unsupported controller ({:#x})", cap);
+            return None;
+        }
+        let len = QUEUE_LEN.min((cap & 0xffff) as u16 + 1);
+        let stride = 4 << ((cap >> 32) & 0xf);
+        // in units of 500 ms
+        let timeout = Duration::from_millis(((cap >> 24) & 0xff) * 500).max(COMMAND_TIMEOUT);
+
+        unsafe {
+            let config = read32(registers + CC);
+            write32(registers + CC, config & !CC_ENABLE);
+        }
+        if !wait_ready(registers, false, timeout).await {
+            println!("NVMe: the controller does not stop");
+            return None;
+        }
+
+        let controller = Controller {
+            number: CONTROLLERS.fetch_add(1, Ordering::SeqCst),
+            admin: spin::Mutex::new(QueuePair::new(registers, stride, 0, len)?),
+            io: spin::Mutex::new(QueuePair::new(registers, stride, IO_QUEUE, len)?),
+            vector,
+        };
+        {
+            let admin = controller.admin.lock();
+            let sizes = (len as u32 - 1) << 16 | (len as u32 - 1);
+            unsafe {
+                write32(registers + AQA, sizes);
+                write64(registers + ASQ, admin.submissions.phys());
+                write64(registers + ACQ, admin.completions.phys());
+                write32(registers + CC, CC_ENABLE | CC_ENTRY_SIZES);
+            }
+        }
+        if !wait_ready(registers, true, timeout).await {
+            println!("NVMe: the controller does not start");
+            return None;
+        }
+        Some(controller)
+    }
+
+    /// Runs an admin command, and gives back its `buffers` (see [`run`]).
+    async fn admin<T: Send + 'static>(&self, command: Command, buffers: T) -> Option<T> {
+        match run(&self.admin, command, buffers).await {
+            Ok((_, buffers)) => Some(buffers),
+            Err(err) => {
+                println!("NVMe: command {} failed: {:?}", command.opcode, err);
+                None
+            }
+        }
+    }
+
+    /// Creates the I/O queue, with the same size as the admin queue.
+    async fn create_io_queue(&self) -> Option<()> {
+        // one submission queue and one completion queue (zero-based)
+        self.admin(
+            Command::new(ADMIN_SET_FEATURES, 0, 0, 0, [FEATURE_QUEUES, 0, 0, 0, 0, 0]),
+            (),
+        )
+        .await?;
+
+        let (submissions, completions, len) = {
+            let io = self.io.lock();
+            (io.submissions.phys(), io.completions.phys(), io.len)
+        };
+        let size = (len as u32 - 1) << 16 | IO_QUEUE as u32;
+        // interrupts go to the first MSI-X entry
+        let interrupts = if self.vector.is_some() {
+            QUEUE_INTERRUPTS
+        } else {
+            0
+        };
+        self.admin(
+            Command::new(
+                ADMIN_CREATE_CQ,
+                0,
+                completions,
+                0,
+                [size, QUEUE_PHYS_CONTIGUOUS | interrupts, 0, 0, 0, 0],
+            ),
+            (),
+        )
+        .await?;
+        self.admin(
+            Command::new(
+                ADMIN_CREATE_SQ,
+                0,
+                submissions,
+                0,
+                [
+                    size,
+                    (IO_QUEUE as u32) << 16 | QUEUE_PHYS_CONTIGUOUS,
+                    0,
+                    0,
+                    0,
+                    0,
+                ],
+            ),
+            (),
+        )
+        .await?;
+        Some(())
+    }
+
+    /// Takes the completions of both queues, each time the controller
+    /// signals some (or regularly, without interrupts).
+    async fn reap(&self) {
+        match self.vector {
+            Some(ref vector) => {
+                let waker = &WAKERS[(vector.as_u8() - DYNAMIC_START) as usize];
+                poll_fn(|cx| {
+                    waker.register(cx.waker());
+                    self.admin.lock().reap();
+                    self.io.lock().reap();
+                    Poll::<()>::Pending
+                })
+                .await
+            }
+            None => loop {
+                self.admin.lock().reap();
+                self.io.lock().reap();
+                time::sleep(POLL_INTERVAL).await;
+            },
+        }
+    }
+}
+
+/// Waits until the controller is ready (or not ready anymore).
+async fn wait_ready(registers: u64, ready: bool, timeout: Duration) -> bool {
+    let wait = async {
+        loop {
+            let status = unsafe { read32(registers + CSTS) };
+            if status & CSTS_FATAL != 0 {
+                return false;
+            }
+            if (status & CSTS_READY != 0) == ready {
+                return true;
+            }
+            time::sleep(Duration::from_millis(1)).await;
+        }
+    };
+    time::timeout(timeout, wait).await.unwrap_or(false)
+}
+
+/// A namespace of a controller: a disk
+pub struct Namespace {
+    name: String,
+    controller: Arc<Controller>,
+    id: u32,
+    block_size: usize,
+    blocks: u64,
+    /// The most pages a command transfers
+    max_pages: usize,
+}
+
+impl Namespace {
+    /// Reads or writes the blocks starting at `first`, with the data in
+    /// `region`, and gives it back.
+    async fn transfer(
+        &self,
+        opcode: u8,
+        first: u64,
+        region: DmaRegion,
+        len: usize,
+    ) -> Result<DmaRegion, BlockError> {
+        let pages = (len + DmaPage::SIZE - 1) / DmaPage::SIZE;
+        // the second entry is the second page, or the list of all the pages
+        // but the first one
+        let list = if pages > 2 {
+            let list = DmaPage::new().ok_or(BlockError::NoMemory)?;
+            for i in 1..pages {
+                let page = region.phys() + (i * DmaPage::SIZE) as u64;
+                unsafe { list.as_ptr::<u64>().add(i - 1).write_volatile(page) };
+            }
+            Some(list)
+        } else {
+            None
+        };
+        let prp2 = match list {
+            Some(ref list) => list.phys(),
+            None if pages == 2 => region.phys() + DmaPage::SIZE as u64,
+            None => 0,
+        };
+        let count = (len / self.block_size) as u32;
+        let command = Command::new(
+            opcode,
+            self.id,
+            region.phys(),
+            prp2,
+            [first as u32, (first >> 32) as u32, count - 1, 0, 0, 0],
+        );
+        let (_, (region, _)) = run(&self.controller.io, command, (region, list)).await?;
+        Ok(region)
+    }
+
+    /// The most bytes a command transfers
+    fn max_transfer(&self) -> usize {
+        self.max_pages * DmaPage::SIZE
+    }
+
+    fn region(len: usize) -> Result<DmaRegion, BlockError> {
+        DmaRegion::new((len + DmaPage::SIZE - 1) / DmaPage::SIZE).ok_or(BlockError::NoMemory)
+    }
+}
+
+impl BlockDevice for Namespace {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.blocks
+    }
+
+    fn read<'a>(
+        &'a self,
+        first: u64,
+        buffer: &'a mut [u8],
+    ) -> LocalBoxFuture<'a, Result<(), BlockError>> {
+        async move {
+            check_range(self, first, buffer.len())?;
+            let mut block = first;
+            for part in buffer.chunks_mut(self.max_transfer()) {
+                let region = Namespace::region(part.len())?;
+                let region = self.transfer(IO_READ, block, region, part.len()).await?;
+                unsafe {
+                    let data = core::slice::from_raw_parts(region.as_ptr::<u8>(), part.len());
+                    part.copy_from_slice(data);
+                }
+                block += (part.len() / self.block_size) as u64;
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn write<'a>(
+        &'a self,
+        first: u64,
+        data: &'a [u8],
+    ) -> LocalBoxFuture<'a, Result<(), BlockError>> {
+        async move {
+            check_range(self, first, data.len())?;
+            let mut block = first;
+            for part in data.chunks(self.max_transfer()) {
+                let region = Namespace::region(part.len())?;
+                unsafe {
+                    let buffer = core::slice::from_raw_parts_mut(region.as_ptr::<u8>(), part.len());
+                    buffer.copy_from_slice(part);
+                }
+                self.transfer(IO_WRITE, block, region, part.len()).await?;
+                block += (part.len() / self.block_size) as u64;
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
+
+/// Creates the I/O queue, finds the namespaces and adds them as block
+/// devices.
+async fn add_namespaces(controller: Arc<Controller>) -> Option<()> {
+    let page = DmaPage::new()?;
+    let page = controller
+        .admin(Command::identify(IDENTIFY_CONTROLLER, 0, &page), page)
+        .await?;
+    let identify = unsafe { core::slice::from_raw_parts(page.as_ptr::<u8>(), DmaPage::SIZE) };
+    let model = String::from_utf8_lossy(&identify[24..64]);
+    println!("NVMe: {}", model.trim());
+    // as a power of two of the page size, 0 meaning no limit
+    let max_pages = match identify[77] {
+        0 => MAX_PAGES,
+        mdts => MAX_PAGES.min(1 << mdts.min(16)),
+    };
+
+    controller.create_io_queue().await?;
+
+    let mut page = controller
+        .admin(
+            Command::identify(IDENTIFY_ACTIVE_NAMESPACES, 0, &page),
+            page,
+        )
+        .await?;
+    let ids: Vec<u32> = (0..DmaPage::SIZE / 4)
+        .map(|i| unsafe { page.as_ptr::<u32>().add(i).read_volatile() })
+        .take_while(|&id| id != 0)
+        .collect();
+
+    for id in ids {
+        page = controller
+            .admin(Command::identify(IDENTIFY_NAMESPACE, id, &page), page)
+            .await?;
+        let (blocks, block_size) = unsafe {
+            let blocks = page.as_ptr::<u64>().read_volatile();
+            // the LBA format in use, and its size (as a power of two)
+            let format = page.as_ptr::<u8>().add(26).read_volatile() & 0xf;
+            let shift = page
+                .as_ptr::<u8>()
+                .add(128 + 4 * format as usize + 2)
+                .read_volatile();
+            (blocks, 1usize << shift.min(31))
+        };
+        let name = alloc::format!("nvme{}n{}", controller.number, id);
+        if blocks == 0 || block_size < 512 || block_size > DmaPage::SIZE {
+            println!(
+                "NVMe: {} has unsupported blocks of {} bytes",
+                name, block_size
+            );
+            continue;
+        }
+        let namespace = Namespace {
+            name,
+            controller: Arc::clone(&controller),
+            id,
+            block_size,
+            blocks,
+            max_pages,
+        };
+        super::add(Arc::new(namespace)).await;
+    }
+    Some(())
+}
+
+pub static DRIVER: Driver = Driver {
+    name: "nvme",
+    matches: &[Match::Class {
+        class: 0x01,
+        sub_class: 0x08,
+        interface: Some(0x02),
+    }],
+    start,
+};
+
+fn start(address: PciAddress, device: &PciDevice) -> Option<DriverFuture> {
+    let mmio = device.bar_address(0)?;
+    let vector = {
+        let access = pci::ACCESS.lock();
+        let access = access.as_ref()?;
+        pci::enable_bus_master(access, address);
+        interrupt::allocate_vector(on_interrupt).and_then(|vector| {
+            pci::enable_message_interrupts(access, address, device, &vector)
+                .ok()
+                .map(|_| vector)
+        })
+    };
+    Some(run_controller(mmio, vector).boxed_local())
+}
+
+/// Drives the controller whose registers are at `mmio` (physical address).
+async fn run_controller(mmio: u64, vector: Option<Vector>) -> Result<(), &'static str> {
+    let controller = Controller::new(mmio, vector)
+        .await
+        .ok_or("could not start the controller")?;
+    let controller = Arc::new(controller);
+    let namespaces = Arc::clone(&controller);
+    task::spawn(Task::new(async move {
+        if add_namespaces(namespaces).await.is_none() {
+            println!("NVMe: could not set up the namespaces");
+        }
+    }));
+    controller.reap().await;
+    Ok(())
+}
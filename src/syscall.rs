@@ -0,0 +1,354 @@
+//! System calls, reached with `int 0x80`.
+//!
+//! The number of the system call goes in `rax`, and its arguments in `rbx`,
+//! `rcx` and `rdx`. The result is returned in `rax`, [`ERROR`] meaning that
+//! the call failed.
+//!
+//! Everything a process can access is referred to by a handle
+//! (see [`crate::process::handle`]), that is checked for each call.
+
+use crate::driver::grant::{DeviceError, DeviceGrant};
+use crate::framebuffer::surface::{Rect, Surface, SurfaceError};
+use crate::println;
+use crate::process::{
+    self,
+    handle::{Handle, HandleError, HandleTable, Object, Rights},
+    Process, Stream, StreamError,
+};
+use crate::security::{self, Denied};
+use core::fmt::Debug;
+
+pub const ERROR: u64 = u64::MAX;
+
+pub fn dispatch(code: u64, arg1: u64, arg2: u64, arg3: u64) -> u64 {
+    let proc = match process::current().and_then(process::get_mut) {
+        Some(proc) if !proc.is_killed() => proc,
+        _ => return ERROR,
+    };
+
+    match code {
+        0 => result(fill_screen(arg1 as u8)),
+        1 => result(open(proc, adb::TypeId(arg1))),
+        2 => result(read(proc, Handle::from_raw(arg1))),
+        3 => result(handles_of(proc, |handles| handles.close(Handle::from_raw(arg1))).map(|_| 0)),
+        4 => result(handles_of(proc, |handles| {
+            handles
+                .duplicate(Handle::from_raw(arg1), Rights::from_bits(arg2))
+                .map(Handle::as_raw)
+        })),
+        5 => result(transfer(
+            proc,
+            Handle::from_raw(arg1),
+            Handle::from_raw(arg2),
+            Rights::from_bits(arg3),
+        )),
+        6 => sleep(arg1),
+        7 => result(map_surface(proc, arg1 as usize, arg2 as usize)),
+        8 => result(surface_address(proc, Handle::from_raw(arg1))),
+        9 => result(surface_info(proc, Handle::from_raw(arg1))),
+        10 => result(present(proc, Handle::from_raw(arg1), arg2, arg3)),
+        11 => result(map_bar(proc, Handle::from_raw(arg1), arg2)),
+        12 => result(allow_ports(proc, Handle::from_raw(arg1), arg2)),
+        13 => result(open_interrupts(proc, Handle::from_raw(arg1))),
+        14 => result(allocate_dma(proc, Handle::from_raw(arg1), arg2, arg3)),
+        15 => result(write(proc, Handle::from_raw(arg1), Handle::from_raw(arg2))),
+        16 => exit(),
+        _ => ERROR,
+    }
+}
+
+fn result<E: Debug>(res: Result<u64, E>) -> u64 {
+    match res {
+        Ok(x) => x,
+        Err(e) => {
+            println!("system call failed: {:?}", e);
+            ERROR
+        }
+    }
+}
+
+/// Runs `f` on the handle table of a process
+fn handles_of<T>(
+    proc: &Process,
+    f: impl FnOnce(&mut HandleTable) -> Result<T, HandleError>,
+) -> Result<T, HandleError> {
+    proc.with_handles(f).unwrap_or(Err(HandleError::Invalid))
+}
+
+/// Fills the screen, if the process could map it (see
+/// [`security::check_screen`])
+fn fill_screen(color: u8) -> Result<u64, Denied> {
+    security::check_screen()?;
+    let mut fb = crate::framebuffer::FRAMEBUFFER.lock();
+    if let Some(ref mut fb) = *fb {
+        fb.fill_bytes(color)
+    }
+    Ok(0)
+}
+
+/// Opens a stream of a given type, and returns a handle to it
+fn open(proc: &mut Process, ty: adb::TypeId) -> Result<u64, StreamError> {
+    proc.open_stream(ty).map(Handle::as_raw)
+}
+
+#[derive(Debug)]
+enum StreamCallError {
+    Handle(HandleError),
+    Stream(StreamError),
+}
+
+impl From<HandleError> for StreamCallError {
+    fn from(err: HandleError) -> Self {
+        StreamCallError::Handle(err)
+    }
+}
+
+impl From<StreamError> for StreamCallError {
+    fn from(err: StreamError) -> Self {
+        StreamCallError::Stream(err)
+    }
+}
+
+/// Reads the next object of a stream. For the moment, it is just displayed
+/// on the kernel console.
+///
+/// Returns 1 if an object was read, 0 at the end of the stream.
+fn read(proc: &mut Process, handle: Handle) -> Result<u64, StreamCallError> {
+    let object = proc.handle(handle, Rights::READ)?;
+    let mut object = object.lock();
+    let stream = match *object {
+        Object::Stream(ref mut stream) => stream,
+        _ => return Err(HandleError::WrongKind.into()),
+    };
+
+    match stream.read()? {
+        Some(obj) => {
+            let db = crate::db::DB.lock();
+            let db = db.as_ref().ok_or(StreamError::NoDatabase)?;
+            println!(
+                "{}",
+                crate::db::DbValueDisplay::new(db, obj.value, obj.type_info).compact()
+            );
+            Ok(1)
+        }
+        None => Ok(0),
+    }
+}
+
+/// Writes the last object read from the stream `from` to the stream `to`.
+fn write(proc: &mut Process, to: Handle, from: Handle) -> Result<u64, StreamCallError> {
+    let object = match *proc.handle(from, Rights::READ)?.lock() {
+        Object::Stream(ref stream) => stream.last().ok_or(StreamError::NothingRead)?,
+        _ => return Err(HandleError::WrongKind.into()),
+    };
+    match *proc.handle(to, Rights::WRITE)?.lock() {
+        Object::Stream(ref stream) => stream.write(object)?,
+        _ => return Err(HandleError::WrongKind.into()),
+    }
+    Ok(0)
+}
+
+/// Stops the current process, once the call returns
+fn exit() -> u64 {
+    if let Some(pid) = process::current() {
+        process::kill(pid);
+    }
+    0
+}
+
+/// Waits for `ns` nanoseconds, with the same timers as kernel tasks (or
+/// until the process is killed)
+fn sleep(ns: u64) -> u64 {
+    let duration = crate::time::Duration::from_nanos(ns);
+    process::block_on(crate::time::sleep(duration));
+    0
+}
+
+#[derive(Debug)]
+enum SurfaceCallError {
+    Handle(HandleError),
+    Surface(SurfaceError),
+}
+
+impl From<HandleError> for SurfaceCallError {
+    fn from(err: HandleError) -> Self {
+        SurfaceCallError::Handle(err)
+    }
+}
+
+impl From<SurfaceError> for SurfaceCallError {
+    fn from(err: SurfaceError) -> Self {
+        SurfaceCallError::Surface(err)
+    }
+}
+
+/// Maps a surface, and returns a handle to it. A width of 0 means the
+/// screen, otherwise an off-screen surface of this size is created.
+fn map_surface(proc: &mut Process, width: usize, height: usize) -> Result<u64, SurfaceCallError> {
+    let surface = match width {
+        0 => Surface::screen(proc.space())?,
+        _ => Surface::off_screen(Some(proc.space()), width, height)?,
+    };
+    let handle = proc.insert_handle(Object::Surface(surface), Rights::ALL)?;
+    Ok(handle.as_raw())
+}
+
+fn with_surface(
+    proc: &mut Process,
+    handle: Handle,
+    rights: Rights,
+    f: impl FnOnce(&Surface) -> Result<u64, SurfaceError>,
+) -> Result<u64, SurfaceCallError> {
+    let object = proc.handle(handle, rights)?;
+    let object = object.lock();
+    match *object {
+        Object::Surface(ref surface) => Ok(f(surface)?),
+        _ => Err(HandleError::WrongKind.into()),
+    }
+}
+
+/// Where the pixels of a surface are mapped
+fn surface_address(proc: &mut Process, handle: Handle) -> Result<u64, SurfaceCallError> {
+    with_surface(proc, handle, Rights::WRITE, |surface| {
+        Ok(surface.address.as_u64())
+    })
+}
+
+/// The size and pixel format of a surface (see [`Surface::info`])
+fn surface_info(proc: &mut Process, handle: Handle) -> Result<u64, SurfaceCallError> {
+    with_surface(proc, handle, Rights::READ, |surface| Ok(surface.info()))
+}
+
+/// Most damage rectangles a process can give at once
+const MAX_DAMAGE: u64 = 64;
+
+/// Shows the parts of a surface that changed, given as `count` [`Rect`]s
+/// at `rects` (0 meaning the whole surface).
+fn present(
+    proc: &mut Process,
+    handle: Handle,
+    rects: u64,
+    count: u64,
+) -> Result<u64, SurfaceCallError> {
+    let damage: &[Rect] = if count == 0 {
+        &[]
+    } else {
+        let len = count.min(MAX_DAMAGE) as usize * core::mem::size_of::<Rect>();
+        let valid = proc
+            .space()
+            .lock()
+            .is_accessible(x86_64::VirtAddr::new_truncate(rects), len);
+        if !valid || rects % 4 != 0 {
+            return Err(SurfaceError::InvalidDamage.into());
+        }
+        unsafe { core::slice::from_raw_parts(rects as *const Rect, count.min(MAX_DAMAGE) as usize) }
+    };
+    with_surface(proc, handle, Rights::WRITE, |surface| {
+        surface.present(damage).map(|_| 0)
+    })
+}
+
+#[derive(Debug)]
+enum DeviceCallError {
+    Handle(HandleError),
+    Device(DeviceError),
+}
+
+impl From<HandleError> for DeviceCallError {
+    fn from(err: HandleError) -> Self {
+        DeviceCallError::Handle(err)
+    }
+}
+
+impl From<DeviceError> for DeviceCallError {
+    fn from(err: DeviceError) -> Self {
+        DeviceCallError::Device(err)
+    }
+}
+
+fn with_device<T>(
+    proc: &mut Process,
+    handle: Handle,
+    rights: Rights,
+    f: impl FnOnce(&mut DeviceGrant) -> Result<T, DeviceError>,
+) -> Result<T, DeviceCallError> {
+    let object = proc.handle(handle, rights)?;
+    let mut object = object.lock();
+    match *object {
+        Object::Device(ref mut grant) => Ok(f(grant)?),
+        _ => Err(HandleError::WrongKind.into()),
+    }
+}
+
+/// Maps a memory BAR of a granted device, and returns where it is
+fn map_bar(proc: &mut Process, handle: Handle, bar: u64) -> Result<u64, DeviceCallError> {
+    with_device(proc, handle, Rights::WRITE, |grant| {
+        grant.map_bar(bar as usize)
+    })
+}
+
+/// Lets the process use the ports of an I/O BAR of a granted device, and
+/// returns the first one
+fn allow_ports(proc: &mut Process, handle: Handle, bar: u64) -> Result<u64, DeviceCallError> {
+    with_device(proc, handle, Rights::WRITE, |grant| {
+        grant.allow_ports(bar as usize)
+    })
+}
+
+/// Returns a handle to a stream of the interrupts of a granted device
+fn open_interrupts(proc: &mut Process, handle: Handle) -> Result<u64, DeviceCallError> {
+    let interrupts = with_device(proc, handle, Rights::READ, |grant| grant.open_interrupts())?;
+    let stream = Object::Stream(Stream::interrupts(interrupts));
+    let handle = proc.insert_handle(stream, Rights::ALL)?;
+    Ok(handle.as_raw())
+}
+
+/// Allocates `len` bytes of memory that a granted device can access, and
+/// returns where they are mapped. Their physical address is written at
+/// `phys`.
+fn allocate_dma(
+    proc: &mut Process,
+    handle: Handle,
+    len: u64,
+    phys: u64,
+) -> Result<u64, DeviceCallError> {
+    let valid = proc
+        .space()
+        .lock()
+        .is_accessible(x86_64::VirtAddr::new_truncate(phys), 8);
+    if !valid || phys % 8 != 0 {
+        return Err(DeviceError::NoAccess.into());
+    }
+    let (addr, physical) = with_device(proc, handle, Rights::WRITE, |grant| {
+        grant.allocate_dma(len as usize)
+    })?;
+    unsafe { core::ptr::write_volatile(phys as *mut u64, physical) };
+    Ok(addr)
+}
+
+/// Gives a handle to a child process
+///
+/// Returns the handle in the child's table.
+fn transfer(
+    proc: &mut Process,
+    handle: Handle,
+    child: Handle,
+    rights: Rights,
+) -> Result<u64, HandleError> {
+    let child = match *proc.handle(child, Rights::WRITE)?.lock() {
+        Object::Process(pid) => pid,
+        _ => return Err(HandleError::WrongKind),
+    };
+    let child = process::get(child).ok_or(HandleError::Invalid)?;
+    // one table is locked at a time: the handle is taken out of this one, and
+    // then put in the child's one, or back in this one if the child is full
+    // or was killed
+    let mut moving = Some(handles_of(proc, |handles| {
+        handles.take_transfer(handle, rights)
+    })?);
+    let moved = handles_of(child, |handles| handles.insert_moved(&mut moving));
+    if let Some(moving) = moving {
+        proc.with_handles(|handles| handles.put_back(moving));
+    }
+    moved.map(Handle::as_raw)
+}
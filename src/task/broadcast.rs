@@ -0,0 +1,110 @@
+//! A channel where each message is received by every receiver
+//!
+//! The last messages are kept in a ring buffer. Receivers that are too slow
+//! miss the oldest messages instead of blocking the sender.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub struct Channel<T> {
+    inner: Mutex<Inner<T>>,
+}
+
+struct Inner<T> {
+    messages: VecDeque<T>,
+    capacity: usize,
+    /// Sequence number of the first message in `messages`
+    first: u64,
+    wakers: Vec<Waker>,
+}
+
+impl<T: Clone> Channel<T> {
+    pub fn new(capacity: usize) -> Channel<T> {
+        Channel {
+            inner: Mutex::new(Inner {
+                messages: VecDeque::with_capacity(capacity),
+                capacity,
+                first: 0,
+                wakers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Sends a message to all the receivers, and wakes them.
+    pub fn send(&self, message: T) {
+        let wakers = interrupts::without_interrupts(|| {
+            let mut inner = self.inner.lock();
+            if inner.messages.len() == inner.capacity {
+                inner.messages.pop_front();
+                inner.first += 1;
+            }
+            inner.messages.push_back(message);
+            core::mem::take(&mut inner.wakers)
+        });
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// A new receiver, that will get all the messages sent from now on
+    pub fn subscribe(&'static self) -> Receiver<T> {
+        let next = interrupts::without_interrupts(|| {
+            let inner = self.inner.lock();
+            inner.first + inner.messages.len() as u64
+        });
+        Receiver {
+            channel: self,
+            next,
+        }
+    }
+}
+
+pub struct Receiver<T: 'static> {
+    channel: &'static Channel<T>,
+    /// Sequence number of the next message to receive
+    next: u64,
+}
+
+impl<T: Clone> Receiver<T> {
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<T> {
+        interrupts::without_interrupts(|| {
+            let mut inner = self.channel.inner.lock();
+            // skip the messages we missed
+            self.next = self.next.max(inner.first);
+            match inner.messages.get((self.next - inner.first) as usize) {
+                Some(message) => {
+                    self.next += 1;
+                    Poll::Ready(message.clone())
+                }
+                None => {
+                    if !inner.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                        inner.wakers.push(cx.waker().clone());
+                    }
+                    Poll::Pending
+                }
+            }
+        })
+    }
+
+    /// Waits for the next message.
+    pub fn recv(&mut self) -> Recv<'_, T> {
+        Recv { receiver: self }
+    }
+}
+
+pub struct Recv<'a, T: 'static> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<'a, T: Clone> Future for Recv<'a, T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        self.receiver.poll_recv(cx)
+    }
+}
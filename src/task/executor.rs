@@ -2,12 +2,8 @@ use super::{Task, TaskId};
 use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
 use core::task::{Context, Poll, Waker};
 use crossbeam_queue::ArrayQueue;
-use spin::Mutex;
-
-lazy_static::lazy_static! {
-    pub static ref EXECUTOR: Mutex<Executor> = Mutex::new(Executor::new());
-}
 
+/// Each CPU has its own executor (see `crate::percpu`)
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
     task_queue: Arc<ArrayQueue<TaskId>>,
@@ -55,7 +51,6 @@ impl Executor {
                 .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
             let mut ctx = Context::from_waker(waker);
             *currently_running = Some(task_id);
-            crate::println!("running task {}", task_id.0);
             match task.poll(&mut ctx) {
                 Poll::Ready(_) => {
                     tasks.remove(&task_id);
@@ -68,9 +63,7 @@ impl Executor {
     }
 
     pub fn run(&mut self) -> ! {
-        crate::println!("exec.run");
         loop {
-            crate::println!("loop");
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
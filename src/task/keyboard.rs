@@ -59,22 +59,74 @@ impl Stream for ScancodeStream {
     }
 }
 
+use crate::input::keyboard::{self as input, KeyEvent, KeyState};
 use crate::print;
-use futures_util::stream::StreamExt;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use crate::task::broadcast::Receiver;
+use alloc::string::String;
+use pc_keyboard::DecodedKey;
 
-pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore);
+/// Decoded keyboard input, with a few helpers for console applications.
+///
+/// It listens to the events of `crate::input::keyboard`, that must be running.
+pub struct Console {
+    events: Receiver<KeyEvent>,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Console {
+            events: input::subscribe(),
+        }
+    }
+
+    /// Waits for the next key press (or repetition)
+    pub async fn next_key(&mut self) -> Option<DecodedKey> {
+        loop {
+            let event = self.events.recv().await;
+            match (event.state, event.text) {
+                (KeyState::Up, _) => {}
+                (_, Some(c)) => return Some(DecodedKey::Unicode(c)),
+                (_, None) => return Some(DecodedKey::RawKey(event.code)),
+            }
+        }
+    }
 
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
+    /// Reads a line, echoing it as it is typed.
+    ///
+    /// The returned string doesn't include the final new line.
+    pub async fn read_line(&mut self) -> Option<String> {
+        let mut line = String::new();
+        loop {
+            match self.next_key().await? {
+                DecodedKey::Unicode('\n') => {
+                    print!("\n");
+                    return Some(line);
+                }
+                // backspace
+                DecodedKey::Unicode('\u{8}') => {
+                    if line.pop().is_some() {
+                        print!("\u{8} \u{8}");
+                    }
+                }
+                DecodedKey::Unicode(c) => {
+                    line.push(c);
+                    print!("{}", c);
                 }
+                DecodedKey::RawKey(_) => {}
             }
         }
     }
 }
+
+pub async fn print_keypresses() {
+    echo_keypresses(&mut Console::new()).await
+}
+
+pub async fn echo_keypresses(console: &mut Console) {
+    while let Some(key) = console.next_key().await {
+        match key {
+            DecodedKey::Unicode(character) => print!("{}", character),
+            DecodedKey::RawKey(key) => print!("{:?}", key),
+        }
+    }
+}
@@ -1,11 +1,12 @@
-use alloc::boxed::Box;
-use core::sync::atomic::{AtomicU64, Ordering};
+use alloc::{boxed::Box, sync::Arc, task::Wake};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use core::{
     future::Future,
     pin::Pin,
-    task::{Context, Poll},
+    task::{Context, Poll, Waker},
 };
 
+pub mod broadcast;
 pub mod executor;
 pub mod keyboard;
 pub mod simple_executor;
@@ -20,6 +21,52 @@ impl TaskId {
     }
 }
 
+/// Runs a task on the current CPU.
+///
+/// Tasks never move to another CPU: their futures don't have to be `Send`.
+pub fn spawn(task: Task) {
+    crate::percpu::spawn(task);
+}
+
+/// Runs a future on the current CPU until it completes, halting while it
+/// can't make progress.
+///
+/// This is how system calls wait: the process is stuck in the system call,
+/// but the CPU still handles interrupts (and runs its tasks) in the meantime.
+/// Interrupts are enabled while waiting.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    struct FlagWaker(AtomicBool);
+
+    impl Wake for FlagWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+
+        fn wake_by_ref(self: &Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    use x86_64::instructions::interrupts;
+
+    let mut future = Box::pin(future);
+    let flag = Arc::new(FlagWaker(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut ctx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut ctx) {
+            return output;
+        }
+        // don't miss a wake up between the check and hlt
+        interrupts::disable();
+        if flag.0.swap(false, Ordering::SeqCst) {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
+
 /// An asynchronous task with side effects (it doesn't return anything).
 pub struct Task {
     id: TaskId,
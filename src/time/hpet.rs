@@ -0,0 +1,84 @@
+//! High Precision Event Timer
+//!
+//! Only the main counter is used, as a clock source. If it only has 32 bits,
+//! it is extended to 64 bits in software: the clock has to be read at least
+//! once per half turn of the counter (every few minutes), which the timer
+//! interrupts do.
+//!
+//! https://wiki.osdev.org/HPET
+
+use crate::memory::{AcpiHandler, MEM_OFFSET};
+use acpi::{AcpiTables, HpetInfo};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const CAPABILITIES: u64 = 0x00;
+const CONFIGURATION: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xf0;
+
+/// The main counter has 64 bits
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+const ENABLE: u64 = 1;
+
+pub struct Hpet {
+    /// Virtual address of the registers
+    base: u64,
+    /// Period of the main counter, in femtoseconds
+    period_fs: u64,
+    wide: bool,
+    /// The last value read from a 32 bits counter, extended to 64 bits
+    last: AtomicU64,
+}
+
+impl Hpet {
+    /// Finds the HPET in the ACPI tables, and starts its main counter.
+    pub fn init(tables: &AcpiTables<AcpiHandler>) -> Option<Hpet> {
+        let info = HpetInfo::new(tables).ok()?;
+        let mut hpet = Hpet {
+            base: MEM_OFFSET + info.base_address as u64,
+            period_fs: 0,
+            wide: false,
+            last: AtomicU64::new(0),
+        };
+        let capabilities = unsafe { hpet.read(CAPABILITIES) };
+        hpet.period_fs = capabilities >> 32;
+        hpet.wide = capabilities & COUNT_SIZE_CAP != 0;
+        if hpet.period_fs == 0 {
+            return None;
+        }
+
+        unsafe {
+            let config = hpet.read(CONFIGURATION);
+            hpet.write(CONFIGURATION, config | ENABLE);
+        }
+        Some(hpet)
+    }
+
+    unsafe fn read(&self, reg: u64) -> u64 {
+        core::ptr::read_volatile((self.base + reg) as *const u64)
+    }
+
+    unsafe fn write(&self, reg: u64, value: u64) {
+        core::ptr::write_volatile((self.base + reg) as *mut u64, value);
+    }
+
+    pub fn counter(&self) -> u64 {
+        let counter = unsafe { self.read(MAIN_COUNTER) };
+        if self.wide {
+            return counter;
+        }
+        let last = self.last.load(Ordering::SeqCst);
+        let ahead = (counter as u32).wrapping_sub(last as u32);
+        // read before the value that another CPU stored
+        if ahead >= 1 << 31 {
+            return last;
+        }
+        let value = last + ahead as u64;
+        self.last.fetch_max(value, Ordering::SeqCst);
+        value
+    }
+
+    /// Converts a number of ticks of the main counter to nanoseconds
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period_fs as u128 / 1_000_000) as u64
+    }
+}
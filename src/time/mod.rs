@@ -0,0 +1,286 @@
+//! Monotonic clock and wall clock
+//!
+//! The monotonic clock counts nanoseconds since [`init`] was called. It uses
+//! the HPET if there is one, or the TSC if it is invariant. Otherwise, it
+//! counts the interrupts of the PIT, and is only precise to about 55 ms.
+//!
+//! The wall clock is anchored on the CMOS reading at boot, and then advances
+//! with the monotonic clock.
+//!
+//! Tasks can wait for some time with [`sleep`] and [`timeout`].
+//!
+//! Processes read the time from the database: `Os.Time.DateTime` and
+//! `Os.Time.Uptime` objects are generated with [`datetime_value`] and
+//! [`uptime_value`].
+
+use crate::cmos::{self, DateTime};
+use crate::memory::AcpiHandler;
+use crate::pit;
+use crate::println;
+use acpi::AcpiTables;
+use adb::DbValue;
+use alloc::sync::Arc;
+use conquer_once::spin::OnceCell;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub use core::time::Duration;
+
+pub mod hpet;
+pub mod timer;
+pub mod tsc;
+
+pub use timer::{sleep, sleep_until, timeout, Elapsed};
+
+enum Source {
+    Hpet(hpet::Hpet),
+    Tsc(tsc::Tsc),
+    Pit,
+}
+
+impl Source {
+    fn counter(&self) -> u64 {
+        match self {
+            Source::Hpet(hpet) => hpet.counter(),
+            Source::Tsc(tsc) => tsc.counter(),
+            Source::Pit => pit::ticks(),
+        }
+    }
+
+    fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        match self {
+            Source::Hpet(hpet) => hpet.ticks_to_ns(ticks),
+            Source::Tsc(tsc) => tsc.ticks_to_ns(ticks),
+            Source::Pit => pit::ticks_to_ns(ticks),
+        }
+    }
+}
+
+struct Clock {
+    source: Source,
+    /// Value of the counter at boot
+    start: u64,
+    /// Seconds since the UNIX epoch at boot, according to the CMOS (or to
+    /// the last call to [`set`])
+    boot_time: AtomicU64,
+}
+
+static CLOCK: OnceCell<Clock> = OnceCell::uninit();
+
+/// Chooses a clock source and starts the clock.
+///
+/// Without ACPI tables, only the TSC can be used.
+pub fn init(tables: Option<&AcpiTables<AcpiHandler>>) {
+    if let Some(tables) = tables {
+        cmos::init(tables);
+    }
+
+    let source = match tables.and_then(hpet::Hpet::init) {
+        Some(hpet) => Source::Hpet(hpet),
+        None if tsc::is_invariant() => Source::Tsc(tsc::Tsc::calibrate()),
+        None => {
+            println!("WARNING: no HPET and no invariant TSC, time will be imprecise");
+            Source::Pit
+        }
+    };
+
+    let boot_time = to_unix(&cmos::get_datetime());
+    let start = source.counter();
+    CLOCK
+        .try_init_once(|| Clock {
+            source,
+            start,
+            boot_time: AtomicU64::new(boot_time),
+        })
+        .expect("time::init should only be called once");
+}
+
+pub fn is_ready() -> bool {
+    CLOCK.is_initialized()
+}
+
+/// A point in time, as measured by the monotonic clock
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    /// Returns the current time. Before [`init`], it is always the boot time.
+    pub fn now() -> Instant {
+        match CLOCK.get() {
+            Some(clock) => {
+                let ticks = clock.source.counter().wrapping_sub(clock.start);
+                Instant(clock.source.ticks_to_ns(ticks))
+            }
+            None => Instant(0),
+        }
+    }
+
+    /// Time elapsed since boot
+    pub fn since_boot(self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+
+    pub fn elapsed(self) -> Duration {
+        Instant::now() - self
+    }
+
+    /// Returns a zero duration if `earlier` is actually later
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0.saturating_add(rhs.as_nanos() as u64))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// Time elapsed since boot
+pub fn uptime() -> Duration {
+    Instant::now().since_boot()
+}
+
+/// Time elapsed since the UNIX epoch
+pub fn unix_time() -> Duration {
+    let boot_time = CLOCK
+        .get()
+        .map_or(0, |clock| clock.boot_time.load(Ordering::SeqCst));
+    Duration::from_secs(boot_time) + uptime()
+}
+
+/// Changes the current date and time, in the CMOS too.
+pub fn set(dt: &DateTime) {
+    cmos::set_datetime(dt);
+    if let Some(clock) = CLOCK.get() {
+        let boot_time = to_unix(dt).saturating_sub(uptime().as_secs());
+        clock.boot_time.store(boot_time, Ordering::SeqCst);
+    }
+}
+
+/// The current date and time (UTC, or whatever the CMOS uses)
+pub fn now() -> DateTime {
+    if is_ready() {
+        from_unix(unix_time().as_secs())
+    } else {
+        cmos::get_datetime()
+    }
+}
+
+/// Day of the week, from 0 (Sunday) to 6 (Saturday)
+pub fn weekday(dt: &DateTime) -> u8 {
+    // 1970-01-01 was a Thursday
+    (days_from_civil(dt.year as i64, dt.month, dt.day) + 4).rem_euclid(7) as u8
+}
+
+/// The value of an `Os.Time.DateTime`
+pub fn datetime_value() -> Arc<DbValue> {
+    let unix_time = unix_time().as_secs();
+    let dt = from_unix(unix_time);
+    let fields = [
+        dt.year as u64,
+        dt.month as u64,
+        dt.day as u64,
+        weekday(&dt) as u64,
+        dt.hours as u64,
+        dt.minutes as u64,
+        dt.seconds as u64,
+        unix_time,
+    ];
+    Arc::new(DbValue::Product {
+        fields: fields.iter().map(|&x| Arc::new(DbValue::U64(x))).collect(),
+    })
+}
+
+/// The value of an `Os.Time.Uptime`
+pub fn uptime_value() -> Arc<DbValue> {
+    let uptime = uptime();
+    Arc::new(DbValue::Product {
+        fields: alloc::vec![
+            Arc::new(DbValue::U64(uptime.as_secs())),
+            Arc::new(DbValue::U64(uptime.subsec_nanos() as u64)),
+        ],
+    })
+}
+
+/// Number of days since 1970-01-01
+///
+/// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * shifted_month + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719468;
+    let era = (if days >= 0 { days } else { days - 146096 }) / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+pub fn to_unix(dt: &DateTime) -> u64 {
+    let days = days_from_civil(dt.year as i64, dt.month, dt.day);
+    let secs = days * 86400 + dt.hours as i64 * 3600 + dt.minutes as i64 * 60 + dt.seconds as i64;
+    secs.max(0) as u64
+}
+
+pub fn from_unix(secs: u64) -> DateTime {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    DateTime {
+        year: year as u16,
+        month,
+        day,
+        hours: (secs_of_day / 3600) as u8,
+        minutes: (secs_of_day / 60 % 60) as u8,
+        seconds: (secs_of_day % 60) as u8,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn unix_epoch() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+    }
+
+    #[test_case]
+    fn unix_round_trip() {
+        // 2022-02-28 23:59:59, just before a month change
+        let secs = 1_646_092_799;
+        let dt = from_unix(secs);
+        assert_eq!((dt.year, dt.month, dt.day), (2022, 2, 28));
+        assert_eq!((dt.hours, dt.minutes, dt.seconds), (23, 59, 59));
+        assert_eq!(to_unix(&dt), secs);
+        assert_eq!(from_unix(secs + 1).month, 3);
+        // it was a Monday
+        assert_eq!(weekday(&dt), 1);
+    }
+}
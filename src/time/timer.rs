@@ -0,0 +1,145 @@
+//! Timers for asynchronous tasks
+//!
+//! Pending timers are kept in a map ordered by deadline. Every timer interrupt
+//! (on any CPU) calls [`wake_expired`], that wakes the tasks whose deadline is
+//! reached. Timers are thus only as precise as the timer interrupt: about 55ms
+//! on the bootstrap processor, that still uses the PIT.
+//!
+//! Processes sleep with the same timers (see [`crate::task::block_on`]).
+
+use super::{Duration, Instant};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+lazy_static::lazy_static! {
+    /// The tasks to wake, by deadline. The second part of the key makes it
+    /// possible to have many timers with the same deadline.
+    static ref TIMERS: Mutex<BTreeMap<(Instant, u64), Waker>> = Mutex::new(BTreeMap::new());
+}
+
+/// Wakes all the tasks whose deadline is reached.
+///
+/// Called from interrupt handlers: it does nothing if another CPU is already
+/// doing it.
+pub fn wake_expired() {
+    let mut timers = match TIMERS.try_lock() {
+        Some(timers) => timers,
+        None => return,
+    };
+
+    let now = Instant::now();
+    while let Some(&key) = timers.keys().next() {
+        if key.0 > now {
+            break;
+        }
+        if let Some(waker) = timers.remove(&key) {
+            waker.wake();
+        }
+    }
+}
+
+/// Number of timers that are waiting
+pub fn pending() -> usize {
+    interrupts::without_interrupts(|| TIMERS.lock().len())
+}
+
+/// A future that completes at a given instant
+pub struct Sleep {
+    deadline: Instant,
+    /// The key of this timer in [`TIMERS`], once it is registered
+    key: Option<(Instant, u64)>,
+}
+
+/// Waits for `duration`.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Waits until `deadline` is reached.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        key: None,
+    }
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    fn unregister(&mut self) {
+        if let Some(key) = self.key.take() {
+            interrupts::without_interrupts(|| TIMERS.lock().remove(&key));
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        if Instant::now() >= self.deadline {
+            self.unregister();
+            return Poll::Ready(());
+        }
+
+        // The task may be polled with a different waker than last time
+        let key = match self.key {
+            Some(key) => key,
+            None => (self.deadline, NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+        };
+        interrupts::without_interrupts(|| TIMERS.lock().insert(key, cx.waker().clone()));
+        self.key = Some(key);
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// The error returned by [`timeout`] when the future took too long
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// A future that gives up on another one after some time
+pub struct Timeout<F: Future> {
+    future: Pin<Box<F>>,
+    sleep: Sleep,
+}
+
+/// Runs `future`, but only for `duration`.
+///
+/// The future is dropped if it is not done in time.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future: Box::pin(future),
+        sleep: sleep(duration),
+    }
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Poll::Ready(output) = this.future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Time Stamp Counter
+//!
+//! It can only be used as a clock if it is invariant (i.e. its frequency
+//! doesn't change with power management). Its frequency is measured
+//! with the PIT.
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+
+pub struct Tsc {
+    /// Frequency, in Hz
+    frequency: u64,
+}
+
+impl Tsc {
+    /// Measures the frequency of the TSC with the PIT.
+    pub fn calibrate() -> Tsc {
+        // Number of cycles in 50ms
+        let start = read();
+        crate::pit::wait_us(50_000);
+        let end = read();
+        Tsc {
+            frequency: (end - start) * 20,
+        }
+    }
+
+    pub fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    pub fn counter(&self) -> u64 {
+        read()
+    }
+
+    pub fn ticks_to_ns(&self, ticks: u64) -> u64 {
+        (ticks as u128 * 1_000_000_000 / self.frequency as u128) as u64
+    }
+}
+
+/// Is the frequency of the TSC constant?
+pub fn is_invariant() -> bool {
+    unsafe { __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0 }
+}
+
+fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
@@ -0,0 +1,589 @@
+//! Window compositor
+//!
+//! Windows are off-screen surfaces, stacked from the bottom to the top. The
+//! compositor draws them (with a title bar) when they change, and gives the
+//! input events to them: keys go to the focused window, and pointer events to
+//! the window under the pointer.
+//!
+//! Clicking on a window focuses it and brings it to the top, dragging its
+//! title bar moves it, and Alt+Tab focuses the window at the bottom.
+//!
+//! The compositor is [`start`]ed before opening windows, and stops when the
+//! last one is closed: the kernel console is then shown again.
+
+use super::{Canvas, InputEvent, CHAR_HEIGHT, CHAR_WIDTH, TEXT_SCALE};
+use crate::framebuffer::surface::{Rect, Surface};
+use crate::framebuffer::{console, font, Color, FrameBuffer, FRAMEBUFFER};
+use crate::input::keyboard::{self, KeyEvent, KeyState};
+use crate::input::mouse::{self, Buttons, PointerEvent};
+use crate::input::Modifiers;
+use crate::println;
+use crate::task::{self, Task};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::Poll;
+use crossbeam_queue::ArrayQueue;
+use futures_util::future::poll_fn;
+use futures_util::task::AtomicWaker;
+use pc_keyboard::KeyCode;
+use spin::Mutex;
+
+const BACKGROUND: Color = Color::rgb(0x20, 0x40, 0x60);
+const FOCUSED: Color = Color::rgb(0x30, 0x70, 0xc0);
+const UNFOCUSED: Color = Color::rgb(0x60, 0x60, 0x60);
+const TITLE_TEXT: Color = Color::WHITE;
+const CURSOR_COLOR: Color = Color::WHITE;
+
+const TITLE_HEIGHT: i32 = CHAR_HEIGHT as i32 + 4;
+const BORDER: i32 = 1;
+
+/// The arrow of the pointer, one row per byte (the lowest bit on the left)
+const CURSOR: [u8; 12] = [
+    0b0000_0001,
+    0b0000_0011,
+    0b0000_0111,
+    0b0000_1111,
+    0b0001_1111,
+    0b0011_1111,
+    0b0111_1111,
+    0b1111_1111,
+    0b0001_1111,
+    0b0001_1011,
+    0b0011_0001,
+    0b0011_0000,
+];
+const CURSOR_SCALE: i32 = 2;
+
+/// Events that a window didn't read yet are dropped after that
+const INBOX_SIZE: usize = 64;
+
+/// A rectangle in screen coordinates (it can be partly off-screen)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Area {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+}
+
+impl Area {
+    fn contains(&self, (x, y): (i32, i32)) -> bool {
+        x >= self.x && y >= self.y && x < self.x + self.width && y < self.y + self.height
+    }
+
+    fn intersect(&self, other: &Area) -> Option<Area> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        if right <= x || bottom <= y {
+            return None;
+        }
+        Some(Area {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WindowId(u64);
+
+/// Events waiting to be read by a window
+struct Inbox {
+    queue: ArrayQueue<InputEvent>,
+    waker: AtomicWaker,
+}
+
+impl Inbox {
+    fn send(&self, event: InputEvent) {
+        // if the window is too slow, it misses events
+        if self.queue.push(event).is_ok() {
+            self.waker.wake();
+        }
+    }
+}
+
+struct Window {
+    id: WindowId,
+    title: String,
+    /// Position of the content on the screen (the title bar is above it)
+    x: i32,
+    y: i32,
+    surface: Surface,
+    inbox: Arc<Inbox>,
+}
+
+impl Window {
+    fn content(&self) -> Area {
+        Area {
+            x: self.x,
+            y: self.y,
+            width: self.surface.width as i32,
+            height: self.surface.height as i32,
+        }
+    }
+
+    fn title_bar(&self) -> Area {
+        Area {
+            x: self.x - BORDER,
+            y: self.y - TITLE_HEIGHT,
+            width: self.surface.width as i32 + 2 * BORDER,
+            height: TITLE_HEIGHT,
+        }
+    }
+
+    /// The window with its decorations
+    fn frame(&self) -> Area {
+        let content = self.content();
+        Area {
+            x: content.x - BORDER,
+            y: content.y - TITLE_HEIGHT,
+            width: content.width + 2 * BORDER,
+            height: content.height + TITLE_HEIGHT + BORDER,
+        }
+    }
+}
+
+struct Compositor {
+    screen: Area,
+    /// From the bottom to the top
+    windows: Vec<Window>,
+    focus: Option<WindowId>,
+    pointer: (i32, i32),
+    buttons: Buttons,
+    /// The window being moved, and where the pointer is in it
+    drag: Option<(WindowId, i32, i32)>,
+    /// Which start of the compositor this is, for its input task
+    generation: u64,
+}
+
+static COMPOSITOR: Mutex<Option<Compositor>> = Mutex::new(None);
+static NEXT_WINDOW: AtomicU64 = AtomicU64::new(0);
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Draws the pixels of `bitmap` (rows of 8 bits) that are in `clip`.
+fn draw_bitmap(
+    fb: &mut FrameBuffer,
+    (x, y): (i32, i32),
+    bitmap: &[u8],
+    scale: i32,
+    color: Color,
+    clip: &Area,
+) {
+    for (dy, bits) in bitmap.iter().enumerate() {
+        for dx in (0..8).filter(|dx| bits & (1 << dx) != 0) {
+            let pixel = Area {
+                x: x + dx * scale,
+                y: y + dy as i32 * scale,
+                width: scale,
+                height: scale,
+            };
+            if let Some(part) = pixel.intersect(clip) {
+                let (x, y) = (part.x as usize, part.y as usize);
+                fb.fill_rect(x, y, part.width as usize, part.height as usize, color);
+            }
+        }
+    }
+}
+
+impl Compositor {
+    fn index(&self, id: WindowId) -> Option<usize> {
+        self.windows.iter().position(|window| window.id == id)
+    }
+
+    fn cursor(&self) -> Area {
+        Area {
+            x: self.pointer.0,
+            y: self.pointer.1,
+            width: 8 * CURSOR_SCALE,
+            height: CURSOR.len() as i32 * CURSOR_SCALE,
+        }
+    }
+
+    /// Draws again a part of the screen, from the bottom to the top.
+    fn redraw(&self, area: Area) {
+        let area = match area.intersect(&self.screen) {
+            Some(area) => area,
+            None => return,
+        };
+        let mut fb = FRAMEBUFFER.lock();
+        let fb = match fb.as_mut() {
+            Some(fb) => fb,
+            None => return,
+        };
+        let fill = |fb: &mut FrameBuffer, part: Area, color| {
+            let (x, y) = (part.x as usize, part.y as usize);
+            fb.fill_rect(x, y, part.width as usize, part.height as usize, color);
+        };
+
+        fill(fb, area, BACKGROUND);
+        for window in &self.windows {
+            if let Some(part) = window.frame().intersect(&area) {
+                let color = match self.focus {
+                    Some(id) if id == window.id => FOCUSED,
+                    _ => UNFOCUSED,
+                };
+                fill(fb, part, color);
+                let bar = window.title_bar();
+                let padding = (TITLE_HEIGHT - CHAR_HEIGHT as i32) / 2;
+                for (i, c) in window.title.chars().enumerate() {
+                    let x = bar.x + padding + (i * CHAR_WIDTH) as i32;
+                    if x + CHAR_WIDTH as i32 > bar.x + bar.width {
+                        break;
+                    }
+                    let position = (x, bar.y + padding);
+                    let scale = TEXT_SCALE as i32;
+                    draw_bitmap(fb, position, font::glyph(c), scale, TITLE_TEXT, &part);
+                }
+            }
+            if let Some(part) = window.content().intersect(&area) {
+                let start = (part.x - window.x) as usize;
+                for row in part.y..part.y + part.height {
+                    let line = window.surface.line((row - window.y) as usize);
+                    let line = &line[start..start + part.width as usize];
+                    fb.write_line(part.x as usize, row as usize, line);
+                }
+            }
+        }
+        let cursor = self.cursor();
+        draw_bitmap(
+            fb,
+            (cursor.x, cursor.y),
+            &CURSOR,
+            CURSOR_SCALE,
+            CURSOR_COLOR,
+            &area,
+        );
+    }
+
+    fn redraw_all(&self) {
+        self.redraw(self.screen);
+    }
+
+    fn set_focus(&mut self, id: Option<WindowId>) {
+        if self.focus == id {
+            return;
+        }
+        let previous = core::mem::replace(&mut self.focus, id);
+        for &(id, focused) in [(previous, false), (id, true)].iter() {
+            let window = id.and_then(|id| self.index(id)).map(|i| &self.windows[i]);
+            if let Some(window) = window {
+                window.inbox.send(InputEvent::Focus(focused));
+                self.redraw(window.title_bar());
+            }
+        }
+    }
+
+    /// Brings a window to the top, and focuses it.
+    fn raise(&mut self, index: usize) {
+        let window = self.windows.remove(index);
+        let (id, frame) = (window.id, window.frame());
+        self.windows.push(window);
+        self.redraw(frame);
+        self.set_focus(Some(id));
+    }
+
+    /// The top window under the pointer
+    fn window_at(&self, point: (i32, i32)) -> Option<usize> {
+        self.windows
+            .iter()
+            .rposition(|window| window.frame().contains(point))
+    }
+
+    fn on_key(&mut self, event: KeyEvent) {
+        let alt_tab = event.code == KeyCode::Tab && event.modifiers.contains(Modifiers::ALT);
+        if alt_tab && event.state == KeyState::Down && !self.windows.is_empty() {
+            self.raise(0);
+            return;
+        }
+        let focused = self.focus.and_then(|id| self.index(id));
+        if let Some(index) = focused {
+            self.windows[index].inbox.send(InputEvent::Key(event));
+        }
+    }
+
+    fn on_pointer(&mut self, event: PointerEvent) {
+        let old_cursor = self.cursor();
+        let (x, y) = self.pointer;
+        let (width, height) = (self.screen.width, self.screen.height);
+        self.pointer = (
+            (x + event.dx).clamp(0, width - 1),
+            (y + event.dy).clamp(0, height - 1),
+        );
+        let was_down = self.buttons.contains(Buttons::LEFT);
+        let is_down = event.buttons.contains(Buttons::LEFT);
+        self.buttons = event.buttons;
+
+        match self.drag {
+            Some(_) if !is_down => self.drag = None,
+            Some((id, dx, dy)) => {
+                if let Some(index) = self.index(id) {
+                    let before = self.windows[index].frame();
+                    let window = &mut self.windows[index];
+                    window.x = self.pointer.0 - dx;
+                    window.y = self.pointer.1 - dy;
+                    let after = window.frame();
+                    self.redraw(before);
+                    self.redraw(after);
+                }
+            }
+            None if is_down && !was_down => {
+                if let Some(index) = self.window_at(self.pointer) {
+                    let window = &self.windows[index];
+                    if window.title_bar().contains(self.pointer) {
+                        let (dx, dy) = (self.pointer.0 - window.x, self.pointer.1 - window.y);
+                        self.drag = Some((window.id, dx, dy));
+                    }
+                    self.raise(index);
+                }
+            }
+            None => {}
+        }
+        self.redraw(old_cursor);
+        self.redraw(self.cursor());
+
+        if self.drag.is_none() {
+            let under = self
+                .windows
+                .iter()
+                .rev()
+                .find(|window| window.content().contains(self.pointer));
+            if let Some(window) = under {
+                window.inbox.send(InputEvent::Pointer {
+                    x: self.pointer.0 - window.x,
+                    y: self.pointer.1 - window.y,
+                    buttons: event.buttons,
+                    wheel: event.wheel,
+                });
+            }
+        }
+    }
+}
+
+/// A window, for the task that draws in it
+///
+/// The window is closed when this is dropped.
+pub struct WindowHandle {
+    id: WindowId,
+    inbox: Arc<Inbox>,
+    pixels: *mut u32,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Opens a window with a content of `width`×`height` pixels, on top of the
+/// other ones. Returns `None` if the compositor is not running.
+pub fn open_window(title: &str, width: usize, height: usize) -> Option<WindowHandle> {
+    let mut compositor = COMPOSITOR.lock();
+    let compositor = compositor.as_mut()?;
+    let surface = match Surface::off_screen(None, width, height) {
+        Ok(surface) => surface,
+        Err(err) => {
+            println!("compositor: can't create a window: {:?}", err);
+            return None;
+        }
+    };
+
+    let id = WindowId(NEXT_WINDOW.fetch_add(1, Ordering::SeqCst));
+    let inbox = Arc::new(Inbox {
+        queue: ArrayQueue::new(INBOX_SIZE),
+        waker: AtomicWaker::new(),
+    });
+    // each window is a bit lower and to the right of the previous one
+    let offset = 32 * (compositor.windows.len() as i32 % 8);
+    let handle = WindowHandle {
+        id,
+        inbox: Arc::clone(&inbox),
+        pixels: surface.address.as_mut_ptr(),
+        width,
+        height,
+    };
+    compositor.windows.push(Window {
+        id,
+        title: String::from(title),
+        x: 32 + offset,
+        y: 32 + offset + TITLE_HEIGHT,
+        surface,
+        inbox,
+    });
+    let index = compositor.windows.len() - 1;
+    compositor.raise(index);
+    Some(handle)
+}
+
+impl WindowHandle {
+    /// The content of the window. Call [`WindowHandle::present`] to show the
+    /// changes.
+    pub fn canvas(&mut self) -> Canvas<'_> {
+        let len = self.width * self.height;
+        let pixels = unsafe { core::slice::from_raw_parts_mut(self.pixels, len) };
+        Canvas::new(pixels, self.width, self.height)
+    }
+
+    /// Shows the parts of the window that changed (all of it if `damage` is
+    /// empty).
+    pub fn present(&self, damage: &[Rect]) {
+        let compositor = COMPOSITOR.lock();
+        let compositor = match compositor.as_ref() {
+            Some(compositor) => compositor,
+            None => return,
+        };
+        let window = match compositor.index(self.id) {
+            Some(index) => &compositor.windows[index],
+            None => return,
+        };
+        let content = window.content();
+        if damage.is_empty() {
+            compositor.redraw(content);
+        }
+        for rect in damage {
+            let area = Area {
+                x: window.x + rect.x as i32,
+                y: window.y + rect.y as i32,
+                width: rect.width as i32,
+                height: rect.height as i32,
+            };
+            if let Some(area) = area.intersect(&content) {
+                compositor.redraw(area);
+            }
+        }
+    }
+
+    /// Waits for the next event for this window.
+    pub async fn next_event(&self) -> InputEvent {
+        poll_fn(|cx| {
+            self.inbox.waker.register(cx.waker());
+            match self.inbox.queue.pop() {
+                Ok(event) => Poll::Ready(event),
+                Err(_) => Poll::Pending,
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for WindowHandle {
+    fn drop(&mut self) {
+        let stopped = {
+            let mut compositor = COMPOSITOR.lock();
+            if let Some(compositor) = compositor.as_mut() {
+                if let Some(index) = compositor.index(self.id) {
+                    // this frees the surface
+                    let window = compositor.windows.remove(index);
+                    compositor.redraw(window.frame());
+                    let top = compositor.windows.last().map(|window| window.id);
+                    compositor.set_focus(top);
+                }
+            }
+            match *compositor {
+                Some(ref running) if running.windows.is_empty() => compositor.take(),
+                _ => None,
+            }
+        };
+        if stopped.is_some() {
+            console::set_shown(true);
+        }
+    }
+}
+
+enum Input {
+    Key(KeyEvent),
+    Pointer(PointerEvent),
+}
+
+/// Takes the screen (the kernel console stops drawing on it), and starts
+/// routing the input events to the windows. Returns `false` if there is no
+/// screen.
+pub fn start() -> bool {
+    let mut compositor = COMPOSITOR.lock();
+    if compositor.is_some() {
+        return true;
+    }
+    let screen = FRAMEBUFFER.lock().as_ref().map(|fb| Area {
+        x: 0,
+        y: 0,
+        width: fb.width() as i32,
+        height: fb.height() as i32,
+    });
+    let screen = match screen {
+        Some(screen) => screen,
+        None => {
+            println!("compositor: there is no screen");
+            return false;
+        }
+    };
+    console::set_shown(false);
+    let generation = GENERATION.fetch_add(1, Ordering::SeqCst);
+    let compositor = compositor.insert(Compositor {
+        screen,
+        windows: Vec::new(),
+        focus: None,
+        pointer: (screen.width / 2, screen.height / 2),
+        buttons: Buttons::NONE,
+        drag: None,
+        generation,
+    });
+    compositor.redraw_all();
+    task::spawn(Task::new(route_input(generation)));
+    true
+}
+
+/// Gives the input events to the windows, until the compositor stops.
+async fn route_input(generation: u64) {
+    let mut keys = keyboard::subscribe();
+    let mut pointer = mouse::subscribe();
+    loop {
+        let input = poll_fn(|cx| {
+            if let Poll::Ready(event) = keys.poll_recv(cx) {
+                return Poll::Ready(Input::Key(event));
+            }
+            pointer.poll_recv(cx).map(Input::Pointer)
+        })
+        .await;
+
+        let mut compositor = COMPOSITOR.lock();
+        match compositor.as_mut() {
+            Some(compositor) if compositor.generation == generation => match input {
+                Input::Key(event) => compositor.on_key(event),
+                Input::Pointer(event) => compositor.on_pointer(event),
+            },
+            // it stopped (and maybe started again)
+            _ => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn area_intersection() {
+        let a = Area {
+            x: -10,
+            y: 0,
+            width: 30,
+            height: 10,
+        };
+        let b = Area {
+            x: 0,
+            y: 5,
+            width: 100,
+            height: 100,
+        };
+        let expected = Area {
+            x: 0,
+            y: 5,
+            width: 20,
+            height: 5,
+        };
+        assert!(a.intersect(&b) == Some(expected));
+        assert!(a.contains((-10, 9)));
+        assert!(!a.contains((20, 0)));
+    }
+}
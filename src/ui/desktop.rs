@@ -0,0 +1,87 @@
+//! A small desktop, to browse the database in windows
+//!
+//! The first window lists the types, and choosing one opens a window with its
+//! objects. Closing every window goes back to the console.
+
+use super::compositor::{self, WindowHandle};
+use super::widget::{self, Button, Column, List, Text, Widget};
+use crate::db::{self, DbValueDisplay};
+use crate::task::{self, Task};
+use alloc::string::String;
+use alloc::vec::Vec;
+
+/// Lines shown in lists
+const LIST_LINES: usize = 12;
+/// Objects shown for a type
+const MAX_OBJECTS: usize = 64;
+/// Longer lines are cut
+const MAX_LINE: usize = 60;
+
+/// A list above a "Close" button, that sends the index just after the last
+/// item
+fn list_window(title: &str, items: Vec<String>) -> Option<(WindowHandle, Column<usize>)> {
+    let close = items.len();
+    let column = Column::new()
+        .push(Text::new(title))
+        .push(List::new(items, LIST_LINES))
+        .push(Button::new("Close", close));
+    let (width, height) = column.size();
+    let window = compositor::open_window(title, width.max(1), height.max(1))?;
+    Some((window, column))
+}
+
+fn cut(mut line: String) -> String {
+    if let Some((end, _)) = line.char_indices().nth(MAX_LINE) {
+        line.truncate(end);
+        line.push_str("...");
+    }
+    line
+}
+
+/// Starts the compositor if needed, and opens the window of the types.
+pub async fn run() {
+    if !compositor::start() {
+        return;
+    }
+    let types: Vec<(adb::TypeId, String)> = match db::DB.lock().as_ref() {
+        Some(db) => db
+            .all_type_ids()
+            .into_iter()
+            .filter_map(|id| db.get_type_info(id).map(|ty| (id, ty.name.clone())))
+            .collect(),
+        None => Vec::new(),
+    };
+    let names = types.iter().map(|(_, name)| name.clone()).collect();
+    let (mut window, mut column) = match list_window("Types", names) {
+        Some(window) => window,
+        None => return,
+    };
+    while let Some(&(ty, _)) = types.get(widget::show(&mut window, &mut column).await) {
+        task::spawn(Task::new(show_objects(ty)));
+    }
+}
+
+/// Opens a window with the objects of a type.
+async fn show_objects(ty: adb::TypeId) {
+    let (name, objects) = {
+        let mut db = db::DB.lock();
+        let db = match db.as_mut() {
+            Some(db) => db,
+            None => return,
+        };
+        let info = match db.get_type_info(ty) {
+            Some(info) => info,
+            None => return,
+        };
+        let objects: Vec<_> = db.iter_type(ty).take(MAX_OBJECTS).collect();
+        let lines = objects.into_iter().map(|object| {
+            let value = DbValueDisplay::new(&*db, object.value, object.type_info).compact();
+            cut(alloc::format!("{}", value))
+        });
+        (info.name.clone(), lines.collect::<Vec<_>>())
+    };
+    let close: usize = objects.len();
+    if let Some((mut window, mut column)) = list_window(&name, objects) {
+        while widget::show(&mut window, &mut column).await != close {}
+    }
+}
@@ -0,0 +1,129 @@
+//! Graphical user interface
+//!
+//! The [`compositor`] owns the screen: it shows the windows, and routes the
+//! input events to them. Inside a window, [`widget`]s form a tree, like in
+//! Elm: events go down from the window to the widget that handles them, and
+//! messages go up to the parents.
+//!
+//! The [`desktop`] is started by the `desktop` command of the shell.
+
+use crate::framebuffer::font;
+use crate::framebuffer::surface::Rect;
+use crate::framebuffer::Color;
+use crate::input::keyboard::KeyEvent;
+use crate::input::mouse::Buttons;
+
+pub mod compositor;
+pub mod desktop;
+pub mod widget;
+
+/// Size of the pixels of the font
+pub const TEXT_SCALE: usize = 2;
+pub const CHAR_WIDTH: usize = font::WIDTH * TEXT_SCALE;
+pub const CHAR_HEIGHT: usize = font::HEIGHT * TEXT_SCALE;
+
+/// What a window receives
+#[derive(Clone, Copy, Debug)]
+pub enum InputEvent {
+    /// Only sent to the focused window
+    Key(KeyEvent),
+    /// Sent to the window under the pointer, with coordinates relative to
+    /// its content (or to a widget, once passed down)
+    Pointer {
+        x: i32,
+        y: i32,
+        buttons: Buttons,
+        wheel: i8,
+    },
+    /// The window got (`true`) or lost the focus
+    Focus(bool),
+}
+
+/// A part of a window to draw on
+///
+/// Everything is clipped: drawing outside of the canvas does nothing.
+pub struct Canvas<'a> {
+    pixels: &'a mut [u32],
+    stride: usize,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl<'a> Canvas<'a> {
+    /// A canvas for a whole `width`×`height` image of `0x00RRGGBB` pixels
+    pub fn new(pixels: &'a mut [u32], width: usize, height: usize) -> Canvas<'a> {
+        assert!(pixels.len() >= width * height);
+        Canvas {
+            pixels,
+            stride: width,
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Where this canvas is in the window, to present it
+    pub fn rect(&self) -> Rect {
+        Rect {
+            x: self.x as u32,
+            y: self.y as u32,
+            width: self.width as u32,
+            height: self.height as u32,
+        }
+    }
+
+    /// A part of this canvas (coordinates are relative to this canvas)
+    pub fn sub(&mut self, x: usize, y: usize, width: usize, height: usize) -> Canvas<'_> {
+        let x = x.min(self.width);
+        let y = y.min(self.height);
+        Canvas {
+            pixels: self.pixels,
+            stride: self.stride,
+            x: self.x + x,
+            y: self.y + y,
+            width: width.min(self.width - x),
+            height: height.min(self.height - y),
+        }
+    }
+
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let width = width.min(self.width - x);
+        let height = height.min(self.height - y);
+        let color = color.to_xrgb();
+        for row in self.y + y..self.y + y + height {
+            let start = row * self.stride + self.x + x;
+            self.pixels[start..start + width].fill(color);
+        }
+    }
+
+    pub fn fill(&mut self, color: Color) {
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+
+    /// Writes text on one line, without background.
+    pub fn text(&mut self, x: usize, y: usize, text: &str, color: Color) {
+        for (i, c) in text.chars().enumerate() {
+            let left = x + i * CHAR_WIDTH;
+            for (dy, bits) in font::glyph(c).iter().enumerate() {
+                for dx in (0..font::WIDTH).filter(|dx| bits & (1 << dx) != 0) {
+                    let (px, py) = (left + dx * TEXT_SCALE, y + dy * TEXT_SCALE);
+                    self.fill_rect(px, py, TEXT_SCALE, TEXT_SCALE, color);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,349 @@
+//! Widgets
+//!
+//! A widget draws itself on a [`Canvas`], and handles the events it receives.
+//! When something happens that its parent should know about (a button was
+//! clicked, an item was chosen), it returns a message. Containers pass the
+//! events down to their children, and their messages up.
+
+use super::compositor::WindowHandle;
+use super::{Canvas, InputEvent, CHAR_HEIGHT, CHAR_WIDTH};
+use crate::framebuffer::Color;
+use crate::input::keyboard::KeyState;
+use crate::input::mouse::Buttons;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use pc_keyboard::KeyCode;
+
+pub const BACKGROUND: Color = Color::rgb(0xe0, 0xe0, 0xe0);
+pub const FOREGROUND: Color = Color::BLACK;
+const ACCENT: Color = Color::rgb(0x30, 0x70, 0xc0);
+const BUTTON: Color = Color::rgb(0xc0, 0xc0, 0xc0);
+const PADDING: usize = 4;
+
+/// What a widget did with an event
+#[derive(Debug, PartialEq, Eq)]
+pub enum Handled<M> {
+    Ignored,
+    /// Something changed, the widget must be drawn again
+    Redraw,
+    Message(M),
+}
+
+pub trait Widget {
+    type Message;
+
+    /// The size the widget needs, in pixels
+    fn size(&self) -> (usize, usize);
+
+    fn draw(&self, canvas: &mut Canvas);
+
+    /// Pointer coordinates are relative to the widget.
+    fn handle(&mut self, event: &InputEvent) -> Handled<Self::Message>;
+}
+
+/// Whether the event is a key being pressed (or repeated)
+fn pressed(event: &InputEvent) -> Option<KeyCode> {
+    match event {
+        InputEvent::Key(key) if key.state != KeyState::Up => Some(key.code),
+        _ => None,
+    }
+}
+
+/// A line of text
+pub struct Text<M> {
+    pub text: String,
+    _message: core::marker::PhantomData<M>,
+}
+
+impl<M> Text<M> {
+    pub fn new(text: &str) -> Text<M> {
+        Text {
+            text: String::from(text),
+            _message: core::marker::PhantomData,
+        }
+    }
+}
+
+impl<M> Widget for Text<M> {
+    type Message = M;
+
+    fn size(&self) -> (usize, usize) {
+        let width = self.text.chars().count() * CHAR_WIDTH;
+        (width + 2 * PADDING, CHAR_HEIGHT + 2 * PADDING)
+    }
+
+    fn draw(&self, canvas: &mut Canvas) {
+        canvas.text(PADDING, PADDING, &self.text, FOREGROUND);
+    }
+
+    fn handle(&mut self, _event: &InputEvent) -> Handled<M> {
+        Handled::Ignored
+    }
+}
+
+/// Sends its message when clicked, or when Enter or Space is pressed while
+/// it has the focus
+pub struct Button<M> {
+    label: String,
+    message: M,
+    /// The left button was pressed on it, and not released yet
+    pressed: bool,
+}
+
+impl<M: Clone> Button<M> {
+    pub fn new(label: &str, message: M) -> Button<M> {
+        Button {
+            label: String::from(label),
+            message,
+            pressed: false,
+        }
+    }
+}
+
+impl<M: Clone> Widget for Button<M> {
+    type Message = M;
+
+    fn size(&self) -> (usize, usize) {
+        let width = self.label.chars().count() * CHAR_WIDTH;
+        (width + 4 * PADDING, CHAR_HEIGHT + 4 * PADDING)
+    }
+
+    fn draw(&self, canvas: &mut Canvas) {
+        let (width, height) = (canvas.width(), canvas.height());
+        canvas.fill_rect(0, 0, width, height, FOREGROUND);
+        let face = if self.pressed { ACCENT } else { BUTTON };
+        let mut inside = canvas.sub(1, 1, width.saturating_sub(2), height.saturating_sub(2));
+        inside.fill(face);
+        inside.text(2 * PADDING - 1, 2 * PADDING - 1, &self.label, FOREGROUND);
+    }
+
+    fn handle(&mut self, event: &InputEvent) -> Handled<M> {
+        if let Some(KeyCode::Enter) | Some(KeyCode::Spacebar) = pressed(event) {
+            return Handled::Message(self.message.clone());
+        }
+        match *event {
+            InputEvent::Pointer { buttons, .. } => {
+                let down = buttons.contains(Buttons::LEFT);
+                if down == self.pressed {
+                    return Handled::Ignored;
+                }
+                self.pressed = down;
+                if down {
+                    Handled::Redraw
+                } else {
+                    Handled::Message(self.message.clone())
+                }
+            }
+            _ => Handled::Ignored,
+        }
+    }
+}
+
+/// Items to choose from, with the arrow keys or the pointer
+///
+/// Sends the index of the item chosen with Enter, or clicked.
+pub struct List {
+    pub items: Vec<String>,
+    pub selected: usize,
+    /// Lines that are always shown
+    lines: usize,
+    was_down: bool,
+}
+
+impl List {
+    pub fn new(items: Vec<String>, lines: usize) -> List {
+        List {
+            items,
+            selected: 0,
+            lines,
+            was_down: false,
+        }
+    }
+
+    fn line_height() -> usize {
+        CHAR_HEIGHT + PADDING
+    }
+}
+
+impl Widget for List {
+    type Message = usize;
+
+    fn size(&self) -> (usize, usize) {
+        let longest = self.items.iter().map(|item| item.chars().count());
+        let width = longest.max().unwrap_or(0) * CHAR_WIDTH + 2 * PADDING;
+        (width, self.lines * List::line_height())
+    }
+
+    fn draw(&self, canvas: &mut Canvas) {
+        canvas.fill(Color::WHITE);
+        let visible = (canvas.height() / List::line_height()).max(1);
+        // keep the selected item in view
+        let first = (self.selected + 1).saturating_sub(visible);
+        let items = self.items.iter().enumerate().skip(first).take(visible);
+        for (line, (i, item)) in items.enumerate() {
+            let y = line * List::line_height();
+            let color = if i == self.selected {
+                let width = canvas.width();
+                canvas.fill_rect(0, y, width, List::line_height(), ACCENT);
+                Color::WHITE
+            } else {
+                FOREGROUND
+            };
+            canvas.text(PADDING, y + PADDING / 2, item, color);
+        }
+    }
+
+    fn handle(&mut self, event: &InputEvent) -> Handled<usize> {
+        if self.items.is_empty() {
+            return Handled::Ignored;
+        }
+        let last = self.items.len() - 1;
+        match pressed(event) {
+            Some(KeyCode::ArrowUp) if self.selected > 0 => {
+                self.selected -= 1;
+                return Handled::Redraw;
+            }
+            Some(KeyCode::ArrowDown) if self.selected < last => {
+                self.selected += 1;
+                return Handled::Redraw;
+            }
+            Some(KeyCode::Enter) => return Handled::Message(self.selected),
+            _ => {}
+        }
+        if let InputEvent::Pointer { y, buttons, .. } = *event {
+            let down = buttons.contains(Buttons::LEFT);
+            let clicked = down && !self.was_down;
+            self.was_down = down;
+            if clicked {
+                // the list is scrolled like in `draw`
+                let visible = self.lines.max(1);
+                let first = (self.selected + 1).saturating_sub(visible);
+                let line = y.max(0) as usize / List::line_height();
+                self.selected = (first + line).min(last);
+                return Handled::Message(self.selected);
+            }
+        }
+        Handled::Ignored
+    }
+}
+
+/// Widgets on top of each other
+///
+/// Keys go to the focused child, and Tab focuses the next one.
+pub struct Column<M> {
+    children: Vec<Box<dyn Widget<Message = M>>>,
+    focus: usize,
+}
+
+impl<M> Column<M> {
+    pub fn new() -> Column<M> {
+        Column {
+            children: Vec::new(),
+            focus: 0,
+        }
+    }
+
+    pub fn push(mut self, child: impl Widget<Message = M> + 'static) -> Column<M> {
+        self.children.push(Box::new(child));
+        self
+    }
+
+    /// The child at a height, and where it starts
+    fn child_at(&self, y: i32) -> Option<(usize, usize)> {
+        let mut top = 0;
+        for (i, child) in self.children.iter().enumerate() {
+            let height = child.size().1;
+            if y >= 0 && (y as usize) < top + height {
+                return Some((i, top));
+            }
+            top += height;
+        }
+        None
+    }
+}
+
+impl<M> Default for Column<M> {
+    fn default() -> Self {
+        Column::new()
+    }
+}
+
+impl<M> Widget for Column<M> {
+    type Message = M;
+
+    fn size(&self) -> (usize, usize) {
+        let sizes = self.children.iter().map(|child| child.size());
+        sizes.fold((0, 0), |(width, height), (w, h)| (width.max(w), height + h))
+    }
+
+    fn draw(&self, canvas: &mut Canvas) {
+        let mut top = 0;
+        for child in &self.children {
+            let (width, height) = child.size();
+            child.draw(&mut canvas.sub(0, top, width, height));
+            top += height;
+        }
+    }
+
+    fn handle(&mut self, event: &InputEvent) -> Handled<M> {
+        match *event {
+            InputEvent::Key(_) if pressed(event) == Some(KeyCode::Tab) => {
+                if !self.children.is_empty() {
+                    self.focus = (self.focus + 1) % self.children.len();
+                }
+                Handled::Ignored
+            }
+            InputEvent::Key(_) => match self.children.get_mut(self.focus) {
+                Some(child) => child.handle(event),
+                None => Handled::Ignored,
+            },
+            InputEvent::Pointer {
+                x,
+                y,
+                buttons,
+                wheel,
+            } => match self.child_at(y) {
+                Some((i, top)) => {
+                    if buttons.contains(Buttons::LEFT) {
+                        self.focus = i;
+                    }
+                    let event = InputEvent::Pointer {
+                        x,
+                        y: y - top as i32,
+                        buttons,
+                        wheel,
+                    };
+                    self.children[i].handle(&event)
+                }
+                None => Handled::Ignored,
+            },
+            InputEvent::Focus(_) => Handled::Ignored,
+        }
+    }
+}
+
+/// Draws a widget on a whole window.
+fn draw<W: Widget>(window: &mut WindowHandle, widget: &W) {
+    let mut canvas = window.canvas();
+    canvas.fill(BACKGROUND);
+    widget.draw(&mut canvas);
+    window.present(&[]);
+}
+
+/// Shows a widget in a window until it sends a message, and returns it.
+pub async fn show<W: Widget>(window: &mut WindowHandle, widget: &mut W) -> W::Message {
+    draw(window, widget);
+    loop {
+        let event = window.next_event().await;
+        match widget.handle(&event) {
+            Handled::Ignored => {}
+            Handled::Redraw => draw(window, widget),
+            Handled::Message(message) => {
+                // show the last changes, like a released button
+                draw(window, widget);
+                return message;
+            }
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! HID keyboards and mice, with the boot protocol
+//!
+//! The boot protocol has fixed report formats, so there is no need to parse
+//! report descriptors. The reports are turned into the same events as the ones
+//! of the PS/2 drivers.
+//!
+//! https://www.usb.org/sites/default/files/hid1_11.pdf (appendix B)
+
+use super::{InterfaceDescriptor, SetupPacket};
+use crate::input::keyboard;
+use crate::input::mouse::{self, Buttons, PointerEvent};
+use pc_keyboard::{KeyCode, KeyEvent, KeyState};
+
+pub const CLASS_HID: u8 = 3;
+pub const SUBCLASS_BOOT: u8 = 1;
+pub const PROTOCOL_KEYBOARD: u8 = 1;
+pub const PROTOCOL_MOUSE: u8 = 2;
+
+const REQUEST_SET_IDLE: u8 = 0x0a;
+const REQUEST_SET_PROTOCOL: u8 = 0x0b;
+
+/// Uses the boot protocol instead of the report protocol.
+pub fn set_boot_protocol(interface: u8) -> SetupPacket {
+    SetupPacket {
+        request_type: SetupPacket::CLASS | SetupPacket::TO_INTERFACE,
+        request: REQUEST_SET_PROTOCOL,
+        value: 0,
+        index: interface as u16,
+        length: 0,
+    }
+}
+
+/// Only sends reports when something changes.
+pub fn set_idle(interface: u8) -> SetupPacket {
+    SetupPacket {
+        request_type: SetupPacket::CLASS | SetupPacket::TO_INTERFACE,
+        request: REQUEST_SET_IDLE,
+        value: 0,
+        index: interface as u16,
+        length: 0,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Keyboard,
+    Mouse,
+}
+
+impl Kind {
+    pub fn of(interface: &InterfaceDescriptor) -> Option<Kind> {
+        match (interface.class, interface.sub_class, interface.protocol) {
+            (CLASS_HID, SUBCLASS_BOOT, PROTOCOL_KEYBOARD) => Some(Kind::Keyboard),
+            (CLASS_HID, SUBCLASS_BOOT, PROTOCOL_MOUSE) => Some(Kind::Mouse),
+            _ => None,
+        }
+    }
+}
+
+/// Keys of the modifier byte of keyboard reports, by bit
+const MODIFIER_KEYS: [KeyCode; 8] = [
+    KeyCode::ControlLeft,
+    KeyCode::ShiftLeft,
+    KeyCode::AltLeft,
+    KeyCode::WindowsLeft,
+    KeyCode::ControlRight,
+    KeyCode::ShiftRight,
+    KeyCode::AltRight,
+    KeyCode::WindowsRight,
+];
+
+/// Sent in all the key slots when too many keys are pressed
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+/// What we remember about a device between two reports
+pub enum Device {
+    Keyboard { previous: [u8; 8] },
+    Mouse,
+}
+
+impl Device {
+    pub fn new(kind: Kind) -> Device {
+        match kind {
+            Kind::Keyboard => Device::Keyboard { previous: [0; 8] },
+            Kind::Mouse => Device::Mouse,
+        }
+    }
+
+    pub fn handle_report(&mut self, report: &[u8]) {
+        match self {
+            Device::Keyboard { previous } => {
+                if report.len() < 8 {
+                    return;
+                }
+                let mut current = [0; 8];
+                current.copy_from_slice(&report[..8]);
+                if current[2..].contains(&ERROR_ROLL_OVER) {
+                    return;
+                }
+                for (code, state) in keyboard_changes(previous, &current) {
+                    keyboard::send_raw(KeyEvent::new(code, state));
+                }
+                *previous = current;
+            }
+            Device::Mouse => {
+                if let Some(event) = mouse_event(report) {
+                    mouse::send(event);
+                }
+            }
+        }
+    }
+}
+
+/// The keys that were pressed or released between two keyboard reports
+fn keyboard_changes<'a>(
+    previous: &'a [u8; 8],
+    current: &'a [u8; 8],
+) -> impl Iterator<Item = (KeyCode, KeyState)> + 'a {
+    let modifiers = (0..8).filter_map(move |bit| {
+        let mask = 1 << bit;
+        match (previous[0] & mask != 0, current[0] & mask != 0) {
+            (false, true) => Some((MODIFIER_KEYS[bit], KeyState::Down)),
+            (true, false) => Some((MODIFIER_KEYS[bit], KeyState::Up)),
+            _ => None,
+        }
+    });
+    let released = previous[2..]
+        .iter()
+        .filter(move |usage| !current[2..].contains(usage))
+        .filter_map(|&usage| Some((usage_to_key(usage)?, KeyState::Up)));
+    let pressed = current[2..]
+        .iter()
+        .filter(move |usage| !previous[2..].contains(usage))
+        .filter_map(|&usage| Some((usage_to_key(usage)?, KeyState::Down)));
+    modifiers.chain(released).chain(pressed)
+}
+
+fn mouse_event(report: &[u8]) -> Option<PointerEvent> {
+    if report.len() < 3 {
+        return None;
+    }
+    let buttons = [Buttons::LEFT, Buttons::RIGHT, Buttons::MIDDLE]
+        .iter()
+        .enumerate()
+        .filter(|(bit, _)| report[0] & (1 << bit) != 0)
+        .fold(Buttons::NONE, |all, (_, button)| all | *button);
+    Some(PointerEvent {
+        dx: report[1] as i8 as i32,
+        dy: report[2] as i8 as i32,
+        // USB counts the wheel the other way around
+        wheel: report.get(3).map_or(0, |&z| (z as i8).saturating_neg()),
+        buttons,
+    })
+}
+
+/// Converts a key usage (page 7 of the HID usage tables) to a key code.
+fn usage_to_key(usage: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    const LETTERS: [KeyCode; 26] = [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    ];
+    const DIGITS: [KeyCode; 10] = [Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0];
+    const FUNCTIONS: [KeyCode; 12] = [F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12];
+    const NUMPAD: [KeyCode; 10] = [
+        Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9, Numpad0,
+    ];
+
+    let key = match usage {
+        0x04..=0x1d => LETTERS[(usage - 0x04) as usize],
+        0x1e..=0x27 => DIGITS[(usage - 0x1e) as usize],
+        0x28 => Enter,
+        0x29 => Escape,
+        0x2a => Backspace,
+        0x2b => Tab,
+        0x2c => Spacebar,
+        0x2d => Minus,
+        0x2e => Equals,
+        0x2f => BracketSquareLeft,
+        0x30 => BracketSquareRight,
+        0x31 => BackSlash,
+        0x32 => HashTilde,
+        0x33 => SemiColon,
+        0x34 => Quote,
+        0x35 => BackTick,
+        0x36 => Comma,
+        0x37 => Fullstop,
+        0x38 => Slash,
+        0x39 => CapsLock,
+        0x3a..=0x45 => FUNCTIONS[(usage - 0x3a) as usize],
+        0x46 => PrintScreen,
+        0x47 => ScrollLock,
+        0x48 => PauseBreak,
+        0x49 => Insert,
+        0x4a => Home,
+        0x4b => PageUp,
+        0x4c => Delete,
+        0x4d => End,
+        0x4e => PageDown,
+        0x4f => ArrowRight,
+        0x50 => ArrowLeft,
+        0x51 => ArrowDown,
+        0x52 => ArrowUp,
+        0x53 => NumpadLock,
+        0x54 => NumpadSlash,
+        0x55 => NumpadStar,
+        0x56 => NumpadMinus,
+        0x57 => NumpadPlus,
+        0x58 => NumpadEnter,
+        0x59..=0x62 => NUMPAD[(usage - 0x59) as usize],
+        0x63 => NumpadPeriod,
+        0x64 => BackSlash,
+        0x65 => Menus,
+        _ => return None,
+    };
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn keyboard_report_changes() {
+        // left shift and A
+        let previous = [0x02, 0, 0x04, 0, 0, 0, 0, 0];
+        // A released, B pressed
+        let current = [0x02, 0, 0x05, 0, 0, 0, 0, 0];
+        let mut changes = keyboard_changes(&previous, &current);
+        assert!(changes.next() == Some((KeyCode::A, KeyState::Up)));
+        assert!(changes.next() == Some((KeyCode::B, KeyState::Down)));
+        assert!(changes.next().is_none());
+    }
+}
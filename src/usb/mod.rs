@@ -0,0 +1,194 @@
+//! USB
+//!
+//! Only xHCI controllers are supported, and only HID devices (keyboards and
+//! mice, with the boot protocol) are driven.
+//!
+//! https://wiki.osdev.org/Universal_Serial_Bus
+
+use alloc::vec::Vec;
+
+pub mod hid;
+pub mod xhci;
+
+pub const DESCRIPTOR_DEVICE: u8 = 1;
+pub const DESCRIPTOR_CONFIGURATION: u8 = 2;
+pub const DESCRIPTOR_INTERFACE: u8 = 4;
+pub const DESCRIPTOR_ENDPOINT: u8 = 5;
+
+pub const REQUEST_GET_DESCRIPTOR: u8 = 6;
+pub const REQUEST_SET_CONFIGURATION: u8 = 9;
+
+/// The first stage of a control transfer
+#[derive(Clone, Copy, Debug)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    /// Device to host
+    pub const IN: u8 = 0x80;
+    pub const CLASS: u8 = 0x20;
+    pub const TO_INTERFACE: u8 = 0x01;
+
+    pub fn get_descriptor(ty: u8, index: u8, length: u16) -> SetupPacket {
+        SetupPacket {
+            request_type: SetupPacket::IN,
+            request: REQUEST_GET_DESCRIPTOR,
+            value: ((ty as u16) << 8) | index as u16,
+            index: 0,
+            length,
+        }
+    }
+
+    pub fn set_configuration(value: u8) -> SetupPacket {
+        SetupPacket {
+            request_type: 0,
+            request: REQUEST_SET_CONFIGURATION,
+            value: value as u16,
+            index: 0,
+            length: 0,
+        }
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.request_type & SetupPacket::IN != 0
+    }
+
+    /// The packet, as it is sent on the bus
+    pub fn as_u64(&self) -> u64 {
+        self.request_type as u64
+            | (self.request as u64) << 8
+            | (self.value as u64) << 16
+            | (self.index as u64) << 32
+            | (self.length as u64) << 48
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub class: u8,
+    pub sub_class: u8,
+    pub protocol: u8,
+    pub max_packet_size: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub const SIZE: usize = 18;
+
+    pub fn parse(bytes: &[u8]) -> Option<DeviceDescriptor> {
+        if bytes.len() < DeviceDescriptor::SIZE || bytes[1] != DESCRIPTOR_DEVICE {
+            return None;
+        }
+        Some(DeviceDescriptor {
+            usb_version: u16::from_le_bytes([bytes[2], bytes[3]]),
+            class: bytes[4],
+            sub_class: bytes[5],
+            protocol: bytes[6],
+            max_packet_size: bytes[7],
+            vendor_id: u16::from_le_bytes([bytes[8], bytes[9]]),
+            product_id: u16::from_le_bytes([bytes[10], bytes[11]]),
+            configurations: bytes[17],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct EndpointDescriptor {
+    /// Number in the low bits, direction in the high bit (set for IN)
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub const TRANSFER_INTERRUPT: u8 = 3;
+
+    pub fn number(&self) -> u8 {
+        self.address & 0xf
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.address & 0x80 != 0
+    }
+
+    pub fn transfer_type(&self) -> u8 {
+        self.attributes & 0b11
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub alternate: u8,
+    pub class: u8,
+    pub sub_class: u8,
+    pub protocol: u8,
+    pub endpoints: Vec<EndpointDescriptor>,
+}
+
+/// A configuration descriptor, with the descriptors that follow it
+#[derive(Clone, Debug)]
+pub struct ConfigurationDescriptor {
+    pub total_length: u16,
+    pub value: u8,
+    pub interfaces: Vec<InterfaceDescriptor>,
+}
+
+impl ConfigurationDescriptor {
+    pub const HEADER_SIZE: usize = 9;
+
+    /// Parses the whole configuration (`total_length` bytes). Descriptors that
+    /// are not interfaces or endpoints are ignored.
+    pub fn parse(bytes: &[u8]) -> Option<ConfigurationDescriptor> {
+        if bytes.len() < Self::HEADER_SIZE || bytes[1] != DESCRIPTOR_CONFIGURATION {
+            return None;
+        }
+        let mut config = ConfigurationDescriptor {
+            total_length: u16::from_le_bytes([bytes[2], bytes[3]]),
+            value: bytes[5],
+            interfaces: Vec::new(),
+        };
+
+        let end = bytes.len().min(config.total_length as usize);
+        let mut offset = bytes[0] as usize;
+        while offset + 2 <= end {
+            let len = bytes[offset] as usize;
+            if len < 2 || offset + len > end {
+                break;
+            }
+            let desc = &bytes[offset..offset + len];
+            match desc[1] {
+                DESCRIPTOR_INTERFACE if len >= 9 => config.interfaces.push(InterfaceDescriptor {
+                    number: desc[2],
+                    alternate: desc[3],
+                    class: desc[5],
+                    sub_class: desc[6],
+                    protocol: desc[7],
+                    endpoints: Vec::new(),
+                }),
+                DESCRIPTOR_ENDPOINT if len >= 7 => {
+                    if let Some(interface) = config.interfaces.last_mut() {
+                        interface.endpoints.push(EndpointDescriptor {
+                            address: desc[2],
+                            attributes: desc[3],
+                            max_packet_size: u16::from_le_bytes([desc[4], desc[5]]) & 0x7ff,
+                            interval: desc[6],
+                        });
+                    }
+                }
+                _ => {}
+            }
+            offset += len;
+        }
+        Some(config)
+    }
+}
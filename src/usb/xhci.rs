@@ -0,0 +1,888 @@
+//! xHCI (USB 3) host controller
+//!
+//! The controller is polled by [`run`] instead of using interrupts: HID devices
+//! send at most a report every few milliseconds, so polling is good enough, and
+//! it works even when the controller has no usable interrupt.
+//!
+//! All the structures shared with the controller (rings, contexts, buffers) are
+//! [`DmaPage`]s.
+//!
+//! https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/extensible-host-controler-interface-usb-xhci.pdf
+
+use super::hid::{self, Kind};
+use super::{ConfigurationDescriptor, DeviceDescriptor, EndpointDescriptor, SetupPacket};
+use crate::driver::{Driver, DriverFuture, Match};
+use crate::memory::{DmaPage, MEM_OFFSET};
+use crate::pci::{self, PciDevice};
+use crate::println;
+use crate::time::{self, Duration, Instant};
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use futures_util::future::FutureExt;
+use pci_types::PciAddress;
+
+// Capability registers
+const CAPLENGTH: u64 = 0x00;
+const HCSPARAMS1: u64 = 0x04;
+const HCSPARAMS2: u64 = 0x08;
+const HCCPARAMS1: u64 = 0x10;
+const DBOFF: u64 = 0x14;
+const RTSOFF: u64 = 0x18;
+
+// Operational registers
+const USBCMD: u64 = 0x00;
+const USBSTS: u64 = 0x04;
+const CRCR: u64 = 0x18;
+const DCBAAP: u64 = 0x30;
+const CONFIG: u64 = 0x38;
+const PORTSC: u64 = 0x400;
+
+const CMD_RUN: u32 = 1 << 0;
+const CMD_RESET: u32 = 1 << 1;
+const STS_HALTED: u32 = 1 << 0;
+const STS_NOT_READY: u32 = 1 << 11;
+
+const PORT_CONNECTED: u32 = 1 << 0;
+const PORT_ENABLED: u32 = 1 << 1;
+const PORT_RESET: u32 = 1 << 4;
+const PORT_RESET_CHANGE: u32 = 1 << 21;
+/// The status change bits, that are cleared by writing 1
+const PORT_CHANGES: u32 = 0x7f << 17;
+/// The bits to write back when changing a port, to keep their value
+const PORT_PRESERVE: u32 = 0x0e00_c200;
+
+// Interrupter 0, relative to the runtime registers
+const ERSTSZ: u64 = 0x28;
+const ERSTBA: u64 = 0x30;
+const ERDP: u64 = 0x38;
+const ERDP_BUSY: u64 = 1 << 3;
+
+/// Extended capability for the handoff from the BIOS
+const CAP_LEGACY: u32 = 1;
+const LEGACY_BIOS_OWNED: u32 = 1 << 16;
+const LEGACY_OS_OWNED: u32 = 1 << 24;
+
+// TRB types
+const TRB_NORMAL: u32 = 1;
+const TRB_SETUP: u32 = 2;
+const TRB_DATA: u32 = 3;
+const TRB_STATUS: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_DISABLE_SLOT: u32 = 10;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_TRANSFER_EVENT: u32 = 32;
+const TRB_COMMAND_COMPLETION: u32 = 33;
+const TRB_PORT_STATUS_CHANGE: u32 = 34;
+
+// TRB control bits
+const TRB_CYCLE: u32 = 1 << 0;
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+const TRB_SHORT_PACKET: u32 = 1 << 2;
+const TRB_INTERRUPT_ON_COMPLETION: u32 = 1 << 5;
+const TRB_IMMEDIATE_DATA: u32 = 1 << 6;
+const TRB_DIR_IN: u32 = 1 << 16;
+
+const COMPLETION_SUCCESS: u8 = 1;
+const COMPLETION_SHORT_PACKET: u8 = 13;
+
+// Port speeds
+const SPEED_FULL: u8 = 1;
+const SPEED_LOW: u8 = 2;
+const SPEED_HIGH: u8 = 3;
+
+// Endpoint types, in endpoint contexts
+const EP_CONTROL: u32 = 4;
+const EP_INTERRUPT_IN: u32 = 7;
+
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(1);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Transfer Request Block, the unit of all the rings
+#[derive(Clone, Copy, Debug, Default)]
+#[repr(C)]
+struct Trb {
+    parameter: u64,
+    status: u32,
+    control: u32,
+}
+
+impl Trb {
+    fn new(ty: u32, parameter: u64, status: u32, control: u32) -> Trb {
+        Trb {
+            parameter,
+            status,
+            control: ty << 10 | control,
+        }
+    }
+
+    fn ty(&self) -> u32 {
+        (self.control >> 10) & 0x3f
+    }
+
+    fn completion_code(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    fn is_success(&self) -> bool {
+        matches!(
+            self.completion_code(),
+            COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET
+        )
+    }
+
+    fn slot(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+
+    /// Device context index of the endpoint, for transfer events
+    fn endpoint(&self) -> u8 {
+        ((self.control >> 16) & 0x1f) as u8
+    }
+
+    /// Bytes that were not transferred, for transfer events
+    fn residual(&self) -> usize {
+        (self.status & 0xff_ffff) as usize
+    }
+}
+
+/// A command or transfer ring: TRBs produced by the driver
+struct Ring {
+    page: DmaPage,
+    enqueue: usize,
+    cycle: bool,
+}
+
+impl Ring {
+    /// Number of TRBs, including the link back to the start
+    const LEN: usize = DmaPage::SIZE / 16;
+
+    fn new() -> Option<Ring> {
+        let ring = Ring {
+            page: DmaPage::new()?,
+            enqueue: 0,
+            cycle: true,
+        };
+        let link = Trb::new(TRB_LINK, ring.page.phys(), 0, TRB_TOGGLE_CYCLE);
+        unsafe { ring.trb(Ring::LEN - 1).write_volatile(link) };
+        Some(ring)
+    }
+
+    fn trb(&self, index: usize) -> *mut Trb {
+        unsafe { self.page.as_ptr::<Trb>().add(index) }
+    }
+
+    /// The value for the dequeue pointer of the controller
+    fn dequeue_pointer(&self) -> u64 {
+        self.page.phys() | self.cycle as u64
+    }
+
+    /// Adds a TRB to the ring, and returns its physical address.
+    fn push(&mut self, trb: Trb) -> u64 {
+        let cycle = self.cycle as u32;
+        let ptr = self.trb(self.enqueue);
+        unsafe {
+            core::ptr::addr_of_mut!((*ptr).parameter).write_volatile(trb.parameter);
+            core::ptr::addr_of_mut!((*ptr).status).write_volatile(trb.status);
+            // the controller owns the TRB as soon as the cycle bit is written
+            fence(Ordering::SeqCst);
+            core::ptr::addr_of_mut!((*ptr).control)
+                .write_volatile(trb.control & !TRB_CYCLE | cycle);
+        }
+        let phys = self.page.phys() + (self.enqueue * 16) as u64;
+
+        self.enqueue += 1;
+        if self.enqueue == Ring::LEN - 1 {
+            let link = self.trb(Ring::LEN - 1);
+            unsafe {
+                let control = core::ptr::addr_of!((*link).control).read_volatile();
+                core::ptr::addr_of_mut!((*link).control)
+                    .write_volatile(control & !TRB_CYCLE | cycle);
+            }
+            self.enqueue = 0;
+            self.cycle = !self.cycle;
+        }
+        phys
+    }
+}
+
+/// The event ring: TRBs produced by the controller
+struct EventRing {
+    segment: DmaPage,
+    /// The segment table, with a single entry
+    table: DmaPage,
+    dequeue: usize,
+    cycle: bool,
+}
+
+impl EventRing {
+    const LEN: usize = DmaPage::SIZE / 16;
+
+    fn new() -> Option<EventRing> {
+        let ring = EventRing {
+            segment: DmaPage::new()?,
+            table: DmaPage::new()?,
+            dequeue: 0,
+            cycle: true,
+        };
+        unsafe {
+            let entry = ring.table.as_ptr::<u64>();
+            entry.write_volatile(ring.segment.phys());
+            entry.add(1).write_volatile(EventRing::LEN as u64);
+        }
+        Some(ring)
+    }
+
+    fn dequeue_pointer(&self) -> u64 {
+        self.segment.phys() + (self.dequeue * 16) as u64
+    }
+
+    /// Takes the next event, if the controller wrote one.
+    fn pop(&mut self) -> Option<Trb> {
+        let ptr = unsafe { self.segment.as_ptr::<Trb>().add(self.dequeue) };
+        let trb = unsafe { ptr.read_volatile() };
+        if (trb.control & TRB_CYCLE != 0) != self.cycle {
+            return None;
+        }
+        self.dequeue += 1;
+        if self.dequeue == EventRing::LEN {
+            self.dequeue = 0;
+            self.cycle = !self.cycle;
+        }
+        Some(trb)
+    }
+}
+
+/// The interrupt IN endpoint of a HID interface
+struct Endpoint {
+    slot: u8,
+    /// Device context index
+    index: u8,
+    ring: Ring,
+    buffer: DmaPage,
+    max_packet_size: u16,
+    device: hid::Device,
+}
+
+/// A device that has an address
+struct Device {
+    slot: u8,
+    port: u8,
+    speed: u8,
+    /// Output device context, written by the controller
+    _context: DmaPage,
+    input: DmaPage,
+    control: Ring,
+    /// For the data stage of control transfers
+    buffer: DmaPage,
+}
+
+struct Controller {
+    /// Virtual addresses of the register sets
+    operational: u64,
+    runtime: u64,
+    doorbells: u64,
+    ports: u8,
+    /// Size of the contexts in bytes (32 or 64)
+    context_size: usize,
+    dcbaa: DmaPage,
+    _scratchpads: Vec<DmaPage>,
+    commands: Ring,
+    events: EventRing,
+    devices: Vec<Device>,
+    endpoints: Vec<Endpoint>,
+    /// Ports whose status changed, and that have to be (re)enumerated
+    changed_ports: Vec<u8>,
+}
+
+unsafe fn read32(addr: u64) -> u32 {
+    core::ptr::read_volatile(addr as *const u32)
+}
+
+unsafe fn write32(addr: u64, value: u32) {
+    core::ptr::write_volatile(addr as *mut u32, value);
+}
+
+unsafe fn write64(addr: u64, value: u64) {
+    write32(addr, value as u32);
+    write32(addr + 4, (value >> 32) as u32);
+}
+
+/// Waits until `done` returns true, for at most `timeout`.
+async fn wait_until(timeout: Duration, mut done: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !done() {
+        if Instant::now() > deadline {
+            return false;
+        }
+        time::sleep(Duration::from_millis(1)).await;
+    }
+    true
+}
+
+impl Controller {
+    /// Takes the controller from the firmware, resets it and starts it.
+    ///
+    /// `mmio` is the physical address of the registers (BAR 0).
+    async fn new(mmio: u64) -> Option<Controller> {
+        let base = MEM_OFFSET + mmio;
+        let (cap_length, params1, params2, cc_params1, db_offset, rt_offset) = unsafe {
+            (
+                read32(base + CAPLENGTH) & 0xff,
+                read32(base + HCSPARAMS1),
+                read32(base + HCSPARAMS2),
+                read32(base + HCCPARAMS1),
+                read32(base + DBOFF) & !0b11,
+                read32(base + RTSOFF) & !0x1f,
+            )
+        };
+
+        let mut controller = Controller {
+            operational: base + cap_length as u64,
+            runtime: base + rt_offset as u64,
+            doorbells: base + db_offset as u64,
+            ports: (params1 >> 24) as u8,
+            context_size: if cc_params1 & (1 << 2) != 0 { 64 } else { 32 },
+            dcbaa: DmaPage::new()?,
+            _scratchpads: Vec::new(),
+            commands: Ring::new()?,
+            events: EventRing::new()?,
+            devices: Vec::new(),
+            endpoints: Vec::new(),
+            changed_ports: Vec::new(),
+        };
+
+        let extended_caps = ((cc_params1 >> 16) << 2) as u64;
+        if extended_caps != 0 {
+            controller.take_ownership(base + extended_caps).await;
+        }
+
+        let op = controller.operational;
+        unsafe {
+            let command = read32(op + USBCMD);
+            write32(op + USBCMD, command & !CMD_RUN);
+        }
+        if !wait_until(
+            COMMAND_TIMEOUT,
+            || unsafe { read32(op + USBSTS) } & STS_HALTED != 0,
+        )
+        .await
+        {
+            println!("xHCI: the controller does not stop");
+            return None;
+        }
+        unsafe { write32(op + USBCMD, CMD_RESET) };
+        let reset = wait_until(COMMAND_TIMEOUT, || unsafe {
+            read32(op + USBCMD) & CMD_RESET == 0 && read32(op + USBSTS) & STS_NOT_READY == 0
+        })
+        .await;
+        if !reset {
+            println!("xHCI: the controller does not reset");
+            return None;
+        }
+
+        // the context array has room for 255 slots
+        let max_slots = params1 & 0xff;
+        let scratchpads = ((params2 >> 21) & 0x1f) << 5 | (params2 >> 27);
+        if scratchpads > 0 {
+            let array = DmaPage::new()?;
+            for i in 0..scratchpads as usize {
+                let page = DmaPage::new()?;
+                unsafe { array.as_ptr::<u64>().add(i).write_volatile(page.phys()) };
+                controller._scratchpads.push(page);
+            }
+            unsafe {
+                controller
+                    .dcbaa
+                    .as_ptr::<u64>()
+                    .write_volatile(array.phys())
+            };
+            controller._scratchpads.push(array);
+        }
+
+        let runtime = controller.runtime;
+        unsafe {
+            write32(op + CONFIG, max_slots);
+            write64(op + DCBAAP, controller.dcbaa.phys());
+            write64(op + CRCR, controller.commands.dequeue_pointer());
+
+            write32(runtime + ERSTSZ, 1);
+            write64(runtime + ERDP, controller.events.dequeue_pointer());
+            write64(runtime + ERSTBA, controller.events.table.phys());
+
+            write32(op + USBCMD, CMD_RUN);
+        }
+        if !wait_until(
+            COMMAND_TIMEOUT,
+            || unsafe { read32(op + USBSTS) } & STS_HALTED == 0,
+        )
+        .await
+        {
+            println!("xHCI: the controller does not start");
+            return None;
+        }
+
+        controller.changed_ports = (1..=controller.ports).rev().collect();
+        Some(controller)
+    }
+
+    /// Asks the BIOS to give up the controller, if it uses it to emulate a
+    /// PS/2 keyboard.
+    async fn take_ownership(&self, mut cap: u64) {
+        loop {
+            let value = unsafe { read32(cap) };
+            if value & 0xff == CAP_LEGACY {
+                unsafe { write32(cap, value | LEGACY_OS_OWNED) };
+                let released = wait_until(
+                    COMMAND_TIMEOUT,
+                    || unsafe { read32(cap) } & LEGACY_BIOS_OWNED == 0,
+                )
+                .await;
+                if !released {
+                    println!("xHCI: the BIOS does not release the controller");
+                }
+                return;
+            }
+            let next = (value >> 8) & 0xff;
+            if next == 0 {
+                return;
+            }
+            cap += (next << 2) as u64;
+        }
+    }
+
+    fn ring_doorbell(&self, slot: u8, target: u8) {
+        fence(Ordering::SeqCst);
+        unsafe { write32(self.doorbells + slot as u64 * 4, target as u32) };
+    }
+
+    fn port_register(&self, port: u8) -> u64 {
+        self.operational + PORTSC + 0x10 * (port as u64 - 1)
+    }
+
+    /// Takes the next event, and tells the controller it was handled.
+    fn next_event(&mut self) -> Option<Trb> {
+        let event = self.events.pop()?;
+        let dequeue = self.events.dequeue_pointer() | ERDP_BUSY;
+        unsafe { write64(self.runtime + ERDP, dequeue) };
+        Some(event)
+    }
+
+    /// Handles the events that nobody is waiting for.
+    fn handle_event(&mut self, event: Trb) {
+        match event.ty() {
+            TRB_TRANSFER_EVENT => {
+                let endpoint = self
+                    .endpoints
+                    .iter_mut()
+                    .find(|ep| ep.slot == event.slot() && ep.index == event.endpoint());
+                if let Some(endpoint) = endpoint {
+                    if event.is_success() {
+                        let len = (endpoint.max_packet_size as usize)
+                            .saturating_sub(event.residual())
+                            .min(DmaPage::SIZE);
+                        let report = unsafe {
+                            core::slice::from_raw_parts(endpoint.buffer.as_ptr::<u8>(), len)
+                        };
+                        endpoint.device.handle_report(report);
+                    }
+                    let (slot, index) = (endpoint.slot, endpoint.index);
+                    endpoint.queue_transfer();
+                    self.ring_doorbell(slot, index);
+                }
+            }
+            TRB_PORT_STATUS_CHANGE => {
+                let port = (event.parameter >> 24) as u8;
+                if !self.changed_ports.contains(&port) {
+                    self.changed_ports.push(port);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Waits for an event that `matches` accepts. The other events are handled.
+    async fn wait_event(&mut self, matches: impl Fn(&Trb) -> bool) -> Option<Trb> {
+        let deadline = Instant::now() + COMMAND_TIMEOUT;
+        loop {
+            while let Some(event) = self.next_event() {
+                if matches(&event) {
+                    return Some(event);
+                }
+                self.handle_event(event);
+            }
+            if Instant::now() > deadline {
+                return None;
+            }
+            time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    /// Runs a command, and returns its completion event if it succeeded.
+    async fn command(&mut self, trb: Trb) -> Option<Trb> {
+        let address = self.commands.push(trb);
+        self.ring_doorbell(0, 0);
+        let event = self
+            .wait_event(|event| event.ty() == TRB_COMMAND_COMPLETION && event.parameter == address)
+            .await;
+        match event {
+            Some(event) if event.is_success() => Some(event),
+            Some(event) => {
+                println!(
+                    "xHCI: command {} failed with code {}",
+                    trb.ty(),
+                    event.completion_code()
+                );
+                None
+            }
+            None => {
+                println!("xHCI: command {} timed out", trb.ty());
+                None
+            }
+        }
+    }
+
+    /// Runs a control transfer on the default endpoint of a device. The data
+    /// is read from or written to the buffer of the device.
+    async fn control(&mut self, device: usize, setup: SetupPacket) -> Option<()> {
+        let dev = &mut self.devices[device];
+        let slot = dev.slot;
+        let length = setup.length as u32;
+        let (transfer_type, data_dir, status_dir) = match (length, setup.is_in()) {
+            (0, _) => (0, 0, TRB_DIR_IN),
+            (_, true) => (3, TRB_DIR_IN, 0),
+            (_, false) => (2, 0, TRB_DIR_IN),
+        };
+        dev.control.push(Trb::new(
+            TRB_SETUP,
+            setup.as_u64(),
+            8,
+            TRB_IMMEDIATE_DATA | transfer_type << 16,
+        ));
+        if length > 0 {
+            let buffer = dev.buffer.phys();
+            dev.control
+                .push(Trb::new(TRB_DATA, buffer, length, data_dir));
+        }
+        dev.control.push(Trb::new(
+            TRB_STATUS,
+            0,
+            0,
+            status_dir | TRB_INTERRUPT_ON_COMPLETION,
+        ));
+        self.ring_doorbell(slot, 1);
+
+        // only the status stage interrupts, unless a stage fails
+        let event = self
+            .wait_event(|event| {
+                event.ty() == TRB_TRANSFER_EVENT && event.slot() == slot && event.endpoint() == 1
+            })
+            .await?;
+        if !event.is_success() {
+            println!(
+                "xHCI: control transfer {} failed with code {}",
+                setup.request,
+                event.completion_code()
+            );
+            return None;
+        }
+        Some(())
+    }
+
+    fn buffer(&self, device: usize) -> &[u8] {
+        let buffer = &self.devices[device].buffer;
+        unsafe { core::slice::from_raw_parts(buffer.as_ptr::<u8>(), DmaPage::SIZE) }
+    }
+
+    /// A context of the input context of a device (0 is the input control
+    /// context, 1 the slot context, and 2 onwards the endpoint contexts)
+    fn input_context(&self, device: usize, index: usize) -> *mut u32 {
+        let input = &self.devices[device].input;
+        unsafe { input.as_ptr::<u8>().add(index * self.context_size) as *mut u32 }
+    }
+
+    /// Handles a connection or a disconnection on a port.
+    async fn port_changed(&mut self, port: u8) {
+        if port == 0 || port > self.ports {
+            return;
+        }
+        let register = self.port_register(port);
+        let status = unsafe { read32(register) };
+        unsafe { write32(register, status & PORT_PRESERVE | status & PORT_CHANGES) };
+
+        let connected = status & PORT_CONNECTED != 0;
+        let known = self.devices.iter().position(|dev| dev.port == port);
+        match (connected, known) {
+            (true, None) => {
+                if self.enumerate(port).await.is_none() {
+                    println!("xHCI: could not set up the device on port {}", port);
+                }
+            }
+            (false, Some(device)) => self.disconnect(device).await,
+            _ => {}
+        }
+    }
+
+    async fn disconnect(&mut self, device: usize) {
+        let slot = self.devices[device].slot;
+        // the pages of the device can only be reused once the slot is disabled
+        self.command(Trb::new(TRB_DISABLE_SLOT, 0, 0, (slot as u32) << 24))
+            .await;
+        unsafe {
+            self.dcbaa
+                .as_ptr::<u64>()
+                .add(slot as usize)
+                .write_volatile(0)
+        };
+        self.endpoints.retain(|ep| ep.slot != slot);
+        let dev = self.devices.remove(device);
+        println!("USB: device on port {} disconnected", dev.port);
+    }
+
+    /// Resets the port (USB 2 ports are only enabled by a reset), and returns
+    /// the speed of the device.
+    async fn reset_port(&self, port: u8) -> Option<u8> {
+        let register = self.port_register(port);
+        let status = unsafe { read32(register) };
+        if status & PORT_ENABLED == 0 {
+            unsafe { write32(register, status & PORT_PRESERVE | PORT_RESET) };
+            let done = || unsafe { read32(register) } & PORT_RESET_CHANGE != 0;
+            if !wait_until(COMMAND_TIMEOUT, done).await {
+                return None;
+            }
+            let status = unsafe { read32(register) };
+            unsafe { write32(register, status & PORT_PRESERVE | PORT_RESET_CHANGE) };
+        }
+        let status = unsafe { read32(register) };
+        if status & PORT_ENABLED == 0 {
+            return None;
+        }
+        Some(((status >> 10) & 0xf) as u8)
+    }
+
+    /// Gives an address to the device on the port, and drives its HID
+    /// interfaces.
+    async fn enumerate(&mut self, port: u8) -> Option<()> {
+        let speed = self.reset_port(port).await?;
+        let event = self.command(Trb::new(TRB_ENABLE_SLOT, 0, 0, 0)).await?;
+        let slot = event.slot();
+
+        let context = DmaPage::new()?;
+        unsafe {
+            let entry = self.dcbaa.as_ptr::<u64>().add(slot as usize);
+            entry.write_volatile(context.phys());
+        }
+        self.devices.push(Device {
+            slot,
+            port,
+            speed,
+            _context: context,
+            input: DmaPage::new()?,
+            control: Ring::new()?,
+            buffer: DmaPage::new()?,
+        });
+        let device = self.devices.len() - 1;
+
+        let max_packet_size = match speed {
+            SPEED_FULL | SPEED_LOW => 8,
+            SPEED_HIGH => 64,
+            _ => 512,
+        };
+        unsafe {
+            let control = self.input_context(device, 0);
+            // add the slot and the default endpoint
+            control.add(1).write_volatile(0b11);
+            let slot_context = self.input_context(device, 1);
+            slot_context.write_volatile((speed as u32) << 20 | 1 << 27);
+            slot_context.add(1).write_volatile((port as u32) << 16);
+            let ep0 = self.input_context(device, 2);
+            ep0.add(1)
+                .write_volatile(3 << 1 | EP_CONTROL << 3 | max_packet_size << 16);
+            let dequeue = self.devices[device].control.dequeue_pointer();
+            (ep0.add(2) as *mut u64).write_volatile(dequeue);
+            ep0.add(4).write_volatile(8);
+        }
+        let input = self.devices[device].input.phys();
+        let slot_bits = (slot as u32) << 24;
+        self.command(Trb::new(TRB_ADDRESS_DEVICE, input, 0, slot_bits))
+            .await?;
+
+        // the real packet size of the default endpoint is in the first 8
+        // bytes of the device descriptor
+        self.control(
+            device,
+            SetupPacket::get_descriptor(super::DESCRIPTOR_DEVICE, 0, 8),
+        )
+        .await?;
+        let actual = self.buffer(device)[7] as u32;
+        let full_speed = matches!(speed, SPEED_FULL | SPEED_LOW);
+        if full_speed && actual != 0 && actual != max_packet_size {
+            unsafe {
+                self.input_context(device, 0).add(1).write_volatile(0b10);
+                let ep0 = self.input_context(device, 2);
+                ep0.add(1)
+                    .write_volatile(3 << 1 | EP_CONTROL << 3 | actual << 16);
+            }
+            self.command(Trb::new(TRB_EVALUATE_CONTEXT, input, 0, slot_bits))
+                .await?;
+        }
+
+        let len = DeviceDescriptor::SIZE as u16;
+        self.control(
+            device,
+            SetupPacket::get_descriptor(super::DESCRIPTOR_DEVICE, 0, len),
+        )
+        .await?;
+        let descriptor = DeviceDescriptor::parse(self.buffer(device))?;
+        println!(
+            "USB: device {:04x}:{:04x} on port {}",
+            descriptor.vendor_id, descriptor.product_id, port
+        );
+
+        let header = ConfigurationDescriptor::HEADER_SIZE as u16;
+        let get_config = |len| SetupPacket::get_descriptor(super::DESCRIPTOR_CONFIGURATION, 0, len);
+        self.control(device, get_config(header)).await?;
+        let total = u16::from_le_bytes([self.buffer(device)[2], self.buffer(device)[3]]);
+        let total = total.min(DmaPage::SIZE as u16);
+        self.control(device, get_config(total)).await?;
+        let config = ConfigurationDescriptor::parse(&self.buffer(device)[..total as usize])?;
+
+        let interfaces: Vec<_> = config
+            .interfaces
+            .iter()
+            .filter(|interface| interface.alternate == 0)
+            .filter_map(|interface| {
+                let kind = Kind::of(interface)?;
+                let endpoint = interface.endpoints.iter().find(|ep| {
+                    ep.is_in() && ep.transfer_type() == EndpointDescriptor::TRANSFER_INTERRUPT
+                })?;
+                Some((interface.number, kind, *endpoint))
+            })
+            .collect();
+        if interfaces.is_empty() {
+            return Some(());
+        }
+
+        self.control(device, SetupPacket::set_configuration(config.value))
+            .await?;
+        for &(number, kind, endpoint) in &interfaces {
+            self.control(device, hid::set_boot_protocol(number)).await?;
+            // not all devices support it
+            self.control(device, hid::set_idle(number)).await;
+            self.add_endpoint(device, kind, &endpoint).await?;
+            println!("USB: {:?} on port {}", kind, port);
+        }
+        Some(())
+    }
+
+    /// Configures an interrupt IN endpoint, and starts polling it.
+    async fn add_endpoint(
+        &mut self,
+        device: usize,
+        kind: Kind,
+        desc: &EndpointDescriptor,
+    ) -> Option<()> {
+        let index = desc.number() * 2 + 1;
+        let speed = self.devices[device].speed;
+        let slot = self.devices[device].slot;
+        // in units of 125 µs, as a power of two
+        let interval = match speed {
+            SPEED_FULL | SPEED_LOW => {
+                let frames = desc.interval.max(1) as u32 * 8;
+                31 - frames.leading_zeros()
+            }
+            _ => desc.interval.clamp(1, 16) as u32 - 1,
+        };
+        let max_packet_size = desc.max_packet_size as u32;
+
+        let mut endpoint = Endpoint {
+            slot,
+            index,
+            ring: Ring::new()?,
+            buffer: DmaPage::new()?,
+            max_packet_size: desc.max_packet_size,
+            device: hid::Device::new(kind),
+        };
+
+        unsafe {
+            let control = self.input_context(device, 0);
+            control.write_volatile(0);
+            control.add(1).write_volatile(1 | 1 << index);
+            let slot_context = self.input_context(device, 1);
+            let entries = slot_context.read_volatile() >> 27;
+            let entries = entries.max(index as u32);
+            let route = slot_context.read_volatile() & 0x07ff_ffff;
+            slot_context.write_volatile(route | entries << 27);
+
+            let context = self.input_context(device, index as usize + 1);
+            context.write_volatile(interval << 16);
+            context
+                .add(1)
+                .write_volatile(3 << 1 | EP_INTERRUPT_IN << 3 | max_packet_size << 16);
+            (context.add(2) as *mut u64).write_volatile(endpoint.ring.dequeue_pointer());
+            context
+                .add(4)
+                .write_volatile(max_packet_size | max_packet_size << 16);
+        }
+        let input = self.devices[device].input.phys();
+        let slot_bits = (slot as u32) << 24;
+        self.command(Trb::new(TRB_CONFIGURE_ENDPOINT, input, 0, slot_bits))
+            .await?;
+
+        endpoint.queue_transfer();
+        self.ring_doorbell(slot, index);
+        self.endpoints.push(endpoint);
+        Some(())
+    }
+}
+
+impl Endpoint {
+    /// Asks for the next report.
+    fn queue_transfer(&mut self) {
+        let trb = Trb::new(
+            TRB_NORMAL,
+            self.buffer.phys(),
+            self.max_packet_size as u32,
+            TRB_INTERRUPT_ON_COMPLETION | TRB_SHORT_PACKET,
+        );
+        self.ring.push(trb);
+    }
+}
+
+pub static DRIVER: Driver = Driver {
+    name: "xhci",
+    matches: &[Match::Class {
+        class: 0x0c,
+        sub_class: 0x03,
+        interface: Some(0x30),
+    }],
+    start,
+};
+
+fn start(address: PciAddress, device: &PciDevice) -> Option<DriverFuture> {
+    let mmio = device.bar_address(0)?;
+    pci::enable_bus_master(pci::ACCESS.lock().as_ref()?, address);
+    Some(run(mmio).boxed_local())
+}
+
+/// Drives the controller whose registers are at `mmio` (physical address):
+/// enumerates the devices when they are connected, and gives the reports of
+/// HID devices to the input drivers.
+pub async fn run(mmio: u64) -> Result<(), &'static str> {
+    let mut controller = Controller::new(mmio)
+        .await
+        .ok_or("could not start the controller")?;
+    println!("xHCI: {} ports", controller.ports);
+
+    loop {
+        while let Some(port) = controller.changed_ports.pop() {
+            controller.port_changed(port).await;
+        }
+        while let Some(event) = controller.next_event() {
+            controller.handle_event(event);
+        }
+        time::sleep(POLL_INTERVAL).await;
+    }
+}
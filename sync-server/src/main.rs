@@ -0,0 +1,335 @@
+//! A stand-in for another computer, to try the `pull` and `push` commands of
+//! the shell: it keeps the objects that are pushed to it in memory, and gives
+//! them back when they are pulled.
+//!
+//! It speaks the protocol of `docs/sync-protocol.md`, but doesn't look inside
+//! values. With QEMU's user networking, the host is `10.0.2.2` for the guest.
+//!
+//! Usage: `cargo sync-server [<address>:<port>]`
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+const DEFAULT_ADDRESS: &str = "0.0.0.0:2779";
+
+const MAGIC: u16 = 0x0adb;
+const VERSION: u16 = 1;
+
+const HELLO: u8 = 1;
+const PULL: u8 = 2;
+const PUSH: u8 = 3;
+const TYPE: u8 = 4;
+const OBJECT: u8 = 5;
+const END: u8 = 6;
+const ERROR: u8 = 7;
+
+const DEF_PRIMITIVE: u8 = 0;
+const DEF_ARRAY: u8 = 1;
+const DEF_PRODUCT: u8 = 2;
+const DEF_SUM: u8 = 3;
+
+const MAX_MESSAGE_SIZE: usize = 16 * 1024;
+/// Types below this ID are the same everywhere
+const BUILTIN_END: u64 = 0x10;
+/// The ID of the first type that is pushed
+const FIRST_ID: u64 = 0x100;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Definition {
+    Primitive,
+    Array(u64),
+    Product(Vec<(String, u64)>),
+    Sum(Vec<(String, u64)>),
+}
+
+impl Definition {
+    /// The types it uses
+    fn uses(&self) -> Vec<u64> {
+        match self {
+            Definition::Primitive => Vec::new(),
+            Definition::Array(item) => vec![*item],
+            Definition::Product(items) | Definition::Sum(items) => {
+                items.iter().map(|(_, id)| *id).collect()
+            }
+        }
+    }
+
+    /// Changes the IDs of the types it uses.
+    fn map_ids(&self, map: impl Fn(u64) -> Option<u64>) -> Option<Definition> {
+        let named = |items: &[(String, u64)]| {
+            items
+                .iter()
+                .map(|(name, id)| Some((name.clone(), map(*id)?)))
+                .collect::<Option<Vec<_>>>()
+        };
+        Some(match self {
+            Definition::Primitive => Definition::Primitive,
+            Definition::Array(item) => Definition::Array(map(*item)?),
+            Definition::Product(items) => Definition::Product(named(items)?),
+            Definition::Sum(items) => Definition::Sum(named(items)?),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Type {
+    name: String,
+    definition: Definition,
+}
+
+/// The types and the objects that were pushed, the types by ID minus
+/// `FIRST_ID`
+#[derive(Default)]
+struct Store {
+    types: Vec<Type>,
+    /// The values of the objects, as they were received, by type
+    objects: BTreeMap<u64, Vec<Vec<u8>>>,
+}
+
+impl Store {
+    fn get(&self, id: u64) -> Option<&Type> {
+        self.types.get(id.checked_sub(FIRST_ID)? as usize)
+    }
+
+    fn find(&self, name: &str) -> Option<u64> {
+        let index = self.types.iter().position(|ty| ty.name == name)?;
+        Some(FIRST_ID + index as u64)
+    }
+
+    /// Adds the types that a type uses (and itself) to `ids`, the ones it
+    /// uses first.
+    fn dependencies(&self, id: u64, ids: &mut Vec<u64>) {
+        if id < BUILTIN_END || ids.contains(&id) {
+            return;
+        }
+        if let Some(ty) = self.get(id) {
+            for used in ty.definition.uses() {
+                self.dependencies(used, ids);
+            }
+        }
+        ids.push(id);
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(invalid("truncated message"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let mut x = [0; 2];
+        x.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_be_bytes(x))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut x = [0; 4];
+        x.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_be_bytes(x))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut x = [0; 8];
+        x.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_be_bytes(x))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| invalid("invalid UTF-8"))
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_string(buffer: &mut Vec<u8>, s: &str) {
+    buffer.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    buffer.extend_from_slice(s.as_bytes());
+}
+
+fn read_type(message: &mut Reader) -> io::Result<(u64, Type)> {
+    let id = message.u64()?;
+    let name = message.string()?;
+    let named = |message: &mut Reader| -> io::Result<Vec<(String, u64)>> {
+        (0..message.u32()?)
+            .map(|_| Ok((message.string()?, message.u64()?)))
+            .collect()
+    };
+    let definition = match message.u8()? {
+        DEF_PRIMITIVE => Definition::Primitive,
+        DEF_ARRAY => Definition::Array(message.u64()?),
+        DEF_PRODUCT => Definition::Product(named(message)?),
+        DEF_SUM => Definition::Sum(named(message)?),
+        _ => return Err(invalid("unknown kind of type")),
+    };
+    Ok((id, Type { name, definition }))
+}
+
+fn write_type(buffer: &mut Vec<u8>, id: u64, ty: &Type) {
+    buffer.extend_from_slice(&id.to_be_bytes());
+    write_string(buffer, &ty.name);
+    let named = |buffer: &mut Vec<u8>, kind, items: &[(String, u64)]| {
+        buffer.push(kind);
+        buffer.extend_from_slice(&(items.len() as u32).to_be_bytes());
+        for (name, id) in items {
+            write_string(buffer, name);
+            buffer.extend_from_slice(&id.to_be_bytes());
+        }
+    };
+    match &ty.definition {
+        Definition::Primitive => buffer.push(DEF_PRIMITIVE),
+        Definition::Array(item) => {
+            buffer.push(DEF_ARRAY);
+            buffer.extend_from_slice(&item.to_be_bytes());
+        }
+        Definition::Product(items) => named(buffer, DEF_PRODUCT, items),
+        Definition::Sum(items) => named(buffer, DEF_SUM, items),
+    }
+}
+
+fn send(stream: &mut TcpStream, kind: u8, payload: &[u8]) -> io::Result<()> {
+    let mut message = vec![kind];
+    message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    message.extend_from_slice(payload);
+    stream.write_all(&message)
+}
+
+fn receive(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 5];
+    stream.read_exact(&mut header)?;
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(invalid("message too large"));
+    }
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    Ok((header[0], payload))
+}
+
+/// Sends the objects of a type, and the types they need.
+fn pull(store: &Store, stream: &mut TcpStream, name: &str) -> io::Result<()> {
+    let id = match store.find(name) {
+        Some(id) => id,
+        None => return Err(invalid(&format!("unknown type {}", name))),
+    };
+    let mut ids = Vec::new();
+    store.dependencies(id, &mut ids);
+    for &id in &ids {
+        let mut message = Vec::new();
+        write_type(&mut message, id, store.get(id).unwrap());
+        send(stream, TYPE, &message)?;
+    }
+    let objects = store.objects.get(&id).map_or(&[][..], |objects| objects);
+    for value in objects {
+        let mut message = id.to_be_bytes().to_vec();
+        message.extend_from_slice(value);
+        send(stream, OBJECT, &message)?;
+    }
+    println!("Sent {} object(s) of {}", objects.len(), name);
+    send(stream, END, &[])
+}
+
+/// Receives types and objects, until the end of the push. The objects are
+/// only kept if everything was received.
+fn push(store: &mut Store, stream: &mut TcpStream) -> io::Result<()> {
+    // the IDs of the store, by ID of the client
+    let mut ids = BTreeMap::new();
+    let mut objects = Vec::new();
+    loop {
+        let (kind, message) = receive(stream)?;
+        let mut message = Reader { data: &message };
+        match kind {
+            TYPE => {
+                let (client_id, ty) = read_type(&mut message)?;
+                let map = |id| {
+                    if id < BUILTIN_END {
+                        Some(id)
+                    } else {
+                        ids.get(&id).copied()
+                    }
+                };
+                let definition = ty
+                    .definition
+                    .map_ids(map)
+                    .ok_or_else(|| invalid("a type uses a type that was not sent"))?;
+                let id = match store.find(&ty.name) {
+                    Some(id) if store.get(id).unwrap().definition == definition => id,
+                    Some(_) => return Err(invalid(&format!("{} has another definition", ty.name))),
+                    None => {
+                        store.types.push(Type {
+                            name: ty.name,
+                            definition,
+                        });
+                        FIRST_ID + store.types.len() as u64 - 1
+                    }
+                };
+                ids.insert(client_id, id);
+            }
+            OBJECT => {
+                let id = message.u64()?;
+                let id = *ids.get(&id).ok_or_else(|| invalid("unknown type"))?;
+                objects.push((id, message.data.to_vec()));
+            }
+            END => break,
+            _ => return Err(invalid("unexpected message")),
+        }
+    }
+    println!("Received {} object(s)", objects.len());
+    for (id, value) in objects {
+        store.objects.entry(id).or_default().push(value);
+    }
+    send(stream, END, &[])
+}
+
+fn handle(store: &mut Store, stream: &mut TcpStream) -> io::Result<()> {
+    let (kind, hello) = receive(stream)?;
+    let mut hello = Reader { data: &hello };
+    if kind != HELLO || hello.u16()? != MAGIC || hello.u16()? != VERSION {
+        return Err(invalid("not a client of the same version"));
+    }
+    let mut reply = MAGIC.to_be_bytes().to_vec();
+    reply.extend_from_slice(&VERSION.to_be_bytes());
+    send(stream, HELLO, &reply)?;
+
+    let (kind, request) = receive(stream)?;
+    match kind {
+        PULL => pull(store, stream, &Reader { data: &request }.string()?),
+        PUSH => push(store, stream),
+        _ => Err(invalid("unknown request")),
+    }
+}
+
+fn main() -> io::Result<()> {
+    let address = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_ADDRESS.to_string());
+    let listener = TcpListener::bind(&address)?;
+    println!("Listening on {}", address);
+    let mut store = Store::default();
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        if let Err(err) = handle(&mut store, &mut stream) {
+            println!("Error: {}", err);
+            let mut message = Vec::new();
+            write_string(&mut message, &err.to_string());
+            let _ = send(&mut stream, ERROR, &message);
+        }
+    }
+    Ok(())
+}